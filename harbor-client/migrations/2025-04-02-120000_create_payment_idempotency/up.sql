@@ -0,0 +1,6 @@
+CREATE TABLE payment_idempotency
+(
+    key          TEXT PRIMARY KEY NOT NULL,
+    operation_id TEXT             NOT NULL,
+    created_at   TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
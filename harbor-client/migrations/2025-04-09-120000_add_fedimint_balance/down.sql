@@ -0,0 +1 @@
+ALTER TABLE fedimint DROP COLUMN balance_msats;
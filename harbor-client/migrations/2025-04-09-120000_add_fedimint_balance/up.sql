@@ -0,0 +1 @@
+ALTER TABLE fedimint ADD COLUMN balance_msats BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE fedimint ADD COLUMN network TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE lightning_payments DROP COLUMN kind;
+ALTER TABLE lightning_receives DROP COLUMN kind;
+ALTER TABLE on_chain_payments DROP COLUMN kind;
+ALTER TABLE on_chain_receives DROP COLUMN kind;
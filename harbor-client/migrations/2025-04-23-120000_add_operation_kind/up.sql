@@ -0,0 +1,5 @@
+-- existing rows get the kind their table implies
+ALTER TABLE lightning_payments ADD COLUMN kind INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE lightning_receives ADD COLUMN kind INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE on_chain_payments ADD COLUMN kind INTEGER NOT NULL DEFAULT 3;
+ALTER TABLE on_chain_receives ADD COLUMN kind INTEGER NOT NULL DEFAULT 4;
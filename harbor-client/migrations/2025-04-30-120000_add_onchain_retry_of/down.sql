@@ -0,0 +1 @@
+ALTER TABLE on_chain_payments DROP COLUMN retry_of;
@@ -0,0 +1 @@
+ALTER TABLE on_chain_payments ADD COLUMN retry_of TEXT;
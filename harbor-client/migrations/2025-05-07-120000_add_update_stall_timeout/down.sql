@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN update_stall_timeout_secs;
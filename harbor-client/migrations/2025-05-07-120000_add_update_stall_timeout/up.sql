@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN update_stall_timeout_secs INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE fedimint ADD COLUMN spend_password_hash TEXT;
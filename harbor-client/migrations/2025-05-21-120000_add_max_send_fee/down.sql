@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN max_send_fee_sats;
@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN max_send_fee_sats INTEGER;
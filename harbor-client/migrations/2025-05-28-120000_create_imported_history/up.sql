@@ -0,0 +1,12 @@
+-- transactions imported from other wallets, kept apart from our own operations
+CREATE TABLE imported_history
+(
+    operation_id TEXT PRIMARY KEY NOT NULL,
+    fedimint_id  TEXT             NOT NULL,
+    kind         INTEGER          NOT NULL,
+    amount_msats BIGINT           NOT NULL,
+    fee_msats    BIGINT           NOT NULL,
+    txid         TEXT,
+    preimage     TEXT,
+    created_at   TIMESTAMP        NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE federation_config
+(
+    federation_id TEXT PRIMARY KEY NOT NULL,
+    config        TEXT             NOT NULL,
+    network       TEXT             NOT NULL,
+    created_at    TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
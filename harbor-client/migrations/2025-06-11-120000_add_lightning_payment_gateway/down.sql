@@ -0,0 +1,2 @@
+ALTER TABLE lightning_payments DROP COLUMN gateway_pubkey;
+ALTER TABLE lightning_payments DROP COLUMN gateway_alias;
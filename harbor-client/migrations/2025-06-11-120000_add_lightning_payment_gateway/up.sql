@@ -0,0 +1,2 @@
+ALTER TABLE lightning_payments ADD COLUMN gateway_alias TEXT;
+ALTER TABLE lightning_payments ADD COLUMN gateway_pubkey TEXT;
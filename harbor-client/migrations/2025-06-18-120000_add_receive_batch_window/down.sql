@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN receive_batch_window_secs;
@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN receive_batch_window_secs INTEGER;
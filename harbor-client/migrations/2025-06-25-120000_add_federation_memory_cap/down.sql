@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN federation_memory_cap_bytes;
@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN federation_memory_cap_bytes BIGINT;
@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN require_private_payments;
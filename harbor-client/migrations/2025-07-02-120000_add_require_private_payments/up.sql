@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN require_private_payments INTEGER NOT NULL DEFAULT 0;
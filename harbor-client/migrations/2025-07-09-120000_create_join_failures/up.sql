@@ -0,0 +1,8 @@
+-- failed attempts to join a federation, kept so they can be shown to support
+CREATE TABLE join_failures
+(
+    id               INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    invite_code_hash TEXT      NOT NULL,
+    error            TEXT      NOT NULL,
+    created_at       TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+ALTER TABLE profile DROP COLUMN dust_threshold_sats;
+ALTER TABLE profile DROP COLUMN include_pending_balance;
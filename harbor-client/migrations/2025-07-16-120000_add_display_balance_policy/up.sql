@@ -0,0 +1,2 @@
+ALTER TABLE profile ADD COLUMN include_pending_balance INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE profile ADD COLUMN dust_threshold_sats INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- key/value data integrations attach to an operation, stored as a JSON object
+CREATE TABLE operation_metadata
+(
+    operation_id TEXT PRIMARY KEY NOT NULL,
+    metadata     TEXT      NOT NULL,
+    updated_at   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN max_concurrent_subscriptions INTEGER;
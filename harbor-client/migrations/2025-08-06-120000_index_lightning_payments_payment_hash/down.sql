@@ -0,0 +1 @@
+DROP INDEX lightning_payments_payment_hash;
@@ -0,0 +1 @@
+CREATE INDEX lightning_payments_payment_hash ON lightning_payments (payment_hash);
@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN join_timeout_secs INTEGER;
@@ -0,0 +1 @@
+ALTER TABLE fedimint ADD COLUMN joined_at TIMESTAMP;
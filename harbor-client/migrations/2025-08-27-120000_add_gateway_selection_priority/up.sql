@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN gateway_selection_priority INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP TABLE history_totals;
+DROP TABLE archived_history;
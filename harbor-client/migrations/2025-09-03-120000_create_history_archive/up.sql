@@ -0,0 +1,23 @@
+-- finished transactions moved out of the history tables by pruning
+CREATE TABLE archived_history
+(
+    operation_id TEXT PRIMARY KEY NOT NULL,
+    mint         TEXT    NOT NULL,
+    kind         INTEGER NOT NULL,
+    amount_sats  BIGINT  NOT NULL,
+    txid         TEXT,
+    preimage     TEXT,
+    status       INTEGER NOT NULL,
+    timestamp    BIGINT  NOT NULL
+);
+
+-- what pruned transactions added up to, so totals survive pruning
+CREATE TABLE history_totals
+(
+    mint        TEXT    NOT NULL,
+    kind        INTEGER NOT NULL,
+    status      INTEGER NOT NULL,
+    count       BIGINT  NOT NULL,
+    amount_sats BIGINT  NOT NULL,
+    PRIMARY KEY (mint, kind, status)
+);
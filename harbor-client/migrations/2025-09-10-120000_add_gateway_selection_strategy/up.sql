@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN gateway_selection_strategy TEXT;
@@ -0,0 +1 @@
+ALTER TABLE fedimint ADD COLUMN preferred_gateway TEXT;
@@ -0,0 +1 @@
+ALTER TABLE profile DROP COLUMN federation_status_interval_secs;
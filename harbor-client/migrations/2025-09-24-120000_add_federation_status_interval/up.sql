@@ -0,0 +1 @@
+ALTER TABLE profile ADD COLUMN federation_status_interval_secs INTEGER;
@@ -0,0 +1,4 @@
+DROP TRIGGER update_timestamp_ecash_receives;
+DROP TRIGGER update_timestamp_ecash_payments;
+DROP TABLE ecash_receives;
+DROP TABLE ecash_payments;
@@ -0,0 +1,38 @@
+CREATE TABLE ecash_payments
+(
+    operation_id TEXT PRIMARY KEY NOT NULL,
+    fedimint_id  TEXT             NOT NULL REFERENCES fedimint (id),
+    amount_msats BIGINT           NOT NULL,
+    status       INTEGER          NOT NULL,
+    created_at   TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at   TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE ecash_receives
+(
+    operation_id TEXT PRIMARY KEY NOT NULL,
+    fedimint_id  TEXT             NOT NULL REFERENCES fedimint (id),
+    amount_msats BIGINT           NOT NULL,
+    status       INTEGER          NOT NULL,
+    created_at   TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at   TIMESTAMP        NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER update_timestamp_ecash_payments
+    AFTER UPDATE
+    ON ecash_payments
+    FOR EACH ROW
+BEGIN
+UPDATE ecash_payments
+SET updated_at = CURRENT_TIMESTAMP
+WHERE operation_id = OLD.operation_id;
+END;
+CREATE TRIGGER update_timestamp_ecash_receives
+    AFTER UPDATE
+    ON ecash_receives
+    FOR EACH ROW
+BEGIN
+UPDATE ecash_receives
+SET updated_at = CURRENT_TIMESTAMP
+WHERE operation_id = OLD.operation_id;
+END;
@@ -0,0 +1 @@
+DROP TABLE fedimint_kv;
@@ -0,0 +1,7 @@
+CREATE TABLE fedimint_kv
+(
+    fedimint_id TEXT NOT NULL REFERENCES fedimint (id),
+    key         BLOB NOT NULL,
+    value       BLOB NOT NULL,
+    PRIMARY KEY (fedimint_id, key)
+);
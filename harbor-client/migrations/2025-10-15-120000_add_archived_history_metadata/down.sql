@@ -0,0 +1 @@
+ALTER TABLE archived_history DROP COLUMN metadata;
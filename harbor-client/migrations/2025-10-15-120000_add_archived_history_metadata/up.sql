@@ -0,0 +1 @@
+ALTER TABLE archived_history ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
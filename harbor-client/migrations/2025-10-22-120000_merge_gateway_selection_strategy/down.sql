@@ -0,0 +1,2 @@
+ALTER TABLE profile DROP COLUMN manual_gateway;
+ALTER TABLE profile ADD COLUMN gateway_selection_strategy TEXT;
@@ -0,0 +1,4 @@
+-- the selection strategy is now part of the selection priority, only the manually
+-- chosen gateway needs storing on its own
+ALTER TABLE profile DROP COLUMN gateway_selection_strategy;
+ALTER TABLE profile ADD COLUMN manual_gateway TEXT;
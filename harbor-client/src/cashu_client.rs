@@ -2,12 +2,14 @@ use crate::db::DBConnection;
 use crate::fedimint_client::update_history;
 use crate::http::{make_get_request_tor, make_tor_request};
 use crate::{
-    CoreUIMsg, CoreUIMsgPacket, HarborCore, MintIdentifier, ReceiveSuccessMsg, SendSuccessMsg,
+    BalanceUpdates, CoreUIMsg, CoreUIMsgPacket, HarborCore, MintIdentifier, ReceiveSuccessMsg,
+    SendSuccessMsg, check_received_amount,
 };
 use async_trait::async_trait;
 use bitcoin::hex::FromHex;
 use cdk::amount::SplitTarget;
 use cdk::mint_url::MintUrl;
+use cdk::nuts::nut00::ProofsMethods;
 use cdk::nuts::{
     CheckStateRequest, CheckStateResponse, Id, KeySet, KeysResponse, KeysetResponse,
     MeltBolt11Request, MeltQuoteBolt11Request, MeltQuoteBolt11Response, MintBolt11Request,
@@ -185,6 +187,7 @@ impl MintConnector for TorMintConnector {
 
 pub fn spawn_lightning_payment_thread(
     mut sender: Sender<CoreUIMsgPacket>,
+    balance_updates: BalanceUpdates,
     client: Wallet,
     storage: Arc<dyn DBConnection + Send + Sync>,
     quote: MeltQuote,
@@ -204,8 +207,14 @@ pub fn spawn_lightning_payment_thread(
                 let params = if is_transfer {
                     SendSuccessMsg::Transfer
                 } else {
-                    SendSuccessMsg::Lightning { preimage }
+                    SendSuccessMsg::Lightning {
+                        preimage: Some(preimage),
+                    }
                 };
+                if let Err(e) = storage.set_lightning_payment_preimage(quote.id, preimage) {
+                    error!("Could not set preimage for lightning payment: {e}");
+                }
+
                 HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
                     .await;
 
@@ -214,19 +223,14 @@ pub fn spawn_lightning_payment_thread(
                     .await
                     .expect("failed to get balance")
                     .into();
-                HarborCore::send_msg(
-                    &mut sender,
-                    Some(msg_id),
-                    CoreUIMsg::MintBalanceUpdated {
-                        id: MintIdentifier::Cashu(client.mint_url.clone()),
-                        balance: Amount::from_sats(bal),
-                    },
-                )
-                .await;
-
-                if let Err(e) = storage.set_lightning_payment_preimage(quote.id, preimage) {
-                    error!("Could not set preimage for lightning payment: {e}");
-                }
+                balance_updates
+                    .send(
+                        &mut sender,
+                        Some(msg_id),
+                        MintIdentifier::Cashu(client.mint_url.clone()),
+                        Amount::from_sats(bal),
+                    )
+                    .await;
 
                 update_history(storage, msg_id, &mut sender).await;
             }
@@ -237,11 +241,11 @@ pub fn spawn_lightning_payment_thread(
                 } else {
                     CoreUIMsg::SendFailure(e.to_string())
                 };
-                HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
-
                 if let Err(e) = storage.mark_lightning_payment_as_failed(quote.id) {
                     error!("Could not mark lightning payment as failed: {e}");
                 }
+
+                HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
             }
         }
     });
@@ -249,6 +253,7 @@ pub fn spawn_lightning_payment_thread(
 
 pub fn spawn_lightning_receive_thread(
     mut sender: Sender<CoreUIMsgPacket>,
+    balance_updates: BalanceUpdates,
     client: Wallet,
     storage: Arc<dyn DBConnection + Send + Sync>,
     quote: MintQuote,
@@ -272,33 +277,51 @@ pub fn spawn_lightning_receive_thread(
             };
 
             if mint_quote_response.state == MintQuoteState::Paid {
-                client
+                let proofs = client
                     .mint(&quote.id, SplitTarget::default(), None)
                     .await
                     .expect("Failed to mint receive tokens");
 
+                // the mint may have issued a different amount than the quote was for
+                let requested = Amount::from_sats(quote.amount.into());
+                let received = match proofs.total_amount() {
+                    Ok(total) => Amount::from_sats(total.into()),
+                    Err(e) => {
+                        error!("Could not total minted proofs: {e}");
+                        requested
+                    }
+                };
+                check_received_amount(
+                    &storage,
+                    &mut sender,
+                    msg_id,
+                    quote.id.clone(),
+                    requested,
+                    received,
+                )
+                .await;
+
                 let params = if is_transfer {
                     ReceiveSuccessMsg::Transfer
                 } else {
                     ReceiveSuccessMsg::Lightning
                 };
-                HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::ReceiveSuccess(params))
-                    .await;
-
                 if let Err(e) = storage.mark_ln_receive_as_success(quote.id) {
                     error!("Could not mark lightning receive as success: {e}");
                 }
 
+                HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::ReceiveSuccess(params))
+                    .await;
+
                 let new_balance = client.total_balance().await.expect("Failed to get balance");
-                HarborCore::send_msg(
-                    &mut sender,
-                    Some(msg_id),
-                    CoreUIMsg::MintBalanceUpdated {
-                        id: MintIdentifier::Cashu(client.mint_url.clone()),
-                        balance: Amount::from_sats(new_balance.into()),
-                    },
-                )
-                .await;
+                balance_updates
+                    .send(
+                        &mut sender,
+                        Some(msg_id),
+                        MintIdentifier::Cashu(client.mint_url.clone()),
+                        Amount::from_sats(new_balance.into()),
+                    )
+                    .await;
 
                 update_history(storage, msg_id, &mut sender).await;
 
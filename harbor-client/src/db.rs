@@ -1,15 +1,21 @@
 #![allow(clippy::too_many_arguments)]
 
 use crate::db_models::mint_metadata::MintMetadata;
-use crate::db_models::transaction_item::TransactionItem;
+use crate::db_models::transaction_item::{PaymentRoute, TransactionItem};
 use crate::db_models::{
-    CashuMint, Fedimint, LightningPayment, LightningReceive, NewFedimint, NewProfile,
-    OnChainPayment, OnChainReceive, Profile,
+    ArchivedHistory, CashuMint, EcashPayment, EcashReceive, FederationConfig, FederationInfo,
+    Fedimint, FedimintKv, GatewayStats, HistoryItem, HistoryTotal, ImportedHistory, JoinFailure,
+    JoinedFederation, LightningPayment, LightningReceive, NewFedimint, NewProfile, OnChainPayment,
+    OnChainReceive, OperationKind, OperationMetadata, PaymentIdempotency, Profile, PrunePolicy,
+    history_retention,
 };
+use crate::fedimint_client::SelectionPriority;
 use crate::metadata::FederationMeta;
+use crate::{DisplayBalancePolicy, MintIdentifier};
 use anyhow::anyhow;
 use bip39::{Language, Mnemonic};
-use bitcoin::{Address, Txid};
+use bitcoin::hashes::sha256;
+use bitcoin::{Address, Network, Txid};
 use cdk::mint_url::MintUrl;
 use diesel::{
     SqliteConnection,
@@ -18,13 +24,16 @@ use diesel::{
 };
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use fedimint_core::Amount;
-use fedimint_core::config::FederationId;
+use fedimint_core::config::{ClientConfig, FederationId};
 use fedimint_core::invite_code::InviteCode;
+use fedimint_core::secp256k1::PublicKey;
 use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
 use log::{error, info};
 use rusqlite::{Connection, OpenFlags};
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use std::{sync::Arc, time::Duration};
+use zeroize::Zeroizing;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
@@ -86,6 +95,36 @@ pub trait DBConnection {
     // Sets the tor enabled flag
     fn set_tor_enabled(&self, enabled: bool) -> anyhow::Result<()>;
 
+    // Sets how long payment updates can stall before polling, None for the default
+    fn set_update_stall_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()>;
+
+    // Sets the most a lightning payment may pay in gateway fees, None for no limit
+    fn set_max_send_fee(&self, max_fee: Option<Amount>) -> anyhow::Result<()>;
+
+    // Sets how long after a receive further receives are reported together, None to turn it off
+    fn set_receive_batch_window(&self, window: Option<Duration>) -> anyhow::Result<()>;
+
+    // Sets how much federation data can be loaded before warning, None for the default
+    fn set_federation_memory_cap(&self, cap: Option<usize>) -> anyhow::Result<()>;
+
+    // Sets whether only gateways that support private payments may be used
+    fn set_require_private_payments(&self, required: bool) -> anyhow::Result<()>;
+
+    // Sets how balances are adjusted before they are shown
+    fn set_display_balance_policy(&self, policy: DisplayBalancePolicy) -> anyhow::Result<()>;
+
+    // Sets how many payment subscriptions may run at once, None for the default
+    fn set_max_concurrent_subscriptions(&self, limit: Option<usize>) -> anyhow::Result<()>;
+
+    // Sets how long a federation's config download may take when joining, None for the default
+    fn set_join_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()>;
+
+    // Sets how often federations' guardians are probed for their status, None for the default
+    fn set_federation_status_interval(&self, interval: Option<Duration>) -> anyhow::Result<()>;
+
+    // Sets what gateway selection optimizes for
+    fn set_gateway_selection_priority(&self, priority: SelectionPriority) -> anyhow::Result<()>;
+
     // Retrieves the mnemonic from the DB
     fn retrieve_mnemonic(&self) -> anyhow::Result<Mnemonic>;
 
@@ -98,8 +137,14 @@ pub trait DBConnection {
     // Removes a federation from the DB
     fn remove_federation(&self, f: FederationId) -> anyhow::Result<()>;
 
+    // Deletes a federation's data after it was left, its history is kept
+    fn purge_federation(&self, f: FederationId) -> anyhow::Result<()>;
+
     fn remove_cashu_mint(&self, f: &MintUrl) -> anyhow::Result<()>;
 
+    // Deletes a federation we have no data for, after joining it failed
+    fn delete_unjoined_federation(&self, f: FederationId) -> anyhow::Result<()>;
+
     // Sets a federation as active
     fn set_federation_active(&self, f: FederationId) -> anyhow::Result<()>;
 
@@ -112,8 +157,15 @@ pub trait DBConnection {
     // gets the federation data for a specific federation
     fn list_federations(&self) -> anyhow::Result<Vec<String>>;
 
+    // lists the joined federations with their name, network and last saved balance
+    // only reads storage, so it can be used before any client is loaded
+    fn list_joined_federations(&self) -> anyhow::Result<Vec<JoinedFederation>>;
+
     fn get_archived_fedimints(&self) -> anyhow::Result<Vec<MintMetadata>>;
 
+    // Gets when a federation was joined and first and last used, None if we don't know it
+    fn get_federation_info(&self, f: FederationId) -> anyhow::Result<Option<FederationInfo>>;
+
     fn list_cashu_mints(&self) -> anyhow::Result<Vec<String>>;
 
     fn list_archived_cashu_mints(&self) -> anyhow::Result<Vec<MintUrl>>;
@@ -125,6 +177,49 @@ pub trait DBConnection {
     // updates the federation data
     fn update_fedimint_data(&self, id: String, value: Vec<u8>) -> anyhow::Result<()>;
 
+    // gets the key/value pairs a federation client saved one by one
+    fn get_fedimint_kv(&self, id: String) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    // deletes a federation client's removed keys and saves its changed pairs in one transaction
+    fn write_fedimint_kv(
+        &self,
+        id: String,
+        deletes: Vec<Vec<u8>>,
+        upserts: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<()>;
+
+    // Gets the config saved when the federation was first joined
+    fn get_federation_config(&self, f: FederationId) -> anyhow::Result<Option<FederationConfig>>;
+
+    // Saves a federation's config so it can be rejoined without downloading it again
+    fn set_federation_config(
+        &self,
+        f: FederationId,
+        config: &ClientConfig,
+        network: Network,
+    ) -> anyhow::Result<()>;
+
+    // Gets the last balance saved for a federation
+    fn get_federation_balance(&self, f: FederationId) -> anyhow::Result<Option<Amount>>;
+
+    // Saves the latest balance for a federation
+    fn set_federation_balance(&self, f: FederationId, balance: Amount) -> anyhow::Result<()>;
+
+    // Gets the hash of a federation's spend password, if it has one
+    fn get_spend_password_hash(&self, f: FederationId) -> anyhow::Result<Option<String>>;
+
+    // Sets or clears the hash of a federation's spend password
+    fn set_spend_password_hash(&self, f: FederationId, hash: Option<String>) -> anyhow::Result<()>;
+
+    // Saves the gateway a federation's payments go through, so later payments use it too
+    fn set_preferred_gateway(&self, f: FederationId, gateway_id: PublicKey) -> anyhow::Result<()>;
+
+    // Gets the gateway a federation's payments went through last, if any
+    fn get_preferred_gateway(&self, f: FederationId) -> anyhow::Result<Option<PublicKey>>;
+
+    // Forgets every federation's preferred gateway, so the next payments select one again
+    fn clear_preferred_gateways(&self) -> anyhow::Result<()>;
+
     fn create_ln_receive(
         &self,
         operation_id: String,
@@ -137,6 +232,9 @@ pub trait DBConnection {
 
     fn mark_ln_receive_as_success(&self, operation_id: String) -> anyhow::Result<()>;
 
+    // Records the amount actually received, when it differs from the invoice
+    fn set_ln_receive_amount(&self, operation_id: String, amount: Amount) -> anyhow::Result<()>;
+
     fn mark_ln_receive_as_failed(&self, operation_id: String) -> anyhow::Result<()>;
 
     fn create_lightning_payment(
@@ -147,6 +245,7 @@ pub trait DBConnection {
         bolt11: Bolt11Invoice,
         amount: Amount,
         fee: Amount,
+        kind: OperationKind,
     ) -> anyhow::Result<()>;
 
     fn set_lightning_payment_preimage(
@@ -155,8 +254,22 @@ pub trait DBConnection {
         preimage: [u8; 32],
     ) -> anyhow::Result<()>;
 
+    // Records how a lightning payment was routed
+    fn set_lightning_payment_route(
+        &self,
+        operation_id: String,
+        route: PaymentRoute,
+    ) -> anyhow::Result<()>;
+
     fn mark_lightning_payment_as_failed(&self, operation_id: String) -> anyhow::Result<()>;
 
+    // Marks a lightning payment as successful when its preimage isn't known, like for LNv2
+    // payments, see set_lightning_payment_preimage otherwise
+    fn mark_lightning_payment_as_success(&self, operation_id: String) -> anyhow::Result<()>;
+
+    // Marks a failed lightning payment whose funds came back to us
+    fn mark_lightning_payment_as_refunded(&self, operation_id: String) -> anyhow::Result<()>;
+
     fn create_onchain_payment(
         &self,
         operation_id: String,
@@ -165,6 +278,7 @@ pub trait DBConnection {
         address: Address,
         amount_sats: u64,
         fee_sats: u64,
+        retry_of: Option<OperationId>,
     ) -> anyhow::Result<()>;
 
     fn set_onchain_payment_txid(&self, operation_id: String, txid: Txid) -> anyhow::Result<()>;
@@ -191,8 +305,80 @@ pub trait DBConnection {
 
     fn mark_onchain_receive_as_confirmed(&self, operation_id: String) -> anyhow::Result<()>;
 
+    // Records ecash spent as notes, pending until they are redeemed or reclaimed
+    fn create_ecash_payment(
+        &self,
+        operation_id: String,
+        fedimint_id: FederationId,
+        amount: Amount,
+    ) -> anyhow::Result<()>;
+
+    fn mark_ecash_payment_as_success(&self, operation_id: String) -> anyhow::Result<()>;
+
+    // Marks spent ecash as reclaimed, the recipient never redeemed it
+    fn mark_ecash_payment_as_failed(&self, operation_id: String) -> anyhow::Result<()>;
+
+    // Records ecash notes being reissued into our balance
+    fn create_ecash_receive(
+        &self,
+        operation_id: String,
+        fedimint_id: FederationId,
+        amount: Amount,
+    ) -> anyhow::Result<()>;
+
+    fn mark_ecash_receive_as_success(&self, operation_id: String) -> anyhow::Result<()>;
+
+    fn mark_ecash_receive_as_failed(&self, operation_id: String) -> anyhow::Result<()>;
+
+    // Gets the newest unused deposit address for a federation, if it was created within the window
+    fn get_unused_onchain_receive(
+        &self,
+        fedimint_id: FederationId,
+        window: Duration,
+    ) -> anyhow::Result<Option<OnChainReceive>>;
+
     fn get_transaction_history(&self) -> anyhow::Result<Vec<TransactionItem>>;
 
+    // Gets `limit` transactions of the history starting at `offset`, newest first, along with
+    // how many transactions there are in total. This only pages what is sent to the UI, the
+    // whole history is still read from the database to build each page
+    fn get_transaction_history_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<TransactionItem>, usize)>;
+
+    // Prunes finished history as the policy says, returning what was pruned
+    fn prune_history(&self, policy: &PrunePolicy) -> anyhow::Result<Vec<TransactionItem>>;
+
+    // Gets the transactions pruning moved to the archive, newest first
+    fn get_archived_history(&self) -> anyhow::Result<Vec<TransactionItem>>;
+
+    // Gets what pruned transactions added up to
+    fn get_history_totals(&self) -> anyhow::Result<Vec<HistoryTotal>>;
+
+    // Attaches key/value data for integrations to an operation, replacing what it had
+    fn set_operation_metadata(
+        &self,
+        operation_id: String,
+        metadata: BTreeMap<String, String>,
+    ) -> anyhow::Result<()>;
+
+    // Gets the data attached to an operation with set_operation_metadata
+    fn get_operation_metadata(
+        &self,
+        operation_id: String,
+    ) -> anyhow::Result<Option<BTreeMap<String, String>>>;
+
+    // Merges transactions from another wallet into the history, returns how many were new
+    fn import_history(&self, entries: Vec<HistoryItem>) -> anyhow::Result<usize>;
+
+    // Records a failed attempt to join a federation, without the invite code itself
+    fn record_join_failure(&self, invite_code: &InviteCode, error: &str) -> anyhow::Result<()>;
+
+    // Gets the recorded join failures, newest first
+    fn recent_join_failures(&self) -> anyhow::Result<Vec<JoinFailure>>;
+
     fn get_pending_onchain_receives(&self) -> anyhow::Result<Vec<OnChainReceive>>;
 
     fn get_pending_onchain_payments(&self) -> anyhow::Result<Vec<OnChainPayment>>;
@@ -205,6 +391,12 @@ pub trait DBConnection {
 
     fn get_onchain_payment(&self, operation_id: String) -> anyhow::Result<Option<OnChainPayment>>;
 
+    // Gets the withdrawal started as a retry of the given one, if any
+    fn get_onchain_payment_retry(
+        &self,
+        operation_id: String,
+    ) -> anyhow::Result<Option<OnChainPayment>>;
+
     fn get_lightning_receive(
         &self,
         operation_id: String,
@@ -215,6 +407,15 @@ pub trait DBConnection {
         operation_id: String,
     ) -> anyhow::Result<Option<LightningPayment>>;
 
+    // Gets our successful payment of the invoice with this payment hash, if any
+    fn get_paid_lightning_payment(
+        &self,
+        payment_hash: sha256::Hash,
+    ) -> anyhow::Result<Option<LightningPayment>>;
+
+    // Gets how our finished payments through each gateway went, by the gateway's node key
+    fn get_gateway_stats(&self) -> anyhow::Result<HashMap<PublicKey, GatewayStats>>;
+
     fn get_federation_metadata(&self, id: FederationId) -> anyhow::Result<Option<FederationMeta>>;
 
     fn upsert_federation_metadata(
@@ -222,6 +423,16 @@ pub trait DBConnection {
         id: FederationId,
         metadata: FederationMeta,
     ) -> anyhow::Result<()>;
+
+    // Gets the operation started for an idempotency key, if it was started within the window
+    fn get_idempotent_operation(
+        &self,
+        key: String,
+        window: Duration,
+    ) -> anyhow::Result<Option<String>>;
+
+    // Records the operation started for an idempotency key
+    fn set_idempotent_operation(&self, key: String, operation_id: String) -> anyhow::Result<()>;
 }
 
 pub struct SQLConnection {
@@ -258,6 +469,66 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn set_update_stall_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_update_stall_timeout(conn, timeout)?;
+        Ok(())
+    }
+
+    fn set_max_send_fee(&self, max_fee: Option<Amount>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_max_send_fee(conn, max_fee)?;
+        Ok(())
+    }
+
+    fn set_receive_batch_window(&self, window: Option<Duration>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_receive_batch_window(conn, window)?;
+        Ok(())
+    }
+
+    fn set_federation_memory_cap(&self, cap: Option<usize>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_federation_memory_cap(conn, cap)?;
+        Ok(())
+    }
+
+    fn set_join_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_join_timeout(conn, timeout)?;
+        Ok(())
+    }
+
+    fn set_federation_status_interval(&self, interval: Option<Duration>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_federation_status_interval(conn, interval)?;
+        Ok(())
+    }
+
+    fn set_gateway_selection_priority(&self, priority: SelectionPriority) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_gateway_selection_priority(conn, priority)?;
+        Ok(())
+    }
+
+    fn set_max_concurrent_subscriptions(&self, limit: Option<usize>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_max_concurrent_subscriptions(conn, limit)?;
+        Ok(())
+    }
+
+    fn set_require_private_payments(&self, required: bool) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_require_private_payments(conn, required)?;
+        Ok(())
+    }
+
+    fn set_display_balance_policy(&self, policy: DisplayBalancePolicy) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Profile::set_display_balance_policy(conn, policy)?;
+        Ok(())
+    }
+
     fn get_federation_value(&self, id: String) -> anyhow::Result<Option<Vec<u8>>> {
         let conn = &mut self.db.get()?;
         Fedimint::get_value(conn, id)
@@ -268,6 +539,31 @@ impl DBConnection for SQLConnection {
         Fedimint::get_ids(conn)
     }
 
+    fn list_joined_federations(&self) -> anyhow::Result<Vec<JoinedFederation>> {
+        let conn = &mut self.db.get()?;
+        Fedimint::get_joined(conn)
+    }
+
+    fn get_federation_info(&self, f: FederationId) -> anyhow::Result<Option<FederationInfo>> {
+        let Some(fedimint) = Fedimint::get(&mut self.db.get()?, f.to_string())? else {
+            return Ok(None);
+        };
+
+        let timestamps: Vec<u64> = self
+            .get_transaction_history()?
+            .into_iter()
+            .filter(|item| item.mint_identifier == MintIdentifier::Fedimint(f))
+            .map(|item| item.timestamp)
+            .collect();
+
+        Ok(Some(FederationInfo {
+            federation_id: f,
+            joined_at: fedimint.joined_at.map(|t| t.and_utc().timestamp() as u64),
+            first_transaction_at: timestamps.iter().min().copied(),
+            last_transaction_at: timestamps.iter().max().copied(),
+        }))
+    }
+
     fn insert_new_federation(&self, f: NewFedimint) -> anyhow::Result<Fedimint> {
         let conn = &mut self.db.get()?;
         f.insert(conn)
@@ -278,6 +574,73 @@ impl DBConnection for SQLConnection {
         Fedimint::update_value(conn, id, value)
     }
 
+    fn get_fedimint_kv(&self, id: String) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let conn = &mut self.db.get()?;
+        FedimintKv::get_all(conn, id)
+    }
+
+    fn write_fedimint_kv(
+        &self,
+        id: String,
+        deletes: Vec<Vec<u8>>,
+        upserts: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        FedimintKv::write(conn, id, deletes, upserts)
+    }
+
+    fn get_federation_config(&self, f: FederationId) -> anyhow::Result<Option<FederationConfig>> {
+        let conn = &mut self.db.get()?;
+        FederationConfig::get(conn, f)
+    }
+
+    fn set_federation_config(
+        &self,
+        f: FederationId,
+        config: &ClientConfig,
+        network: Network,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        FederationConfig::upsert(conn, f, config, network)
+    }
+
+    fn get_federation_balance(&self, f: FederationId) -> anyhow::Result<Option<Amount>> {
+        let conn = &mut self.db.get()?;
+        Ok(Fedimint::get(conn, f.to_string())?.and_then(|f| f.balance()))
+    }
+
+    fn set_federation_balance(&self, f: FederationId, balance: Amount) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::set_balance(conn, f.to_string(), balance)
+    }
+
+    fn get_spend_password_hash(&self, f: FederationId) -> anyhow::Result<Option<String>> {
+        let conn = &mut self.db.get()?;
+        Ok(Fedimint::get(conn, f.to_string())?.and_then(|f| f.spend_password_hash))
+    }
+
+    fn set_spend_password_hash(&self, f: FederationId, hash: Option<String>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::set_spend_password_hash(conn, f.to_string(), hash)
+    }
+
+    fn set_preferred_gateway(&self, f: FederationId, gateway_id: PublicKey) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::set_preferred_gateway(conn, f.to_string(), Some(gateway_id.to_string()))
+    }
+
+    fn get_preferred_gateway(&self, f: FederationId) -> anyhow::Result<Option<PublicKey>> {
+        let conn = &mut self.db.get()?;
+        Ok(Fedimint::get(conn, f.to_string())?
+            .and_then(|f| f.preferred_gateway)
+            .and_then(|id| PublicKey::from_str(&id).ok()))
+    }
+
+    fn clear_preferred_gateways(&self) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::clear_preferred_gateways(conn)
+    }
+
     fn set_federation_active(&self, f: FederationId) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
         Fedimint::set_active(conn, f.to_string())
@@ -330,6 +693,11 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn set_ln_receive_amount(&self, operation_id: String, amount: Amount) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        LightningReceive::set_amount(conn, operation_id, amount)
+    }
+
     fn mark_ln_receive_as_failed(&self, operation_id: String) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
 
@@ -346,6 +714,7 @@ impl DBConnection for SQLConnection {
         bolt11: Bolt11Invoice,
         amount: Amount,
         fee: Amount,
+        kind: OperationKind,
     ) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
 
@@ -357,6 +726,7 @@ impl DBConnection for SQLConnection {
             bolt11,
             amount,
             fee,
+            kind,
         )?;
 
         Ok(())
@@ -374,6 +744,15 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn set_lightning_payment_route(
+        &self,
+        operation_id: String,
+        route: PaymentRoute,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        LightningPayment::set_route(conn, operation_id, route)
+    }
+
     fn mark_lightning_payment_as_failed(&self, operation_id: String) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
 
@@ -382,6 +761,22 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn mark_lightning_payment_as_success(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        LightningPayment::mark_as_success(conn, operation_id)?;
+
+        Ok(())
+    }
+
+    fn mark_lightning_payment_as_refunded(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        LightningPayment::mark_as_refunded(conn, operation_id)?;
+
+        Ok(())
+    }
+
     fn create_onchain_receive(
         &self,
         operation_id: String,
@@ -404,6 +799,7 @@ impl DBConnection for SQLConnection {
         address: Address,
         amount_sats: u64,
         fee_sats: u64,
+        retry_of: Option<OperationId>,
     ) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
 
@@ -415,6 +811,7 @@ impl DBConnection for SQLConnection {
             address,
             amount_sats,
             fee_sats,
+            retry_of,
         )?;
 
         Ok(())
@@ -466,6 +863,74 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn create_ecash_payment(
+        &self,
+        operation_id: String,
+        fedimint_id: FederationId,
+        amount: Amount,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashPayment::create(conn, operation_id, fedimint_id, amount)?;
+
+        Ok(())
+    }
+
+    fn mark_ecash_payment_as_success(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashPayment::mark_as_success(conn, operation_id)?;
+
+        Ok(())
+    }
+
+    fn mark_ecash_payment_as_failed(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashPayment::mark_as_failed(conn, operation_id)?;
+
+        Ok(())
+    }
+
+    fn create_ecash_receive(
+        &self,
+        operation_id: String,
+        fedimint_id: FederationId,
+        amount: Amount,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashReceive::create(conn, operation_id, fedimint_id, amount)?;
+
+        Ok(())
+    }
+
+    fn mark_ecash_receive_as_success(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashReceive::mark_as_success(conn, operation_id)?;
+
+        Ok(())
+    }
+
+    fn mark_ecash_receive_as_failed(&self, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+
+        EcashReceive::mark_as_failed(conn, operation_id)?;
+
+        Ok(())
+    }
+
+    fn get_unused_onchain_receive(
+        &self,
+        fedimint_id: FederationId,
+        window: Duration,
+    ) -> anyhow::Result<Option<OnChainReceive>> {
+        let since = chrono::Utc::now().naive_utc() - chrono::Duration::from_std(window)?;
+        let conn = &mut self.db.get()?;
+        OnChainReceive::get_unused_since(conn, fedimint_id.to_string(), since)
+    }
+
     fn get_transaction_history(&self) -> anyhow::Result<Vec<TransactionItem>> {
         let conn = &mut self.db.get()?;
 
@@ -473,28 +938,58 @@ impl DBConnection for SQLConnection {
         let onchain_receives = OnChainReceive::get_history(conn)?;
         let lightning_payments = LightningPayment::get_history(conn)?;
         let lightning_receives = LightningReceive::get_history(conn)?;
+        let ecash_payments = EcashPayment::get_history(conn)?;
+        let ecash_receives = EcashReceive::get_history(conn)?;
+        let imported = ImportedHistory::get_history(conn)?;
+        let mut metadata = OperationMetadata::get_all(conn)?;
+        let mut with_metadata = |operation_id: &str, item: TransactionItem| TransactionItem {
+            metadata: metadata.remove(operation_id).unwrap_or_default(),
+            ..item
+        };
 
         let mut items: Vec<TransactionItem> = Vec::with_capacity(
             onchain_payments.len()
                 + onchain_receives.len()
                 + lightning_payments.len()
-                + lightning_receives.len(),
+                + lightning_receives.len()
+                + ecash_payments.len()
+                + ecash_receives.len()
+                + imported.len(),
         );
 
         for onchain_payment in onchain_payments {
-            items.push(onchain_payment.into());
+            let operation_id = onchain_payment.operation_id.clone();
+            items.push(with_metadata(&operation_id, onchain_payment.into()));
         }
 
         for onchain_receive in onchain_receives {
-            items.push(onchain_receive.into());
+            let operation_id = onchain_receive.operation_id.clone();
+            items.push(with_metadata(&operation_id, onchain_receive.into()));
         }
 
         for lightning_payment in lightning_payments {
-            items.push(lightning_payment.into());
+            let operation_id = lightning_payment.operation_id.clone();
+            items.push(with_metadata(&operation_id, lightning_payment.into()));
         }
 
         for lightning_receive in lightning_receives {
-            items.push(lightning_receive.into());
+            let operation_id = lightning_receive.operation_id.clone();
+            items.push(with_metadata(&operation_id, lightning_receive.into()));
+        }
+
+        for ecash_payment in ecash_payments {
+            let operation_id = ecash_payment.operation_id.clone();
+            items.push(with_metadata(&operation_id, ecash_payment.into()));
+        }
+
+        for ecash_receive in ecash_receives {
+            let operation_id = ecash_receive.operation_id.clone();
+            items.push(with_metadata(&operation_id, ecash_receive.into()));
+        }
+
+        for item in imported {
+            let operation_id = item.operation_id.clone();
+            items.push(with_metadata(&operation_id, item.into()));
         }
 
         // sort by timestamp so that the most recent items are at the top
@@ -503,24 +998,99 @@ impl DBConnection for SQLConnection {
         Ok(items)
     }
 
-    fn remove_federation(&self, f: FederationId) -> anyhow::Result<()> {
+    fn get_transaction_history_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<(Vec<TransactionItem>, usize)> {
+        // the history is spread over several tables with their own timestamps and status
+        // filters, so all of it is loaded and merged before it can be paged
+        let history = self.get_transaction_history()?;
+        let total = history.len();
+        let page = history.into_iter().skip(offset).take(limit).collect();
+        Ok((page, total))
+    }
+
+    fn prune_history(&self, policy: &PrunePolicy) -> anyhow::Result<Vec<TransactionItem>> {
         let conn = &mut self.db.get()?;
-        Fedimint::remove_federation(conn, f.to_string())?;
-        Ok(())
+        history_retention::prune(conn, policy)
     }
 
-    fn remove_cashu_mint(&self, f: &MintUrl) -> anyhow::Result<()> {
+    fn get_archived_history(&self) -> anyhow::Result<Vec<TransactionItem>> {
         let conn = &mut self.db.get()?;
-        CashuMint::remove_mint(conn, f.to_string())?;
-        Ok(())
+        Ok(ArchivedHistory::get_all(conn)?
+            .into_iter()
+            .map(TransactionItem::from)
+            .collect())
     }
 
-    fn retrieve_mnemonic(&self) -> anyhow::Result<Mnemonic> {
-        match self.get_seed()? {
-            Some(m) => {
-                info!("retrieved existing seed");
-                Ok(Mnemonic::from_str(&m)?)
-            }
+    fn get_history_totals(&self) -> anyhow::Result<Vec<HistoryTotal>> {
+        let conn = &mut self.db.get()?;
+        HistoryTotal::get_all(conn)
+    }
+
+    fn set_operation_metadata(
+        &self,
+        operation_id: String,
+        metadata: BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        OperationMetadata::set(conn, operation_id, &metadata)
+    }
+
+    fn get_operation_metadata(
+        &self,
+        operation_id: String,
+    ) -> anyhow::Result<Option<BTreeMap<String, String>>> {
+        let conn = &mut self.db.get()?;
+        OperationMetadata::get(conn, operation_id)
+    }
+
+    fn import_history(&self, entries: Vec<HistoryItem>) -> anyhow::Result<usize> {
+        let conn = &mut self.db.get()?;
+        ImportedHistory::import(conn, entries)
+    }
+
+    fn record_join_failure(&self, invite_code: &InviteCode, error: &str) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        JoinFailure::record(conn, invite_code, error)
+    }
+
+    fn recent_join_failures(&self) -> anyhow::Result<Vec<JoinFailure>> {
+        let conn = &mut self.db.get()?;
+        JoinFailure::recent(conn)
+    }
+
+    fn remove_federation(&self, f: FederationId) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::remove_federation(conn, f.to_string())?;
+        Ok(())
+    }
+
+    fn purge_federation(&self, f: FederationId) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::purge(conn, f.to_string())
+    }
+
+    fn delete_unjoined_federation(&self, f: FederationId) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Fedimint::delete_unjoined(conn, f.to_string())?;
+        Ok(())
+    }
+
+    fn remove_cashu_mint(&self, f: &MintUrl) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        CashuMint::remove_mint(conn, f.to_string())?;
+        Ok(())
+    }
+
+    fn retrieve_mnemonic(&self) -> anyhow::Result<Mnemonic> {
+        match self.get_seed()? {
+            Some(m) => {
+                info!("retrieved existing seed");
+                let m = Zeroizing::new(m);
+                Ok(Mnemonic::from_str(&m)?)
+            }
             None => {
                 error!("Tried to retrieve seed but none was stored");
                 Err(anyhow!("No seed stored"))
@@ -575,6 +1145,14 @@ impl DBConnection for SQLConnection {
         OnChainPayment::get_by_operation_id(conn, operation_id)
     }
 
+    fn get_onchain_payment_retry(
+        &self,
+        operation_id: String,
+    ) -> anyhow::Result<Option<OnChainPayment>> {
+        let conn = &mut self.db.get()?;
+        OnChainPayment::get_retry(conn, operation_id)
+    }
+
     fn get_lightning_receive(
         &self,
         operation_id: String,
@@ -591,6 +1169,19 @@ impl DBConnection for SQLConnection {
         LightningPayment::get_by_operation_id(conn, operation_id)
     }
 
+    fn get_paid_lightning_payment(
+        &self,
+        payment_hash: sha256::Hash,
+    ) -> anyhow::Result<Option<LightningPayment>> {
+        let conn = &mut self.db.get()?;
+        LightningPayment::get_paid_by_payment_hash(conn, payment_hash)
+    }
+
+    fn get_gateway_stats(&self) -> anyhow::Result<HashMap<PublicKey, GatewayStats>> {
+        let conn = &mut self.db.get()?;
+        LightningPayment::gateway_stats(conn)
+    }
+
     fn get_federation_metadata(&self, id: FederationId) -> anyhow::Result<Option<FederationMeta>> {
         let conn = &mut self.db.get()?;
         let meta = MintMetadata::get(conn, id.to_string())?.map(|i| i.into());
@@ -608,6 +1199,21 @@ impl DBConnection for SQLConnection {
         Ok(())
     }
 
+    fn get_idempotent_operation(
+        &self,
+        key: String,
+        window: Duration,
+    ) -> anyhow::Result<Option<String>> {
+        let since = chrono::Utc::now().naive_utc() - chrono::Duration::from_std(window)?;
+        let conn = &mut self.db.get()?;
+        Ok(PaymentIdempotency::get_since(conn, key, since)?.map(|p| p.operation_id))
+    }
+
+    fn set_idempotent_operation(&self, key: String, operation_id: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        PaymentIdempotency::upsert(conn, key, operation_id)
+    }
+
     fn get_archived_fedimints(&self) -> anyhow::Result<Vec<MintMetadata>> {
         let conn = &mut self.db.get()?;
         let ids = Fedimint::get_archived_ids(conn)?;
@@ -678,8 +1284,15 @@ impl diesel::r2d2::CustomizeConnection<SqliteConnection, diesel::r2d2::Error>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db_models::transaction_item::{TransactionDirection, TransactionItemKind};
     use crate::db_models::{
-        LightningPayment, LightningReceive, OnChainPayment, OnChainReceive, PaymentStatus,
+        EcashPayment, EcashReceive, LightningPayment, LightningReceive, OnChainPayment,
+        OnChainReceive, PaymentStatus,
+    };
+    use crate::{
+        DEFAULT_FEDERATION_MEMORY_CAP, DEFAULT_FEDERATION_STATUS_INTERVAL, DEFAULT_JOIN_TIMEOUT,
+        DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS, DEFAULT_UPDATE_STALL_TIMEOUT,
+        MIN_FEDERATION_STATUS_INTERVAL,
     };
     use bip39::{Language, Mnemonic};
     use bitcoin::hashes::Hash;
@@ -719,6 +1332,7 @@ mod tests {
             id: FEDERATION_ID.to_string(),
             invite_code: INVITE_CODE.to_string(),
             value: vec![],
+            network: None,
         };
         db.insert_new_federation(new_fedimint).unwrap();
 
@@ -744,6 +1358,225 @@ mod tests {
         assert_eq!(seed.unwrap(), p.seed_words);
     }
 
+    #[test]
+    fn test_update_stall_timeout() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.update_stall_timeout(), DEFAULT_UPDATE_STALL_TIMEOUT);
+
+        db.set_update_stall_timeout(Some(Duration::from_secs(30)))
+            .unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.update_stall_timeout(), Duration::from_secs(30));
+
+        db.set_update_stall_timeout(None).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.update_stall_timeout(), DEFAULT_UPDATE_STALL_TIMEOUT);
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.join_timeout(), DEFAULT_JOIN_TIMEOUT);
+
+        db.set_join_timeout(Some(Duration::from_secs(10))).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.join_timeout(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_federation_status_interval() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.federation_status_interval(),
+            DEFAULT_FEDERATION_STATUS_INTERVAL
+        );
+
+        db.set_federation_status_interval(Some(Duration::from_secs(15)))
+            .unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.federation_status_interval(),
+            Duration::from_secs(15)
+        );
+
+        // the guardians aren't probed in a tight loop
+        db.set_federation_status_interval(Some(Duration::ZERO))
+            .unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.federation_status_interval(),
+            MIN_FEDERATION_STATUS_INTERVAL
+        );
+
+        db.set_federation_status_interval(None).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.federation_status_interval(),
+            DEFAULT_FEDERATION_STATUS_INTERVAL
+        );
+    }
+
+    #[test]
+    fn test_delete_unjoined_federation() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        // once the client saved data the federation is kept
+        db.update_fedimint_data(federation_id.to_string(), vec![1, 2, 3])
+            .unwrap();
+        db.delete_unjoined_federation(federation_id).unwrap();
+        assert!(
+            db.get_federation_value(federation_id.to_string())
+                .unwrap()
+                .is_some()
+        );
+
+        db.update_fedimint_data(federation_id.to_string(), vec![])
+            .unwrap();
+        db.delete_unjoined_federation(federation_id).unwrap();
+        assert!(
+            db.get_federation_value(federation_id.to_string())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_max_send_fee() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.max_send_fee(), None);
+
+        db.set_max_send_fee(Some(Amount::from_sats(50))).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.max_send_fee(), Some(Amount::from_sats(50)));
+    }
+
+    #[test]
+    fn test_receive_batch_window() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.receive_batch_window(), None);
+
+        db.set_receive_batch_window(Some(Duration::from_secs(5)))
+            .unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.receive_batch_window(), Some(Duration::from_secs(5)));
+
+        db.set_receive_batch_window(None).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.receive_batch_window(), None);
+    }
+
+    #[test]
+    fn test_federation_memory_cap() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.federation_memory_cap(),
+            DEFAULT_FEDERATION_MEMORY_CAP
+        );
+
+        db.set_federation_memory_cap(Some(1_024)).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.federation_memory_cap(), 1_024);
+    }
+
+    #[test]
+    fn test_max_concurrent_subscriptions() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.max_concurrent_subscriptions(),
+            DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS
+        );
+
+        db.set_max_concurrent_subscriptions(Some(8)).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.max_concurrent_subscriptions(), 8);
+
+        db.set_max_concurrent_subscriptions(None).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.max_concurrent_subscriptions(),
+            DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS
+        );
+    }
+
+    #[test]
+    fn test_require_private_payments() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert!(!profile.require_private_payments());
+
+        db.set_require_private_payments(true).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert!(profile.require_private_payments());
+    }
+
+    #[test]
+    fn test_gateway_selection_priority() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.gateway_selection_priority(),
+            SelectionPriority::CheapestFee
+        );
+
+        db.set_gateway_selection_priority(SelectionPriority::LowestLatency)
+            .unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.gateway_selection_priority(),
+            SelectionPriority::LowestLatency
+        );
+
+        let id = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        for priority in [
+            SelectionPriority::VettedOnly,
+            SelectionPriority::Manual(id),
+            SelectionPriority::CheapestFee,
+        ] {
+            db.set_gateway_selection_priority(priority).unwrap();
+            let profile = db.get_profile().unwrap().unwrap();
+            assert_eq!(profile.gateway_selection_priority(), priority);
+        }
+    }
+
+    #[test]
+    fn test_display_balance_policy() {
+        let db = setup_test_db_with_data();
+
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(
+            profile.display_balance_policy(),
+            DisplayBalancePolicy::default()
+        );
+
+        let policy = DisplayBalancePolicy {
+            include_pending: true,
+            dust_threshold: Amount::from_sats(100),
+        };
+        db.set_display_balance_policy(policy).unwrap();
+        let profile = db.get_profile().unwrap().unwrap();
+        assert_eq!(profile.display_balance_policy(), policy);
+    }
+
     #[test]
     fn test_insert_new_federation() {
         let db = setup_test_db();
@@ -762,6 +1595,7 @@ mod tests {
             id: FEDERATION_ID.to_string(),
             invite_code: INVITE_CODE.to_string(),
             value: vec![],
+            network: None,
         };
         db.insert_new_federation(new_fedimint.clone()).unwrap();
 
@@ -770,6 +1604,441 @@ mod tests {
         assert_eq!(federation.unwrap(), new_fedimint.value);
     }
 
+    #[test]
+    fn test_federation_balance() {
+        let db = setup_test_db_with_data();
+        let id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        assert_eq!(db.get_federation_balance(id).unwrap(), None);
+
+        db.set_federation_balance(id, Amount::from_sats(1_000))
+            .unwrap();
+        assert_eq!(
+            db.get_federation_balance(id).unwrap(),
+            Some(Amount::from_sats(1_000))
+        );
+    }
+
+    #[test]
+    fn test_list_joined_federations() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        let joined = db.list_joined_federations().unwrap();
+        assert_eq!(
+            joined,
+            vec![JoinedFederation {
+                federation_id,
+                name: None,
+                network: None,
+                cached_balance: None,
+            }]
+        );
+
+        db.upsert_federation_metadata(
+            federation_id,
+            FederationMeta {
+                federation_name: Some("Test Federation".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.set_federation_balance(federation_id, Amount::from_sats(21))
+            .unwrap();
+
+        let joined = db.list_joined_federations().unwrap();
+        assert_eq!(joined[0].name.as_deref(), Some("Test Federation"));
+        assert_eq!(joined[0].cached_balance, Some(Amount::from_sats(21)));
+
+        db.remove_federation(federation_id).unwrap();
+        assert!(db.list_joined_federations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_history() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        let now = chrono::Utc::now().timestamp() as u64;
+        let days = |d: u64| now - d * 24 * 60 * 60;
+        let item = |kind, preimage, timestamp| HistoryItem {
+            operation_id: OperationId::new_random(),
+            federation_id,
+            kind,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            txid: None,
+            preimage,
+            timestamp,
+        };
+        let old_send = item(OperationKind::LightningSend, Some([2; 32]), days(120));
+        let old_send_id = old_send.operation_id.fmt_full().to_string();
+        db.import_history(vec![
+            item(OperationKind::LightningReceive, None, days(1)),
+            item(OperationKind::LightningReceive, None, days(60)),
+            // still within the proof of payment grace window
+            item(OperationKind::LightningSend, Some([1; 32]), days(60)),
+            old_send,
+        ])
+        .unwrap();
+        let metadata = BTreeMap::from([("order".to_string(), "a,\"b\"".to_string())]);
+        db.set_operation_metadata(old_send_id.clone(), metadata.clone())
+            .unwrap();
+
+        let tmp_dir = TempDir::new("harbor_prune").unwrap();
+        let export = tmp_dir.path().join("pruned.csv");
+        let policy = PrunePolicy {
+            older_than: Duration::from_secs(30 * 24 * 60 * 60),
+            archive: true,
+            export_to: Some(export.clone()),
+        };
+        let pruned = db.prune_history(&policy).unwrap();
+        assert_eq!(pruned.len(), 2);
+        assert_eq!(pruned[0].operation_kind, OperationKind::LightningReceive);
+        assert_eq!(pruned[1].operation_kind, OperationKind::LightningSend);
+
+        assert_eq!(db.get_transaction_history().unwrap().len(), 2);
+        let archived = db.get_archived_history().unwrap();
+        assert_eq!(archived.len(), 2);
+        assert_eq!(archived[1].preimage, Some([2; 32]));
+        assert_eq!(archived[1].metadata, metadata);
+        assert!(db.get_operation_metadata(old_send_id).unwrap().is_none());
+        let csv = std::fs::read_to_string(&export).unwrap();
+        assert_eq!(csv.lines().count(), 3);
+        // the metadata is one quoted field
+        assert!(csv.trim_end().ends_with(r#","{""order"":""a,\""b\""""}""#));
+
+        let mut totals = db.get_history_totals().unwrap();
+        totals.sort_by_key(|t| t.kind);
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[0].kind, OperationKind::LightningSend);
+        assert_eq!(totals[1].count, 1);
+        assert_eq!(totals[1].amount_sats, 1_000);
+        assert_eq!(
+            totals[1].mint_identifier,
+            MintIdentifier::Fedimint(federation_id)
+        );
+
+        // nothing left that is old enough
+        assert!(db.prune_history(&policy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_federation_info() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        let info = db.get_federation_info(federation_id).unwrap().unwrap();
+        assert!(info.joined_at.is_some());
+        assert_eq!(info.first_transaction_at, None);
+        assert_eq!(info.last_transaction_at, None);
+
+        let item = |timestamp| HistoryItem {
+            operation_id: OperationId::new_random(),
+            federation_id,
+            kind: OperationKind::LightningReceive,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            txid: None,
+            preimage: None,
+            timestamp,
+        };
+        db.import_history(vec![item(1_700_000_000), item(1_600_000_000)])
+            .unwrap();
+
+        let info = db.get_federation_info(federation_id).unwrap().unwrap();
+        assert_eq!(info.first_transaction_at, Some(1_600_000_000));
+        assert_eq!(info.last_transaction_at, Some(1_700_000_000));
+
+        let unknown = FederationId::from_str(
+            "c8d423964c7ad944d30f57359b6e5b260e211dcfdb945140e28d4df51fd572d2",
+        )
+        .unwrap();
+        assert!(db.get_federation_info(unknown).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_archive_federation() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        db.upsert_federation_metadata(
+            federation_id,
+            FederationMeta {
+                federation_name: Some("Test Federation".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.import_history(vec![HistoryItem {
+            operation_id: OperationId::new_random(),
+            federation_id,
+            kind: OperationKind::LightningReceive,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            txid: None,
+            preimage: None,
+            timestamp: 1_700_000_000,
+        }])
+        .unwrap();
+
+        // archiving keeps the history, metadata and client data
+        db.remove_federation(federation_id).unwrap();
+        assert!(db.list_joined_federations().unwrap().is_empty());
+        let archived = db.get_archived_fedimints().unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].name.as_deref(), Some("Test Federation"));
+        assert_eq!(db.get_transaction_history().unwrap().len(), 1);
+        assert!(
+            db.get_federation_value(federation_id.to_string())
+                .unwrap()
+                .is_some()
+        );
+
+        db.set_federation_active(federation_id).unwrap();
+        assert_eq!(db.list_joined_federations().unwrap().len(), 1);
+        assert!(db.get_archived_fedimints().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_preferred_gateway() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        assert_eq!(db.get_preferred_gateway(federation_id).unwrap(), None);
+
+        let gateway_id = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        db.set_preferred_gateway(federation_id, gateway_id).unwrap();
+        assert_eq!(
+            db.get_preferred_gateway(federation_id).unwrap(),
+            Some(gateway_id)
+        );
+
+        db.clear_preferred_gateways().unwrap();
+        assert_eq!(db.get_preferred_gateway(federation_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_purge_federation() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        db.update_fedimint_data(federation_id.to_string(), vec![1, 2, 3])
+            .unwrap();
+        db.set_federation_balance(federation_id, Amount::ZERO)
+            .unwrap();
+        db.import_history(vec![HistoryItem {
+            operation_id: OperationId::new_random(),
+            federation_id,
+            kind: OperationKind::LightningReceive,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            txid: None,
+            preimage: None,
+            timestamp: 1_700_000_000,
+        }])
+        .unwrap();
+
+        // the client data is gone but the history stays
+        db.purge_federation(federation_id).unwrap();
+        assert!(db.list_joined_federations().unwrap().is_empty());
+        assert_eq!(
+            db.get_federation_value(federation_id.to_string()).unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(db.get_transaction_history().unwrap().len(), 1);
+
+        // a client still shutting down can't write its data back
+        db.update_fedimint_data(federation_id.to_string(), vec![4, 5, 6])
+            .unwrap();
+        assert_eq!(
+            db.get_federation_value(federation_id.to_string()).unwrap(),
+            Some(vec![])
+        );
+    }
+
+    #[test]
+    fn test_transaction_history_page() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        db.import_history(
+            (1..=5)
+                .map(|timestamp| HistoryItem {
+                    operation_id: OperationId::new_random(),
+                    federation_id,
+                    kind: OperationKind::LightningReceive,
+                    amount: Amount::from_sats(1_000),
+                    fee: Amount::ZERO,
+                    txid: None,
+                    preimage: None,
+                    timestamp,
+                })
+                .collect(),
+        )
+        .unwrap();
+        let timestamps = |page: Vec<TransactionItem>| -> Vec<u64> {
+            page.into_iter().map(|item| item.timestamp).collect()
+        };
+
+        let (page, total) = db.get_transaction_history_page(0, 2).unwrap();
+        assert_eq!(timestamps(page), vec![5, 4]);
+        assert_eq!(total, 5);
+
+        let (page, _) = db.get_transaction_history_page(4, 2).unwrap();
+        assert_eq!(timestamps(page), vec![1]);
+
+        let (page, total) = db.get_transaction_history_page(10, 2).unwrap();
+        assert!(page.is_empty());
+        assert_eq!(total, 5);
+    }
+
+    #[test]
+    fn test_fedimint_kv() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        let id = federation_id.to_string();
+        assert!(db.get_fedimint_kv(id.clone()).unwrap().is_empty());
+
+        db.write_fedimint_kv(
+            id.clone(),
+            vec![],
+            vec![(vec![1], vec![1]), (vec![2], vec![2])],
+        )
+        .unwrap();
+        db.write_fedimint_kv(id.clone(), vec![vec![2], vec![4]], vec![(vec![1], vec![3])])
+            .unwrap();
+        assert_eq!(
+            db.get_fedimint_kv(id.clone()).unwrap(),
+            vec![(vec![1], vec![3])]
+        );
+
+        // a federation with saved pairs isn't deleted as unjoined, nor are they lost when
+        // it is archived
+        db.delete_unjoined_federation(federation_id).unwrap();
+        db.remove_federation(federation_id).unwrap();
+        db.write_fedimint_kv(id.clone(), vec![], vec![(vec![5], vec![5])])
+            .unwrap();
+        assert_eq!(db.get_fedimint_kv(id.clone()).unwrap().len(), 2);
+
+        // purging deletes them, and a client still shutting down can't write them back
+        db.purge_federation(federation_id).unwrap();
+        assert!(db.get_fedimint_kv(id.clone()).unwrap().is_empty());
+        db.write_fedimint_kv(id.clone(), vec![], vec![(vec![6], vec![6])])
+            .unwrap();
+        assert!(db.get_fedimint_kv(id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_paid_lightning_payment() {
+        let db = setup_test_db_with_data();
+        let invoice = Bolt11Invoice::from_str("lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0").unwrap();
+        let payment_hash = *invoice.payment_hash();
+
+        // a failed attempt doesn't count as paid
+        let failed = OperationId::new_random().fmt_full().to_string();
+        db.create_lightning_payment(
+            failed.clone(),
+            FederationId::from_str(FEDERATION_ID).ok(),
+            None,
+            invoice.clone(),
+            Amount::from_sats(1_000),
+            Amount::from_sats(1),
+            OperationKind::LightningSend,
+        )
+        .unwrap();
+        assert!(
+            db.get_paid_lightning_payment(payment_hash)
+                .unwrap()
+                .is_none()
+        );
+        db.mark_lightning_payment_as_failed(failed.clone()).unwrap();
+        assert!(db.get_transaction_history().unwrap().is_empty());
+
+        // nor does a refunded one, but it is shown in history
+        db.mark_lightning_payment_as_refunded(failed.clone())
+            .unwrap();
+        assert!(
+            db.get_paid_lightning_payment(payment_hash)
+                .unwrap()
+                .is_none()
+        );
+        let history = db.get_transaction_history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].status, PaymentStatus::Refunded);
+
+        let paid = OperationId::new_random().fmt_full().to_string();
+        db.create_lightning_payment(
+            paid.clone(),
+            FederationId::from_str(FEDERATION_ID).ok(),
+            None,
+            invoice,
+            Amount::from_sats(1_000),
+            Amount::from_sats(1),
+            OperationKind::LightningSend,
+        )
+        .unwrap();
+        db.set_lightning_payment_preimage(paid.clone(), [1; 32])
+            .unwrap();
+
+        let payment = db
+            .get_paid_lightning_payment(payment_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.operation_id, paid);
+        assert!(
+            db.get_paid_lightning_payment(sha256::Hash::hash(&[0; 32]))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_gateway_stats() {
+        let db = setup_test_db_with_data();
+        let invoice = Bolt11Invoice::from_str("lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0").unwrap();
+        let node_pub_key = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let pay = || {
+            let operation_id = OperationId::new_random().fmt_full().to_string();
+            db.create_lightning_payment(
+                operation_id.clone(),
+                FederationId::from_str(FEDERATION_ID).ok(),
+                None,
+                invoice.clone(),
+                Amount::from_sats(1_000),
+                Amount::from_sats(1),
+                OperationKind::LightningSend,
+            )
+            .unwrap();
+            db.set_lightning_payment_route(
+                operation_id.clone(),
+                PaymentRoute::Gateway {
+                    alias: "gateway".to_string(),
+                    node_pub_key,
+                },
+            )
+            .unwrap();
+            operation_id
+        };
+
+        // pending payments aren't counted
+        let pending = pay();
+        assert!(db.get_gateway_stats().unwrap().is_empty());
+
+        db.set_lightning_payment_preimage(pending, [1; 32]).unwrap();
+        db.mark_lightning_payment_as_failed(pay()).unwrap();
+        db.mark_lightning_payment_as_failed(pay()).unwrap();
+        db.mark_lightning_payment_as_failed(pay()).unwrap();
+
+        let stats = db.get_gateway_stats().unwrap();
+        let stats = stats.get(&node_pub_key).unwrap();
+        assert_eq!(stats.successes, 1);
+        assert_eq!(stats.failures, 3);
+        assert_eq!(stats.success_rate_permille(), Some(250));
+        assert!(stats.average_latency.is_some());
+    }
+
     #[test]
     fn test_lightning_payment_db() {
         let db = setup_test_db_with_data();
@@ -787,6 +2056,7 @@ mod tests {
             invoice.clone(),
             Amount::from_sats(1_000),
             Amount::from_sats(1),
+            OperationKind::LightningSend,
         )
         .unwrap();
 
@@ -807,8 +2077,62 @@ mod tests {
         assert_eq!(payment.bolt11(), invoice);
         assert_eq!(payment.amount(), Amount::from_sats(1_000));
         assert_eq!(payment.fee(), Amount::from_sats(1));
+        assert_eq!(payment.kind(), OperationKind::LightningSend);
         assert_eq!(payment.preimage(), None);
+        assert_eq!(payment.proof_of_payment(), None);
         assert_eq!(payment.status(), PaymentStatus::Pending);
+        assert_eq!(payment.route(), None);
+
+        let route = PaymentRoute::Gateway {
+            alias: "gateway".to_string(),
+            node_pub_key: fedimint_core::secp256k1::PublicKey::from_str(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+        };
+        LightningPayment::set_route(
+            &mut conn,
+            operation_id.fmt_full().to_string(),
+            route.clone(),
+        )
+        .unwrap();
+        let payment =
+            LightningPayment::get_by_operation_id(&mut conn, operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!(payment.route(), Some(route));
+
+        LightningPayment::set_route(
+            &mut conn,
+            operation_id.fmt_full().to_string(),
+            PaymentRoute::Internal,
+        )
+        .unwrap();
+        let payment =
+            LightningPayment::get_by_operation_id(&mut conn, operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!(payment.route(), Some(PaymentRoute::Internal));
+
+        // a payment can succeed without us learning its preimage
+        LightningPayment::mark_as_success(&mut conn, operation_id.fmt_full().to_string()).unwrap();
+        let payment =
+            LightningPayment::get_by_operation_id(&mut conn, operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!(payment.status(), PaymentStatus::Success);
+        assert_eq!(payment.preimage(), None);
+        assert_eq!(payment.proof_of_payment(), None);
+
+        // a preimage that doesn't match the invoice is not a proof of payment
+        LightningPayment::set_preimage(&mut conn, operation_id.fmt_full().to_string(), [0; 32])
+            .unwrap();
+        let payment =
+            LightningPayment::get_by_operation_id(&mut conn, operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!(payment.preimage(), Some([0; 32]));
+        assert_eq!(payment.proof_of_payment(), None);
 
         // sleep for a second to make sure the timestamps are different
         std::thread::sleep(Duration::from_secs(1));
@@ -821,7 +2145,6 @@ mod tests {
                 .unwrap();
 
         assert_eq!(failed.status(), PaymentStatus::Failed);
-        assert_eq!(failed.preimage(), None);
         assert_ne!(failed.updated_at, failed.created_at);
         assert_ne!(failed.updated_at, payment.updated_at);
     }
@@ -863,8 +2186,21 @@ mod tests {
         assert_eq!(receive.bolt11(), invoice);
         assert_eq!(receive.amount(), Amount::from_sats(1_000));
         assert_eq!(receive.fee(), Amount::from_sats(1));
+        assert_eq!(receive.kind(), OperationKind::LightningReceive);
         assert_eq!(receive.status(), PaymentStatus::Pending);
 
+        LightningReceive::set_amount(
+            &mut conn,
+            operation_id.fmt_full().to_string(),
+            Amount::from_sats(900),
+        )
+        .unwrap();
+        let receive =
+            LightningReceive::get_by_operation_id(&mut conn, operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap();
+        assert_eq!(receive.amount(), Amount::from_sats(900));
+
         // sleep for a second to make sure the timestamps are different
         std::thread::sleep(Duration::from_secs(1));
 
@@ -900,6 +2236,7 @@ mod tests {
             address.clone().assume_checked(),
             amount,
             fee,
+            None,
         )
         .unwrap();
 
@@ -938,6 +2275,32 @@ mod tests {
         assert_eq!(with_txid.txid(), Some(Txid::all_zeros()));
         assert_ne!(with_txid.updated_at, with_txid.created_at);
         assert_ne!(with_txid.updated_at, payment.updated_at);
+        assert_eq!(with_txid.retry_of(), None);
+
+        // a retry links back to the payment it replaces
+        let retry_id = OperationId::new_random();
+        OnChainPayment::create(
+            &mut conn,
+            retry_id.fmt_full().to_string(),
+            FederationId::from_str(FEDERATION_ID).ok(),
+            None,
+            address.clone().assume_checked(),
+            amount,
+            fee,
+            Some(operation_id),
+        )
+        .unwrap();
+
+        let retry = OnChainPayment::get_retry(&mut conn, operation_id.fmt_full().to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(retry.operation_id(), retry_id);
+        assert_eq!(retry.retry_of(), Some(operation_id));
+        assert!(
+            OnChainPayment::get_retry(&mut conn, retry_id.fmt_full().to_string())
+                .unwrap()
+                .is_none()
+        );
     }
 
     #[test]
@@ -1020,4 +2383,296 @@ mod tests {
         assert_ne!(confirmed.updated_at, confirmed.created_at);
         assert_ne!(confirmed.updated_at, with_txid.updated_at);
     }
+
+    #[test]
+    fn test_ecash_history() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        let spent = OperationId::new_random().fmt_full().to_string();
+        let reclaimed = OperationId::new_random().fmt_full().to_string();
+        let received = OperationId::new_random().fmt_full().to_string();
+        let amount = Amount::from_sats(1_000);
+        db.create_ecash_payment(spent.clone(), federation_id, amount)
+            .unwrap();
+        db.create_ecash_payment(reclaimed.clone(), federation_id, amount)
+            .unwrap();
+        db.create_ecash_receive(received.clone(), federation_id, amount)
+            .unwrap();
+
+        // nothing shows until the notes are redeemed
+        assert!(db.get_transaction_history().unwrap().is_empty());
+
+        db.mark_ecash_payment_as_success(spent.clone()).unwrap();
+        db.mark_ecash_payment_as_failed(reclaimed.clone()).unwrap();
+        db.mark_ecash_receive_as_success(received.clone()).unwrap();
+
+        let mut conn = db.db.get().unwrap();
+        let payment = EcashPayment::get_by_operation_id(&mut conn, reclaimed)
+            .unwrap()
+            .unwrap();
+        assert_eq!(payment.status(), PaymentStatus::Failed);
+        let receive = EcashReceive::get_by_operation_id(&mut conn, received)
+            .unwrap()
+            .unwrap();
+        assert_eq!(receive.status(), PaymentStatus::Success);
+        assert_eq!(receive.amount(), amount);
+
+        let history = db.get_transaction_history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|i| i.kind == TransactionItemKind::Ecash
+            && i.amount == 1_000
+            && i.mint_identifier == MintIdentifier::Fedimint(federation_id)));
+        assert!(
+            history
+                .iter()
+                .any(|i| i.operation_kind == OperationKind::EcashSpend
+                    && i.direction == TransactionDirection::Outgoing)
+        );
+        assert!(
+            history
+                .iter()
+                .any(|i| i.operation_kind == OperationKind::EcashReissue
+                    && i.direction == TransactionDirection::Incoming)
+        );
+    }
+
+    #[test]
+    fn test_join_failures() {
+        let db = setup_test_db();
+        let invite_code = InviteCode::from_str(INVITE_CODE).unwrap();
+
+        assert!(db.recent_join_failures().unwrap().is_empty());
+
+        db.record_join_failure(&invite_code, "Could not download federation info")
+            .unwrap();
+        db.record_join_failure(&invite_code, &"x".repeat(1_000))
+            .unwrap();
+
+        let failures = db.recent_join_failures().unwrap();
+        assert_eq!(failures.len(), 2);
+        // newest first, with long errors cut short
+        assert_eq!(failures[0].error.len(), 500);
+        assert_eq!(failures[1].error, "Could not download federation info");
+        // the invite code can be matched but isn't stored
+        assert_eq!(
+            failures[0].invite_code_hash,
+            crate::db_models::invite_code_hash(&invite_code)
+        );
+        assert!(!failures[0].invite_code_hash.contains(INVITE_CODE));
+    }
+
+    #[test]
+    fn test_operation_metadata() {
+        let db = setup_test_db_with_data();
+        let operation_id = OperationId::new_random();
+        let item = HistoryItem {
+            operation_id,
+            federation_id: FederationId::from_str(FEDERATION_ID).unwrap(),
+            kind: OperationKind::LightningReceive,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            txid: None,
+            preimage: None,
+            timestamp: 1_700_000_000,
+        };
+        db.import_history(vec![item]).unwrap();
+        let operation_id = operation_id.fmt_full().to_string();
+
+        assert_eq!(
+            db.get_operation_metadata(operation_id.clone()).unwrap(),
+            None
+        );
+        assert!(db.get_transaction_history().unwrap()[0].metadata.is_empty());
+
+        let metadata = BTreeMap::from([
+            ("order_id".to_string(), "1234".to_string()),
+            ("customer".to_string(), "alice".to_string()),
+        ]);
+        db.set_operation_metadata(operation_id.clone(), metadata.clone())
+            .unwrap();
+        assert_eq!(
+            db.get_operation_metadata(operation_id.clone()).unwrap(),
+            Some(metadata.clone())
+        );
+        assert_eq!(db.get_transaction_history().unwrap()[0].metadata, metadata);
+
+        // setting it again replaces it, an empty map removes it
+        let metadata = BTreeMap::from([("order_id".to_string(), "5678".to_string())]);
+        db.set_operation_metadata(operation_id.clone(), metadata.clone())
+            .unwrap();
+        assert_eq!(db.get_transaction_history().unwrap()[0].metadata, metadata);
+
+        db.set_operation_metadata(operation_id.clone(), BTreeMap::new())
+            .unwrap();
+        assert_eq!(
+            db.get_operation_metadata(operation_id.clone()).unwrap(),
+            None
+        );
+        assert!(db.get_transaction_history().unwrap()[0].metadata.is_empty());
+
+        // a row that can't be read is an error, but doesn't break the history
+        db.set_operation_metadata(operation_id.clone(), metadata)
+            .unwrap();
+        db.db
+            .get()
+            .unwrap()
+            .batch_execute("UPDATE operation_metadata SET metadata = 'not json'")
+            .unwrap();
+        assert!(db.get_operation_metadata(operation_id).is_err());
+        assert!(db.get_transaction_history().unwrap()[0].metadata.is_empty());
+    }
+
+    #[test]
+    fn test_import_history() {
+        let db = setup_test_db_with_data();
+        let pool = db.db.clone();
+        let mut conn = pool.get().unwrap();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        let item = HistoryItem {
+            operation_id: OperationId::new_random(),
+            federation_id,
+            kind: OperationKind::LightningSend,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::from_sats(1),
+            txid: None,
+            preimage: Some([1; 32]),
+            timestamp: 1_700_000_000,
+        };
+
+        assert_eq!(db.import_history(vec![item.clone()]).unwrap(), 1);
+        // importing the same history again adds nothing
+        assert_eq!(db.import_history(vec![item.clone()]).unwrap(), 0);
+
+        let history = db.get_transaction_history().unwrap();
+        let imported = history.iter().find(|t| t.imported).unwrap();
+        assert_eq!(imported.amount, 1_000);
+        assert_eq!(imported.direction, TransactionDirection::Outgoing);
+        assert_eq!(imported.preimage, Some([1; 32]));
+
+        // a payment we made ourselves is not imported again
+        let operation_id = OperationId::new_random();
+        let invoice = Bolt11Invoice::from_str("lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0").unwrap();
+        LightningPayment::create(
+            &mut conn,
+            operation_id.fmt_full().to_string(),
+            Some(federation_id),
+            None,
+            invoice,
+            Amount::from_sats(1_000),
+            Amount::from_sats(1),
+            OperationKind::LightningSend,
+        )
+        .unwrap();
+        LightningPayment::set_preimage(&mut conn, operation_id.fmt_full().to_string(), [2; 32])
+            .unwrap();
+
+        let same_id = HistoryItem {
+            operation_id,
+            preimage: None,
+            ..item.clone()
+        };
+        let same_preimage = HistoryItem {
+            operation_id: OperationId::new_random(),
+            preimage: Some([2; 32]),
+            ..item.clone()
+        };
+        assert_eq!(db.import_history(vec![same_id, same_preimage]).unwrap(), 0);
+
+        // ecash and empty transactions are rejected
+        let ecash = HistoryItem {
+            operation_id: OperationId::new_random(),
+            kind: OperationKind::EcashSpend,
+            preimage: None,
+            ..item.clone()
+        };
+        assert!(db.import_history(vec![ecash]).is_err());
+        let empty = HistoryItem {
+            operation_id: OperationId::new_random(),
+            amount: Amount::ZERO,
+            ..item
+        };
+        assert!(db.import_history(vec![empty]).is_err());
+
+        let history = db.get_transaction_history().unwrap();
+        assert_eq!(history.iter().filter(|t| t.imported).count(), 1);
+    }
+
+    #[test]
+    fn test_unused_onchain_receive() {
+        let db = setup_test_db_with_data();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        let window = Duration::from_secs(60);
+
+        assert!(
+            db.get_unused_onchain_receive(federation_id, window)
+                .unwrap()
+                .is_none()
+        );
+
+        let operation_id = OperationId::new_random().fmt_full().to_string();
+        let address = Address::from_str("tb1qd28npep0s8frcm3y7dxqajkcy2m40eysplyr9v")
+            .unwrap()
+            .assume_checked();
+        db.create_onchain_receive(operation_id.clone(), Some(federation_id), None, address)
+            .unwrap();
+
+        let unused = db
+            .get_unused_onchain_receive(federation_id, window)
+            .unwrap()
+            .unwrap();
+        assert_eq!(unused.operation_id().fmt_full().to_string(), operation_id);
+
+        // once something is sent to it, it's no longer unused
+        db.set_onchain_receive_txid(operation_id, Txid::all_zeros(), 10_000, 0)
+            .unwrap();
+        assert!(
+            db.get_unused_onchain_receive(federation_id, window)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_payment_idempotency_db() {
+        let db = setup_test_db();
+
+        let key = "key".to_string();
+        let window = Duration::from_secs(60);
+        assert!(
+            db.get_idempotent_operation(key.clone(), window)
+                .unwrap()
+                .is_none()
+        );
+
+        let operation_id = OperationId::new_random().fmt_full().to_string();
+        db.set_idempotent_operation(key.clone(), operation_id.clone())
+            .unwrap();
+
+        assert_eq!(
+            db.get_idempotent_operation(key.clone(), window).unwrap(),
+            Some(operation_id)
+        );
+
+        // only entries recorded after the start of the window count
+        let conn = &mut db.db.get().unwrap();
+        let entry = PaymentIdempotency::get_since(conn, key.clone(), chrono::NaiveDateTime::MIN)
+            .unwrap()
+            .unwrap();
+        assert!(
+            PaymentIdempotency::get_since(conn, key.clone(), entry.created_at)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            PaymentIdempotency::get_since(
+                conn,
+                key,
+                entry.created_at - chrono::Duration::seconds(1)
+            )
+            .unwrap()
+            .is_some()
+        );
+    }
 }
@@ -0,0 +1,127 @@
+use crate::MintIdentifier;
+use crate::db_models::schema::ecash_receives;
+use crate::db_models::transaction_item::{
+    TransactionDirection, TransactionItem, TransactionItemKind,
+};
+use crate::db_models::{OperationKind, PaymentStatus};
+use diesel::prelude::*;
+use fedimint_core::Amount;
+use fedimint_core::config::FederationId;
+use fedimint_core::core::OperationId;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Ecash notes someone gave us, reissued into our balance
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = ecash_receives)]
+pub struct EcashReceive {
+    pub operation_id: String,
+    fedimint_id: String,
+    amount_msats: i64,
+    status: i32,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = ecash_receives)]
+struct NewEcashReceive {
+    operation_id: String,
+    fedimint_id: String,
+    amount_msats: i64,
+    status: i32,
+}
+
+impl EcashReceive {
+    pub fn operation_id(&self) -> OperationId {
+        OperationId::from_str(&self.operation_id).expect("invalid operation id")
+    }
+
+    pub fn fedimint_id(&self) -> FederationId {
+        FederationId::from_str(&self.fedimint_id).expect("invalid fedimint_id")
+    }
+
+    pub fn amount(&self) -> Amount {
+        Amount::from_msats(self.amount_msats as u64)
+    }
+
+    pub fn status(&self) -> PaymentStatus {
+        PaymentStatus::from_i32(self.status)
+    }
+
+    pub fn create(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+        fedimint_id: FederationId,
+        amount: Amount,
+    ) -> anyhow::Result<()> {
+        let new = NewEcashReceive {
+            operation_id,
+            fedimint_id: fedimint_id.to_string(),
+            amount_msats: amount.msats as i64,
+            status: PaymentStatus::Pending as i32,
+        };
+
+        diesel::insert_into(ecash_receives::table)
+            .values(new)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_by_operation_id(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(ecash_receives::table
+            .filter(ecash_receives::operation_id.eq(operation_id))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// The notes were reissued into our balance
+    pub fn mark_as_success(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<()> {
+        diesel::update(ecash_receives::table.filter(ecash_receives::operation_id.eq(operation_id)))
+            .set(ecash_receives::status.eq(PaymentStatus::Success as i32))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// The notes could not be reissued, likely because they were already spent
+    pub fn mark_as_failed(conn: &mut SqliteConnection, operation_id: String) -> anyhow::Result<()> {
+        diesel::update(ecash_receives::table.filter(ecash_receives::operation_id.eq(operation_id)))
+            .set(ecash_receives::status.eq(PaymentStatus::Failed as i32))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_history(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        Ok(ecash_receives::table
+            .filter(ecash_receives::status.eq(PaymentStatus::Success as i32))
+            .load::<Self>(conn)?)
+    }
+}
+
+impl From<EcashReceive> for TransactionItem {
+    fn from(receive: EcashReceive) -> Self {
+        Self {
+            kind: TransactionItemKind::Ecash,
+            operation_kind: OperationKind::EcashReissue,
+            amount: receive.amount().sats_round_down(),
+            txid: None,
+            preimage: None,
+            direction: TransactionDirection::Incoming,
+            mint_identifier: MintIdentifier::Fedimint(receive.fedimint_id()),
+            status: receive.status(),
+            timestamp: receive.updated_at.and_utc().timestamp() as u64,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::db_models::schema::federation_config;
+use bitcoin::Network;
+use diesel::prelude::*;
+use fedimint_core::config::{ClientConfig, FederationId};
+use std::str::FromStr;
+
+/// The config downloaded when first joining a federation, kept so rejoining doesn't need
+/// to reach the guardians
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = federation_config)]
+pub struct FederationConfig {
+    pub federation_id: String,
+    config: String,
+    network: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = federation_config)]
+struct NewFederationConfig {
+    federation_id: String,
+    config: String,
+    network: String,
+}
+
+impl FederationConfig {
+    pub fn config(&self) -> anyhow::Result<ClientConfig> {
+        Ok(serde_json::from_str(&self.config)?)
+    }
+
+    /// The network the wallet was on when the config was saved
+    pub fn network(&self) -> Option<Network> {
+        Network::from_str(&self.network).ok()
+    }
+
+    pub fn get(
+        conn: &mut SqliteConnection,
+        federation_id: FederationId,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(federation_config::table
+            .filter(federation_config::federation_id.eq(federation_id.to_string()))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    pub fn upsert(
+        conn: &mut SqliteConnection,
+        federation_id: FederationId,
+        config: &ClientConfig,
+        network: Network,
+    ) -> anyhow::Result<()> {
+        let new = NewFederationConfig {
+            federation_id: federation_id.to_string(),
+            config: serde_json::to_string(config)?,
+            network: network.to_string(),
+        };
+
+        diesel::insert_into(federation_config::table)
+            .values(new.clone())
+            .on_conflict(federation_config::federation_id)
+            .do_update()
+            .set((
+                federation_config::config.eq(new.config),
+                federation_config::network.eq(new.network),
+                federation_config::created_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
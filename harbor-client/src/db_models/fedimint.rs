@@ -1,6 +1,12 @@
-use crate::db_models::schema::fedimint;
+use crate::db_models::mint_metadata::MintMetadata;
+use crate::db_models::schema::{fedimint, fedimint_kv, mint_metadata};
+use bitcoin::Network;
 use diesel::prelude::*;
+use fedimint_core::Amount;
+use fedimint_core::config::FederationId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
 
 #[derive(
     QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
@@ -11,6 +17,38 @@ pub struct Fedimint {
     pub invite_code: String,
     pub value: Vec<u8>,
     pub active: i32,
+    /// Last balance we saw for the federation, shown until the client is loaded
+    pub balance_msats: Option<i64>,
+    /// Network the federation was joined on, unknown for federations joined before it was saved
+    pub network: Option<String>,
+    /// Hash of the password needed to send from the federation, if one is set
+    pub spend_password_hash: Option<String>,
+    /// When the federation was first joined, unknown for federations joined before it was saved
+    pub joined_at: Option<chrono::NaiveDateTime>,
+    /// Id of the gateway payments went through last, kept so payments keep using it
+    pub preferred_gateway: Option<String>,
+}
+
+/// A joined federation with what storage knows about it, used to list federations
+/// before their clients are loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinedFederation {
+    pub federation_id: FederationId,
+    pub name: Option<String>,
+    pub network: Option<Network>,
+    pub cached_balance: Option<Amount>,
+}
+
+/// When a federation was joined and used, for showing alongside its details.
+/// Timestamps are unix timestamps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationInfo {
+    pub federation_id: FederationId,
+    /// `None` for federations joined before the join time was saved
+    pub joined_at: Option<u64>,
+    /// The first transaction in the federation's history, `None` until there is one
+    pub first_transaction_at: Option<u64>,
+    pub last_transaction_at: Option<u64>,
 }
 
 impl Fedimint {
@@ -22,6 +60,16 @@ impl Fedimint {
             .map(|v| v.value))
     }
 
+    pub fn balance(&self) -> Option<Amount> {
+        self.balance_msats.map(|b| Amount::from_msats(b as u64))
+    }
+
+    pub fn network(&self) -> Option<Network> {
+        self.network
+            .as_deref()
+            .and_then(|n| Network::from_str(n).ok())
+    }
+
     pub fn get(conn: &mut SqliteConnection, id: String) -> anyhow::Result<Option<Fedimint>> {
         Ok(fedimint::table
             .filter(fedimint::id.eq(id))
@@ -51,6 +99,45 @@ impl Fedimint {
         Ok(())
     }
 
+    /// Deletes a federation that was never joined, one we have no data for yet.
+    /// Federations with data are left alone, those are only ever marked inactive.
+    pub fn delete_unjoined(conn: &mut SqliteConnection, id: String) -> anyhow::Result<()> {
+        let pairs: i64 = fedimint_kv::table
+            .filter(fedimint_kv::fedimint_id.eq(&id))
+            .count()
+            .get_result(conn)?;
+        if pairs > 0 {
+            return Ok(());
+        }
+
+        diesel::delete(
+            fedimint::table
+                .filter(fedimint::id.eq(id))
+                .filter(fedimint::value.eq(Vec::<u8>::new())),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+
+    /// Clears the federation's data and deactivates it. The row stays for the history that
+    /// refers to it, and the invite code lets it be joined again.
+    pub fn purge(conn: &mut SqliteConnection, id: String) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            diesel::delete(fedimint_kv::table.filter(fedimint_kv::fedimint_id.eq(&id)))
+                .execute(conn)?;
+            diesel::update(fedimint::table)
+                .filter(fedimint::id.eq(id))
+                .set((
+                    fedimint::value.eq(Vec::<u8>::new()),
+                    fedimint::active.eq(0),
+                    fedimint::balance_msats.eq(None::<i64>),
+                    fedimint::preferred_gateway.eq(None::<String>),
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
     pub fn set_active(conn: &mut SqliteConnection, id: String) -> anyhow::Result<()> {
         diesel::update(fedimint::table)
             .filter(fedimint::id.eq(id))
@@ -68,6 +155,32 @@ impl Fedimint {
             .collect())
     }
 
+    pub fn get_joined(conn: &mut SqliteConnection) -> anyhow::Result<Vec<JoinedFederation>> {
+        let federations = fedimint::table
+            .filter(fedimint::active.eq(1))
+            .load::<Self>(conn)?;
+
+        let ids: Vec<&String> = federations.iter().map(|f| &f.id).collect();
+        let names: HashMap<String, Option<String>> = mint_metadata::table
+            .filter(mint_metadata::id.eq_any(ids))
+            .load::<MintMetadata>(conn)?
+            .into_iter()
+            .map(|m| (m.id, m.name))
+            .collect();
+
+        federations
+            .into_iter()
+            .map(|f| {
+                Ok(JoinedFederation {
+                    federation_id: FederationId::from_str(&f.id)?,
+                    name: names.get(&f.id).cloned().flatten(),
+                    network: f.network(),
+                    cached_balance: f.balance(),
+                })
+            })
+            .collect()
+    }
+
     pub fn get_archived_ids(conn: &mut SqliteConnection) -> anyhow::Result<Vec<String>> {
         Ok(fedimint::table
             .filter(fedimint::active.eq(0))
@@ -77,13 +190,63 @@ impl Fedimint {
             .collect())
     }
 
+    pub fn set_balance(
+        conn: &mut SqliteConnection,
+        id: String,
+        balance: Amount,
+    ) -> anyhow::Result<()> {
+        diesel::update(fedimint::table)
+            .filter(fedimint::id.eq(id))
+            .set(fedimint::balance_msats.eq(balance.msats as i64))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_spend_password_hash(
+        conn: &mut SqliteConnection,
+        id: String,
+        hash: Option<String>,
+    ) -> anyhow::Result<()> {
+        diesel::update(fedimint::table)
+            .filter(fedimint::id.eq(id))
+            .set(fedimint::spend_password_hash.eq(hash))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_preferred_gateway(
+        conn: &mut SqliteConnection,
+        id: String,
+        gateway_id: Option<String>,
+    ) -> anyhow::Result<()> {
+        diesel::update(fedimint::table)
+            .filter(fedimint::id.eq(id))
+            .set(fedimint::preferred_gateway.eq(gateway_id))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn clear_preferred_gateways(conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        diesel::update(fedimint::table)
+            .set(fedimint::preferred_gateway.eq(None::<String>))
+            .execute(conn)?;
+        Ok(())
+    }
+
     pub fn update_value(
         conn: &mut SqliteConnection,
         id: String,
         value: Vec<u8>,
     ) -> anyhow::Result<()> {
-        let _ = diesel::update(fedimint::table)
+        // a federation that was left has been purged, a client still shutting down
+        // shouldn't bring its data back
+        diesel::update(fedimint::table)
             .filter(fedimint::id.eq(&id))
+            .filter(
+                fedimint::active
+                    .eq(1)
+                    .or(fedimint::value.ne(Vec::<u8>::new())),
+            )
             .set((fedimint::value.eq(&value),))
             .execute(conn)?;
 
@@ -97,6 +260,7 @@ pub struct NewFedimint {
     pub id: String,
     pub invite_code: String,
     pub value: Vec<u8>,
+    pub network: Option<String>,
 }
 
 impl From<&NewFedimint> for Fedimint {
@@ -106,16 +270,25 @@ impl From<&NewFedimint> for Fedimint {
             invite_code: new_fedimint.invite_code.clone(),
             value: new_fedimint.value.clone(),
             active: 1,
+            balance_msats: None,
+            network: new_fedimint.network.clone(),
+            spend_password_hash: None,
+            joined_at: None,
+            preferred_gateway: None,
         }
     }
 }
 
 impl NewFedimint {
     pub fn insert(&self, conn: &mut SqliteConnection) -> anyhow::Result<Fedimint> {
+        let joined_at = chrono::Utc::now().naive_utc();
         let _ = diesel::insert_into(fedimint::table)
-            .values(self)
+            .values((self, fedimint::joined_at.eq(Some(joined_at))))
             .execute(conn)?;
 
-        Ok(self.into())
+        Ok(Fedimint {
+            joined_at: Some(joined_at),
+            ..self.into()
+        })
     }
 }
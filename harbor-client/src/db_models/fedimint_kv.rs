@@ -0,0 +1,93 @@
+use crate::db_models::schema::{fedimint, fedimint_kv};
+use diesel::prelude::*;
+
+/// One of a federation client's key/value pairs. Each pair is saved on its own so a
+/// transaction only writes the pairs it changed, the key and value are as the client
+/// storage sealed them.
+#[derive(QueryableByName, Queryable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = fedimint_kv)]
+pub struct FedimintKv {
+    pub fedimint_id: String,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl FedimintKv {
+    pub fn get_all(
+        conn: &mut SqliteConnection,
+        fedimint_id: String,
+    ) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(fedimint_kv::table
+            .filter(fedimint_kv::fedimint_id.eq(fedimint_id))
+            .load::<Self>(conn)?
+            .into_iter()
+            .map(|kv| (kv.key, kv.value))
+            .collect())
+    }
+
+    pub fn upsert(
+        conn: &mut SqliteConnection,
+        fedimint_id: String,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            // a federation that was left has been purged, a client still shutting down
+            // shouldn't bring its data back
+            let writable: i64 = fedimint::table
+                .filter(fedimint::id.eq(&fedimint_id))
+                .filter(fedimint::active.eq(1))
+                .count()
+                .get_result(conn)?;
+            let saved: i64 = fedimint_kv::table
+                .filter(fedimint_kv::fedimint_id.eq(&fedimint_id))
+                .count()
+                .get_result(conn)?;
+            if writable == 0 && saved == 0 {
+                return Ok(());
+            }
+
+            for (key, value) in pairs {
+                diesel::replace_into(fedimint_kv::table)
+                    .values(Self {
+                        fedimint_id: fedimint_id.clone(),
+                        key,
+                        value,
+                    })
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn delete(
+        conn: &mut SqliteConnection,
+        fedimint_id: String,
+        keys: Vec<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        diesel::delete(
+            fedimint_kv::table
+                .filter(fedimint_kv::fedimint_id.eq(fedimint_id))
+                .filter(fedimint_kv::key.eq_any(keys)),
+        )
+        .execute(conn)?;
+        Ok(())
+    }
+
+    /// Deletes the keys and saves the pairs together, either all of it is written or none.
+    pub fn write(
+        conn: &mut SqliteConnection,
+        fedimint_id: String,
+        deletes: Vec<Vec<u8>>,
+        upserts: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            if !deletes.is_empty() {
+                Self::delete(conn, fedimint_id.clone(), deletes)?;
+            }
+            if !upserts.is_empty() {
+                Self::upsert(conn, fedimint_id, upserts)?;
+            }
+            Ok(())
+        })
+    }
+}
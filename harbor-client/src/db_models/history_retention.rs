@@ -0,0 +1,408 @@
+use crate::MintIdentifier;
+use crate::db_models::schema::{
+    archived_history, ecash_payments, ecash_receives, history_totals, imported_history,
+    lightning_payments, lightning_receives, on_chain_payments, on_chain_receives,
+    operation_metadata, payment_idempotency,
+};
+use crate::db_models::transaction_item::{
+    TransactionDirection, TransactionItem, TransactionItemKind,
+};
+use crate::db_models::{
+    EcashPayment, EcashReceive, ImportedHistory, LightningPayment, LightningReceive,
+    OnChainPayment, OnChainReceive, OperationKind, OperationMetadata, PaymentStatus,
+};
+use bitcoin::Txid;
+use bitcoin::hashes::hex::FromHex;
+use cdk::mint_url::MintUrl;
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use fedimint_core::config::FederationId;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Successful lightning payments are kept at least this long when pruning, as their
+/// preimage is the proof the invoice was paid
+pub const PROOF_OF_PAYMENT_GRACE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// How [`crate::db::DBConnection::prune_history`] trims old history. Only finished
+/// transactions are pruned, and what they added up to is kept as [`HistoryTotal`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrunePolicy {
+    /// Transactions that finished longer ago than this are pruned
+    pub older_than: Duration,
+    /// Move pruned transactions to the archive instead of deleting them
+    pub archive: bool,
+    /// Write the pruned transactions to this CSV file first, nothing is pruned if it can't be
+    pub export_to: Option<PathBuf>,
+}
+
+/// What pruned transactions of one kind and outcome added up to in a mint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryTotal {
+    pub mint_identifier: MintIdentifier,
+    pub kind: OperationKind,
+    pub status: PaymentStatus,
+    pub count: u64,
+    pub amount_sats: u64,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = history_totals)]
+struct HistoryTotalRow {
+    mint: String,
+    kind: i32,
+    status: i32,
+    count: i64,
+    amount_sats: i64,
+}
+
+impl HistoryTotal {
+    pub fn get_all(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        let rows = history_totals::table.load::<HistoryTotalRow>(conn)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| HistoryTotal {
+                mint_identifier: mint_from_key(&row.mint),
+                kind: OperationKind::from_i32(row.kind),
+                status: PaymentStatus::from_i32(row.status),
+                count: row.count as u64,
+                amount_sats: row.amount_sats as u64,
+            })
+            .collect())
+    }
+
+    fn add(conn: &mut SqliteConnection, item: &TransactionItem) -> anyhow::Result<()> {
+        let row = HistoryTotalRow {
+            mint: mint_key(&item.mint_identifier),
+            kind: item.operation_kind as i32,
+            status: item.status as i32,
+            count: 1,
+            amount_sats: item.amount as i64,
+        };
+        diesel::insert_into(history_totals::table)
+            .values(row)
+            .on_conflict((
+                history_totals::mint,
+                history_totals::kind,
+                history_totals::status,
+            ))
+            .do_update()
+            .set((
+                history_totals::count.eq(history_totals::count + 1),
+                history_totals::amount_sats
+                    .eq(history_totals::amount_sats + excluded(history_totals::amount_sats)),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+/// A pruned transaction kept in the archive
+#[derive(QueryableByName, Queryable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = archived_history)]
+pub struct ArchivedHistory {
+    pub operation_id: String,
+    mint: String,
+    kind: i32,
+    amount_sats: i64,
+    txid: Option<String>,
+    preimage: Option<String>,
+    status: i32,
+    timestamp: i64,
+    /// The operation's metadata as JSON, see [`OperationMetadata`]
+    metadata: String,
+}
+
+impl ArchivedHistory {
+    fn new(operation_id: String, item: &TransactionItem) -> anyhow::Result<Self> {
+        Ok(Self {
+            operation_id,
+            mint: mint_key(&item.mint_identifier),
+            kind: item.operation_kind as i32,
+            amount_sats: item.amount as i64,
+            txid: item.txid.map(|t| t.to_string()),
+            preimage: item.preimage.map(hex::encode),
+            status: item.status as i32,
+            timestamp: item.timestamp as i64,
+            metadata: serde_json::to_string(&item.metadata)?,
+        })
+    }
+
+    pub fn get_all(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        Ok(archived_history::table
+            .order(archived_history::timestamp.desc())
+            .load::<Self>(conn)?)
+    }
+}
+
+impl From<ArchivedHistory> for TransactionItem {
+    fn from(item: ArchivedHistory) -> Self {
+        let operation_kind = OperationKind::from_i32(item.kind);
+        let kind = match operation_kind {
+            OperationKind::OnchainSend | OperationKind::OnchainReceive => {
+                TransactionItemKind::Onchain
+            }
+            OperationKind::EcashSpend | OperationKind::EcashReissue => TransactionItemKind::Ecash,
+            _ => TransactionItemKind::Lightning,
+        };
+        let direction = match operation_kind {
+            OperationKind::LightningReceive
+            | OperationKind::OnchainReceive
+            | OperationKind::EcashReissue => TransactionDirection::Incoming,
+            _ => TransactionDirection::Outgoing,
+        };
+
+        Self {
+            kind,
+            operation_kind,
+            amount: item.amount_sats as u64,
+            txid: item
+                .txid
+                .as_ref()
+                .map(|t| Txid::from_str(t).expect("invalid txid")),
+            preimage: item
+                .preimage
+                .as_ref()
+                .map(|p| FromHex::from_hex(p).expect("invalid preimage")),
+            direction,
+            mint_identifier: mint_from_key(&item.mint),
+            status: PaymentStatus::from_i32(item.status),
+            timestamp: item.timestamp as u64,
+            imported: false,
+            route: None,
+            metadata: serde_json::from_str(&item.metadata).unwrap_or_else(|e| {
+                log::error!(
+                    "Invalid metadata for archived operation {}: {e}",
+                    item.operation_id
+                );
+                BTreeMap::new()
+            }),
+        }
+    }
+}
+
+/// Mints are stored by federation id or mint url, which can't be mistaken for each other
+fn mint_key(mint: &MintIdentifier) -> String {
+    match mint {
+        MintIdentifier::Fedimint(id) => id.to_string(),
+        MintIdentifier::Cashu(url) => url.to_string(),
+    }
+}
+
+fn mint_from_key(key: &str) -> MintIdentifier {
+    match FederationId::from_str(key) {
+        Ok(id) => MintIdentifier::Fedimint(id),
+        Err(_) => MintIdentifier::Cashu(MintUrl::from_str(key).expect("invalid mint url")),
+    }
+}
+
+/// Prunes finished history as the policy says, see [`PrunePolicy`].
+/// Returns the pruned transactions, newest first.
+pub fn prune(
+    conn: &mut SqliteConnection,
+    policy: &PrunePolicy,
+) -> anyhow::Result<Vec<TransactionItem>> {
+    let now = chrono::Utc::now().naive_utc();
+    let before = now - chrono::Duration::from_std(policy.older_than)?;
+    let proof_before =
+        now - chrono::Duration::from_std(policy.older_than.max(PROOF_OF_PAYMENT_GRACE))?;
+
+    conn.transaction(|conn| {
+        let mut items = prunable(conn, before)?;
+        // the metadata is deleted with the operation, carry it along
+        let mut metadata = OperationMetadata::get_all(conn)?;
+        for (operation_id, item) in items.iter_mut() {
+            if let Some(m) = metadata.remove(operation_id) {
+                item.metadata = m;
+            }
+        }
+        // the preimage of a payment is the proof it was made, keep it around for a while
+        let proof_before = proof_before.and_utc().timestamp() as u64;
+        items.retain(|(_, item)| {
+            item.direction == TransactionDirection::Incoming
+                || item.preimage.is_none()
+                || item.timestamp < proof_before
+        });
+        items.sort_by(|(_, a), (_, b)| b.timestamp.cmp(&a.timestamp));
+
+        if let Some(path) = &policy.export_to {
+            write_csv(path, &items)?;
+        }
+
+        for (operation_id, item) in &items {
+            HistoryTotal::add(conn, item)?;
+            if policy.archive {
+                diesel::insert_into(archived_history::table)
+                    .values(ArchivedHistory::new(operation_id.clone(), item)?)
+                    .execute(conn)?;
+            }
+            delete_operation(conn, operation_id)?;
+        }
+
+        Ok(items.into_iter().map(|(_, item)| item).collect())
+    })
+}
+
+/// Finished transactions last updated before `before`, with their operation ids.
+/// Onchain payments a pending retry refers to are left out.
+fn prunable(
+    conn: &mut SqliteConnection,
+    before: chrono::NaiveDateTime,
+) -> anyhow::Result<Vec<(String, TransactionItem)>> {
+    let finished = [
+        PaymentStatus::Success as i32,
+        PaymentStatus::Failed as i32,
+        PaymentStatus::Refunded as i32,
+    ];
+    let mut items = vec![];
+
+    let payments = lightning_payments::table
+        .filter(lightning_payments::status.eq_any(finished))
+        .filter(lightning_payments::updated_at.lt(before))
+        .load::<LightningPayment>(conn)?;
+    items.extend(
+        payments
+            .into_iter()
+            .map(|p| (p.operation_id.clone(), p.into())),
+    );
+
+    let receives = lightning_receives::table
+        .filter(lightning_receives::status.eq_any(finished))
+        .filter(lightning_receives::updated_at.lt(before))
+        .load::<LightningReceive>(conn)?;
+    items.extend(
+        receives
+            .into_iter()
+            .map(|r| (r.operation_id.clone(), r.into())),
+    );
+
+    let retried: HashSet<String> = on_chain_payments::table
+        .filter(on_chain_payments::status.eq_any([
+            PaymentStatus::Pending as i32,
+            PaymentStatus::WaitingConfirmation as i32,
+        ]))
+        .select(on_chain_payments::retry_of)
+        .load::<Option<String>>(conn)?
+        .into_iter()
+        .flatten()
+        .collect();
+    let payments = on_chain_payments::table
+        .filter(on_chain_payments::status.eq_any(finished))
+        .filter(on_chain_payments::updated_at.lt(before))
+        .load::<OnChainPayment>(conn)?;
+    items.extend(
+        payments
+            .into_iter()
+            .filter(|p| !retried.contains(&p.operation_id))
+            .map(|p| (p.operation_id.clone(), p.into())),
+    );
+
+    let receives = on_chain_receives::table
+        .filter(on_chain_receives::status.eq_any(finished))
+        .filter(on_chain_receives::updated_at.lt(before))
+        .load::<OnChainReceive>(conn)?;
+    items.extend(
+        receives
+            .into_iter()
+            .map(|r| (r.operation_id.clone(), r.into())),
+    );
+
+    let payments = ecash_payments::table
+        .filter(ecash_payments::status.eq_any(finished))
+        .filter(ecash_payments::updated_at.lt(before))
+        .load::<EcashPayment>(conn)?;
+    items.extend(
+        payments
+            .into_iter()
+            .map(|p| (p.operation_id.clone(), p.into())),
+    );
+
+    let receives = ecash_receives::table
+        .filter(ecash_receives::status.eq_any(finished))
+        .filter(ecash_receives::updated_at.lt(before))
+        .load::<EcashReceive>(conn)?;
+    items.extend(
+        receives
+            .into_iter()
+            .map(|r| (r.operation_id.clone(), r.into())),
+    );
+
+    let imported = imported_history::table
+        .filter(imported_history::created_at.lt(before))
+        .load::<ImportedHistory>(conn)?;
+    items.extend(
+        imported
+            .into_iter()
+            .map(|i| (i.operation_id.clone(), i.into())),
+    );
+
+    Ok(items)
+}
+
+/// Deletes the operation from whichever history table has it, along with what refers to it
+fn delete_operation(conn: &mut SqliteConnection, operation_id: &str) -> anyhow::Result<()> {
+    diesel::delete(
+        lightning_payments::table.filter(lightning_payments::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        lightning_receives::table.filter(lightning_receives::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        on_chain_payments::table.filter(on_chain_payments::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        on_chain_receives::table.filter(on_chain_receives::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(ecash_payments::table.filter(ecash_payments::operation_id.eq(operation_id)))
+        .execute(conn)?;
+    diesel::delete(ecash_receives::table.filter(ecash_receives::operation_id.eq(operation_id)))
+        .execute(conn)?;
+    diesel::delete(imported_history::table.filter(imported_history::operation_id.eq(operation_id)))
+        .execute(conn)?;
+    diesel::delete(
+        operation_metadata::table.filter(operation_metadata::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    diesel::delete(
+        payment_idempotency::table.filter(payment_idempotency::operation_id.eq(operation_id)),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+fn write_csv(path: &Path, items: &[(String, TransactionItem)]) -> anyhow::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    writeln!(
+        file,
+        "operation_id,timestamp,mint,kind,status,amount_sats,txid,preimage,metadata"
+    )?;
+    for (operation_id, item) in items {
+        let metadata = if item.metadata.is_empty() {
+            String::new()
+        } else {
+            // JSON has commas and quotes of its own, quote it as a single field
+            let json = serde_json::to_string(&item.metadata)?;
+            format!("\"{}\"", json.replace('"', "\"\""))
+        };
+        writeln!(
+            file,
+            "{operation_id},{},{},{:?},{:?},{},{},{},{metadata}",
+            item.timestamp,
+            mint_key(&item.mint_identifier),
+            item.operation_kind,
+            item.status,
+            item.amount,
+            item.txid.map(|t| t.to_string()).unwrap_or_default(),
+            item.preimage.map(hex::encode).unwrap_or_default(),
+        )?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use crate::MintIdentifier;
+use crate::db_models::schema::{
+    imported_history, lightning_payments, lightning_receives, on_chain_payments, on_chain_receives,
+};
+use crate::db_models::transaction_item::{
+    TransactionDirection, TransactionItem, TransactionItemKind,
+};
+use crate::db_models::{OperationKind, PaymentStatus};
+use anyhow::bail;
+use bitcoin::Txid;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{Hash, sha256};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use fedimint_core::Amount;
+use fedimint_core::config::FederationId;
+use fedimint_core::core::OperationId;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// A transaction from another fedimint wallet's history, to be imported into ours
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryItem {
+    pub operation_id: OperationId,
+    pub federation_id: FederationId,
+    pub kind: OperationKind,
+    pub amount: Amount,
+    pub fee: Amount,
+    pub txid: Option<Txid>,
+    pub preimage: Option<[u8; 32]>,
+    /// Unix timestamp of when the transaction happened
+    pub timestamp: u64,
+}
+
+impl HistoryItem {
+    /// Checks the item describes a transaction that can be shown in history
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let id = self.operation_id.fmt_full();
+        if self.amount == Amount::ZERO {
+            bail!("Imported transaction {id} has no amount");
+        }
+        let total = self.amount.msats.checked_add(self.fee.msats);
+        if total.and_then(|t| i64::try_from(t).ok()).is_none() {
+            bail!("Imported transaction {id} has an invalid amount");
+        }
+        if chrono::DateTime::from_timestamp(self.timestamp as i64, 0).is_none() {
+            bail!("Imported transaction {id} has an invalid timestamp");
+        }
+
+        match self.kind {
+            OperationKind::LightningSend
+            | OperationKind::LightningReceive
+            | OperationKind::InternalSend => {
+                if self.txid.is_some() {
+                    bail!("Lightning transaction {id} can't have a txid");
+                }
+            }
+            OperationKind::OnchainSend | OperationKind::OnchainReceive => {
+                if self.preimage.is_some() {
+                    bail!("Onchain transaction {id} can't have a preimage");
+                }
+            }
+            OperationKind::EcashSpend | OperationKind::EcashReissue => {
+                bail!("Ecash transaction {id} can't be imported");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = imported_history)]
+pub struct ImportedHistory {
+    pub operation_id: String,
+    fedimint_id: String,
+    kind: i32,
+    amount_msats: i64,
+    fee_msats: i64,
+    txid: Option<String>,
+    preimage: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = imported_history)]
+struct NewImportedHistory {
+    operation_id: String,
+    fedimint_id: String,
+    kind: i32,
+    amount_msats: i64,
+    fee_msats: i64,
+    txid: Option<String>,
+    preimage: Option<String>,
+    created_at: chrono::NaiveDateTime,
+}
+
+impl ImportedHistory {
+    pub fn operation_id(&self) -> OperationId {
+        OperationId::from_str(&self.operation_id).expect("invalid operation id")
+    }
+
+    pub fn fedimint_id(&self) -> FederationId {
+        FederationId::from_str(&self.fedimint_id).expect("invalid fedimint_id")
+    }
+
+    pub fn kind(&self) -> OperationKind {
+        OperationKind::from_i32(self.kind)
+    }
+
+    pub fn amount(&self) -> Amount {
+        Amount::from_msats(self.amount_msats as u64)
+    }
+
+    pub fn fee(&self) -> Amount {
+        Amount::from_msats(self.fee_msats as u64)
+    }
+
+    pub fn txid(&self) -> Option<Txid> {
+        self.txid
+            .as_ref()
+            .map(|t| Txid::from_str(t).expect("invalid txid"))
+    }
+
+    pub fn preimage(&self) -> Option<[u8; 32]> {
+        self.preimage
+            .as_ref()
+            .map(|p| FromHex::from_hex(p).expect("invalid preimage"))
+    }
+
+    /// Validates and saves the items, skipping any we already have by operation id,
+    /// txid or preimage. Returns how many were imported.
+    pub fn import(conn: &mut SqliteConnection, items: Vec<HistoryItem>) -> anyhow::Result<usize> {
+        for item in &items {
+            item.validate()?;
+        }
+
+        conn.transaction(|conn| {
+            let mut imported = 0;
+            for item in items {
+                if Self::is_known(conn, &item)? {
+                    continue;
+                }
+
+                let new = NewImportedHistory {
+                    operation_id: item.operation_id.fmt_full().to_string(),
+                    fedimint_id: item.federation_id.to_string(),
+                    kind: item.kind as i32,
+                    amount_msats: item.amount.msats as i64,
+                    fee_msats: item.fee.msats as i64,
+                    txid: item.txid.map(|t| t.to_string()),
+                    preimage: item.preimage.map(hex::encode),
+                    created_at: chrono::DateTime::from_timestamp(item.timestamp as i64, 0)
+                        .expect("validated timestamp")
+                        .naive_utc(),
+                };
+                diesel::insert_into(imported_history::table)
+                    .values(new)
+                    .execute(conn)?;
+                imported += 1;
+            }
+
+            Ok(imported)
+        })
+    }
+
+    /// Whether the transaction is already in our history, made by us or imported before
+    fn is_known(conn: &mut SqliteConnection, item: &HistoryItem) -> anyhow::Result<bool> {
+        let operation_id = item.operation_id.fmt_full().to_string();
+        let known = diesel::select(
+            exists(
+                lightning_payments::table
+                    .filter(lightning_payments::operation_id.eq(&operation_id)),
+            )
+            .or(exists(
+                lightning_receives::table
+                    .filter(lightning_receives::operation_id.eq(&operation_id)),
+            ))
+            .or(exists(
+                on_chain_payments::table.filter(on_chain_payments::operation_id.eq(&operation_id)),
+            ))
+            .or(exists(
+                on_chain_receives::table.filter(on_chain_receives::operation_id.eq(&operation_id)),
+            ))
+            .or(exists(
+                imported_history::table.filter(imported_history::operation_id.eq(&operation_id)),
+            )),
+        )
+        .get_result::<bool>(conn)?;
+        if known {
+            return Ok(true);
+        }
+
+        if let Some(txid) = item.txid.map(|t| t.to_string()) {
+            let known = diesel::select(
+                exists(on_chain_payments::table.filter(on_chain_payments::txid.eq(&txid)))
+                    .or(exists(
+                        on_chain_receives::table.filter(on_chain_receives::txid.eq(&txid)),
+                    ))
+                    .or(exists(
+                        imported_history::table.filter(imported_history::txid.eq(&txid)),
+                    )),
+            )
+            .get_result::<bool>(conn)?;
+            if known {
+                return Ok(true);
+            }
+        }
+
+        if let Some(preimage) = item.preimage {
+            // we don't keep preimages for receives, their payment hash identifies them instead
+            let payment_hash = sha256::Hash::hash(&preimage).to_string();
+            let preimage = hex::encode(preimage);
+            let known = diesel::select(
+                exists(
+                    lightning_payments::table.filter(lightning_payments::preimage.eq(&preimage)),
+                )
+                .or(exists(
+                    lightning_receives::table
+                        .filter(lightning_receives::payment_hash.eq(&payment_hash)),
+                ))
+                .or(exists(
+                    imported_history::table.filter(imported_history::preimage.eq(&preimage)),
+                )),
+            )
+            .get_result::<bool>(conn)?;
+            if known {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub fn get_history(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        Ok(imported_history::table.load::<Self>(conn)?)
+    }
+}
+
+impl From<ImportedHistory> for TransactionItem {
+    fn from(item: ImportedHistory) -> Self {
+        let kind = match item.kind() {
+            OperationKind::OnchainSend | OperationKind::OnchainReceive => {
+                TransactionItemKind::Onchain
+            }
+            _ => TransactionItemKind::Lightning,
+        };
+        let direction = match item.kind() {
+            OperationKind::LightningReceive | OperationKind::OnchainReceive => {
+                TransactionDirection::Incoming
+            }
+            _ => TransactionDirection::Outgoing,
+        };
+
+        Self {
+            kind,
+            operation_kind: item.kind(),
+            amount: item.amount().sats_round_down(),
+            txid: item.txid(),
+            preimage: item.preimage(),
+            direction,
+            mint_identifier: MintIdentifier::Fedimint(item.fedimint_id()),
+            status: PaymentStatus::Success,
+            timestamp: item.created_at.and_utc().timestamp() as u64,
+            imported: true,
+            route: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
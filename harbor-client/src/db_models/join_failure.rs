@@ -0,0 +1,68 @@
+use crate::db_models::schema::join_failures;
+use bitcoin::hashes::{Hash, sha256};
+use diesel::prelude::*;
+use fedimint_core::invite_code::InviteCode;
+
+/// How many join failures are kept, older ones are removed as new ones are recorded
+const MAX_JOIN_FAILURES: i64 = 50;
+
+/// Longest error summary that is kept for a join failure
+const MAX_ERROR_LEN: usize = 500;
+
+/// A failed attempt to join a federation. Only a hash of the invite code is kept, which is
+/// enough to match it against an invite code the user shares.
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = join_failures)]
+pub struct JoinFailure {
+    pub id: i32,
+    pub invite_code_hash: String,
+    pub error: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = join_failures)]
+struct NewJoinFailure {
+    invite_code_hash: String,
+    error: String,
+}
+
+/// The hash an invite code's join failures are recorded under
+pub fn invite_code_hash(invite_code: &InviteCode) -> String {
+    sha256::Hash::hash(invite_code.to_string().as_bytes()).to_string()
+}
+
+impl JoinFailure {
+    pub fn record(
+        conn: &mut SqliteConnection,
+        invite_code: &InviteCode,
+        error: &str,
+    ) -> anyhow::Result<()> {
+        let error = error.chars().take(MAX_ERROR_LEN).collect();
+        let new = NewJoinFailure {
+            invite_code_hash: invite_code_hash(invite_code),
+            error,
+        };
+
+        conn.transaction(|conn| {
+            diesel::insert_into(join_failures::table)
+                .values(new)
+                .execute(conn)?;
+
+            let keep = join_failures::table
+                .select(join_failures::id)
+                .order(join_failures::id.desc())
+                .limit(MAX_JOIN_FAILURES);
+            diesel::delete(join_failures::table.filter(join_failures::id.ne_all(keep)))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// The most recent join failures, newest first
+    pub fn recent(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        Ok(join_failures::table
+            .order(join_failures::id.desc())
+            .load::<Self>(conn)?)
+    }
+}
@@ -1,17 +1,21 @@
 use crate::MintIdentifier;
-use crate::db_models::PaymentStatus;
 use crate::db_models::schema::lightning_payments;
 use crate::db_models::transaction_item::{
-    TransactionDirection, TransactionItem, TransactionItemKind,
+    PaymentRoute, TransactionDirection, TransactionItem, TransactionItemKind,
 };
+use crate::db_models::{OperationKind, PaymentStatus};
 use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{Hash, sha256};
 use cdk::mint_url::MintUrl;
 use diesel::prelude::*;
 use fedimint_core::Amount;
 use fedimint_core::config::FederationId;
 use fedimint_core::core::OperationId;
+use fedimint_core::secp256k1::PublicKey;
 use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
 #[diesel(table_name = lightning_payments)]
@@ -27,6 +31,29 @@ pub struct LightningPayment {
     status: i32,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    kind: i32,
+    gateway_alias: Option<String>,
+    gateway_pubkey: Option<String>,
+}
+
+/// Stored as the gateway alias of internal payments, which have no gateway node
+const INTERNAL_ROUTE: &str = "internal";
+
+/// How our finished lightning payments through a gateway went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GatewayStats {
+    pub successes: u32,
+    pub failures: u32,
+    /// Average time from starting a payment to it succeeding, `None` until one has
+    pub average_latency: Option<Duration>,
+}
+
+impl GatewayStats {
+    /// Share of finished payments that succeeded in thousandths, `None` until one finished
+    pub fn success_rate_permille(&self) -> Option<u32> {
+        let finished = self.successes + self.failures;
+        (finished > 0).then(|| self.successes * 1_000 / finished)
+    }
 }
 
 #[derive(Insertable, Clone)]
@@ -40,6 +67,7 @@ struct NewLightningPayment {
     amount_msats: i64,
     fee_msats: i64,
     status: i32,
+    kind: i32,
 }
 
 impl LightningPayment {
@@ -88,10 +116,36 @@ impl LightningPayment {
             .map(|p| FromHex::from_hex(p).expect("invalid preimage"))
     }
 
+    /// The invoice and preimage proving this payment was made. Only given when the
+    /// preimage actually hashes to the invoice's payment hash.
+    pub fn proof_of_payment(&self) -> Option<(Bolt11Invoice, [u8; 32])> {
+        let preimage = self.preimage()?;
+        if sha256::Hash::hash(&preimage).to_byte_array() != self.payment_hash() {
+            return None;
+        }
+        Some((self.bolt11(), preimage))
+    }
+
+    pub fn kind(&self) -> OperationKind {
+        OperationKind::from_i32(self.kind)
+    }
+
     pub fn status(&self) -> PaymentStatus {
         PaymentStatus::from_i32(self.status)
     }
 
+    /// How the payment was routed, `None` if it wasn't recorded
+    pub fn route(&self) -> Option<PaymentRoute> {
+        match (&self.gateway_alias, &self.gateway_pubkey) {
+            (Some(alias), Some(pubkey)) => Some(PaymentRoute::Gateway {
+                alias: alias.clone(),
+                node_pub_key: PublicKey::from_str(pubkey).expect("invalid gateway pubkey"),
+            }),
+            (Some(alias), None) if alias == INTERNAL_ROUTE => Some(PaymentRoute::Internal),
+            _ => None,
+        }
+    }
+
     pub fn create(
         conn: &mut SqliteConnection,
         operation_id: String,
@@ -100,6 +154,7 @@ impl LightningPayment {
         bolt11: Bolt11Invoice,
         amount: Amount,
         fee: Amount,
+        kind: OperationKind,
     ) -> anyhow::Result<()> {
         // Make sure the amount matches
         if bolt11
@@ -119,6 +174,7 @@ impl LightningPayment {
             amount_msats: amount.msats as i64,
             fee_msats: fee.msats as i64,
             status: PaymentStatus::Pending as i32,
+            kind: kind as i32,
         };
 
         diesel::insert_into(lightning_payments::table)
@@ -138,6 +194,18 @@ impl LightningPayment {
             .optional()?)
     }
 
+    /// The successful payment of the invoice with this payment hash, if we made one
+    pub fn get_paid_by_payment_hash(
+        conn: &mut SqliteConnection,
+        payment_hash: sha256::Hash,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(lightning_payments::table
+            .filter(lightning_payments::payment_hash.eq(payment_hash.to_string()))
+            .filter(lightning_payments::status.eq(PaymentStatus::Success as i32))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
     pub fn set_preimage(
         conn: &mut SqliteConnection,
         operation_id: String,
@@ -155,6 +223,86 @@ impl LightningPayment {
         Ok(())
     }
 
+    pub fn set_route(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+        route: PaymentRoute,
+    ) -> anyhow::Result<()> {
+        let (alias, pubkey) = match route {
+            PaymentRoute::Internal => (INTERNAL_ROUTE.to_string(), None),
+            PaymentRoute::Gateway {
+                alias,
+                node_pub_key,
+            } => (alias, Some(node_pub_key.to_string())),
+        };
+
+        diesel::update(
+            lightning_payments::table.filter(lightning_payments::operation_id.eq(operation_id)),
+        )
+        .set((
+            lightning_payments::gateway_alias.eq(Some(alias)),
+            lightning_payments::gateway_pubkey.eq(pubkey),
+        ))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Stats for every gateway we finished a payment through, by the gateway's node key.
+    /// Latency is measured from when the payment was created to its last update, which for
+    /// a successful payment is when it succeeded.
+    pub fn gateway_stats(
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<HashMap<PublicKey, GatewayStats>> {
+        let payments = lightning_payments::table
+            .filter(lightning_payments::gateway_pubkey.is_not_null())
+            .filter(lightning_payments::status.eq_any([
+                PaymentStatus::Success as i32,
+                PaymentStatus::Failed as i32,
+                PaymentStatus::Refunded as i32,
+            ]))
+            .load::<Self>(conn)?;
+
+        let mut stats: HashMap<PublicKey, GatewayStats> = HashMap::new();
+        let mut total_latency: HashMap<PublicKey, Duration> = HashMap::new();
+        for payment in payments {
+            let Some(Ok(key)) = payment.gateway_pubkey.as_deref().map(PublicKey::from_str) else {
+                continue;
+            };
+            let entry = stats.entry(key).or_default();
+            if payment.status() == PaymentStatus::Success {
+                entry.successes += 1;
+                let latency = (payment.updated_at - payment.created_at)
+                    .to_std()
+                    .unwrap_or_default();
+                *total_latency.entry(key).or_default() += latency;
+            } else {
+                entry.failures += 1;
+            }
+        }
+
+        for (key, total) in total_latency {
+            if let Some(entry) = stats.get_mut(&key) {
+                entry.average_latency = Some(total / entry.successes);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Marks the payment as successful without a preimage, for when it wasn't reported
+    pub fn mark_as_success(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<()> {
+        diesel::update(
+            lightning_payments::table.filter(lightning_payments::operation_id.eq(operation_id)),
+        )
+        .set(lightning_payments::status.eq(PaymentStatus::Success as i32))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
     pub fn mark_as_failed(conn: &mut SqliteConnection, operation_id: String) -> anyhow::Result<()> {
         diesel::update(
             lightning_payments::table.filter(lightning_payments::operation_id.eq(operation_id)),
@@ -165,9 +313,27 @@ impl LightningPayment {
         Ok(())
     }
 
+    pub fn mark_as_refunded(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<()> {
+        diesel::update(
+            lightning_payments::table.filter(lightning_payments::operation_id.eq(operation_id)),
+        )
+        .set(lightning_payments::status.eq(PaymentStatus::Refunded as i32))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Successful payments, and failed ones that were refunded so it's clear where the
+    /// money went
     pub fn get_history(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
         Ok(lightning_payments::table
-            .filter(lightning_payments::status.eq(PaymentStatus::Success as i32))
+            .filter(lightning_payments::status.eq_any([
+                PaymentStatus::Success as i32,
+                PaymentStatus::Refunded as i32,
+            ]))
             .load::<Self>(conn)?)
     }
 
@@ -185,6 +351,7 @@ impl From<LightningPayment> for TransactionItem {
     fn from(payment: LightningPayment) -> Self {
         Self {
             kind: TransactionItemKind::Lightning,
+            operation_kind: payment.kind(),
             amount: payment.amount().sats_round_down(),
             txid: None,
             preimage: payment.preimage(),
@@ -192,6 +359,9 @@ impl From<LightningPayment> for TransactionItem {
             mint_identifier: payment.mint_identifier(),
             status: payment.status(),
             timestamp: payment.updated_at.and_utc().timestamp() as u64,
+            imported: false,
+            route: payment.route(),
+            metadata: BTreeMap::new(),
         }
     }
 }
@@ -1,9 +1,9 @@
 use crate::MintIdentifier;
-use crate::db_models::PaymentStatus;
 use crate::db_models::schema::lightning_receives;
 use crate::db_models::transaction_item::{
     TransactionDirection, TransactionItem, TransactionItemKind,
 };
+use crate::db_models::{OperationKind, PaymentStatus};
 use bitcoin::hashes::hex::FromHex;
 use cdk::mint_url::MintUrl;
 use diesel::prelude::*;
@@ -11,6 +11,7 @@ use fedimint_core::Amount;
 use fedimint_core::config::FederationId;
 use fedimint_core::core::OperationId;
 use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 #[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
@@ -26,6 +27,7 @@ pub struct LightningReceive {
     status: i32,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    kind: i32,
 }
 
 #[derive(Insertable, Clone)]
@@ -39,6 +41,7 @@ struct NewLightningReceive {
     amount_msats: i64,
     fee_msats: i64,
     status: i32,
+    kind: i32,
 }
 
 impl LightningReceive {
@@ -81,6 +84,10 @@ impl LightningReceive {
         Amount::from_msats(self.fee_msats as u64)
     }
 
+    pub fn kind(&self) -> OperationKind {
+        OperationKind::from_i32(self.kind)
+    }
+
     pub fn status(&self) -> PaymentStatus {
         PaymentStatus::from_i32(self.status)
     }
@@ -113,6 +120,7 @@ impl LightningReceive {
             amount_msats: amount.msats as i64,
             fee_msats: fee.msats as i64,
             status: PaymentStatus::Pending as i32,
+            kind: OperationKind::LightningReceive as i32,
         };
 
         diesel::insert_into(lightning_receives::table)
@@ -145,6 +153,20 @@ impl LightningReceive {
         Ok(())
     }
 
+    pub fn set_amount(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+        amount: Amount,
+    ) -> anyhow::Result<()> {
+        diesel::update(
+            lightning_receives::table.filter(lightning_receives::operation_id.eq(operation_id)),
+        )
+        .set(lightning_receives::amount_msats.eq(amount.msats as i64))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
     pub fn mark_as_failed(conn: &mut SqliteConnection, operation_id: String) -> anyhow::Result<()> {
         diesel::update(
             lightning_receives::table.filter(lightning_receives::operation_id.eq(operation_id)),
@@ -175,6 +197,7 @@ impl From<LightningReceive> for TransactionItem {
     fn from(payment: LightningReceive) -> Self {
         Self {
             kind: TransactionItemKind::Lightning,
+            operation_kind: payment.kind(),
             amount: payment.amount().sats_round_down(),
             txid: None,
             preimage: None,
@@ -182,6 +205,9 @@ impl From<LightningReceive> for TransactionItem {
             mint_identifier: payment.mint_identifier(),
             status: payment.status(),
             timestamp: payment.updated_at.and_utc().timestamp() as u64,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
         }
     }
 }
@@ -4,6 +4,9 @@ pub use profile::*;
 pub mod fedimint;
 pub use fedimint::*;
 
+pub mod fedimint_kv;
+pub use fedimint_kv::*;
+
 pub mod cashu_mint;
 pub use cashu_mint::*;
 
@@ -19,6 +22,30 @@ pub use onchain_payment::*;
 pub mod onchain_receive;
 pub use onchain_receive::*;
 
+pub mod ecash_payment;
+pub use ecash_payment::*;
+
+pub mod ecash_receive;
+pub use ecash_receive::*;
+
+pub mod payment_idempotency;
+pub use payment_idempotency::*;
+
+pub mod imported_history;
+pub use imported_history::*;
+
+pub mod federation_config;
+pub use federation_config::*;
+
+pub mod join_failure;
+pub use join_failure::*;
+
+pub mod operation_metadata;
+pub use operation_metadata::*;
+
+pub mod history_retention;
+pub use history_retention::*;
+
 pub(crate) mod schema;
 
 pub mod mint_metadata;
@@ -26,19 +53,45 @@ pub mod transaction_item;
 
 use crate::MintIdentifier;
 use crate::metadata::FederationMeta;
-use fedimint_core::config::FederationId;
-use fedimint_core::core::ModuleKind;
+use fedimint_core::config::{ClientConfig, FederationId};
+use fedimint_core::core::{ModuleInstanceId, ModuleKind};
+
+/// A module a federation runs, as described by its client config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub instance_id: ModuleInstanceId,
+    pub kind: ModuleKind,
+}
+
+impl ModuleInfo {
+    /// Lists the modules in a federation's config, ordered by instance id.
+    pub fn from_config(config: &ClientConfig) -> Vec<Self> {
+        config
+            .modules
+            .iter()
+            .map(|(instance_id, module_config)| Self {
+                instance_id: *instance_id,
+                kind: module_config.kind().to_owned(),
+            })
+            .collect()
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MintItem {
     pub id: MintIdentifier,
     pub name: String,
+    /// What can be spent, in sats
     pub balance: u64,
+    /// The balance to show, adjusted by the user's display balance policy
+    pub display_balance: u64,
     pub guardians: Option<Vec<String>>,
-    pub module_kinds: Option<Vec<ModuleKind>>,
+    pub modules: Option<Vec<ModuleInfo>>,
     pub metadata: FederationMeta,
     pub on_chain_supported: bool,
     pub active: bool,
+    /// The balance is the last one saved, the client hasn't loaded yet
+    pub balance_cached: bool,
 }
 
 impl MintItem {
@@ -47,13 +100,40 @@ impl MintItem {
             id: MintIdentifier::Fedimint(id),
             name: "Unknown".to_string(),
             balance: 0,
+            display_balance: 0,
             guardians: None,
-            module_kinds: None,
+            modules: None,
             metadata: FederationMeta::default(),
             on_chain_supported: false,
             active: true,
+            balance_cached: false,
         }
     }
+
+    /// Whether the mint is known to run a module of the given kind.
+    /// Returns false if the module list is unknown, e.g. for cashu mints.
+    pub fn has_module(&self, kind: &ModuleKind) -> bool {
+        self.modules
+            .as_ref()
+            .is_some_and(|modules| modules.iter().any(|m| &m.kind == kind))
+    }
+
+    /// Whether the mint can send and receive over lightning.
+    /// Cashu mints always can, federations need one of the lightning modules.
+    pub fn supports_lightning(&self) -> bool {
+        match self.id {
+            MintIdentifier::Cashu(_) => true,
+            MintIdentifier::Fedimint(_) => {
+                self.has_module(&fedimint_ln_common::KIND)
+                    || self.has_module(&fedimint_lnv2_common::KIND)
+            }
+        }
+    }
+
+    /// Whether the mint runs a wallet module, which is required for on-chain payments.
+    pub fn has_wallet_module(&self) -> bool {
+        self.has_module(&fedimint_wallet_client::KIND)
+    }
 }
 
 impl PartialOrd for MintItem {
@@ -72,6 +152,40 @@ impl Ord for MintItem {
     }
 }
 
+/// What an operation does, saved when it is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OperationKind {
+    /// Paying a lightning invoice through a gateway
+    LightningSend = 0,
+    /// Receiving a lightning payment
+    LightningReceive = 1,
+    /// Paying an invoice from the same federation, settled without a gateway
+    InternalSend = 2,
+    /// Withdrawing onchain
+    OnchainSend = 3,
+    /// Depositing onchain
+    OnchainReceive = 4,
+    /// Spending ecash out of band
+    EcashSpend = 5,
+    /// Reissuing ecash received out of band
+    EcashReissue = 6,
+}
+
+impl OperationKind {
+    pub fn from_i32(kind: i32) -> Self {
+        match kind {
+            0 => OperationKind::LightningSend,
+            1 => OperationKind::LightningReceive,
+            2 => OperationKind::InternalSend,
+            3 => OperationKind::OnchainSend,
+            4 => OperationKind::OnchainReceive,
+            5 => OperationKind::EcashSpend,
+            6 => OperationKind::EcashReissue,
+            _ => panic!("invalid operation kind"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum PaymentStatus {
     /// Payment is in flight or has not been received yet
@@ -82,6 +196,8 @@ pub enum PaymentStatus {
     Success = 2,
     /// Payment failed
     Failed = 3,
+    /// Payment failed and what it locked up was refunded to our balance
+    Refunded = 4,
 }
 
 impl PaymentStatus {
@@ -91,6 +207,7 @@ impl PaymentStatus {
             1 => PaymentStatus::WaitingConfirmation,
             2 => PaymentStatus::Success,
             3 => PaymentStatus::Failed,
+            4 => PaymentStatus::Refunded,
             _ => panic!("invalid status"),
         }
     }
@@ -1,21 +1,22 @@
 use crate::MintIdentifier;
-use crate::db_models::PaymentStatus;
 use crate::db_models::schema::on_chain_payments;
 use crate::db_models::transaction_item::{
     TransactionDirection, TransactionItem, TransactionItemKind,
 };
+use crate::db_models::{OperationKind, PaymentStatus};
 use bitcoin::address::NetworkUnchecked;
 use bitcoin::{Address, Txid};
 use cdk::mint_url::MintUrl;
 use diesel::prelude::*;
 use fedimint_core::config::FederationId;
 use fedimint_core::core::OperationId;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 #[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
 #[diesel(table_name = on_chain_payments)]
 pub struct OnChainPayment {
-    operation_id: String,
+    pub operation_id: String,
     fedimint_id: Option<String>,
     cashu_mint_url: Option<String>,
     address: String,
@@ -25,6 +26,8 @@ pub struct OnChainPayment {
     status: i32,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    kind: i32,
+    retry_of: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -37,6 +40,8 @@ struct NewOnChainPayment {
     amount_sats: i64,
     fee_sats: i64,
     status: i32,
+    kind: i32,
+    retry_of: Option<String>,
 }
 
 impl OnChainPayment {
@@ -73,10 +78,21 @@ impl OnChainPayment {
             .map(|p| Txid::from_str(p).expect("invalid txid"))
     }
 
+    pub fn kind(&self) -> OperationKind {
+        OperationKind::from_i32(self.kind)
+    }
+
     pub fn status(&self) -> PaymentStatus {
         PaymentStatus::from_i32(self.status)
     }
 
+    /// The failed withdrawal this one was retrying, if any
+    pub fn retry_of(&self) -> Option<OperationId> {
+        self.retry_of
+            .as_ref()
+            .map(|o| OperationId::from_str(o).expect("invalid operation id"))
+    }
+
     pub fn create(
         conn: &mut SqliteConnection,
         operation_id: String,
@@ -85,6 +101,7 @@ impl OnChainPayment {
         address: Address,
         amount_sats: u64,
         fee_sats: u64,
+        retry_of: Option<OperationId>,
     ) -> anyhow::Result<()> {
         let new = NewOnChainPayment {
             operation_id,
@@ -94,6 +111,8 @@ impl OnChainPayment {
             amount_sats: amount_sats as i64,
             fee_sats: fee_sats as i64,
             status: PaymentStatus::Pending as i32,
+            kind: OperationKind::OnchainSend as i32,
+            retry_of: retry_of.map(|o| o.fmt_full().to_string()),
         };
 
         diesel::insert_into(on_chain_payments::table)
@@ -141,6 +160,17 @@ impl OnChainPayment {
         Ok(())
     }
 
+    /// Finds the withdrawal that was started to retry the given one
+    pub fn get_retry(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(on_chain_payments::table
+            .filter(on_chain_payments::retry_of.eq(Some(operation_id)))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
     pub fn get_history(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
         Ok(on_chain_payments::table
             .filter(on_chain_payments::status.eq(PaymentStatus::Success as i32))
@@ -161,6 +191,7 @@ impl From<OnChainPayment> for TransactionItem {
     fn from(payment: OnChainPayment) -> Self {
         Self {
             kind: TransactionItemKind::Onchain,
+            operation_kind: payment.kind(),
             amount: payment.amount_sats as u64,
             txid: payment
                 .txid
@@ -171,6 +202,9 @@ impl From<OnChainPayment> for TransactionItem {
             mint_identifier: payment.mint_identifier(),
             status: payment.status(),
             timestamp: payment.updated_at.and_utc().timestamp() as u64,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
         }
     }
 }
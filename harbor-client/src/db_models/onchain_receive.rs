@@ -1,21 +1,22 @@
 use crate::MintIdentifier;
-use crate::db_models::PaymentStatus;
 use crate::db_models::schema::on_chain_receives;
 use crate::db_models::transaction_item::{
     TransactionDirection, TransactionItem, TransactionItemKind,
 };
+use crate::db_models::{OperationKind, PaymentStatus};
 use bitcoin::address::NetworkUnchecked;
 use bitcoin::{Address, Txid};
 use cdk::mint_url::MintUrl;
 use diesel::prelude::*;
 use fedimint_core::config::FederationId;
 use fedimint_core::core::OperationId;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 #[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
 #[diesel(table_name = on_chain_receives)]
 pub struct OnChainReceive {
-    operation_id: String,
+    pub operation_id: String,
     fedimint_id: Option<String>,
     cashu_mint_url: Option<String>,
     address: String,
@@ -25,6 +26,7 @@ pub struct OnChainReceive {
     status: i32,
     pub created_at: chrono::NaiveDateTime,
     pub updated_at: chrono::NaiveDateTime,
+    kind: i32,
 }
 
 #[derive(Insertable)]
@@ -35,6 +37,7 @@ struct NewOnChainReceive {
     cashu_mint_url: Option<String>,
     address: String,
     status: i32,
+    kind: i32,
 }
 
 impl OnChainReceive {
@@ -71,6 +74,10 @@ impl OnChainReceive {
             .map(|p| Txid::from_str(p).expect("invalid txid"))
     }
 
+    pub fn kind(&self) -> OperationKind {
+        OperationKind::from_i32(self.kind)
+    }
+
     pub fn status(&self) -> PaymentStatus {
         PaymentStatus::from_i32(self.status)
     }
@@ -88,6 +95,7 @@ impl OnChainReceive {
             cashu_mint_url: cashu_mint_url.map(|f| f.to_string()),
             address: address.to_string(),
             status: PaymentStatus::Pending as i32,
+            kind: OperationKind::OnchainReceive as i32,
         };
 
         diesel::insert_into(on_chain_receives::table)
@@ -163,6 +171,23 @@ impl OnChainReceive {
             .load::<Self>(conn)?)
     }
 
+    /// Gets the newest address for the federation created after `since` that hasn't
+    /// received anything yet
+    pub fn get_unused_since(
+        conn: &mut SqliteConnection,
+        fedimint_id: String,
+        since: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(on_chain_receives::table
+            .filter(on_chain_receives::fedimint_id.eq(fedimint_id))
+            .filter(on_chain_receives::status.eq(PaymentStatus::Pending as i32))
+            .filter(on_chain_receives::txid.is_null())
+            .filter(on_chain_receives::created_at.gt(since))
+            .order(on_chain_receives::created_at.desc())
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
     pub fn get_pending(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
         Ok(on_chain_receives::table
             .filter(on_chain_receives::status.eq_any([
@@ -177,6 +202,7 @@ impl From<OnChainReceive> for TransactionItem {
     fn from(payment: OnChainReceive) -> Self {
         Self {
             kind: TransactionItemKind::Onchain,
+            operation_kind: payment.kind(),
             amount: payment.amount_sats.unwrap_or(0) as u64, // todo handle this better
             txid: payment
                 .txid
@@ -187,6 +213,9 @@ impl From<OnChainReceive> for TransactionItem {
             mint_identifier: payment.mint_identifier(),
             status: payment.status(),
             timestamp: payment.updated_at.and_utc().timestamp() as u64,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
         }
     }
 }
@@ -0,0 +1,87 @@
+use crate::db_models::schema::operation_metadata;
+use diesel::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Key/value data an integration attached to an operation, like an order id or customer
+/// reference. This is for machines, it is never shown as a label.
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = operation_metadata)]
+pub struct OperationMetadata {
+    pub operation_id: String,
+    /// The map as a JSON object
+    metadata: String,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = operation_metadata)]
+struct NewOperationMetadata {
+    operation_id: String,
+    metadata: String,
+}
+
+impl OperationMetadata {
+    pub fn metadata(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        Ok(serde_json::from_str(&self.metadata)?)
+    }
+
+    /// Replaces the operation's metadata, an empty map removes it
+    pub fn set(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+        metadata: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        if metadata.is_empty() {
+            diesel::delete(
+                operation_metadata::table.filter(operation_metadata::operation_id.eq(operation_id)),
+            )
+            .execute(conn)?;
+            return Ok(());
+        }
+
+        let new = NewOperationMetadata {
+            operation_id,
+            metadata: serde_json::to_string(metadata)?,
+        };
+        diesel::insert_into(operation_metadata::table)
+            .values(new.clone())
+            .on_conflict(operation_metadata::operation_id)
+            .do_update()
+            .set((
+                operation_metadata::metadata.eq(new.metadata),
+                operation_metadata::updated_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn get(
+        conn: &mut SqliteConnection,
+        operation_id: String,
+    ) -> anyhow::Result<Option<BTreeMap<String, String>>> {
+        let row = operation_metadata::table
+            .filter(operation_metadata::operation_id.eq(operation_id))
+            .first::<Self>(conn)
+            .optional()?;
+        row.map(|r| r.metadata()).transpose()
+    }
+
+    /// Every operation's metadata by operation id, rows that can't be read are skipped
+    /// so one bad row doesn't hide the rest
+    pub fn get_all(
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<HashMap<String, BTreeMap<String, String>>> {
+        let rows = operation_metadata::table.load::<Self>(conn)?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|r| match r.metadata() {
+                Ok(metadata) => Some((r.operation_id, metadata)),
+                Err(e) => {
+                    log::error!("Invalid metadata for operation {}: {e}", r.operation_id);
+                    None
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,52 @@
+use crate::db_models::schema::payment_idempotency;
+use diesel::prelude::*;
+
+#[derive(QueryableByName, Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = payment_idempotency)]
+pub struct PaymentIdempotency {
+    pub key: String,
+    pub operation_id: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, Clone)]
+#[diesel(table_name = payment_idempotency)]
+struct NewPaymentIdempotency {
+    key: String,
+    operation_id: String,
+}
+
+impl PaymentIdempotency {
+    /// Gets the entry for the key if it was recorded after `since`
+    pub fn get_since(
+        conn: &mut SqliteConnection,
+        key: String,
+        since: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(payment_idempotency::table
+            .filter(payment_idempotency::key.eq(key))
+            .filter(payment_idempotency::created_at.gt(since))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    pub fn upsert(
+        conn: &mut SqliteConnection,
+        key: String,
+        operation_id: String,
+    ) -> anyhow::Result<()> {
+        let new = NewPaymentIdempotency { key, operation_id };
+
+        diesel::insert_into(payment_idempotency::table)
+            .values(new.clone())
+            .on_conflict(payment_idempotency::key)
+            .do_update()
+            .set((
+                payment_idempotency::operation_id.eq(new.operation_id),
+                payment_idempotency::created_at.eq(diesel::dsl::now),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
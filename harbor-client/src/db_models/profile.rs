@@ -1,8 +1,17 @@
 use crate::db_models::schema::profile;
+use crate::fedimint_client::SelectionPriority;
+use crate::{
+    DEFAULT_FEDERATION_MEMORY_CAP, DEFAULT_FEDERATION_STATUS_INTERVAL, DEFAULT_JOIN_TIMEOUT,
+    DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS, DEFAULT_UPDATE_STALL_TIMEOUT, DisplayBalancePolicy,
+    MIN_FEDERATION_STATUS_INTERVAL,
+};
 use bip39::Mnemonic;
 use diesel::prelude::*;
+use fedimint_core::Amount;
+use fedimint_core::secp256k1::PublicKey;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(
     QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
@@ -13,6 +22,19 @@ pub struct Profile {
     pub seed_words: String,
     onchain_receive_enabled: i32,
     tor_enabled: i32,
+    update_stall_timeout_secs: Option<i32>,
+    max_send_fee_sats: Option<i32>,
+    receive_batch_window_secs: Option<i32>,
+    federation_memory_cap_bytes: Option<i64>,
+    require_private_payments: i32,
+    include_pending_balance: i32,
+    dust_threshold_sats: i64,
+    max_concurrent_subscriptions: Option<i32>,
+    join_timeout_secs: Option<i32>,
+    gateway_selection_priority: i32,
+    federation_status_interval_secs: Option<i32>,
+    /// The gateway of [`SelectionPriority::Manual`]
+    manual_gateway: Option<String>,
 }
 
 impl Profile {
@@ -55,6 +77,194 @@ impl Profile {
     pub fn tor_enabled(&self) -> bool {
         self.tor_enabled == 1
     }
+
+    pub fn set_update_stall_timeout(
+        conn: &mut SqliteConnection,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating update stall timeout in database to: {timeout:?}");
+        diesel::update(profile::table)
+            .set(profile::update_stall_timeout_secs.eq(timeout.map(|t| t.as_secs() as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_max_send_fee(
+        conn: &mut SqliteConnection,
+        max_fee: Option<Amount>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating max send fee in database to: {max_fee:?}");
+        diesel::update(profile::table)
+            .set(profile::max_send_fee_sats.eq(max_fee.map(|f| f.sats_round_down() as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn set_receive_batch_window(
+        conn: &mut SqliteConnection,
+        window: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating receive batch window in database to: {window:?}");
+        diesel::update(profile::table)
+            .set(profile::receive_batch_window_secs.eq(window.map(|w| w.as_secs() as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How long after a receive further receives are reported together, `None` to report
+    /// each one
+    pub fn receive_batch_window(&self) -> Option<Duration> {
+        self.receive_batch_window_secs
+            .map(|s| Duration::from_secs(s as u64))
+    }
+
+    pub fn set_federation_memory_cap(
+        conn: &mut SqliteConnection,
+        cap: Option<usize>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating federation memory cap in database to: {cap:?}");
+        diesel::update(profile::table)
+            .set(profile::federation_memory_cap_bytes.eq(cap.map(|c| c as i64)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How many bytes of federation data can be loaded into memory before we warn about it
+    pub fn federation_memory_cap(&self) -> usize {
+        self.federation_memory_cap_bytes
+            .map_or(DEFAULT_FEDERATION_MEMORY_CAP, |c| c as usize)
+    }
+
+    pub fn set_require_private_payments(
+        conn: &mut SqliteConnection,
+        required: bool,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating require private payments in database to: {required}");
+        diesel::update(profile::table)
+            .set(profile::require_private_payments.eq(required as i32))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Whether lightning payments may only use gateways that support private payments
+    pub fn require_private_payments(&self) -> bool {
+        self.require_private_payments == 1
+    }
+
+    pub fn set_display_balance_policy(
+        conn: &mut SqliteConnection,
+        policy: DisplayBalancePolicy,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating display balance policy in database to: {policy:?}");
+        diesel::update(profile::table)
+            .set((
+                profile::include_pending_balance.eq(policy.include_pending as i32),
+                profile::dust_threshold_sats.eq(policy.dust_threshold.sats_round_down() as i64),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How balances are adjusted before they are shown
+    pub fn display_balance_policy(&self) -> DisplayBalancePolicy {
+        DisplayBalancePolicy {
+            include_pending: self.include_pending_balance == 1,
+            dust_threshold: Amount::from_sats(self.dust_threshold_sats as u64),
+        }
+    }
+
+    pub fn set_max_concurrent_subscriptions(
+        conn: &mut SqliteConnection,
+        limit: Option<usize>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating max concurrent subscriptions in database to: {limit:?}");
+        diesel::update(profile::table)
+            .set(profile::max_concurrent_subscriptions.eq(limit.map(|l| l as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How many payment subscriptions may run at once, more are queued until one finishes
+    pub fn max_concurrent_subscriptions(&self) -> usize {
+        self.max_concurrent_subscriptions
+            .map_or(DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS, |l| l as usize)
+    }
+
+    pub fn set_join_timeout(
+        conn: &mut SqliteConnection,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating join timeout in database to: {timeout:?}");
+        diesel::update(profile::table)
+            .set(profile::join_timeout_secs.eq(timeout.map(|t| t.as_secs() as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How long downloading a federation's config may take before joining gives up
+    pub fn join_timeout(&self) -> Duration {
+        self.join_timeout_secs
+            .map_or(DEFAULT_JOIN_TIMEOUT, |s| Duration::from_secs(s as u64))
+    }
+
+    pub fn set_federation_status_interval(
+        conn: &mut SqliteConnection,
+        interval: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating federation status interval in database to: {interval:?}");
+        diesel::update(profile::table)
+            .set(profile::federation_status_interval_secs.eq(interval.map(|i| i.as_secs() as i32)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// How often a federation's guardians are probed for their status
+    pub fn federation_status_interval(&self) -> Duration {
+        self.federation_status_interval_secs
+            .map_or(DEFAULT_FEDERATION_STATUS_INTERVAL, |s| {
+                Duration::from_secs(s.max(0) as u64)
+            })
+            .max(MIN_FEDERATION_STATUS_INTERVAL)
+    }
+
+    pub fn set_gateway_selection_priority(
+        conn: &mut SqliteConnection,
+        priority: SelectionPriority,
+    ) -> anyhow::Result<()> {
+        log::debug!("Updating gateway selection priority in database to: {priority:?}");
+        diesel::update(profile::table)
+            .set((
+                profile::gateway_selection_priority.eq(priority.to_i32()),
+                profile::manual_gateway.eq(match priority {
+                    SelectionPriority::Manual(id) => Some(id.to_string()),
+                    _ => None,
+                }),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// What gateway selection optimizes for
+    pub fn gateway_selection_priority(&self) -> SelectionPriority {
+        let manual_gateway = self
+            .manual_gateway
+            .as_deref()
+            .and_then(|id| PublicKey::from_str(id).ok());
+        SelectionPriority::from_i32(self.gateway_selection_priority, manual_gateway)
+    }
+
+    /// The most a lightning payment may pay in gateway fees, `None` for no limit
+    pub fn max_send_fee(&self) -> Option<Amount> {
+        self.max_send_fee_sats.map(|s| Amount::from_sats(s as u64))
+    }
+
+    /// How long a payment's updates can stop before we check on it ourselves
+    pub fn update_stall_timeout(&self) -> Duration {
+        self.update_stall_timeout_secs
+            .map_or(DEFAULT_UPDATE_STALL_TIMEOUT, |s| {
+                Duration::from_secs(s as u64)
+            })
+    }
 }
 
 #[derive(Insertable)]
@@ -71,6 +281,18 @@ impl From<&NewProfile> for Profile {
             seed_words: new_profile.seed_words.clone(),
             onchain_receive_enabled: 0,
             tor_enabled: 1,
+            update_stall_timeout_secs: None,
+            max_send_fee_sats: None,
+            receive_batch_window_secs: None,
+            federation_memory_cap_bytes: None,
+            require_private_payments: 0,
+            include_pending_balance: 0,
+            dust_threshold_sats: 0,
+            max_concurrent_subscriptions: None,
+            join_timeout_secs: None,
+            gateway_selection_priority: 0,
+            federation_status_interval_secs: None,
+            manual_gateway: None,
         }
     }
 }
@@ -1,5 +1,19 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    archived_history (operation_id) {
+        operation_id -> Text,
+        mint -> Text,
+        kind -> Integer,
+        amount_sats -> BigInt,
+        txid -> Nullable<Text>,
+        preimage -> Nullable<Text>,
+        status -> Integer,
+        timestamp -> BigInt,
+        metadata -> Text,
+    }
+}
+
 diesel::table! {
     cashu_mint (mint_url) {
         mint_url -> Text,
@@ -7,12 +21,88 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    ecash_payments (operation_id) {
+        operation_id -> Text,
+        fedimint_id -> Text,
+        amount_msats -> BigInt,
+        status -> Integer,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    ecash_receives (operation_id) {
+        operation_id -> Text,
+        fedimint_id -> Text,
+        amount_msats -> BigInt,
+        status -> Integer,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     fedimint (id) {
         id -> Text,
         invite_code -> Text,
         value -> Binary,
         active -> Integer,
+        balance_msats -> Nullable<BigInt>,
+        network -> Nullable<Text>,
+        spend_password_hash -> Nullable<Text>,
+        joined_at -> Nullable<Timestamp>,
+        preferred_gateway -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    fedimint_kv (fedimint_id, key) {
+        fedimint_id -> Text,
+        key -> Binary,
+        value -> Binary,
+    }
+}
+
+diesel::table! {
+    federation_config (federation_id) {
+        federation_id -> Text,
+        config -> Text,
+        network -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    history_totals (mint, kind, status) {
+        mint -> Text,
+        kind -> Integer,
+        status -> Integer,
+        count -> BigInt,
+        amount_sats -> BigInt,
+    }
+}
+
+diesel::table! {
+    imported_history (operation_id) {
+        operation_id -> Text,
+        fedimint_id -> Text,
+        kind -> Integer,
+        amount_msats -> BigInt,
+        fee_msats -> BigInt,
+        txid -> Nullable<Text>,
+        preimage -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    join_failures (id) {
+        id -> Integer,
+        invite_code_hash -> Text,
+        error -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -29,6 +119,9 @@ diesel::table! {
         status -> Integer,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        kind -> Integer,
+        gateway_alias -> Nullable<Text>,
+        gateway_pubkey -> Nullable<Text>,
     }
 }
 
@@ -44,6 +137,7 @@ diesel::table! {
         status -> Integer,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        kind -> Integer,
     }
 }
 
@@ -73,6 +167,8 @@ diesel::table! {
         status -> Integer,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        kind -> Integer,
+        retry_of -> Nullable<Text>,
     }
 }
 
@@ -88,6 +184,23 @@ diesel::table! {
         status -> Integer,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        kind -> Integer,
+    }
+}
+
+diesel::table! {
+    operation_metadata (operation_id) {
+        operation_id -> Text,
+        metadata -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    payment_idempotency (key) {
+        key -> Text,
+        operation_id -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -97,9 +210,24 @@ diesel::table! {
         seed_words -> Text,
         onchain_receive_enabled -> Integer,
         tor_enabled -> Integer,
+        update_stall_timeout_secs -> Nullable<Integer>,
+        max_send_fee_sats -> Nullable<Integer>,
+        receive_batch_window_secs -> Nullable<Integer>,
+        federation_memory_cap_bytes -> Nullable<BigInt>,
+        require_private_payments -> Integer,
+        include_pending_balance -> Integer,
+        dust_threshold_sats -> BigInt,
+        max_concurrent_subscriptions -> Nullable<Integer>,
+        join_timeout_secs -> Nullable<Integer>,
+        gateway_selection_priority -> Integer,
+        federation_status_interval_secs -> Nullable<Integer>,
+        manual_gateway -> Nullable<Text>,
     }
 }
 
+diesel::joinable!(ecash_payments -> fedimint (fedimint_id));
+diesel::joinable!(ecash_receives -> fedimint (fedimint_id));
+diesel::joinable!(fedimint_kv -> fedimint (fedimint_id));
 diesel::joinable!(lightning_payments -> cashu_mint (cashu_mint_url));
 diesel::joinable!(lightning_payments -> fedimint (fedimint_id));
 diesel::joinable!(lightning_receives -> cashu_mint (cashu_mint_url));
@@ -110,12 +238,22 @@ diesel::joinable!(on_chain_receives -> cashu_mint (cashu_mint_url));
 diesel::joinable!(on_chain_receives -> fedimint (fedimint_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    archived_history,
     cashu_mint,
+    ecash_payments,
+    ecash_receives,
     fedimint,
+    fedimint_kv,
+    federation_config,
+    history_totals,
+    imported_history,
+    join_failures,
     lightning_payments,
     lightning_receives,
     mint_metadata,
     on_chain_payments,
     on_chain_receives,
+    operation_metadata,
+    payment_idempotency,
     profile,
 );
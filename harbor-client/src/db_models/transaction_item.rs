@@ -1,13 +1,17 @@
 use crate::MintIdentifier;
-use crate::db_models::PaymentStatus;
+use crate::db_models::{OperationKind, PaymentStatus};
 use bitcoin::Txid;
 use bitcoin::hashes::Hash;
 use fedimint_core::config::FederationId;
+use fedimint_core::secp256k1::PublicKey;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TransactionItemKind {
     Lightning,
     Onchain,
+    /// Ecash notes handed over out of band
+    Ecash,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,9 +20,22 @@ pub enum TransactionDirection {
     Outgoing,
 }
 
+/// How a lightning payment left the federation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentRoute {
+    /// Paid to another user of the same federation, no gateway was involved
+    Internal,
+    /// Paid through the gateway run by this lightning node
+    Gateway {
+        alias: String,
+        node_pub_key: PublicKey,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TransactionItem {
     pub kind: TransactionItemKind,
+    pub operation_kind: OperationKind,
     pub amount: u64,
     pub txid: Option<Txid>,
     pub preimage: Option<[u8; 32]>,
@@ -26,12 +43,20 @@ pub struct TransactionItem {
     pub mint_identifier: MintIdentifier,
     pub status: PaymentStatus,
     pub timestamp: u64,
+    /// Brought over from another wallet's history rather than made by us
+    pub imported: bool,
+    /// How a lightning payment was routed, if it was recorded
+    pub route: Option<PaymentRoute>,
+    /// Data an integration attached to the operation, like an order id.
+    /// Set with [`crate::db::DBConnection::set_operation_metadata`].
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl TransactionItem {
     pub fn make_dummy() -> Self {
         Self {
             kind: TransactionItemKind::Lightning,
+            operation_kind: OperationKind::LightningReceive,
             amount: 100,
             txid: None,
             preimage: None,
@@ -39,12 +64,16 @@ impl TransactionItem {
             mint_identifier: MintIdentifier::Fedimint(FederationId::dummy()),
             status: PaymentStatus::Success,
             timestamp: 0,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
         }
     }
 
     pub fn make_dummy_onchain() -> Self {
         Self {
             kind: TransactionItemKind::Onchain,
+            operation_kind: OperationKind::OnchainSend,
             amount: 100,
             txid: Some(Txid::all_zeros()),
             preimage: None,
@@ -52,6 +81,9 @@ impl TransactionItem {
             mint_identifier: MintIdentifier::Fedimint(FederationId::dummy()),
             status: PaymentStatus::Success,
             timestamp: 0,
+            imported: false,
+            route: None,
+            metadata: BTreeMap::new(),
         }
     }
 }
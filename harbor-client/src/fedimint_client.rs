@@ -1,5 +1,11 @@
+use crate::db_models::{GatewayStats, HistoryItem, OperationKind, PaymentStatus};
 use crate::{
-    CoreUIMsg, CoreUIMsgPacket, HarborCore, MintIdentifier, ReceiveSuccessMsg, SendSuccessMsg,
+    BalanceUpdates, CoreUIMsg, CoreUIMsgPacket, DEFAULT_FEDERATION_STATUS_INTERVAL,
+    DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS, DEFAULT_UPDATE_STALL_TIMEOUT,
+    FEDERATION_INIT_CONCURRENCY, GATEWAY_CACHE_REFRESH_INTERVAL, HISTORY_PAGE_SIZE,
+    HISTORY_REFRESH_ATTEMPTS, HISTORY_REFRESH_RETRY_DELAY, HarborCore, JOIN_DOWNLOAD_RETRIES,
+    MintIdentifier, PAYMENT_RESUBSCRIBE_ATTEMPTS, PAYMENT_RESUBSCRIBE_DELAY, PING_TIMEOUT,
+    ReceiveSuccessMsg, SendError, SendSuccessMsg, WITHDRAW_STUCK_THRESHOLD, check_received_amount,
 };
 use crate::{db::DBConnection, db_models::NewFedimint};
 use anyhow::anyhow;
@@ -7,44 +13,382 @@ use async_trait::async_trait;
 use bip39::Mnemonic;
 use bitcoin::Network;
 use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{Hash, sha256};
+use fedimint_api_client::api::FederationApiExt;
 use fedimint_bip39::Bip39RootSecretStrategy;
-use fedimint_client::ClientHandleArc;
 use fedimint_client::backup::Metadata;
-use fedimint_client::oplog::UpdateStreamOrOutcome;
+use fedimint_client::oplog::{OperationLogEntry, UpdateStreamOrOutcome};
 use fedimint_client::secret::{RootSecretStrategy, get_default_client_secret};
-use fedimint_core::config::FederationId;
-use fedimint_core::core::OperationId;
+use fedimint_client::{ClientBuilder, ClientHandle, ClientHandleArc};
+use fedimint_core::config::{ClientConfig, FederationId};
+use fedimint_core::core::{ModuleInstanceId, ModuleKind, OperationId};
+use fedimint_core::db::Database;
 use fedimint_core::db::IDatabaseTransactionOps;
 use fedimint_core::db::IRawDatabase;
 use fedimint_core::db::IRawDatabaseTransaction;
 use fedimint_core::db::PrefixStream;
 use fedimint_core::db::mem_impl::MemDatabase;
 use fedimint_core::db::mem_impl::MemTransaction;
+use fedimint_core::endpoint_constants::SESSION_COUNT_ENDPOINT;
+use fedimint_core::module::ApiRequestErased;
+use fedimint_core::secp256k1::PublicKey;
+use fedimint_core::{Amount, PeerId};
 use fedimint_core::{db::IDatabaseTransactionOpsCore, invite_code::InviteCode};
+use fedimint_derive_secret::{ChildId, DerivableSecret};
 use fedimint_ln_client::{
     InternalPayState, LightningClientInit, LightningClientModule, LnPayState, LnReceiveState,
 };
 use fedimint_ln_common::LightningGateway;
+use fedimint_ln_common::config::FeeToAmount;
+use fedimint_ln_common::route_hints::RouteHint;
 use fedimint_lnv2_client::{ReceiveOperationState, SendOperationState};
-use fedimint_mint_client::MintClientInit;
-use fedimint_wallet_client::{DepositStateV2, WalletClientInit, WalletClientModule, WithdrawState};
-use futures::StreamExt;
+use fedimint_mint_client::{
+    MintClientInit, MintClientModule, OOBNotes, ReissueExternalNotesState,
+    SelectNotesWithAtleastAmount, SpendOOBState,
+};
+use fedimint_wallet_client::config::WalletClientConfig;
+use fedimint_wallet_client::{
+    DepositStateV2, PegOutFees, WalletClientInit, WalletClientModule, WithdrawState,
+};
 use futures::channel::mpsc::Sender;
-use log::{debug, error, info, trace};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt, stream};
+use rand::Rng;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::Range;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fmt, sync::atomic::AtomicBool};
 use tokio::spawn;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{Instrument, Span, debug, error, info, info_span, trace, warn};
 use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Why a federation client could not be joined or opened
+#[derive(Debug, thiserror::Error)]
+pub enum FedimintError {
+    /// The federation isn't stored yet and only its id was given, the user has to paste
+    /// its invite code to join it
+    #[error("Paste the invite code to join federation {federation_id}")]
+    NeedInviteCode { federation_id: FederationId },
+    /// The federation is on a different bitcoin network than the wallet
+    #[error("Network mismatch, expected: {expected}, federation is on: {actual}")]
+    NetworkMismatch { expected: Network, actual: Network },
+    /// The federation config could not be downloaded from the guardians
+    #[error("Could not download federation info: {0}")]
+    DownloadFailed(anyhow::Error),
+    /// The guardians didn't send the federation config in time
+    #[error("Timed out downloading federation info after {}s", .0.as_secs())]
+    JoinTimeout(Duration),
+    /// The guardians answered with a config that can't be used
+    #[error("Federation config is invalid: {0}")]
+    ConfigInvalid(anyhow::Error),
+    /// The saved federation data failed its checksum, it was likely only partially written
+    #[error("Federation data is corrupt")]
+    CorruptFederationData,
+    /// The saved federation data was written by a newer version of the app
+    #[error("Federation data has unsupported format version {0}")]
+    UnsupportedFederationData(u8),
+    #[error("Federation data could not be decrypted, it may be from another seed")]
+    UndecryptableFederationData,
+    /// The federation does not run a mint module
+    #[error("Federation does not have a mint module")]
+    NoMintModule,
+    /// The federation does not run a wallet module, so it can't send or receive onchain
+    #[error("Federation {0} does not support onchain payments, use lightning instead")]
+    OnchainUnsupported(FederationId),
+    #[error("Could not open federation client: {0}")]
+    OpenFailed(anyhow::Error),
+    #[error("Could not join federation: {0}")]
+    JoinFailed(anyhow::Error),
+    /// The federation has already been joined, it can't be joined a second time
+    #[error("Federation {0} has already been added")]
+    AlreadyJoined(FederationId),
+    /// The balance can't cover the payment and its fees, so it was never started
+    #[error(
+        "Insufficient balance: Cannot pay {} sats, current balance is only {} sats",
+        .needed.sats_round_down(),
+        .available.sats_round_down()
+    )]
+    InsufficientFunds { needed: Amount, available: Amount },
+    /// The federation refused to start the payment
+    #[error("Could not send payment: {0}")]
+    SendFailed(anyhow::Error),
+    /// The federation can't be left while it still holds funds, they have to be swept first
+    #[error("Federation still holds {} sats, move them out before leaving", .0.sats_round_down())]
+    BalanceNotEmpty(Amount),
+    /// Payments still in flight would be lost with the federation's data
+    #[error("Federation has {0} payments in progress, wait for them to finish before leaving")]
+    PaymentsInProgress(usize),
+    #[error("Could not recover federation: {0}")]
+    RecoveryFailed(anyhow::Error),
+    /// The federation's recovery is still running, its client can't be opened until it's done
+    #[error("Federation {0} is still recovering")]
+    Recovering(FederationId),
+    /// Anything else, these are not expected to happen
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct FedimintClient {
     pub(crate) fedimint_client: ClientHandleArc,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    sender: Sender<CoreUIMsgPacket>,
     stop: Arc<AtomicBool>,
+    /// Set once the client is shut down, like when the federation is left, stops its
+    /// background tasks
+    left: Arc<AtomicBool>,
+    /// The background task refreshing the gateway cache, see [`FedimintClient::is_online`]
+    gateway_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// The background task probing the guardians, see [`FedimintClient::federation_status`]
+    status_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// The background task saving and reporting balance changes
+    balance_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Whether the last check of the federation, see [`FedimintClient::is_online`], succeeded
+    online: Arc<AtomicBool>,
+    /// Aborts the client's subscription tasks once the last clone is dropped
+    pub(crate) subscriptions: Arc<SubscriptionTasks>,
+}
+
+/// Subscription tasks spawned for a client, they are aborted when this is dropped so
+/// they can't keep writing to storage after the client has gone away.
+#[derive(Debug)]
+pub(crate) struct SubscriptionTasks {
+    handles: std::sync::Mutex<Vec<AbortHandle>>,
+    limit: SubscriptionLimit,
+}
+
+impl SubscriptionTasks {
+    pub(crate) fn new(limit: SubscriptionLimit) -> Self {
+        Self {
+            handles: std::sync::Mutex::new(Vec::new()),
+            limit,
+        }
+    }
+
+    /// The limit the client's subscriptions share with every other client's
+    pub(crate) fn limit(&self) -> &SubscriptionLimit {
+        &self.limit
+    }
+
+    fn push(&self, handle: AbortHandle) {
+        let mut handles = self
+            .handles
+            .lock()
+            .expect("subscription tasks lock poisoned");
+        handles.retain(|h| !h.is_finished());
+        handles.push(handle);
+    }
+
+    fn abort_all(&self) {
+        let mut handles = self
+            .handles
+            .lock()
+            .expect("subscription tasks lock poisoned");
+        for handle in handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+impl Drop for SubscriptionTasks {
+    fn drop(&mut self) {
+        let handles = self
+            .handles
+            .get_mut()
+            .expect("subscription tasks lock poisoned");
+        for handle in handles.drain(..) {
+            handle.abort();
+        }
+    }
+}
+
+/// Caps how many subscription updates are handled at once across every federation.
+/// A permit is only held while an update is being handled, subscriptions that are just
+/// waiting for their next update don't count against the limit.
+#[derive(Debug, Clone)]
+pub struct SubscriptionLimit {
+    permits: Arc<Semaphore>,
+    limit: Arc<std::sync::Mutex<usize>>,
+}
+
+impl Default for SubscriptionLimit {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS)
+    }
+}
+
+impl SubscriptionLimit {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit: Arc::new(std::sync::Mutex::new(limit)),
+        }
+    }
+
+    /// Changes the limit in place so updates already waiting for a permit see it too.
+    /// Lowering it takes effect as the permits in use are given back.
+    pub(crate) fn set(&self, limit: usize) {
+        let limit = limit.max(1);
+        let mut current = self.limit.lock().expect("subscription limit lock poisoned");
+        if limit > *current {
+            self.permits.add_permits(limit - *current);
+        } else if limit < *current {
+            let excess = *current - limit;
+            let forgotten = self.permits.forget_permits(excess);
+            if forgotten < excess {
+                let permits = self.permits.clone();
+                spawn(async move {
+                    if let Ok(permit) = permits
+                        .acquire_many_owned((excess - forgotten) as u32)
+                        .await
+                    {
+                        permit.forget();
+                    }
+                });
+            }
+        }
+        *current = limit;
+    }
+
+    /// Waits for a permit to handle a subscription update
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        if self.permits.available_permits() == 0 {
+            debug!("Subscription limit reached, waiting to handle update");
+        }
+        self.permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("subscription permits are never closed")
+    }
+}
+
+/// Federations whose recovery is running, shared by the core and the clients so a
+/// federation isn't opened a second time while a recovery holds its client.
+#[derive(Debug, Clone, Default)]
+pub struct Recoveries(Arc<std::sync::Mutex<HashSet<FederationId>>>);
+
+impl Recoveries {
+    pub fn is_recovering(&self, federation_id: FederationId) -> bool {
+        self.0
+            .lock()
+            .expect("recoveries lock poisoned")
+            .contains(&federation_id)
+    }
+
+    /// Marks the federation as recovering until the guard is dropped, `None` if it
+    /// already is
+    fn start(&self, federation_id: FederationId) -> Option<RecoveryGuard> {
+        let started = self
+            .0
+            .lock()
+            .expect("recoveries lock poisoned")
+            .insert(federation_id);
+        started.then(|| RecoveryGuard {
+            recoveries: self.clone(),
+            federation_id,
+        })
+    }
+}
+
+/// The handles every federation client shares with the core: storage, the secret clients are
+/// derived from, the channel to the UI and the limits and flags that apply across clients.
+#[derive(Clone)]
+pub struct ClientContext {
+    pub storage: Arc<dyn DBConnection + Send + Sync>,
+    pub secret_provider: Arc<dyn RootSecretProvider>,
+    pub network: Network,
+    pub reconnect_policy: ReconnectPolicy,
+    pub stop: Arc<AtomicBool>,
+    pub backgrounded: Arc<AtomicBool>,
+    pub sender: Sender<CoreUIMsgPacket>,
+    pub balance_updates: BalanceUpdates,
+    pub subscription_limit: SubscriptionLimit,
+    pub recoveries: Recoveries,
+}
+
+struct RecoveryGuard {
+    recoveries: Recoveries,
+    federation_id: FederationId,
+}
+
+impl Drop for RecoveryGuard {
+    fn drop(&mut self) {
+        self.recoveries
+            .0
+            .lock()
+            .expect("recoveries lock poisoned")
+            .remove(&self.federation_id);
+    }
+}
+
+/// Spawns a subscription task that is aborted when the client owning `subscriptions` is
+/// dropped. The task takes a permit from the client's [`SubscriptionLimit`] for each update.
+pub(crate) fn spawn_subscription<F>(subscriptions: &SubscriptionTasks, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = spawn(task).abort_handle();
+    subscriptions.push(handle);
+}
+
+/// What joining a federation gave us, so callers don't have to query the new client for it
+#[derive(Debug, Clone)]
+pub struct JoinResult {
+    pub client: FedimintClient,
+    pub federation_id: FederationId,
+    /// The name the federation gives itself in its config, if any
+    pub name: Option<String>,
+    pub network: Network,
+    /// False when the federation's data was already stored and the client was reopened
+    pub was_new_join: bool,
+    pub module_kinds: Vec<ModuleKind>,
+}
+
+/// A raw entry of a fedimint client's operation log. Unlike harbor's own history this is
+/// straight from the client, so it can be used to reconcile history that drifted.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpLogEntry {
+    pub operation_id: String,
+    /// Seconds since the unix epoch
+    pub creation_time: u64,
+    pub module_kind: String,
+    pub meta: serde_json::Value,
+    /// `None` while the operation hasn't finished
+    pub outcome: Option<serde_json::Value>,
+}
+
+impl OpLogEntry {
+    fn new(
+        operation_id: OperationId,
+        creation_time: SystemTime,
+        entry: &OperationLogEntry,
+    ) -> Self {
+        Self {
+            operation_id: operation_id.fmt_full().to_string(),
+            creation_time: creation_time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            module_kind: entry.operation_module_kind().to_string(),
+            meta: entry.meta::<serde_json::Value>(),
+            outcome: entry.outcome::<serde_json::Value>(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -69,95 +413,345 @@ impl FederationInviteOrId {
     }
 }
 
+/// The string is neither an invite code nor a federation id
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Not a valid invite code or federation id")]
+pub struct ParseFederationInviteOrIdError;
+
+impl FromStr for FederationInviteOrId {
+    type Err = ParseFederationInviteOrIdError;
+
+    /// Parses an invite code, or a federation id if it isn't one
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(invite_code) = InviteCode::from_str(s) {
+            return Ok(FederationInviteOrId::Invite(invite_code));
+        }
+        FederationId::from_str(s)
+            .map(FederationInviteOrId::Id)
+            .map_err(|_| ParseFederationInviteOrIdError)
+    }
+}
+
+impl fmt::Display for FederationInviteOrId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FederationInviteOrId::Invite(i) => write!(f, "{i}"),
+            FederationInviteOrId::Id(i) => write!(f, "{i}"),
+        }
+    }
+}
+
+/// Where the config for joining a federation comes from, it is only used when the
+/// federation has no saved state yet
+#[derive(Debug, Clone, Default)]
+pub enum JoinConfig {
+    /// Reuse the config saved when the federation was first joined, downloading it if
+    /// there isn't one
+    #[default]
+    Cached,
+    /// Download the config from the guardians even if one is saved
+    Refresh,
+    /// Use a config the caller already has
+    Provided(ClientConfig),
+}
+
+/// Where the root secret that every federation's client secret is derived from comes from.
+/// Implement this to derive it outside the app, e.g. on a hardware device, so the seed
+/// never has to be held in memory.
+pub trait RootSecretProvider: Send + Sync {
+    fn root_secret(&self) -> DerivableSecret;
+}
+
+/// The default provider, derives the root secret from the wallet's seed words
+impl RootSecretProvider for Mnemonic {
+    fn root_secret(&self) -> DerivableSecret {
+        Bip39RootSecretStrategy::<12>::to_root_secret(self)
+    }
+}
+
+/// How the background gateway cache refresh backs off while the federation can't be reached.
+/// The delay doubles from `base_delay` after each failed attempt, up to `max_delay`, and is
+/// randomized by up to `jitter` of itself so clients don't retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay, between 0 and 1, it can be randomly shortened or lengthened by
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before the next attempt after `failures` attempts in a row have failed,
+    /// without jitter.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// The delay before the next attempt after `failures` attempts in a row have failed
+    pub fn delay(&self, failures: u32) -> Duration {
+        let backoff = self.backoff(failures);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + jitter * rand::thread_rng().gen_range(-1.0..=1.0);
+        backoff.mul_f64(factor)
+    }
+}
+
+/// Whether a guardian answered the last status probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuardianStatus {
+    pub peer_id: PeerId,
+    pub reachable: bool,
+}
+
+/// What a federation can do given which of its guardians are reachable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FederationHealth {
+    /// Every guardian answered
+    Online,
+    /// Some guardians didn't answer, but enough did for the federation to reach consensus
+    Degraded,
+    /// Too few guardians answered for the federation to reach consensus
+    Offline,
+}
+
+/// The result of probing each of a federation's guardians,
+/// see [`FedimintClient::federation_status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederationStatus {
+    pub guardians: Vec<GuardianStatus>,
+    pub health: FederationHealth,
+}
+
+impl FederationStatus {
+    /// Summarizes the guardians' reachability. Like fedimint we assume up to a third of the
+    /// guardians may be faulty, so consensus needs all but that many to be reachable.
+    pub fn from_guardians(guardians: Vec<GuardianStatus>) -> Self {
+        let total = guardians.len();
+        let reachable = guardians.iter().filter(|g| g.reachable).count();
+        let threshold = total - total.saturating_sub(1) / 3;
+
+        let health = if total > 0 && reachable == total {
+            FederationHealth::Online
+        } else if total > 0 && reachable >= threshold {
+            FederationHealth::Degraded
+        } else {
+            FederationHealth::Offline
+        };
+
+        Self { guardians, health }
+    }
+}
+
+/// The highest fixed fee rate a withdrawal pays, anything above it is almost certainly a
+/// typo and would burn most of the amount in fees.
+pub const MAX_FEE_RATE_SATS_PER_VBYTE: u64 = 1_000;
+
+/// What fee rate an onchain withdrawal pays. Federations refuse withdrawals paying less
+/// than their own estimate, so every choice is relative to it and never goes below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeeRate {
+    /// The federation's estimate, the lowest rate it accepts
+    #[default]
+    Economy,
+    /// Half again the federation's estimate
+    Normal,
+    /// Twice the federation's estimate, for withdrawals that should confirm quickly
+    Priority,
+    /// A fixed rate, raised to the federation's estimate if it is lower and capped at
+    /// [`MAX_FEE_RATE_SATS_PER_VBYTE`]
+    SatsPerVByte(u64),
+}
+
+impl FeeRate {
+    /// The rate to pay in sats per kvB, given the federation's estimate
+    pub fn sats_per_kvb(&self, estimate: u64) -> u64 {
+        match self {
+            FeeRate::Economy => estimate,
+            FeeRate::Normal => estimate.saturating_mul(3) / 2,
+            FeeRate::Priority => estimate.saturating_mul(2),
+            FeeRate::SatsPerVByte(rate) => {
+                estimate.max((*rate).min(MAX_FEE_RATE_SATS_PER_VBYTE) * 1_000)
+            }
+        }
+    }
+}
+
 impl FedimintClient {
     pub async fn new(
-        storage: Arc<dyn DBConnection + Send + Sync>,
+        context: &ClientContext,
         invite_or_id: FederationInviteOrId,
-        mnemonic: &Mnemonic,
-        network: Network,
-        stop: Arc<AtomicBool>,
-        mut sender: Sender<CoreUIMsgPacket>,
+        join_config: JoinConfig,
         msg_id: Option<Uuid>,
-    ) -> anyhow::Result<Self> {
+    ) -> Result<Self, FedimintError> {
+        let ClientContext {
+            storage,
+            secret_provider,
+            network,
+            reconnect_policy,
+            stop,
+            backgrounded,
+            mut sender,
+            balance_updates,
+            subscription_limit,
+            recoveries,
+        } = context.clone();
         let federation_id = invite_or_id.federation_id();
+        if recoveries.is_recovering(federation_id) {
+            return Err(FedimintError::Recovering(federation_id));
+        }
 
         info!("initializing a new federation client: {federation_id}");
 
         trace!("Building fedimint client db");
 
-        let db = FedimintStorage::new(storage.clone(), federation_id, invite_or_id.invite_code())
-            .await?;
-
-        let is_initialized = fedimint_client::Client::is_initialized(&db.clone().into()).await;
+        let db = FedimintStorage::new(
+            storage.clone(),
+            Arc::new(SeedEncryptor::new(secret_provider.as_ref())),
+            federation_id,
+            invite_or_id.invite_code(),
+            network,
+        )
+        .await?;
 
-        let mut client_builder = fedimint_client::Client::builder(db.into()).await?;
+        let loaded_bytes = db.loaded_bytes();
+        let db: Database = db.into();
+        let is_initialized = fedimint_client::Client::is_initialized(&db).await;
 
         // Check if tor is enabled in profile
-        let profile = storage.get_profile()?;
-        let tor_enabled = profile.expect("must have profile").tor_enabled();
-        if tor_enabled {
-            client_builder.with_tor_connector();
-        }
-
-        client_builder.with_module(WalletClientInit(None));
-        client_builder.with_module(MintClientInit);
-        client_builder.with_module(LightningClientInit::default());
-        client_builder.with_module(fedimint_lnv2_client::LightningClientInit::default());
+        let profile = storage.get_profile()?.expect("must have profile");
+        let tor_enabled = profile.tor_enabled();
+        let mut client_builder = client_builder(db.clone(), tor_enabled, true).await?;
 
-        client_builder.with_primary_module_kind(fedimint_mint_client::KIND);
+        // everything is held in memory, so warn before a huge federation history becomes a problem
+        let cap = profile.federation_memory_cap();
+        if loaded_bytes > cap {
+            warn!("Federation {federation_id} loaded {loaded_bytes} bytes, over the cap of {cap}");
+            HarborCore::send_msg(
+                &mut sender,
+                msg_id,
+                CoreUIMsg::StorageWarning {
+                    id: federation_id,
+                    loaded_bytes,
+                    cap,
+                },
+            )
+            .await;
+        }
 
         trace!("Building fedimint client db");
-        let root_secret = Bip39RootSecretStrategy::<12>::to_root_secret(mnemonic);
+        let root_secret = secret_provider.root_secret();
         let secret = get_default_client_secret(&root_secret, &federation_id);
 
+        // the config we joined with, saved once we know the federation is on our network
+        let mut joined_config = None;
         let fedimint_client = if is_initialized {
-            Arc::new(client_builder.open(secret).await.map_err(|e| {
-                error!("Could not open federation client: {e}");
-                e
-            })?)
+            let config = client_builder.load_existing_config().await?;
+            let primary_module = primary_module_instance(&config)?;
+            client_builder.with_primary_module_instance_id(primary_module);
+
+            match client_builder.open(secret.clone()).await {
+                Ok(client) => Arc::new(client),
+                Err(e) => {
+                    error!("Could not open federation client: {e}");
+                    let reason = e.to_string();
+                    let client = build_mint_only(&db, tor_enabled, primary_module, secret)
+                        .await
+                        .map_err(|_| FedimintError::OpenFailed(e))?;
+                    report_unavailable_modules(&mut sender, msg_id, &config, reason).await;
+                    Arc::new(client)
+                }
+            }
         } else if let FederationInviteOrId::Invite(ref invite_code) = invite_or_id {
+            let config = match join_config {
+                JoinConfig::Provided(config) => {
+                    if config.calculate_federation_id() != federation_id {
+                        return Err(FedimintError::Other(anyhow!(
+                            "Config does not match federation {federation_id}"
+                        )));
+                    }
+                    Some(config)
+                }
+                JoinConfig::Cached => cached_join_config(&storage, federation_id, network),
+                JoinConfig::Refresh => None,
+            };
+
             let download = Instant::now();
-            let config = {
-                let config = if tor_enabled {
+            let config = if let Some(config) = config {
+                debug!("Joining with an already downloaded federation config");
+                config
+            } else {
+                let connector = if tor_enabled {
                     fedimint_api_client::api::net::Connector::Tor
-                        .download_from_invite_code(invite_code)
-                        .await
                 } else {
                     fedimint_api_client::api::net::Connector::Tcp
-                        .download_from_invite_code(invite_code)
-                        .await
                 };
-                config.map_err(|e| {
-                    error!("Could not download federation info: {e}");
-                    e
-                })?
+                let config = download_with_retry(
+                    || download_config(&connector, invite_code),
+                    profile.join_timeout(),
+                    &reconnect_policy,
+                    &stop,
+                )
+                .await
+                .inspect_err(|_| cleanup_unjoined(&storage, federation_id))?;
+                trace!(
+                    "Downloaded federation info in: {}ms",
+                    download.elapsed().as_millis()
+                );
+                config
             };
-            trace!(
-                "Downloaded federation info in: {}ms",
-                download.elapsed().as_millis()
-            );
+            joined_config = Some(config.clone());
+
+            let primary_module = primary_module_instance(&config)?;
+            client_builder.with_primary_module_instance_id(primary_module);
 
             let client_backup = client_builder
                 .download_backup_from_federation(&secret, &config, invite_code.api_secret())
-                .await?;
+                .await
+                .map_err(FedimintError::DownloadFailed)?;
 
             match client_backup {
-                None => Arc::new(
-                    client_builder
-                        .join(secret, config, invite_code.api_secret())
-                        .await
-                        .map_err(|e| {
-                            error!("Could not join federation: {e}");
-                            e
-                        })?,
-                ),
+                None => match client_builder
+                    .join(secret.clone(), config.clone(), invite_code.api_secret())
+                    .await
+                {
+                    Ok(client) => Arc::new(client),
+                    Err(e) => {
+                        error!("Could not join federation: {e}");
+                        // the config is saved before the modules are initialized, without it
+                        // the join failed before any module was involved
+                        if !fedimint_client::Client::is_initialized(&db).await {
+                            return Err(FedimintError::JoinFailed(e));
+                        }
+                        let reason = e.to_string();
+                        let client = build_mint_only(&db, tor_enabled, primary_module, secret)
+                            .await
+                            .map_err(|_| FedimintError::JoinFailed(e))?;
+                        report_unavailable_modules(&mut sender, msg_id, &config, reason).await;
+                        Arc::new(client)
+                    }
+                },
                 Some(backup) => {
                     let client = client_builder
                         .recover(secret, config, invite_code.api_secret(), Some(backup))
                         .await
                         .map_err(|e| {
                             error!("Could not join federation: {e}");
-                            e
+                            FedimintError::RecoveryFailed(e)
                         })?;
 
                     HarborCore::send_msg(
@@ -169,7 +763,7 @@ impl FedimintClient {
                         },
                     )
                     .await;
-                    match client.wait_for_all_recoveries().await {
+                    match wait_for_recovery(&client, federation_id, &mut sender, msg_id).await {
                         Ok(_) => {
                             info!("Federation successfully recovered");
                             HarborCore::send_msg(
@@ -182,13 +776,16 @@ impl FedimintClient {
                             )
                             .await;
 
+                            // the client is opened again as an existing one, so save the
+                            // config now, the network can't be checked during a recovery
+                            if let Some(config) = joined_config {
+                                save_join_config(&storage, federation_id, &config, network);
+                            }
+
                             let fut = Box::pin(Self::new(
-                                storage,
+                                context,
                                 invite_or_id,
-                                mnemonic,
-                                network,
-                                stop,
-                                sender,
+                                JoinConfig::Cached,
                                 msg_id,
                             ));
                             return fut.await;
@@ -204,33 +801,74 @@ impl FedimintClient {
                                 },
                             )
                             .await;
-                            return Err(e);
+                            return Err(FedimintError::RecoveryFailed(e));
                         }
                     }
                 }
             }
         } else {
             error!("did not have enough information to join federation");
-            return Err(anyhow!(
-                "did not have enough information to join federation"
-            ));
+            return Err(FedimintError::NeedInviteCode { federation_id });
         };
 
+        // an interrupted recovery carries on once the client is opened, the client has to be
+        // opened again when it's done
+        if fedimint_client.has_pending_recoveries() {
+            let Some(guard) = recoveries.start(federation_id) else {
+                return Err(FedimintError::Recovering(federation_id));
+            };
+
+            // loading the federations at startup shouldn't wait on a recovery that can take
+            // hours, it finishes in the background and the client is opened when retried
+            if msg_id.is_none() {
+                info!("Resuming recovery of {federation_id} in the background");
+                spawn(async move {
+                    let _guard = guard;
+                    match wait_for_recovery(&fedimint_client, federation_id, &mut sender, None)
+                        .await
+                    {
+                        Ok(_) => info!("Recovery of {federation_id} finished"),
+                        Err(e) => error!("Could not recover federation {federation_id}: {e}"),
+                    }
+                });
+                return Err(FedimintError::Recovering(federation_id));
+            }
+
+            info!("Resuming recovery of {federation_id}");
+            wait_for_recovery(&fedimint_client, federation_id, &mut sender, msg_id)
+                .await
+                .map_err(FedimintError::RecoveryFailed)?;
+            drop(fedimint_client);
+            drop(guard);
+
+            let fut = Box::pin(Self::new(context, invite_or_id, JoinConfig::Cached, msg_id));
+            return fut.await;
+        }
+
         trace!("Retrieving fedimint wallet client module");
 
         // we can't check network during a recovery
-        // check federation is on expected network
-        let wallet_client = fedimint_client
-            .get_first_module::<WalletClientModule>()
-            .expect("must have wallet module");
-        // compare magic bytes because different versions of rust-bitcoin
-        if network != wallet_client.get_network() {
-            error!(
-                "Fedimint on different network {}, expected: {network}",
-                wallet_client.get_network()
-            );
+        // check federation is on expected network, if it has a wallet module
+        match fedimint_client.get_first_module::<WalletClientModule>() {
+            Ok(wallet_client) => {
+                // compare magic bytes because different versions of rust-bitcoin
+                if network != wallet_client.get_network() {
+                    error!(
+                        "Fedimint on different network {}, expected: {network}",
+                        wallet_client.get_network()
+                    );
+
+                    return Err(FedimintError::NetworkMismatch {
+                        expected: network,
+                        actual: wallet_client.get_network(),
+                    });
+                }
+            }
+            Err(_) => info!("Federation has no wallet module, skipping network check"),
+        }
 
-            return Err(anyhow::anyhow!("Network mismatch, expected: {network}"));
+        if let Some(config) = joined_config {
+            save_join_config(&storage, federation_id, &config, network);
         }
 
         // Create a backup
@@ -246,866 +884,4913 @@ impl FedimintClient {
             info!("Creating backup took: {}ms", start.elapsed().as_millis());
         });
 
-        // Update gateway cache in background
+        // Reports every balance change, whatever caused it, and saves it so it can be shown
+        // on the next startup before we're loaded. Payment subscriptions rely on this for
+        // the balance after a payment.
+        let client = fedimint_client.clone();
+        let balance_storage = storage.clone();
+        let mut balance_sender = sender.clone();
+        let balance_task = spawn(async move {
+            let mut balances = client.subscribe_balance_changes().await;
+            while let Some(balance) = balances.next().await {
+                if let Err(e) = balance_storage.set_federation_balance(federation_id, balance) {
+                    error!("Could not save federation balance: {e}");
+                }
+                let sent = balance_updates
+                    .send(
+                        &mut balance_sender,
+                        None,
+                        MintIdentifier::Fedimint(federation_id),
+                        balance,
+                    )
+                    .await;
+                if !sent {
+                    debug!("Stopping balance updates for {federation_id}, the UI is gone");
+                    break;
+                }
+            }
+        });
+
+        // Update gateway cache in background, this doubles as our check of whether the
+        // federation can be reached. Without lightning the guardians are asked directly.
         let client_clone = fedimint_client.clone();
-        spawn(async move {
-            let start = Instant::now();
+        let stop_clone = stop.clone();
+        let left = Arc::new(AtomicBool::new(false));
+        let left_clone = left.clone();
+        let online_flag = Arc::new(AtomicBool::new(false));
+        let online_clone = online_flag.clone();
+        let mut sender_clone = sender.clone();
+        let gateway_task = spawn(async move {
             let lightning_module = client_clone
                 .get_first_module::<LightningClientModule>()
-                .expect("must have ln module");
+                .ok();
+            if lightning_module.is_none() {
+                info!("Federation has no lightning module, checking the guardians instead");
+            }
+
+            let mut online = None;
+            let mut failures = 0;
+            while !stop_clone.load(Ordering::Relaxed) && !left_clone.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                let result = match &lightning_module {
+                    Some(lightning_module) => lightning_module.update_gateway_cache().await,
+                    None => client_clone
+                        .api()
+                        .session_count()
+                        .await
+                        .map(|_| ())
+                        .map_err(|e| anyhow!("{e}")),
+                };
+                trace!(
+                    "Updating gateway cache took: {}ms",
+                    start.elapsed().as_millis()
+                );
 
-            match lightning_module.update_gateway_cache().await {
-                Ok(_) => {
-                    trace!("Updated lightning gateway cache");
+                // the update can take a while, don't report it or wait again once stopped
+                if stop_clone.load(Ordering::Relaxed) || left_clone.load(Ordering::Relaxed) {
+                    break;
                 }
-                Err(e) => {
-                    error!("Could not update lightning gateway cache: {e}");
+
+                let delay = match result {
+                    Ok(_) => {
+                        trace!("Updated lightning gateway cache");
+                        failures = 0;
+                        GATEWAY_CACHE_REFRESH_INTERVAL
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        let delay = reconnect_policy.delay(failures);
+                        error!("Could not update lightning gateway cache: {e}");
+                        trace!(
+                            "Backing off gateway cache refresh for {}ms after {failures} failures",
+                            delay.as_millis()
+                        );
+                        delay
+                    }
+                };
+
+                // only tell the UI when the federation goes on or offline
+                let is_online = failures == 0;
+                online_clone.store(is_online, Ordering::Relaxed);
+                if online != Some(is_online) {
+                    online = Some(is_online);
+                    let sent = HarborCore::send_msg(
+                        &mut sender_clone,
+                        None,
+                        CoreUIMsg::FederationConnectivity {
+                            id: federation_id,
+                            online: is_online,
+                        },
+                    )
+                    .await;
+                    if !sent {
+                        debug!(
+                            "Stopping gateway cache refresh for {federation_id}, the UI is gone"
+                        );
+                        break;
+                    }
                 }
+
+                tokio::time::sleep(delay).await;
             }
+        });
 
-            trace!(
-                "Updating gateway cache took: {}ms",
-                start.elapsed().as_millis()
-            );
+        // Probe each guardian so the UI can show how healthy the federation is. Unlike the
+        // gateway refresh this is only for display, so it is skipped in the background.
+        let client_clone = fedimint_client.clone();
+        let status_storage = storage.clone();
+        let stop_clone = stop.clone();
+        let left_clone = left.clone();
+        let mut sender_clone = sender.clone();
+        let status_task = spawn(async move {
+            while !stop_clone.load(Ordering::Relaxed) && !left_clone.load(Ordering::Relaxed) {
+                // resumes at the next interval once the app is back in the foreground
+                if !backgrounded.load(Ordering::Relaxed) {
+                    let status = probe_guardians(&client_clone).await;
+                    trace!("Federation {federation_id} status: {:?}", status.health);
+                    let sent = HarborCore::send_msg(
+                        &mut sender_clone,
+                        None,
+                        CoreUIMsg::FederationStatusUpdated(federation_id, status),
+                    )
+                    .await;
+                    if !sent {
+                        debug!("Stopping status probes for {federation_id}, the UI is gone");
+                        break;
+                    }
+                }
 
-            // continually update gateway cache
-            lightning_module
-                .update_gateway_cache_continuously(|g| async { g })
-                .await;
+                let interval = match status_storage.get_profile() {
+                    Ok(Some(profile)) => profile.federation_status_interval(),
+                    Ok(None) => DEFAULT_FEDERATION_STATUS_INTERVAL,
+                    Err(e) => {
+                        error!("Could not read federation status interval: {e}");
+                        DEFAULT_FEDERATION_STATUS_INTERVAL
+                    }
+                };
+                tokio::time::sleep(interval).await;
+            }
         });
 
         debug!("Built fedimint client");
 
         Ok(FedimintClient {
             fedimint_client,
+            storage,
+            sender,
             stop,
+            left,
+            gateway_task: Arc::new(Mutex::new(Some(gateway_task))),
+            status_task: Arc::new(Mutex::new(Some(status_task))),
+            balance_task: Arc::new(Mutex::new(Some(balance_task))),
+            online: online_flag,
+            subscriptions: Arc::new(SubscriptionTasks::new(subscription_limit)),
         })
     }
 
-    pub fn federation_id(&self) -> FederationId {
-        self.fedimint_client.federation_id()
-    }
-}
-
-pub(crate) async fn select_gateway(client: &ClientHandleArc) -> Option<LightningGateway> {
-    let ln = client
-        .get_first_module::<LightningClientModule>()
-        .expect("must have ln module");
-
-    let gateways = ln.list_gateways().await;
-    let mut selected_gateway: Option<LightningGateway> = None;
-    for gateway in gateways.iter() {
-        // first try to find a vetted gateway
-        if gateway.vetted {
-            // if we can select the gateway, return it
-            if let Some(gateway) = ln.select_gateway(&gateway.info.gateway_id).await {
-                return Some(gateway);
-            }
-        }
+    /// Builds the client like [`FedimintClient::new`], summarizing what was joined.
+    /// Failures are recorded for diagnostics, see [`DBConnection::recent_join_failures`].
+    pub async fn join(
+        context: &ClientContext,
+        invite_or_id: FederationInviteOrId,
+        join_config: JoinConfig,
+        msg_id: Option<Uuid>,
+    ) -> Result<JoinResult, FedimintError> {
+        let federation_id = invite_or_id.federation_id();
+        let was_new_join = context
+            .storage
+            .get_federation_value(federation_id.to_string())?
+            .is_none();
 
-        // if no vetted gateway found, try to find a gateway with reasonable fees
-        let fees = gateway.info.fees;
-        if fees.base_msat >= 1_000 && fees.proportional_millionths >= 100 {
-            if let Some(g) = ln.select_gateway(&gateway.info.gateway_id).await {
-                // only select gateways that support private payments, unless we don't have a gateway
-                if g.supports_private_payments || selected_gateway.is_none() {
-                    selected_gateway = Some(g);
+        let invite_code = invite_or_id.invite_code();
+        let client = match Self::new(context, invite_or_id, join_config, msg_id).await {
+            Ok(client) => client,
+            Err(e) => {
+                // kept so a user who can't join can show support what went wrong
+                if let Some(invite_code) = invite_code {
+                    if let Err(db_err) = context
+                        .storage
+                        .record_join_failure(&invite_code, &e.to_string())
+                    {
+                        error!("Could not record join failure: {db_err}");
+                    }
                 }
+                return Err(e);
             }
-        }
-    }
+        };
 
-    // if no gateway found, just select the first one we can find
-    if selected_gateway.is_none() {
-        for gateway in gateways {
-            if let Some(g) = ln.select_gateway(&gateway.info.gateway_id).await {
-                selected_gateway = Some(g);
-                break;
-            }
-        }
+        let config = client.fedimint_client.config().await;
+        let module_kinds = config.modules.values().map(|m| m.kind.clone()).collect();
+        let name = client.fedimint_client.get_config_meta("federation_name");
+
+        Ok(JoinResult {
+            client,
+            federation_id,
+            name,
+            network: context.network,
+            was_new_join,
+            module_kinds,
+        })
     }
 
-    selected_gateway
-}
+    /// Restores our ecash in a federation from the seed alone, for when the local data was
+    /// lost. Unlike joining, the federation's history is scanned for our notes even when it
+    /// has no backup of ours. Progress is sent as [`CoreUIMsg::RecoveryProgress`], and an
+    /// interrupted recovery resumes where it left off the next time this is called or the
+    /// client is loaded.
+    pub async fn recover(
+        context: &ClientContext,
+        invite_code: InviteCode,
+        msg_id: Option<Uuid>,
+    ) -> Result<JoinResult, FedimintError> {
+        let ClientContext {
+            storage,
+            secret_provider,
+            network,
+            reconnect_policy,
+            stop,
+            mut sender,
+            recoveries,
+            ..
+        } = context.clone();
+        let federation_id = invite_code.federation_id();
 
-pub(crate) async fn update_history(
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    msg_id: Uuid,
-    sender: &mut Sender<CoreUIMsgPacket>,
-) {
-    if let Ok(history) = storage.get_transaction_history() {
-        HarborCore::send_msg(
-            sender,
-            Some(msg_id),
-            CoreUIMsg::TransactionHistoryUpdated(history),
+        let db: Database = FedimintStorage::new(
+            storage.clone(),
+            Arc::new(SeedEncryptor::new(secret_provider.as_ref())),
+            federation_id,
+            Some(invite_code.clone()),
+            network,
         )
-        .await;
-    }
-}
+        .await?
+        .into();
 
-pub(crate) async fn spawn_invoice_receive_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    is_transfer: bool,
-    subscription: UpdateStreamOrOutcome<LnReceiveState>,
-) {
-    info!(
-        "Spawning lightning receive subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                LnReceiveState::Canceled { reason } => {
-                    error!("Payment canceled, reason: {:?}", reason);
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveFailed(reason.to_string()),
-                    )
-                    .await;
+        // an initialized client either finished recovering or resumes it when loaded
+        if !fedimint_client::Client::is_initialized(&db).await {
+            let Some(_guard) = recoveries.start(federation_id) else {
+                return Err(FedimintError::Recovering(federation_id));
+            };
+            info!("Recovering federation {federation_id}");
+            let profile = storage.get_profile()?.expect("must have profile");
+            let tor_enabled = profile.tor_enabled();
 
-                    if let Err(e) =
-                        storage.mark_ln_receive_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning receive as failed: {e}");
-                    }
-                    break;
-                }
-                LnReceiveState::Claimed => {
-                    info!("Payment claimed");
-                    let params = if is_transfer {
-                        ReceiveSuccessMsg::Transfer
+            let config = match cached_join_config(&storage, federation_id, network) {
+                Some(config) => config,
+                None => {
+                    let connector = if tor_enabled {
+                        fedimint_api_client::api::net::Connector::Tor
                     } else {
-                        ReceiveSuccessMsg::Lightning
+                        fedimint_api_client::api::net::Connector::Tcp
                     };
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveSuccess(params),
+                    download_with_retry(
+                        || download_config(&connector, &invite_code),
+                        profile.join_timeout(),
+                        &reconnect_policy,
+                        &stop,
                     )
-                    .await;
+                    .await
+                    .inspect_err(|_| cleanup_unjoined(&storage, federation_id))?
+                }
+            };
 
-                    if let Err(e) =
-                        storage.mark_ln_receive_as_success(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning receive as success: {e}");
-                    }
+            let mut client_builder = client_builder(db, tor_enabled, true).await?;
+            client_builder.with_primary_module_instance_id(primary_module_instance(&config)?);
+            let root_secret = secret_provider.root_secret();
+            let secret = get_default_client_secret(&root_secret, &federation_id);
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
-                    .await;
+            // without a backup our notes are found by scanning the federation's history
+            let backup = client_builder
+                .download_backup_from_federation(&secret, &config, invite_code.api_secret())
+                .await
+                .map_err(FedimintError::DownloadFailed)?;
+            let client = client_builder
+                .recover(secret, config.clone(), invite_code.api_secret(), backup)
+                .await
+                .map_err(|e| {
+                    error!("Could not recover federation: {e}");
+                    FedimintError::RecoveryFailed(e)
+                })?;
+            wait_for_recovery(&client, federation_id, &mut sender, msg_id)
+                .await
+                .map_err(FedimintError::RecoveryFailed)?;
+            drop(client);
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+            save_join_config(&storage, federation_id, &config, network);
+        }
 
-                    client
-                        .backup_to_federation(Metadata::empty())
-                        .await
-                        .expect("Could not backup");
+        Self::join(
+            context,
+            FederationInviteOrId::Invite(invite_code),
+            JoinConfig::Cached,
+            msg_id,
+        )
+        .await
+    }
 
-                    break;
-                }
-                _ => {}
-            }
+    pub fn federation_id(&self) -> FederationId {
+        self.fedimint_client.federation_id()
+    }
+
+    /// Whether the federation could be reached the last time the background check ran,
+    /// which refreshes the gateway cache every [`GATEWAY_CACHE_REFRESH_INTERVAL`]
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Relaxed)
+    }
+
+    /// Asks the guardians for the current session, a cheap call that tells whether the
+    /// federation can be reached right now. Updates [`Self::is_online`].
+    pub async fn ping(&self, timeout: Duration) -> bool {
+        let online = matches!(
+            tokio::time::timeout(timeout, self.fedimint_client.api().session_count()).await,
+            Ok(Ok(_))
+        );
+        self.online.store(online, Ordering::Relaxed);
+        online
+    }
+
+    /// Asks each guardian for the current session, summarizing who answered within
+    /// [`PING_TIMEOUT`]. This is also run in the background, reported with
+    /// [`CoreUIMsg::FederationStatusUpdated`].
+    pub async fn federation_status(&self) -> FederationStatus {
+        probe_guardians(&self.fedimint_client).await
+    }
+
+    /// The fees withdrawing the amount to the address would pay at the fee rate.
+    /// Nothing is started, so this can be shown before the user confirms.
+    pub async fn estimate_withdraw_fee(
+        &self,
+        address: &bitcoin::Address,
+        amount: bitcoin::Amount,
+        fee_rate: FeeRate,
+    ) -> Result<PegOutFees, FedimintError> {
+        let onchain = self
+            .fedimint_client
+            .get_first_module::<WalletClientModule>()
+            .map_err(|_| FedimintError::OnchainUnsupported(self.federation_id()))?;
+
+        let mut fees = onchain
+            .get_withdraw_fees(address, amount)
+            .await
+            .map_err(FedimintError::SendFailed)?;
+        fees.fee_rate.sats_per_kvb = fee_rate.sats_per_kvb(fees.fee_rate.sats_per_kvb);
+        Ok(fees)
+    }
+
+    /// Withdraws the amount to the address paying the fees from
+    /// [`FedimintClient::estimate_withdraw_fee`]. Fails without starting anything if the
+    /// balance can't cover the amount and fees.
+    pub async fn withdraw(
+        &self,
+        address: bitcoin::Address,
+        amount: bitcoin::Amount,
+        fees: PegOutFees,
+    ) -> Result<OperationId, FedimintError> {
+        let total = fees.amount() + amount;
+        let balance = self.fedimint_client.get_balance().await;
+        if total > bitcoin::Amount::from_sat(balance.sats_round_down()) {
+            return Err(FedimintError::InsufficientFunds {
+                needed: Amount::from_sats(total.to_sat()),
+                available: balance,
+            });
         }
-    });
-}
 
-pub(crate) async fn spawn_lnv2_receive_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    is_transfer: bool,
-    subscription: UpdateStreamOrOutcome<ReceiveOperationState>,
-) {
-    info!(
-        "Spawning LNv2 receive subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                ReceiveOperationState::Claimed => {
-                    info!("Payment claimed");
-                    let params = if is_transfer {
-                        ReceiveSuccessMsg::Transfer
-                    } else {
-                        ReceiveSuccessMsg::Lightning
-                    };
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveSuccess(params),
-                    )
-                    .await;
+        let onchain = self
+            .fedimint_client
+            .get_first_module::<WalletClientModule>()
+            .map_err(|_| FedimintError::OnchainUnsupported(self.federation_id()))?;
+        let operation_id = onchain
+            .withdraw(&address, amount, fees, ())
+            .await
+            .map_err(FedimintError::SendFailed)?;
+        info!(
+            "Withdrawing {amount} at {} sats/kvB, operation id: {}",
+            fees.fee_rate.sats_per_kvb,
+            operation_id.fmt_full()
+        );
 
-                    if let Err(e) =
-                        storage.mark_ln_receive_as_success(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning receive as success: {e}");
-                    }
+        Ok(operation_id)
+    }
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
-                    .await;
+    /// Spends ecash as notes that can be handed to someone else, returned encoded. Refused
+    /// with [`FedimintError::InsufficientFunds`] when the balance can't cover it. Notes that
+    /// aren't redeemed within `try_cancel_after` are reclaimed, see
+    /// [`spawn_ecash_spend_subscription`].
+    pub async fn spend_ecash(
+        &self,
+        amount: Amount,
+        try_cancel_after: Duration,
+    ) -> Result<(OperationId, String), FedimintError> {
+        let balance = self.fedimint_client.get_balance().await;
+        if amount > balance {
+            return Err(FedimintError::InsufficientFunds {
+                needed: amount,
+                available: balance,
+            });
+        }
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+        let mint = self
+            .fedimint_client
+            .get_first_module::<MintClientModule>()
+            .map_err(|_| FedimintError::NoMintModule)?;
+        let (operation_id, notes) = mint
+            .spend_notes_with_selector(
+                &SelectNotesWithAtleastAmount,
+                amount,
+                try_cancel_after,
+                false,
+                (),
+            )
+            .await
+            .map_err(FedimintError::SendFailed)?;
+        info!(
+            "Spent {} of ecash, operation id: {}",
+            notes.total_amount(),
+            operation_id.fmt_full()
+        );
 
-                    client
-                        .backup_to_federation(Metadata::empty())
-                        .await
-                        .expect("Could not backup");
+        // the notes are already spent, so they are handed out even if this can't be saved
+        if let Err(e) = self.storage.create_ecash_payment(
+            operation_id.fmt_full().to_string(),
+            self.federation_id(),
+            notes.total_amount(),
+        ) {
+            error!("Could not save ecash payment: {e}");
+        }
 
-                    break;
-                }
-                ReceiveOperationState::Expired => {
-                    error!("Payment expired");
+        Ok((operation_id, notes.to_string()))
+    }
 
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveFailed("Invoice expired".to_string()),
-                    )
-                    .await;
+    /// Reissues ecash notes someone gave us into our balance, returning what they are
+    /// worth. The notes are only ours once [`spawn_ecash_reissue_subscription`] sees
+    /// them reissued, until then they can still be claimed by whoever else holds them.
+    pub async fn reissue_ecash(&self, notes: String) -> anyhow::Result<(OperationId, Amount)> {
+        let notes =
+            OOBNotes::from_str(notes.trim()).map_err(|e| anyhow!("Invalid ecash notes: {e}"))?;
+        let federation_id = self.federation_id();
+        if notes.federation_id_prefix() != federation_id.to_prefix() {
+            return Err(anyhow!("Ecash is not from federation {federation_id}"));
+        }
 
-                    if let Err(e) =
-                        storage.mark_ln_receive_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning receive as failed: {e}");
-                    }
-                    break;
-                }
-                ReceiveOperationState::Failure => {
-                    error!("Payment failed");
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveFailed("Unexpected error".to_string()),
-                    )
-                    .await;
+        let amount = notes.total_amount();
+        let mint = self
+            .fedimint_client
+            .get_first_module::<MintClientModule>()?;
+        let operation_id = mint.reissue_external_notes(notes, ()).await?;
+        info!(
+            "Reissuing {amount} of ecash, operation id: {}",
+            operation_id.fmt_full()
+        );
 
-                    if let Err(e) =
-                        storage.mark_ln_receive_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning receive as failed: {e}");
+        if let Err(e) = self.storage.create_ecash_receive(
+            operation_id.fmt_full().to_string(),
+            federation_id,
+            amount,
+        ) {
+            error!("Could not save ecash receive: {e}");
+        }
+
+        Ok((operation_id, amount))
+    }
+
+    /// Leaves the federation, deleting its saved data. Refused with
+    /// [`FedimintError::BalanceNotEmpty`] while it holds funds, so they can be swept first,
+    /// and with [`FedimintError::PaymentsInProgress`] while payments are still in flight.
+    /// Its history is kept, and it can be joined again with its invite code.
+    pub async fn leave_federation(&self) -> anyhow::Result<()> {
+        let federation_id = self.federation_id();
+        let balance = self.fedimint_client.get_balance().await;
+        if balance > Amount::ZERO {
+            return Err(FedimintError::BalanceNotEmpty(balance).into());
+        }
+        let in_progress = self.payments_in_progress()?;
+        if in_progress > 0 {
+            return Err(FedimintError::PaymentsInProgress(in_progress).into());
+        }
+
+        info!("Leaving federation {federation_id}");
+        self.shut_down().await;
+        self.storage.purge_federation(federation_id)?;
+
+        HarborCore::send_msg(
+            &mut self.sender.clone(),
+            None,
+            CoreUIMsg::FederationLeft(federation_id),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// How many receives and sends of the federation haven't finished yet
+    fn payments_in_progress(&self) -> anyhow::Result<usize> {
+        let mint = MintIdentifier::Fedimint(self.federation_id());
+        let lightning_receives = self
+            .storage
+            .get_pending_lightning_receives()?
+            .into_iter()
+            .filter(|r| r.mint_identifier() == mint)
+            .count();
+        let onchain_receives = self
+            .storage
+            .get_pending_onchain_receives()?
+            .into_iter()
+            .filter(|r| r.mint_identifier() == mint)
+            .count();
+        let lightning_payments = self
+            .storage
+            .get_pending_lightning_payments()?
+            .into_iter()
+            .filter(|p| p.mint_identifier() == mint)
+            .count();
+        let onchain_payments = self
+            .storage
+            .get_pending_onchain_payments()?
+            .into_iter()
+            .filter(|p| p.mint_identifier() == mint)
+            .count();
+        Ok(lightning_receives + onchain_receives + lightning_payments + onchain_payments)
+    }
+
+    /// Stops the client's background tasks and subscriptions, so nothing writes to its
+    /// storage once it is taken out of the core. Its data is left alone.
+    pub(crate) async fn shut_down(&self) {
+        let federation_id = self.federation_id();
+        self.left.store(true, Ordering::Relaxed);
+        // the tasks may be waiting until their next run, so don't wait for them to notice
+        for task in [&self.gateway_task, &self.status_task, &self.balance_task] {
+            if let Some(task) = task.lock().await.take() {
+                task.abort();
+                if let Err(e) = task.await {
+                    if !e.is_cancelled() {
+                        error!("Background task of {federation_id} failed: {e}");
                     }
-                    break;
                 }
-                _ => {}
             }
         }
-    });
+        // streams still open, like an invoice waiting to be paid, are dropped here
+        self.subscriptions.abort_all();
+    }
 }
 
-pub(crate) async fn spawn_lnv2_payment_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    is_transfer: bool,
-    subscription: UpdateStreamOrOutcome<SendOperationState>,
-) {
-    info!(
-        "Spawning LNv2 payment subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                SendOperationState::Failure => {
-                    error!("Unexpected payment error");
-                    let msg = if is_transfer {
-                        CoreUIMsg::TransferFailure("Unexpected failure".to_string())
-                    } else {
-                        CoreUIMsg::SendFailure("Unexpected failure".to_string())
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+impl Debug for FedimintClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FedimintClient")
+            .field("federation_id", &self.federation_id())
+            .field("online", &self.is_online())
+            .finish()
+    }
+}
 
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
-                }
-                SendOperationState::Refunded => {
-                    error!("Payment refunded");
-                    let msg = if is_transfer {
-                        CoreUIMsg::TransferFailure("Payment failed".to_string())
-                    } else {
-                        CoreUIMsg::SendFailure("Payment failed".to_string())
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+/// Pings every guardian of the federation at once, see [`FedimintClient::federation_status`]
+async fn probe_guardians(client: &ClientHandleArc) -> FederationStatus {
+    let peers: Vec<PeerId> = client
+        .config()
+        .await
+        .global
+        .api_endpoints
+        .keys()
+        .copied()
+        .collect();
 
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
-                }
-                SendOperationState::Success => {
-                    info!("Payment success");
-                    // TODO: Get preimage from state
-                    let preimage: [u8; 32] = [0; 32];
-                    let params = if is_transfer {
-                        SendSuccessMsg::Transfer
-                    } else {
-                        SendSuccessMsg::Lightning { preimage }
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
-                        .await;
+    let guardians = futures::future::join_all(peers.into_iter().map(|peer_id| async move {
+        let request = client.api().request_single_peer::<u64>(
+            SESSION_COUNT_ENDPOINT.to_string(),
+            ApiRequestErased::default(),
+            peer_id,
+        );
+        let reachable = matches!(tokio::time::timeout(PING_TIMEOUT, request).await, Ok(Ok(_)));
+        GuardianStatus { peer_id, reachable }
+    }))
+    .await;
 
-                    if let Err(e) = storage.set_lightning_payment_preimage(
-                        operation_id.fmt_full().to_string(),
-                        preimage,
-                    ) {
-                        error!("Could not mark lightning payment as success: {e}");
-                    }
+    FederationStatus::from_guardians(guardians)
+}
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
+/// Builds clients for already joined federations, at most [`FEDERATION_INIT_CONCURRENCY`]
+/// at a time, so a slow or unreachable federation doesn't hold up the others.
+/// Results are returned per federation in the order they were given.
+pub async fn init_all_federations(
+    context: &ClientContext,
+    federations: Vec<FederationId>,
+) -> Vec<(FederationId, Result<FedimintClient, FedimintError>)> {
+    stream::iter(federations)
+        .map(|federation_id| {
+            let context = context.clone();
+            async move {
+                // spawned so the blocking parts of loading a client run in parallel too
+                let result = spawn(async move {
+                    FedimintClient::new(
+                        &context,
+                        FederationInviteOrId::Id(federation_id),
+                        JoinConfig::Cached,
+                        None,
                     )
-                    .await;
-
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+                    .await
+                })
+                .await
+                .map_err(|e| anyhow!("Federation client task failed: {e}"))
+                .map_err(FedimintError::Other)
+                .and_then(|r| r);
 
-                    break;
-                }
-                _ => {}
+                (federation_id, result)
             }
+        })
+        .buffered(FEDERATION_INIT_CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// The config saved when the federation was first joined, if it was saved for this network
+pub(crate) fn cached_join_config(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    federation_id: FederationId,
+    network: Network,
+) -> Option<ClientConfig> {
+    let cached = match storage.get_federation_config(federation_id) {
+        Ok(cached) => cached?,
+        Err(e) => {
+            error!("Could not load saved federation config: {e}");
+            return None;
         }
-    });
+    };
+
+    if cached.network() != Some(network) {
+        warn!("Saved config for {federation_id} is not for {network}, downloading it again");
+        return None;
+    }
+
+    match cached.config() {
+        Ok(config) if config.calculate_federation_id() == federation_id => Some(config),
+        Ok(_) => {
+            warn!("Saved config for {federation_id} is for another federation, ignoring it");
+            None
+        }
+        Err(e) => {
+            error!("Could not read saved federation config: {e}");
+            None
+        }
+    }
 }
 
-pub(crate) async fn spawn_invoice_payment_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    is_transfer: bool,
-    subscription: UpdateStreamOrOutcome<LnPayState>,
+/// Saves the config a federation was joined with, failing to is not worth failing the join
+fn save_join_config(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    federation_id: FederationId,
+    config: &ClientConfig,
+    network: Network,
 ) {
-    info!(
-        "Spawning lightning payment subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                LnPayState::Canceled => {
-                    error!("Payment canceled");
-                    let msg = if is_transfer {
-                        CoreUIMsg::TransferFailure("Canceled".to_string())
-                    } else {
-                        CoreUIMsg::SendFailure("Canceled".to_string())
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+    if let Err(e) = storage.set_federation_config(federation_id, config, network) {
+        error!("Could not save federation config: {e}");
+    }
+}
 
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
-                }
-                LnPayState::UnexpectedError { error_message } => {
-                    error!("Unexpected payment error: {error_message}");
-                    let msg = if is_transfer {
-                        CoreUIMsg::TransferFailure(error_message)
-                    } else {
-                        CoreUIMsg::SendFailure(error_message)
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+/// Builds an invite code pointing at the first guardian listed in the config, so a
+/// federation can be shared again even if the original invite was lost.
+pub(crate) fn invite_code_from_config(
+    config: &ClientConfig,
+    api_secret: Option<String>,
+) -> Option<InviteCode> {
+    let (peer_id, peer_url) = config.global.api_endpoints.iter().next()?;
+    Some(InviteCode::new(
+        peer_url.url.clone(),
+        *peer_id,
+        config.calculate_federation_id(),
+        api_secret,
+    ))
+}
 
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
-                }
-                LnPayState::Success { preimage } => {
-                    info!("Payment success");
-                    let preimage: [u8; 32] =
-                        FromHex::from_hex(&preimage).expect("Invalid preimage");
-                    let params = if is_transfer {
-                        SendSuccessMsg::Transfer
-                    } else {
-                        SendSuccessMsg::Lightning { preimage }
-                    };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
-                        .await;
+/// Modules we use besides the mint. If one of them can't be initialized against a federation,
+/// like when it runs a version we don't understand, the client is built without them.
+const OPTIONAL_MODULE_KINDS: [&str; 3] = ["wallet", "ln", "lnv2"];
 
-                    if let Err(e) = storage.set_lightning_payment_preimage(
-                        operation_id.fmt_full().to_string(),
-                        preimage,
-                    ) {
-                        error!("Could not mark lightning payment as success: {e}");
-                    }
+/// Creates a client builder with the modules we support registered, `optional_modules`
+/// false leaves out everything but the mint.
+async fn client_builder(
+    db: Database,
+    tor_enabled: bool,
+    optional_modules: bool,
+) -> anyhow::Result<ClientBuilder> {
+    let mut client_builder = fedimint_client::Client::builder(db).await?;
+    if tor_enabled {
+        client_builder.with_tor_connector();
+    }
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
-                    .await;
+    client_builder.with_module(MintClientInit);
+    if optional_modules {
+        client_builder.with_module(WalletClientInit(None));
+        client_builder.with_module(LightningClientInit::default());
+        client_builder.with_module(fedimint_lnv2_client::LightningClientInit::default());
+    }
+    Ok(client_builder)
+}
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+/// Opens the client with only the mint module after opening it with every module failed,
+/// so ecash still works. Only the modules differ between the two, so if this fails too
+/// the failure wasn't down to a module and the caller reports its original error.
+async fn build_mint_only(
+    db: &Database,
+    tor_enabled: bool,
+    primary_module: ModuleInstanceId,
+    secret: DerivableSecret,
+) -> anyhow::Result<ClientHandle> {
+    warn!("Retrying with only the mint module");
+    let mut client_builder = client_builder(db.clone(), tor_enabled, false).await?;
+    client_builder.with_primary_module_instance_id(primary_module);
+    client_builder
+        .open(secret)
+        .await
+        .inspect_err(|e| error!("Could not build client with only the mint module: {e}"))
+}
 
-                    break;
-                }
-                _ => {}
+/// Removes the row saved for a federation we failed to join, so it isn't left behind
+/// as an archived federation
+fn cleanup_unjoined(storage: &Arc<dyn DBConnection + Send + Sync>, federation_id: FederationId) {
+    if let Err(e) = storage.delete_unjoined_federation(federation_id) {
+        error!("Could not remove federation {federation_id} after failing to join: {e}");
+    }
+}
+
+/// Why a download in [`download_with_retry`] failed
+#[derive(Debug)]
+pub(crate) enum DownloadError {
+    /// The guardians couldn't be reached or the request failed, asking again may work
+    Network(anyhow::Error),
+    /// The guardians answered with something that can't be used, it won't change by
+    /// asking again
+    Invalid(anyhow::Error),
+}
+
+/// Downloads the federation config from the guardians in the invite code, checking it
+/// belongs to the federation the invite code is for
+pub(crate) async fn download_config(
+    connector: &fedimint_api_client::api::net::Connector,
+    invite_code: &InviteCode,
+) -> Result<ClientConfig, DownloadError> {
+    let config = connector
+        .download_from_invite_code(invite_code)
+        .await
+        .map_err(DownloadError::Network)?;
+    let federation_id = config.global.calculate_federation_id();
+    if federation_id != invite_code.federation_id() {
+        return Err(DownloadError::Invalid(anyhow!(
+            "Guardians sent the config of federation {federation_id}"
+        )));
+    }
+    primary_module_instance(&config).map_err(|e| DownloadError::Invalid(e.into()))?;
+    Ok(config)
+}
+
+/// Runs `download` with a time limit of `timeout`, retrying up to [`JOIN_DOWNLOAD_RETRIES`]
+/// times when it runs out or fails to reach the guardians, backing off as the reconnect
+/// policy says. Gives up early once `stop` is set. Invalid answers aren't retried, what
+/// the guardians sent won't change by asking again.
+pub(crate) async fn download_with_retry<T, F, Fut>(
+    download: F,
+    timeout: Duration,
+    reconnect_policy: &ReconnectPolicy,
+    stop: &AtomicBool,
+) -> Result<T, FedimintError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, DownloadError>>,
+{
+    let mut attempt = 0;
+    loop {
+        let failure = match tokio::time::timeout(timeout, download()).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(DownloadError::Invalid(e))) => {
+                error!("Federation sent invalid info: {e}");
+                return Err(FedimintError::ConfigInvalid(e));
+            }
+            Ok(Err(DownloadError::Network(e))) => {
+                warn!("Could not download federation info: {e}");
+                FedimintError::DownloadFailed(e)
             }
+            Err(_) => {
+                warn!(
+                    "Timed out downloading federation info after {}s",
+                    timeout.as_secs()
+                );
+                FedimintError::JoinTimeout(timeout)
+            }
+        };
+
+        attempt += 1;
+        if attempt > JOIN_DOWNLOAD_RETRIES || stop.load(Ordering::Relaxed) {
+            error!("Could not download federation info, giving up after {attempt} attempts");
+            return Err(failure);
         }
-    });
+
+        let delay = reconnect_policy.delay(attempt);
+        debug!(
+            "Retrying federation info download in {}ms",
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        if stop.load(Ordering::Relaxed) {
+            return Err(failure);
+        }
+    }
 }
 
-pub(crate) async fn spawn_internal_payment_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    subscription: UpdateStreamOrOutcome<InternalPayState>,
+/// Overall progress of a client's module recoveries as a percentage, from each module's
+/// completed and total steps
+fn recovery_percent(progress: &BTreeMap<ModuleInstanceId, (u32, u32)>) -> u8 {
+    let (complete, total) = progress
+        .values()
+        .fold((0u64, 0u64), |(complete, total), (c, t)| {
+            (complete + u64::from(*c.min(t)), total + u64::from(*t))
+        });
+    if total == 0 {
+        return 0;
+    }
+    (complete * 100 / total) as u8
+}
+
+/// Waits for the client's module recoveries to finish, sending their progress to the UI.
+/// The progress is saved in the client's database as it goes.
+async fn wait_for_recovery(
+    client: &ClientHandle,
+    federation_id: FederationId,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    msg_id: Option<Uuid>,
+) -> anyhow::Result<()> {
+    let mut updates = Box::pin(client.subscribe_to_recovery_progress());
+    let recoveries = client.wait_for_all_recoveries();
+    tokio::pin!(recoveries);
+
+    let mut progress = BTreeMap::new();
+    let result = loop {
+        tokio::select! {
+            result = &mut recoveries => break result,
+            Some((module, update)) = updates.next() => {
+                progress.insert(module, (update.complete, update.total));
+                let percent = recovery_percent(&progress);
+                trace!("Recovery of {federation_id} is {percent}% done");
+                HarborCore::send_msg(
+                    sender,
+                    msg_id,
+                    CoreUIMsg::RecoveryProgress {
+                        id: federation_id,
+                        percent,
+                    },
+                )
+                .await;
+            }
+        }
+    };
+
+    if result.is_ok() {
+        HarborCore::send_msg(
+            sender,
+            msg_id,
+            CoreUIMsg::RecoveryProgress {
+                id: federation_id,
+                percent: 100,
+            },
+        )
+        .await;
+    }
+    result
+}
+
+/// Tells the UI which of the federation's modules were left out of its client
+async fn report_unavailable_modules(
+    sender: &mut Sender<CoreUIMsgPacket>,
+    msg_id: Option<Uuid>,
+    config: &ClientConfig,
+    reason: String,
 ) {
-    info!(
-        "Spawning internal payment subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                InternalPayState::FundingFailed { error } => {
-                    error!("Funding failed: {error:?}");
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::SendFailure(error.to_string()),
-                    )
-                    .await;
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
+    let federation_id = config.calculate_federation_id();
+    for module in config.modules.values() {
+        if !OPTIONAL_MODULE_KINDS.contains(&module.kind.as_str()) {
+            continue;
+        }
+        warn!("Module {} of {federation_id} is unavailable", module.kind);
+        HarborCore::send_msg(
+            sender,
+            msg_id,
+            CoreUIMsg::ModuleUnavailable {
+                id: federation_id,
+                kind: module.kind.clone(),
+                reason: reason.clone(),
+            },
+        )
+        .await;
+    }
+}
+
+/// Reads the client's operation log, newest first. The log is only paginated by key so
+/// the entries before `offset` are read and skipped.
+pub(crate) async fn operation_log_entries(
+    client: &ClientHandleArc,
+    limit: usize,
+    offset: usize,
+) -> Vec<OpLogEntry> {
+    client
+        .operation_log()
+        .paginate_operations_rev(offset.saturating_add(limit), None)
+        .await
+        .into_iter()
+        .skip(offset)
+        .map(|(key, entry)| OpLogEntry::new(key.operation_id, key.creation_time, &entry))
+        .collect()
+}
+
+/// How many operation log entries are read at once while reconciling
+const RECONCILE_PAGE_SIZE: usize = 100;
+
+/// What reconciling a federation's history against its operation log repaired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconcileSummary {
+    pub federation_id: FederationId,
+    /// Operations in the log that were compared against our history
+    pub checked: usize,
+    /// Records that weren't successful in our history but are in the log
+    pub marked_success: usize,
+    /// Records that weren't failed in our history but are in the log
+    pub marked_failed: usize,
+    /// Payments that weren't refunded in our history but are in the log
+    pub marked_refunded: usize,
+    /// Successful operations we had no record of, added as imported history
+    pub backfilled: usize,
+}
+
+impl ReconcileSummary {
+    pub fn repairs(&self) -> usize {
+        self.marked_success + self.marked_failed + self.marked_refunded + self.backfilled
+    }
+}
+
+/// How a finished operation ended according to the operation log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LoggedOutcome {
+    /// A lightning payment went through, with its preimage when the module gives one
+    Paid {
+        preimage: Option<[u8; 32]>,
+    },
+    Received,
+    Withdrawn(bitcoin::Txid),
+    Deposited {
+        txid: bitcoin::Txid,
+        amount: bitcoin::Amount,
+    },
+    Failed,
+    /// A lightning payment failed and what it locked up came back to our balance
+    Refunded,
+}
+
+/// An operation from the log, read into what our history keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LoggedOperation {
+    pub kind: OperationKind,
+    pub amount: Amount,
+    pub fee: Amount,
+    /// `None` while the operation hasn't finished
+    pub outcome: Option<LoggedOutcome>,
+}
+
+/// A change reconciling made to our history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Repair {
+    MarkedSuccess,
+    MarkedFailed,
+    MarkedRefunded,
+    Backfilled,
+}
+
+/// Reads a lightning, LNv2 or onchain operation from the log. Other operations, like ecash
+/// spends, aren't part of our history and give `None`.
+pub(crate) fn read_logged_operation(entry: &OpLogEntry) -> Option<LoggedOperation> {
+    let outcome = entry.outcome.clone();
+    match entry.module_kind.as_str() {
+        "ln" => {
+            let meta: fedimint_ln_client::LightningOperationMeta =
+                serde_json::from_value(entry.meta.clone()).ok()?;
+            match meta.variant {
+                fedimint_ln_client::LightningOperationMetaVariant::Pay(pay) => {
+                    let amount = Amount::from_msats(pay.invoice.amount_milli_satoshis()?);
+                    let (kind, outcome) = if pay.is_internal_payment {
+                        let outcome = outcome
+                            .and_then(|o| serde_json::from_value::<InternalPayState>(o).ok())
+                            .and_then(|state| match state {
+                                InternalPayState::Funding => None,
+                                InternalPayState::Preimage(preimage) => Some(LoggedOutcome::Paid {
+                                    preimage: Some(preimage.0),
+                                }),
+                                _ => Some(LoggedOutcome::Failed),
+                            });
+                        (OperationKind::InternalSend, outcome)
+                    } else {
+                        let outcome = outcome
+                            .and_then(|o| serde_json::from_value::<LnPayState>(o).ok())
+                            .and_then(|state| match state {
+                                LnPayState::Success { preimage } => Some(LoggedOutcome::Paid {
+                                    preimage: FromHex::from_hex(&preimage).ok(),
+                                }),
+                                LnPayState::Refunded { .. } => Some(LoggedOutcome::Refunded),
+                                LnPayState::Canceled | LnPayState::UnexpectedError { .. } => {
+                                    Some(LoggedOutcome::Failed)
+                                }
+                                _ => None,
+                            });
+                        (OperationKind::LightningSend, outcome)
+                    };
+                    Some(LoggedOperation {
+                        kind,
+                        amount,
+                        fee: pay.fee,
+                        outcome,
+                    })
                 }
-                InternalPayState::UnexpectedError(error_message) => {
-                    error!("Unexpected payment error: {error_message:?}");
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::SendFailure(error_message),
-                    )
-                    .await;
-                    if let Err(e) = storage
-                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark lightning payment as failed: {e}");
-                    }
-                    break;
+                fedimint_ln_client::LightningOperationMetaVariant::Receive { invoice, .. } => {
+                    let outcome = outcome
+                        .and_then(|o| serde_json::from_value::<LnReceiveState>(o).ok())
+                        .and_then(|state| match state {
+                            LnReceiveState::Claimed => Some(LoggedOutcome::Received),
+                            LnReceiveState::Canceled { .. } => Some(LoggedOutcome::Failed),
+                            _ => None,
+                        });
+                    Some(LoggedOperation {
+                        kind: OperationKind::LightningReceive,
+                        amount: Amount::from_msats(invoice.amount_milli_satoshis()?),
+                        fee: Amount::ZERO,
+                        outcome,
+                    })
                 }
-                InternalPayState::Preimage(preimage) => {
-                    info!("Payment success");
-                    let params = SendSuccessMsg::Lightning {
-                        preimage: preimage.0,
+                _ => None,
+            }
+        }
+        "lnv2" => {
+            let meta: fedimint_lnv2_client::LightningOperationMeta =
+                serde_json::from_value(entry.meta.clone()).ok()?;
+            match meta {
+                fedimint_lnv2_client::LightningOperationMeta::Send(meta) => {
+                    let fedimint_lnv2_common::LightningInvoice::Bolt11(invoice) = &meta.invoice;
+                    let amount = Amount::from_msats(invoice.amount_milli_satoshis()?);
+                    let outcome = outcome
+                        .and_then(|o| serde_json::from_value::<SendOperationState>(o).ok())
+                        .and_then(|state| match state {
+                            // LNv2 doesn't give us the preimage yet
+                            SendOperationState::Success => {
+                                Some(LoggedOutcome::Paid { preimage: None })
+                            }
+                            SendOperationState::Refunded => Some(LoggedOutcome::Refunded),
+                            SendOperationState::Failure => Some(LoggedOutcome::Failed),
+                            _ => None,
+                        });
+                    Some(LoggedOperation {
+                        kind: OperationKind::LightningSend,
+                        amount,
+                        fee: meta.contract.amount.saturating_sub(amount),
+                        outcome,
+                    })
+                }
+                fedimint_lnv2_client::LightningOperationMeta::Receive(meta) => {
+                    let fedimint_lnv2_common::LightningInvoice::Bolt11(invoice) = &meta.invoice;
+                    let amount = Amount::from_msats(invoice.amount_milli_satoshis()?);
+                    let outcome = outcome
+                        .and_then(|o| serde_json::from_value::<ReceiveOperationState>(o).ok())
+                        .and_then(|state| match state {
+                            ReceiveOperationState::Claimed => Some(LoggedOutcome::Received),
+                            ReceiveOperationState::Expired | ReceiveOperationState::Failure => {
+                                Some(LoggedOutcome::Failed)
+                            }
+                            _ => None,
+                        });
+                    Some(LoggedOperation {
+                        kind: OperationKind::LightningReceive,
+                        amount,
+                        fee: amount.saturating_sub(meta.contract.commitment.amount),
+                        outcome,
+                    })
+                }
+            }
+        }
+        "wallet" => {
+            let meta: fedimint_wallet_client::WalletOperationMeta =
+                serde_json::from_value(entry.meta.clone()).ok()?;
+            match meta.variant {
+                fedimint_wallet_client::WalletOperationMetaVariant::Deposit { .. } => {
+                    let outcome = outcome
+                        .and_then(|o| serde_json::from_value::<DepositStateV2>(o).ok())
+                        .and_then(|state| match state {
+                            DepositStateV2::Claimed {
+                                btc_deposited,
+                                btc_out_point,
+                            } => Some(LoggedOutcome::Deposited {
+                                txid: btc_out_point.txid,
+                                amount: btc_deposited,
+                            }),
+                            DepositStateV2::Failed(_) => Some(LoggedOutcome::Failed),
+                            _ => None,
+                        });
+                    // the amount is only known once the deposit is claimed
+                    let amount = match outcome {
+                        Some(LoggedOutcome::Deposited { amount, .. }) => {
+                            Amount::from_sats(amount.to_sat())
+                        }
+                        _ => Amount::ZERO,
                     };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
-                        .await;
+                    Some(LoggedOperation {
+                        kind: OperationKind::OnchainReceive,
+                        amount,
+                        fee: Amount::ZERO,
+                        outcome,
+                    })
+                }
+                fedimint_wallet_client::WalletOperationMetaVariant::Withdraw {
+                    amount,
+                    fee,
+                    ..
+                } => {
+                    let outcome = outcome
+                        .and_then(|o| serde_json::from_value::<WithdrawState>(o).ok())
+                        .and_then(|state| match state {
+                            WithdrawState::Succeeded(txid) => Some(LoggedOutcome::Withdrawn(txid)),
+                            WithdrawState::Failed(_) => Some(LoggedOutcome::Failed),
+                            WithdrawState::Created => None,
+                        });
+                    Some(LoggedOperation {
+                        kind: OperationKind::OnchainSend,
+                        amount: Amount::from_sats(amount.to_sat()),
+                        fee: Amount::from_sats(fee.amount().to_sat()),
+                        outcome,
+                    })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
 
-                    if let Err(e) = storage.set_lightning_payment_preimage(
-                        operation_id.fmt_full().to_string(),
-                        preimage.0,
-                    ) {
-                        error!("Could not mark lightning payment as success: {e}");
-                    }
+/// Brings our record of an operation in line with how the log says it ended.
+/// Pending operations are left to their subscriptions.
+pub(crate) fn reconcile_operation(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    federation_id: FederationId,
+    operation_id: OperationId,
+    timestamp: u64,
+    operation: LoggedOperation,
+) -> anyhow::Result<Option<Repair>> {
+    let Some(outcome) = operation.outcome else {
+        return Ok(None);
+    };
+    let id = operation_id.fmt_full().to_string();
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
-                    .await;
+    let status = match operation.kind {
+        OperationKind::LightningSend | OperationKind::InternalSend => storage
+            .get_lightning_payment(id.clone())?
+            .map(|p| p.status()),
+        OperationKind::LightningReceive => storage
+            .get_lightning_receive(id.clone())?
+            .map(|r| r.status()),
+        OperationKind::OnchainSend => storage.get_onchain_payment(id.clone())?.map(|p| p.status()),
+        OperationKind::OnchainReceive => {
+            storage.get_onchain_receive(id.clone())?.map(|r| r.status())
+        }
+        OperationKind::EcashSpend | OperationKind::EcashReissue => return Ok(None),
+    };
 
-                    update_history(storage, msg_id, &mut sender).await;
+    let Some(status) = status else {
+        if matches!(outcome, LoggedOutcome::Failed | LoggedOutcome::Refunded)
+            || operation.amount == Amount::ZERO
+        {
+            return Ok(None);
+        }
+        let (txid, preimage) = match outcome {
+            LoggedOutcome::Paid { preimage } => (None, preimage),
+            LoggedOutcome::Withdrawn(txid) | LoggedOutcome::Deposited { txid, .. } => {
+                (Some(txid), None)
+            }
+            _ => (None, None),
+        };
+        let imported = storage.import_history(vec![HistoryItem {
+            operation_id,
+            federation_id,
+            kind: operation.kind,
+            amount: operation.amount,
+            fee: operation.fee,
+            txid,
+            preimage,
+            timestamp,
+        }])?;
+        return Ok((imported > 0).then_some(Repair::Backfilled));
+    };
 
-                    break;
-                }
-                _ => {}
+    if matches!(outcome, LoggedOutcome::Failed | LoggedOutcome::Refunded) {
+        // a refund is as final as it gets, a failure is only worth redoing as a refund
+        if status == PaymentStatus::Refunded
+            || (status == PaymentStatus::Failed && outcome == LoggedOutcome::Failed)
+        {
+            return Ok(None);
+        }
+        match operation.kind {
+            OperationKind::LightningSend | OperationKind::InternalSend
+                if outcome == LoggedOutcome::Refunded =>
+            {
+                storage.mark_lightning_payment_as_refunded(id)?;
+                return Ok(Some(Repair::MarkedRefunded));
+            }
+            OperationKind::LightningSend | OperationKind::InternalSend => {
+                storage.mark_lightning_payment_as_failed(id)?
             }
+            OperationKind::LightningReceive => storage.mark_ln_receive_as_failed(id)?,
+            OperationKind::OnchainSend => storage.mark_onchain_payment_as_failed(id)?,
+            OperationKind::OnchainReceive => storage.mark_onchain_receive_as_failed(id)?,
+            OperationKind::EcashSpend | OperationKind::EcashReissue => return Ok(None),
         }
-    });
+        return Ok(Some(Repair::MarkedFailed));
+    }
+
+    if status == PaymentStatus::Success {
+        return Ok(None);
+    }
+    match outcome {
+        LoggedOutcome::Paid {
+            preimage: Some(preimage),
+        } => storage.set_lightning_payment_preimage(id, preimage)?,
+        LoggedOutcome::Paid { preimage: None } => storage.mark_lightning_payment_as_success(id)?,
+        LoggedOutcome::Received => storage.mark_ln_receive_as_success(id)?,
+        LoggedOutcome::Withdrawn(txid) => storage.set_onchain_payment_txid(id, txid)?,
+        LoggedOutcome::Deposited { txid, amount } => {
+            if storage
+                .get_onchain_receive(id.clone())?
+                .is_some_and(|r| r.txid().is_none())
+            {
+                storage.set_onchain_receive_txid(id.clone(), txid, amount.to_sat(), 0)?;
+            }
+            storage.mark_onchain_receive_as_confirmed(id)?
+        }
+        LoggedOutcome::Failed | LoggedOutcome::Refunded => unreachable!("handled above"),
+    }
+    Ok(Some(Repair::MarkedSuccess))
 }
 
-pub(crate) async fn spawn_onchain_payment_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
+/// Walks the whole operation log and repairs our history from it, reporting progress
+/// after every page. Operations that can't be repaired are logged and skipped.
+pub(crate) async fn reconcile_history(
+    client: &ClientHandleArc,
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    sender: &mut Sender<CoreUIMsgPacket>,
     msg_id: Uuid,
-    subscription: UpdateStreamOrOutcome<WithdrawState>,
-) {
-    info!(
-        "Spawning onchain payment subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                WithdrawState::Created => {}
-                WithdrawState::Failed(error) => {
-                    error!("Onchain payment failed: {error}");
-                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendFailure(error))
-                        .await;
-                    if let Err(e) =
-                        storage.mark_onchain_payment_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark onchain payment as failed: {e}");
-                    }
+) -> ReconcileSummary {
+    let federation_id = client.federation_id();
+    let mut summary = ReconcileSummary {
+        federation_id,
+        checked: 0,
+        marked_success: 0,
+        marked_failed: 0,
+        marked_refunded: 0,
+        backfilled: 0,
+    };
 
-                    break;
-                }
-                WithdrawState::Succeeded(txid) => {
-                    info!("Onchain payment success: {txid}");
-                    let params = SendSuccessMsg::Onchain { txid };
-                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
-                        .await;
+    let mut last_seen = None;
+    loop {
+        let page = client
+            .operation_log()
+            .paginate_operations_rev(RECONCILE_PAGE_SIZE, last_seen)
+            .await;
+        let Some((last_key, _)) = page.last() else {
+            break;
+        };
+        last_seen = Some(*last_key);
+        let done = page.len() < RECONCILE_PAGE_SIZE;
 
-                    if let Err(e) =
-                        storage.set_onchain_payment_txid(operation_id.fmt_full().to_string(), txid)
-                    {
-                        error!("Could not mark onchain payment txid: {e}");
-                    }
+        for (key, entry) in page {
+            let entry = OpLogEntry::new(key.operation_id, key.creation_time, &entry);
+            let Some(operation) = read_logged_operation(&entry) else {
+                continue;
+            };
+            summary.checked += 1;
 
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
+            match reconcile_operation(
+                storage,
+                federation_id,
+                key.operation_id,
+                entry.creation_time,
+                operation,
+            ) {
+                Ok(Some(Repair::MarkedSuccess)) => summary.marked_success += 1,
+                Ok(Some(Repair::MarkedFailed)) => summary.marked_failed += 1,
+                Ok(Some(Repair::MarkedRefunded)) => summary.marked_refunded += 1,
+                Ok(Some(Repair::Backfilled)) => summary.backfilled += 1,
+                Ok(None) => {}
+                Err(e) => error!(
+                    "Could not reconcile operation {}: {e}",
+                    key.operation_id.fmt_full()
+                ),
+            }
+        }
+
+        HarborCore::send_msg(
+            sender,
+            Some(msg_id),
+            CoreUIMsg::StatusUpdate {
+                message: format!(
+                    "Checked {} operations, repaired {}",
+                    summary.checked,
+                    summary.repairs()
+                ),
+                operation_id: Some(msg_id),
+            },
+        )
+        .await;
+
+        if done {
+            break;
+        }
+    }
+
+    info!("Reconciled history of {federation_id}: {summary:?}");
+    summary
+}
+
+/// Finds the instance id of the mint module, which we use as the primary module.
+/// Instance ids are assigned by each federation so this has to be read from the config.
+pub(crate) fn primary_module_instance(
+    config: &ClientConfig,
+) -> Result<ModuleInstanceId, FedimintError> {
+    config
+        .modules
+        .iter()
+        .find_map(|(id, module)| module.is_kind(&fedimint_mint_client::KIND).then_some(*id))
+        .ok_or_else(|| {
+            error!("Federation does not have a mint module");
+            FedimintError::NoMintModule
+        })
+}
+
+/// Span for a subscription task, so all events for one payment can be filtered together.
+pub(crate) fn subscription_span(
+    kind: &'static str,
+    client: &ClientHandleArc,
+    operation_id: OperationId,
+    msg_id: Uuid,
+) -> Span {
+    info_span!(
+        "subscription",
+        kind,
+        operation_id = %operation_id.fmt_full(),
+        federation_id = %client.federation_id(),
+        %msg_id,
+    )
+}
+
+/// The largest payment that fits through any of the route hints, a hint is limited by the
+/// smallest htlc maximum along it. `None` when some hint has no limit or there are no hints.
+pub(crate) fn route_hints_capacity(route_hints: &[RouteHint]) -> Option<Amount> {
+    if route_hints.is_empty() {
+        return None;
+    }
+
+    let mut capacity = 0;
+    for hint in route_hints {
+        let hint_max = hint
+            .0
+            .iter()
+            .filter_map(|hop| hop.htlc_maximum_msat)
+            .min()?;
+        capacity = capacity.max(hint_max);
+    }
+    Some(Amount::from_msats(capacity))
+}
+
+/// Whether a gateway can route the amount as far as it advertises,
+/// gateways that don't advertise limits are assumed to manage.
+pub(crate) fn gateway_can_route(gateway: &LightningGateway, amount: Amount) -> bool {
+    route_hints_capacity(&gateway.route_hints).is_none_or(|capacity| amount <= capacity)
+}
+
+/// Selects the gateway to pay `amount` through, see [`choose_gateway`] for the order.
+/// When gateways could route the payment but none of them can be selected, the gateway cache
+/// is likely stale, so it is refreshed and the selection retried once before failing with
+/// [`SendError::StaleGateways`].
+pub(crate) async fn select_gateway(
+    client: &ClientHandleArc,
+    amount: Amount,
+    require_private: bool,
+    ranking: &GatewayRanking,
+) -> anyhow::Result<LightningGateway> {
+    let ln = client
+        .get_first_module::<LightningClientModule>()
+        .map_err(|_| anyhow!("Federation does not have a lightning module"))?;
+
+    let (mut candidates, routable) = gateway_candidates(&ln, amount).await;
+    if routable > 0 && candidates.is_empty() {
+        warn!("Could not select any of {routable} gateways, refreshing the gateway cache");
+        if let Err(e) = ln.update_gateway_cache().await {
+            warn!("Could not refresh the gateway cache: {e}");
+        }
+
+        let (retried, routable) = gateway_candidates(&ln, amount).await;
+        if routable > 0 && retried.is_empty() {
+            return Err(SendError::StaleGateways.into());
+        }
+        candidates = retried;
+    }
+
+    choose_gateway(candidates, amount, require_private, ranking).ok_or_else(|| {
+        match ranking.priority {
+            SelectionPriority::Manual(id) => SendError::GatewayUnavailable(id).into(),
+            _ => no_gateway_error(require_private),
+        }
+    })
+}
+
+/// The cached gateways that can route `amount` and can be selected, along with whether each
+/// is vetted, and how many gateways could route it at all.
+async fn gateway_candidates(
+    ln: &LightningClientModule,
+    amount: Amount,
+) -> (Vec<(bool, LightningGateway)>, usize) {
+    let mut candidates = vec![];
+    let mut routable = 0;
+    for gateway in ln.list_gateways().await {
+        if !gateway_can_route(&gateway.info, amount) {
+            debug!(
+                "Skipping gateway {} that can't route {amount}",
+                gateway.info.gateway_id
+            );
+            continue;
+        }
+        routable += 1;
+        match ln.select_gateway(&gateway.info.gateway_id).await {
+            Some(g) => candidates.push((gateway.vetted, g)),
+            None => debug!("Could not select gateway {}", gateway.info.gateway_id),
+        }
+    }
+    (candidates, routable)
+}
+
+/// What a gateway charges to route `amount`: its base fee plus its proportional fee.
+/// Used to rank gateways, the fee actually paid is rounded the way the lightning module does.
+pub fn effective_fee(gateway: &LightningGateway, amount: Amount) -> Amount {
+    let proportional =
+        u128::from(amount.msats) * u128::from(gateway.fees.proportional_millionths) / 1_000_000;
+    let proportional = u64::try_from(proportional).unwrap_or(u64::MAX);
+    Amount::from_msats(u64::from(gateway.fees.base_msat).saturating_add(proportional))
+}
+
+/// What gateway selection optimizes for, on top of the private payments requirement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionPriority {
+    /// Vetted gateways first, then the lowest fee
+    #[default]
+    CheapestFee,
+    /// The gateway our payments settled through the fastest
+    LowestLatency,
+    /// The gateway our payments most often succeeded through
+    MostReliable,
+    /// Vetted gateways first, then the most reliable
+    PreferVetted,
+    /// Only vetted gateways, the lowest fee first
+    VettedOnly,
+    /// Exactly this gateway, by its id. Payments fail rather than use another one.
+    Manual(PublicKey),
+}
+
+impl SelectionPriority {
+    /// The stored form of the priority, the gateway of [`SelectionPriority::Manual`] is
+    /// stored next to it
+    pub fn to_i32(self) -> i32 {
+        match self {
+            SelectionPriority::CheapestFee => 0,
+            SelectionPriority::LowestLatency => 1,
+            SelectionPriority::MostReliable => 2,
+            SelectionPriority::PreferVetted => 3,
+            SelectionPriority::VettedOnly => 4,
+            SelectionPriority::Manual(_) => 5,
+        }
+    }
+
+    pub fn from_i32(priority: i32, manual_gateway: Option<PublicKey>) -> Self {
+        match (priority, manual_gateway) {
+            (1, _) => SelectionPriority::LowestLatency,
+            (2, _) => SelectionPriority::MostReliable,
+            (3, _) => SelectionPriority::PreferVetted,
+            (4, _) => SelectionPriority::VettedOnly,
+            (5, Some(id)) => SelectionPriority::Manual(id),
+            _ => SelectionPriority::CheapestFee,
+        }
+    }
+
+    /// Whether ranking needs the stats of our past payments through each gateway
+    pub(crate) fn needs_stats(self) -> bool {
+        matches!(
+            self,
+            SelectionPriority::LowestLatency
+                | SelectionPriority::MostReliable
+                | SelectionPriority::PreferVetted
+        )
+    }
+}
+
+/// How gateways are ranked when choosing one to pay through
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GatewayRanking {
+    pub priority: SelectionPriority,
+    /// The gateway the federation's payments went through last, picked again whenever
+    /// it can route the payment so fees stay predictable
+    pub preferred: Option<PublicKey>,
+    /// How our payments through each gateway went, by the gateway's node key
+    pub stats: HashMap<PublicKey, GatewayStats>,
+}
+
+impl GatewayRanking {
+    /// Whether the priority lets the gateway be picked at all
+    fn allows(&self, vetted: bool, gateway: &LightningGateway) -> bool {
+        match self.priority {
+            SelectionPriority::VettedOnly => vetted,
+            SelectionPriority::Manual(id) => gateway.gateway_id == id,
+            _ => true,
+        }
+    }
+
+    /// The part of a gateway's rank that comes before the default ordering, lower is better.
+    /// Gateways we have no measurements for come after those we do.
+    fn priority_key(&self, vetted: bool, gateway: &LightningGateway) -> (bool, bool, u64) {
+        let stats = self
+            .stats
+            .get(&gateway.node_pub_key)
+            .copied()
+            .unwrap_or_default();
+        let latency = stats.average_latency.map(|l| l.as_millis() as u64);
+        let failure_rate = stats.success_rate_permille().map(|r| 1_000 - u64::from(r));
+
+        match self.priority {
+            SelectionPriority::CheapestFee
+            | SelectionPriority::VettedOnly
+            | SelectionPriority::Manual(_) => (false, false, 0),
+            SelectionPriority::LowestLatency => {
+                (false, latency.is_none(), latency.unwrap_or_default())
+            }
+            SelectionPriority::MostReliable => (
+                false,
+                failure_rate.is_none(),
+                failure_rate.unwrap_or_default(),
+            ),
+            SelectionPriority::PreferVetted => (
+                !vetted,
+                failure_rate.is_none(),
+                failure_rate.unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+/// Picks a gateway from those that can route the payment, along with whether each is vetted.
+/// Gateways are ordered by the ranking's [`SelectionPriority`] first. After that vetted
+/// gateways are used first, and within that the one with the lowest [`effective_fee`] for
+/// the amount, preferring one that supports private payments on a tie and then the lowest
+/// gateway id, so the same gateways always give the same pick.
+/// With `require_private` gateways without private payment support are never picked, and
+/// [`SelectionPriority::VettedOnly`] and [`SelectionPriority::Manual`] narrow them down further. The
+/// ranking's preferred gateway comes before all others as long as it is allowed.
+fn choose_gateway(
+    candidates: Vec<(bool, LightningGateway)>,
+    amount: Amount,
+    require_private: bool,
+    ranking: &GatewayRanking,
+) -> Option<LightningGateway> {
+    candidates
+        .into_iter()
+        .filter(|(vetted, g)| gateway_eligible(*vetted, g, require_private, ranking))
+        .min_by_key(|(vetted, g)| {
+            (
+                ranking.preferred != Some(g.gateway_id),
+                ranking.priority_key(*vetted, g),
+                !vetted,
+                effective_fee(g, amount),
+                !g.supports_private_payments,
+                g.gateway_id,
+            )
+        })
+        .map(|(_, g)| g)
+}
+
+/// The error for when [`select_gateway`] finds nothing, typed when it was down to the
+/// private payments requirement.
+pub(crate) fn no_gateway_error(require_private: bool) -> anyhow::Error {
+    if require_private {
+        SendError::NoPrivateGateway.into()
+    } else {
+        anyhow!("Internal error: No gateway found for federation")
+    }
+}
+
+/// The fee the gateway [`select_gateway`] picks would charge for the amount, without paying
+/// anything. Only the cached gateways are read so this is cheap to call as the amount changes.
+pub(crate) async fn estimate_lightning_fee(
+    client: &ClientHandleArc,
+    amount: Amount,
+    require_private: bool,
+    ranking: &GatewayRanking,
+) -> Option<Amount> {
+    let Ok(ln) = client.get_first_module::<LightningClientModule>() else {
+        debug!("Federation has no lightning module, can't estimate fee");
+        return None;
+    };
+
+    let (candidates, _) = gateway_candidates(&ln, amount).await;
+    let Some(gateway) = choose_gateway(candidates, amount, require_private, ranking) else {
+        debug!("No gateway can route {amount}, can't estimate fee");
+        return None;
+    };
+    Some(gateway.fees.to_amount(&amount))
+}
+
+/// Whether a selectable gateway may be used for a payment, given the privacy requirement and
+/// the user's selection priority.
+fn gateway_eligible(
+    vetted: bool,
+    gateway: &LightningGateway,
+    require_private: bool,
+    ranking: &GatewayRanking,
+) -> bool {
+    if require_private && !gateway.supports_private_payments {
+        debug!(
+            "Skipping gateway {} without private payments",
+            gateway.gateway_id
+        );
+        return false;
+    }
+    ranking.allows(vetted, gateway)
+}
+
+/// Selects a gateway like [`select_gateway`], but one whose fee for the amount is within the
+/// limit, falling back to the cheapest gateway. Fails with [`SendError::FeeExceedsLimit`]
+/// when every gateway charges more than the limit.
+pub(crate) async fn select_gateway_within_fee(
+    client: &ClientHandleArc,
+    amount: Amount,
+    limit: Amount,
+    require_private: bool,
+    ranking: &GatewayRanking,
+) -> anyhow::Result<LightningGateway> {
+    if let Ok(gateway) = select_gateway(client, amount, require_private, ranking).await {
+        if gateway.fees.to_amount(&amount) <= limit {
+            return Ok(gateway);
+        }
+    }
+
+    let ln = client.get_first_module::<LightningClientModule>()?;
+    let (candidates, _) = gateway_candidates(&ln, amount).await;
+    let cheapest = candidates
+        .into_iter()
+        .filter(|(vetted, g)| gateway_eligible(*vetted, g, require_private, ranking))
+        .map(|(_, g)| g)
+        .min_by_key(|g| effective_fee(g, amount))
+        .ok_or_else(|| no_gateway_error(require_private))?;
+
+    let best_available_fee = cheapest.fees.to_amount(&amount);
+    if best_available_fee > limit {
+        return Err(SendError::FeeExceedsLimit {
+            best_available_fee,
+            limit,
+        }
+        .into());
+    }
+
+    Ok(cheapest)
+}
+
+/// Sends the first page of the transaction history to the UI, so it shows new transactions.
+/// See [`send_history_page`]. Returns whether the UI is still listening.
+pub(crate) async fn update_history(
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    msg_id: Uuid,
+    sender: &mut Sender<CoreUIMsgPacket>,
+) -> bool {
+    send_history_page(storage, msg_id, sender, 0).await
+}
+
+/// Sends the page of the transaction history starting at `offset` to the UI. Reads are
+/// retried a few times to ride out transient database errors, if they keep failing the UI is
+/// told with [`CoreUIMsg::HistoryRefreshFailed`] so it can offer a retry. Balance updates
+/// sent before this are not affected by a failure here. Returns whether the UI is still
+/// listening. Paging keeps the message to the UI small, but the whole history is still read
+/// from the database for each page, see [`DBConnection::get_transaction_history_page`].
+pub(crate) async fn send_history_page(
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    msg_id: Uuid,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    offset: usize,
+) -> bool {
+    let mut attempt = 1;
+    let msg = loop {
+        match storage.get_transaction_history_page(offset, HISTORY_PAGE_SIZE) {
+            Ok((items, total)) => {
+                break CoreUIMsg::TransactionHistoryPage {
+                    items,
+                    total,
+                    offset,
+                };
+            }
+            Err(e) if attempt < HISTORY_REFRESH_ATTEMPTS => {
+                warn!("Could not read transaction history (attempt {attempt}): {e}");
+                attempt += 1;
+                tokio::time::sleep(HISTORY_REFRESH_RETRY_DELAY).await;
+            }
+            Err(e) => {
+                error!("Could not read transaction history: {e}");
+                break CoreUIMsg::HistoryRefreshFailed(e.to_string());
+            }
+        }
+    };
+    HarborCore::send_msg(sender, Some(msg_id), msg).await
+}
+
+/// Receives claimed since the receive batch window opened
+#[derive(Default)]
+struct ReceiveBatchState {
+    open: bool,
+    count: usize,
+    total: Amount,
+}
+
+/// Checks the amount a claimed lightning receive brought in according to its operation
+/// against what was requested, see [`check_received_amount`]
+async fn check_claimed_amount(
+    client: &ClientHandleArc,
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+) {
+    let id = operation_id.fmt_full().to_string();
+    let requested = match storage.get_lightning_receive(id.clone()) {
+        Ok(Some(receive)) => receive.amount(),
+        Ok(None) => return,
+        Err(e) => {
+            error!("Could not get lightning receive: {e}");
+            return;
+        }
+    };
+    let Some(entry) = client.operation_log().get_operation(operation_id).await else {
+        warn!("Claimed receive {} is not in the operation log", id);
+        return;
+    };
+    let Some(operation) = read_logged_operation(&OpLogEntry::new(operation_id, UNIX_EPOCH, &entry))
+    else {
+        return;
+    };
+    check_received_amount(storage, sender, msg_id, id, requested, operation.amount).await;
+}
+
+/// The core's receive batch window, shared by its receive subscriptions
+#[derive(Clone, Default)]
+pub(crate) struct ReceiveBatch(Arc<Mutex<ReceiveBatchState>>);
+
+/// Tells the UI a lightning receive was claimed. With a receive batch window set, the first
+/// claim is reported as usual and opens the window. Claims until it closes still get their
+/// [`CoreUIMsg::ReceiveSuccess`], but the history is refreshed once for all of them when
+/// the window closes, along with a [`CoreUIMsg::ReceiveBatch`] summary of every claim in the
+/// window, the first one included. Transfers are always reported on their own.
+pub(crate) async fn notify_receive_claimed(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    receive_batch: &ReceiveBatch,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    params: ReceiveSuccessMsg,
+) {
+    let window = match storage.get_profile() {
+        Ok(Some(profile)) => profile.receive_batch_window(),
+        _ => None,
+    };
+
+    if let Some(window) = window.filter(|_| params != ReceiveSuccessMsg::Transfer) {
+        let amount = storage
+            .get_lightning_receive(operation_id.fmt_full().to_string())
+            .ok()
+            .flatten()
+            .map_or(Amount::ZERO, |r| r.amount());
+        let mut batch = receive_batch.0.lock().await;
+        batch.count += 1;
+        batch.total += amount;
+        if batch.open {
+            drop(batch);
+
+            HarborCore::send_msg(sender, Some(msg_id), CoreUIMsg::ReceiveSuccess(params)).await;
+            return;
+        }
+        batch.open = true;
+        drop(batch);
+
+        let storage = storage.clone();
+        let mut sender = sender.clone();
+        let receive_batch = receive_batch.clone();
+        spawn(async move {
+            tokio::time::sleep(window).await;
+            let batch = std::mem::take(&mut *receive_batch.0.lock().await);
+            // the opening claim was already reported on its own
+            if batch.count > 1 {
+                info!("Received {} payments in batch window", batch.count);
+                HarborCore::send_msg(
+                    &mut sender,
+                    None,
+                    CoreUIMsg::ReceiveBatch {
+                        count: batch.count,
+                        total: batch.total,
+                    },
+                )
+                .await;
+                update_history(storage, Uuid::nil(), &mut sender).await;
+            }
+        });
+    }
+
+    HarborCore::send_msg(sender, Some(msg_id), CoreUIMsg::ReceiveSuccess(params)).await;
+    update_history(storage.clone(), msg_id, sender).await;
+}
+
+/// Subscribes to an operation's updates again after its stream ended early
+type Resubscribe<T> =
+    Box<dyn Fn() -> BoxFuture<'static, anyhow::Result<BoxStream<'static, T>>> + Send + Sync>;
+
+/// Looks up an operation's outcome in the operation log
+type OutcomeLookup<T> = Box<dyn Fn() -> BoxFuture<'static, Option<T>> + Send + Sync>;
+
+/// Waits on a payment's update stream. When no update arrives within the stall timeout the
+/// operation log is checked for an outcome, which is used in place of the missing update.
+/// Without one the UI is told the payment has stalled and we keep waiting.
+/// A stream that ends before the payment does, like when the connection to the federation
+/// drops, is subscribed to again up to [`PAYMENT_RESUBSCRIBE_ATTEMPTS`] times.
+struct UpdateWatchdog<T> {
+    stream: BoxStream<'static, T>,
+    resubscribe: Option<Resubscribe<T>>,
+    resubscribes: usize,
+    find_outcome: OutcomeLookup<T>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    timeout: Duration,
+    reported_stall: bool,
+}
+
+impl<T> UpdateWatchdog<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    fn new(
+        stream: BoxStream<'static, T>,
+        client: &ClientHandleArc,
+        storage: &Arc<dyn DBConnection + Send + Sync>,
+        operation_id: OperationId,
+        msg_id: Uuid,
+    ) -> Self {
+        let timeout = match storage.get_profile() {
+            Ok(Some(profile)) => profile.update_stall_timeout(),
+            _ => DEFAULT_UPDATE_STALL_TIMEOUT,
+        };
+        let client = client.clone();
+        let find_outcome: OutcomeLookup<T> = Box::new(move || {
+            let client = client.clone();
+            async move {
+                client
+                    .operation_log()
+                    .get_operation(operation_id)
+                    .await
+                    .and_then(|entry| entry.outcome::<T>())
+            }
+            .boxed()
+        });
+        Self::with_outcome(stream, find_outcome, operation_id, msg_id, timeout)
+    }
+
+    fn with_outcome(
+        stream: BoxStream<'static, T>,
+        find_outcome: OutcomeLookup<T>,
+        operation_id: OperationId,
+        msg_id: Uuid,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            stream,
+            resubscribe: None,
+            resubscribes: 0,
+            find_outcome,
+            operation_id,
+            msg_id,
+            timeout,
+            reported_stall: false,
+        }
+    }
+
+    /// Sets how to get a new update stream when the current one ends early
+    fn with_resubscribe<F>(mut self, resubscribe: F) -> Self
+    where
+        F: Fn() -> BoxFuture<'static, anyhow::Result<BoxStream<'static, T>>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.resubscribe = Some(Box::new(resubscribe));
+        self
+    }
+
+    async fn next(&mut self, sender: &mut Sender<CoreUIMsgPacket>) -> Option<T> {
+        loop {
+            match tokio::time::timeout(self.timeout, self.stream.next()).await {
+                Ok(Some(next)) => return Some(next),
+                Ok(None) => {
+                    // callers stop at a final state, so the stream died before the payment did
+                    if let Some(outcome) = self.outcome().await {
+                        return Some(outcome);
+                    }
+                    if !self.resubscribe_stream().await {
+                        warn!("Update stream ended and could not be resubscribed");
+                        self.report_stall(sender).await;
+                        return None;
+                    }
+                }
+                Err(_) => {
+                    warn!(
+                        "No update for {}s, checking operation log",
+                        self.timeout.as_secs()
+                    );
+                    if let Some(outcome) = self.outcome().await {
+                        return Some(outcome);
+                    }
+                    self.report_stall(sender).await;
+                }
+            }
+        }
+    }
+
+    async fn outcome(&self) -> Option<T> {
+        let outcome = (self.find_outcome)().await;
+        if outcome.is_some() {
+            info!("Found outcome in operation log");
+        }
+        outcome
+    }
+
+    /// Replaces the ended stream with a new subscription, false once out of attempts
+    async fn resubscribe_stream(&mut self) -> bool {
+        let Some(resubscribe) = &self.resubscribe else {
+            return false;
+        };
+        while self.resubscribes < PAYMENT_RESUBSCRIBE_ATTEMPTS {
+            self.resubscribes += 1;
+            warn!(
+                "Update stream ended early, resubscribing (attempt {})",
+                self.resubscribes
+            );
+            tokio::time::sleep(PAYMENT_RESUBSCRIBE_DELAY).await;
+            match resubscribe().await {
+                Ok(stream) => {
+                    self.stream = stream;
+                    return true;
+                }
+                Err(e) => error!("Could not resubscribe to updates: {e}"),
+            }
+        }
+        false
+    }
+
+    async fn report_stall(&mut self, sender: &mut Sender<CoreUIMsgPacket>) {
+        if !self.reported_stall {
+            HarborCore::send_msg(
+                sender,
+                Some(self.msg_id),
+                CoreUIMsg::PaymentStalled {
+                    operation_id: self.operation_id,
+                },
+            )
+            .await;
+            self.reported_stall = true;
+        }
+    }
+}
+
+/// Marks a lightning receive as failed and then tells the UI. Storage is written first
+/// so a backed up UI channel can never hold up persisting the result.
+async fn fail_ln_receive(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    reason: String,
+) {
+    if let Err(e) = storage.mark_ln_receive_as_failed(operation_id.fmt_full().to_string()) {
+        error!("Could not mark lightning receive as failed: {e}");
+    }
+
+    HarborCore::send_msg(sender, Some(msg_id), CoreUIMsg::ReceiveFailed(reason)).await;
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_invoice_receive_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    receive_batch: ReceiveBatch,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<LnReceiveState>,
+) {
+    let span = subscription_span("ln_receive", &client, operation_id, msg_id);
+    info!(
+        "Spawning lightning receive subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let mut stream = subscription.into_stream();
+        while let Some(op_state) = stream.next().await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                LnReceiveState::Canceled { reason } => {
+                    error!("Payment canceled, reason: {:?}", reason);
+                    fail_ln_receive(
+                        &storage,
+                        &mut sender,
+                        operation_id,
+                        msg_id,
+                        reason.to_string(),
+                    )
+                    .await;
+                    break;
+                }
+                LnReceiveState::Claimed => {
+                    info!("Payment claimed");
+                    let params = if is_transfer {
+                        ReceiveSuccessMsg::Transfer
+                    } else {
+                        ReceiveSuccessMsg::Lightning
+                    };
+                    if let Err(e) =
+                        storage.mark_ln_receive_as_success(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark lightning receive as success: {e}");
+                    }
+                    check_claimed_amount(&client, &storage, &mut sender, operation_id, msg_id)
+                        .await;
+
+                    notify_receive_claimed(
+                        &storage,
+                        &mut sender,
+                        &receive_batch,
+                        operation_id,
+                        msg_id,
+                        params,
+                    )
                     .await;
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+                    client
+                        .backup_to_federation(Metadata::empty())
+                        .await
+                        .expect("Could not backup");
+
+                    break;
+                }
+                state => {
+                    if let Some(progress) = ln_receive_progress(&state) {
+                        HarborCore::send_msg(
+                            &mut sender,
+                            Some(msg_id),
+                            CoreUIMsg::ReceiveProgress {
+                                msg_id,
+                                state: progress.to_string(),
+                            },
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_lnv2_receive_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    receive_batch: ReceiveBatch,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<ReceiveOperationState>,
+) {
+    let span = subscription_span("lnv2_receive", &client, operation_id, msg_id);
+    info!(
+        "Spawning LNv2 receive subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let mut stream = subscription.into_stream();
+        while let Some(op_state) = stream.next().await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                ReceiveOperationState::Claimed => {
+                    info!("Payment claimed");
+                    let params = if is_transfer {
+                        ReceiveSuccessMsg::Transfer
+                    } else {
+                        ReceiveSuccessMsg::Lightning
+                    };
+                    if let Err(e) =
+                        storage.mark_ln_receive_as_success(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark lightning receive as success: {e}");
+                    }
+                    check_claimed_amount(&client, &storage, &mut sender, operation_id, msg_id)
+                        .await;
+
+                    notify_receive_claimed(
+                        &storage,
+                        &mut sender,
+                        &receive_batch,
+                        operation_id,
+                        msg_id,
+                        params,
+                    )
+                    .await;
+
+                    client
+                        .backup_to_federation(Metadata::empty())
+                        .await
+                        .expect("Could not backup");
+
+                    break;
+                }
+                ReceiveOperationState::Expired => {
+                    error!("Payment expired");
+
+                    fail_ln_receive(
+                        &storage,
+                        &mut sender,
+                        operation_id,
+                        msg_id,
+                        "Invoice expired".to_string(),
+                    )
+                    .await;
+                    break;
+                }
+                ReceiveOperationState::Failure => {
+                    error!("Payment failed");
+                    fail_ln_receive(
+                        &storage,
+                        &mut sender,
+                        operation_id,
+                        msg_id,
+                        "Unexpected error".to_string(),
+                    )
+                    .await;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_lnv2_payment_subscription(
+    sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<SendOperationState>,
+) {
+    let span = subscription_span("lnv2_pay", &client, operation_id, msg_id);
+    info!(
+        "Spawning LNv2 payment subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let _ = drive_lnv2_payment(
+            sender,
+            client,
+            limit,
+            storage,
+            operation_id,
+            msg_id,
+            is_transfer,
+            subscription,
+        )
+        .await;
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// Follows an LNv2 payment until it reaches a terminal state, like [`drive_invoice_payment`]
+/// does for LNv1.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn drive_lnv2_payment(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    limit: SubscriptionLimit,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<SendOperationState>,
+) -> Result<SendSuccessMsg, SendError> {
+    let resubscribe_client = client.clone();
+    let mut updates = UpdateWatchdog::new(
+        subscription.into_stream(),
+        &client,
+        &storage,
+        operation_id,
+        msg_id,
+    )
+    .with_resubscribe(move || {
+        let client = resubscribe_client.clone();
+        async move {
+            let lnv2 = client.get_first_module::<fedimint_lnv2_client::LightningClientModule>()?;
+            let sub = lnv2
+                .subscribe_send_operation_state_updates(operation_id)
+                .await?;
+            Ok(sub.into_stream())
+        }
+        .boxed()
+    });
+    while let Some(op_state) = updates.next(&mut sender).await {
+        let _permit = limit.acquire().await;
+        let reason = match op_state {
+            SendOperationState::Failure => {
+                error!("Unexpected payment error");
+                "Unexpected failure"
+            }
+            SendOperationState::Refunded => {
+                error!("Payment refunded");
+                return Err(
+                    report_payment_refunded(&mut sender, &storage, operation_id, msg_id).await,
+                );
+            }
+            SendOperationState::Success => {
+                info!("Payment success");
+                // the send state doesn't carry the preimage
+                let params = if is_transfer {
+                    SendSuccessMsg::Transfer
+                } else {
+                    SendSuccessMsg::Lightning { preimage: None }
+                };
+                if let Err(e) =
+                    storage.mark_lightning_payment_as_success(operation_id.fmt_full().to_string())
+                {
+                    error!("Could not mark lightning payment as success: {e}");
+                }
+
+                HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
+                    .await;
+
+                update_history(storage.clone(), msg_id, &mut sender).await;
+
+                return Ok(params);
+            }
+            _ => continue,
+        };
+
+        let msg = if is_transfer {
+            CoreUIMsg::TransferFailure(reason.to_string())
+        } else {
+            CoreUIMsg::SendFailure(reason.to_string())
+        };
+        if let Err(e) =
+            storage.mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
+        {
+            error!("Could not mark lightning payment as failed: {e}");
+        }
+
+        HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+        return Err(SendError::Failed(reason.to_string()));
+    }
+
+    Err(SendError::Incomplete)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_invoice_payment_subscription(
+    sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<LnPayState>,
+) {
+    let span = subscription_span("ln_pay", &client, operation_id, msg_id);
+    info!(
+        "Spawning lightning payment subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let _ = drive_invoice_payment(
+            sender,
+            client,
+            limit,
+            storage,
+            operation_id,
+            msg_id,
+            is_transfer,
+            subscription,
+        )
+        .await;
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// Follows a lightning payment until it reaches a terminal state, updating storage and
+/// the UI along the way.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn drive_invoice_payment(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    limit: SubscriptionLimit,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    is_transfer: bool,
+    subscription: UpdateStreamOrOutcome<LnPayState>,
+) -> Result<SendSuccessMsg, SendError> {
+    let resubscribe_client = client.clone();
+    let mut updates = UpdateWatchdog::new(
+        subscription.into_stream(),
+        &client,
+        &storage,
+        operation_id,
+        msg_id,
+    )
+    .with_resubscribe(move || {
+        let client = resubscribe_client.clone();
+        async move {
+            let ln = client.get_first_module::<LightningClientModule>()?;
+            Ok(ln.subscribe_ln_pay(operation_id).await?.into_stream())
+        }
+        .boxed()
+    });
+    while let Some(op_state) = updates.next(&mut sender).await {
+        let _permit = limit.acquire().await;
+        match op_state {
+            LnPayState::Canceled => {
+                error!("Payment canceled");
+                let msg = if is_transfer {
+                    CoreUIMsg::TransferFailure("Canceled".to_string())
+                } else {
+                    CoreUIMsg::SendFailure("Canceled".to_string())
+                };
+                if let Err(e) =
+                    storage.mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
+                {
+                    error!("Could not mark lightning payment as failed: {e}");
+                }
+
+                HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+                return Err(SendError::Canceled);
+            }
+            LnPayState::UnexpectedError { error_message } => {
+                error!("Unexpected payment error: {error_message}");
+                let msg = if is_transfer {
+                    CoreUIMsg::TransferFailure(error_message.clone())
+                } else {
+                    CoreUIMsg::SendFailure(error_message.clone())
+                };
+                if let Err(e) =
+                    storage.mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
+                {
+                    error!("Could not mark lightning payment as failed: {e}");
+                }
+
+                HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+                return Err(SendError::Failed(error_message));
+            }
+            LnPayState::Success { preimage } => {
+                info!("Payment success");
+                let preimage: [u8; 32] = FromHex::from_hex(&preimage).expect("Invalid preimage");
+                let params = if is_transfer {
+                    SendSuccessMsg::Transfer
+                } else {
+                    SendSuccessMsg::Lightning {
+                        preimage: Some(preimage),
+                    }
+                };
+                if let Err(e) = storage
+                    .set_lightning_payment_preimage(operation_id.fmt_full().to_string(), preimage)
+                {
+                    error!("Could not mark lightning payment as success: {e}");
+                }
+
+                HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
+                    .await;
+
+                update_history(storage.clone(), msg_id, &mut sender).await;
+
+                return Ok(params);
+            }
+            LnPayState::Refunded { gateway_error } => {
+                error!("Payment refunded, gateway error: {gateway_error:?}");
+                return Err(
+                    report_payment_refunded(&mut sender, &storage, operation_id, msg_id).await,
+                );
+            }
+            state => {
+                if let Some(progress) = ln_pay_progress(&state) {
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::SendProgress {
+                            msg_id,
+                            state: progress.to_string(),
+                        },
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    Err(SendError::Incomplete)
+}
+
+/// Marks a lightning payment refunded and tells the UI what came back to our balance,
+/// giving the error to end the payment with
+async fn report_payment_refunded(
+    sender: &mut Sender<CoreUIMsgPacket>,
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+) -> SendError {
+    let id = operation_id.fmt_full().to_string();
+    let amount = match storage.get_lightning_payment(id.clone()) {
+        Ok(Some(payment)) => payment.amount(),
+        _ => Amount::ZERO,
+    };
+    if let Err(e) = storage.mark_lightning_payment_as_refunded(id) {
+        error!("Could not mark lightning payment as refunded: {e}");
+    }
+
+    HarborCore::send_msg(
+        sender,
+        Some(msg_id),
+        CoreUIMsg::PaymentRefunded {
+            operation_id,
+            amount,
+        },
+    )
+    .await;
+
+    update_history(storage.clone(), msg_id, sender).await;
+
+    SendError::Refunded { amount }
+}
+
+/// What to show the user while a lightning payment is in flight, `None` for terminal states
+/// and those not worth showing
+fn ln_pay_progress(state: &LnPayState) -> Option<&'static str> {
+    match state {
+        LnPayState::Created => Some("Payment created"),
+        LnPayState::Funded { .. } => Some("Payment funded, waiting for the gateway"),
+        LnPayState::WaitingForRefund { .. } => Some("Payment failed, waiting for the refund"),
+        LnPayState::AwaitingChange => Some("Payment sent, waiting for change"),
+        _ => None,
+    }
+}
+
+/// What to show the user while a lightning receive is being funded, `None` for terminal
+/// states and those not worth showing
+fn ln_receive_progress(state: &LnReceiveState) -> Option<&'static str> {
+    match state {
+        LnReceiveState::WaitingForPayment { .. } => Some("Waiting for payment"),
+        LnReceiveState::Funded => Some("Payment received, claiming it"),
+        LnReceiveState::AwaitingFunds => Some("Claiming payment, waiting for the funds"),
+        _ => None,
+    }
+}
+
+/// Follows an internal payment to another user of the same federation.
+/// There is no way to cancel one: the lightning module funds the recipient's contract and
+/// reveals the preimage in the same federation transaction, so once it is submitted it
+/// either completes or is refunded by the federation, never by us.
+pub(crate) async fn spawn_internal_payment_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    subscription: UpdateStreamOrOutcome<InternalPayState>,
+) {
+    let span = subscription_span("internal_pay", &client, operation_id, msg_id);
+    info!(
+        "Spawning internal payment subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let resubscribe_client = client.clone();
+        let mut updates = UpdateWatchdog::new(
+            subscription.into_stream(),
+            &client,
+            &storage,
+            operation_id,
+            msg_id,
+        )
+        .with_resubscribe(move || {
+            let client = resubscribe_client.clone();
+            async move {
+                let ln = client.get_first_module::<LightningClientModule>()?;
+                Ok(ln.subscribe_internal_pay(operation_id).await?.into_stream())
+            }
+            .boxed()
+        });
+        while let Some(op_state) = updates.next(&mut sender).await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                InternalPayState::FundingFailed { error } => {
+                    error!("Funding failed: {error:?}");
+                    if let Err(e) = storage
+                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark lightning payment as failed: {e}");
+                    }
+
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::SendFailure(error.to_string()),
+                    )
+                    .await;
+                    break;
+                }
+                InternalPayState::UnexpectedError(error_message) => {
+                    error!("Unexpected payment error: {error_message:?}");
+                    if let Err(e) = storage
+                        .mark_lightning_payment_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark lightning payment as failed: {e}");
+                    }
+
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::SendFailure(error_message),
+                    )
+                    .await;
+                    break;
+                }
+                InternalPayState::Preimage(preimage) => {
+                    info!("Payment success");
+                    let params = SendSuccessMsg::Lightning {
+                        preimage: Some(preimage.0),
+                    };
+                    if let Err(e) = storage.set_lightning_payment_preimage(
+                        operation_id.fmt_full().to_string(),
+                        preimage.0,
+                    ) {
+                        error!("Could not mark lightning payment as success: {e}");
+                    }
+
+                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
+                        .await;
+
+                    update_history(storage, msg_id, &mut sender).await;
+
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// Follows spent ecash until it is redeemed or reclaimed. Reclaiming after the timeout
+/// races the recipient redeeming, the federation only lets one of them spend the notes so
+/// we report a reclaim only once it has gone through.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_ecash_spend_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    amount: Amount,
+    subscription: UpdateStreamOrOutcome<SpendOOBState>,
+) {
+    let span = subscription_span("ecash_spend", &client, operation_id, msg_id);
+    let federation_id = client.federation_id();
+    info!(
+        "Spawning ecash spend subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let mut stream = subscription.into_stream();
+        while let Some(op_state) = stream.next().await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                SpendOOBState::Refunded | SpendOOBState::UserCanceledSuccess => {
+                    info!("Ecash was not redeemed, reclaimed {amount}");
+                    if let Err(e) =
+                        storage.mark_ecash_payment_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark ecash payment as failed: {e}");
+                    }
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::EcashReclaimed {
+                            federation_id,
+                            amount,
+                        },
+                    )
+                    .await;
+                    break;
+                }
+                SpendOOBState::Success | SpendOOBState::UserCanceledFailure => {
+                    info!("Ecash was redeemed by the recipient");
+                    if let Err(e) =
+                        storage.mark_ecash_payment_as_success(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark ecash payment as success: {e}");
+                    }
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::SendSuccess(SendSuccessMsg::Ecash),
+                    )
+                    .await;
+                    update_history(storage.clone(), msg_id, &mut sender).await;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// Follows ecash notes being reissued into our balance until the federation has issued
+/// us new notes for them, or refused because they were already spent.
+pub(crate) async fn spawn_ecash_reissue_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    subscription: UpdateStreamOrOutcome<ReissueExternalNotesState>,
+) {
+    let span = subscription_span("ecash_reissue", &client, operation_id, msg_id);
+    info!(
+        "Spawning ecash reissue subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let mut stream = subscription.into_stream();
+        while let Some(op_state) = stream.next().await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                ReissueExternalNotesState::Done => {
+                    info!("Ecash reissued");
+                    if let Err(e) =
+                        storage.mark_ecash_receive_as_success(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark ecash receive as success: {e}");
+                    }
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::ReceiveSuccess(ReceiveSuccessMsg::Ecash),
+                    )
+                    .await;
+                    update_history(storage.clone(), msg_id, &mut sender).await;
+
+                    if let Err(e) = client.backup_to_federation(Metadata::empty()).await {
+                        error!("Could not backup after reissuing ecash: {e}");
+                    }
+                    break;
+                }
+                ReissueExternalNotesState::Failed(reason) => {
+                    error!("Could not reissue ecash: {reason}");
+                    if let Err(e) =
+                        storage.mark_ecash_receive_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark ecash receive as failed: {e}");
+                    }
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::ReceiveFailed(reason),
+                    )
+                    .await;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+pub(crate) async fn spawn_onchain_payment_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    subscription: UpdateStreamOrOutcome<WithdrawState>,
+) {
+    let span = subscription_span("onchain_pay", &client, operation_id, msg_id);
+    info!(
+        "Spawning onchain payment subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let mut stream = subscription.into_stream();
+        let stuck_at = tokio::time::Instant::now() + WITHDRAW_STUCK_THRESHOLD;
+        let mut reported_stuck = false;
+        loop {
+            let next = if reported_stuck {
+                stream.next().await
+            } else {
+                match tokio::time::timeout_at(stuck_at, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        warn!("Onchain payment has not completed, reporting as stuck");
+                        let sent = HarborCore::send_msg(
+                            &mut sender,
+                            Some(msg_id),
+                            CoreUIMsg::WithdrawStuck { operation_id },
+                        )
+                        .await;
+                        if !sent {
+                            debug!("UI is gone, no longer following the onchain payment");
+                            break;
+                        }
+                        reported_stuck = true;
+                        continue;
+                    }
+                }
+            };
+            let Some(op_state) = next else {
+                break;
+            };
+            let _permit = limit.acquire().await;
+
+            match op_state {
+                WithdrawState::Created => {}
+                WithdrawState::Failed(error) => {
+                    error!("Onchain payment failed: {error}");
+                    if let Err(e) =
+                        storage.mark_onchain_payment_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark onchain payment as failed: {e}");
+                    }
+
+                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendFailure(error))
+                        .await;
+
+                    break;
+                }
+                WithdrawState::Succeeded(txid) => {
+                    info!("Onchain payment success: {txid}");
+                    let params = SendSuccessMsg::Onchain { txid };
+                    if let Err(e) =
+                        storage.set_onchain_payment_txid(operation_id.fmt_full().to_string(), txid)
+                    {
+                        error!("Could not mark onchain payment txid: {e}");
+                    }
+
+                    HarborCore::send_msg(&mut sender, Some(msg_id), CoreUIMsg::SendSuccess(params))
+                        .await;
+
+                    update_history(storage.clone(), msg_id, &mut sender).await;
+
+                    break;
+                }
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// The smallest deposit the federation can claim, deposits below its peg-in fee would
+/// never be credited. `None` if the federation has no wallet module or charges no fee.
+pub(crate) async fn deposit_minimum(client: &ClientHandleArc) -> Option<Amount> {
+    let config = client.config().await;
+    let wallet_config = config
+        .modules
+        .values()
+        .find(|m| m.kind == fedimint_wallet_client::KIND)?
+        .cast::<WalletClientConfig>()
+        .ok()?;
+    let minimum = wallet_config.fee_consensus.peg_in_abs;
+    (minimum > Amount::ZERO).then_some(minimum)
+}
+
+pub(crate) async fn spawn_onchain_receive_subscription(
+    mut sender: Sender<CoreUIMsgPacket>,
+    client: ClientHandleArc,
+    subscriptions: Arc<SubscriptionTasks>,
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    operation_id: OperationId,
+    msg_id: Uuid,
+    subscription: UpdateStreamOrOutcome<DepositStateV2>,
+) {
+    let span = subscription_span("onchain_receive", &client, operation_id, msg_id);
+    info!(
+        "Spawning onchain receive subscription for operation id: {}",
+        operation_id.fmt_full()
+    );
+    let limit = subscriptions.limit().clone();
+    let task = async move {
+        let minimum = deposit_minimum(&client).await;
+        let mut stream = subscription.into_stream();
+        while let Some(op_state) = stream.next().await {
+            let _permit = limit.acquire().await;
+            match op_state {
+                DepositStateV2::WaitingForTransaction => {}
+                DepositStateV2::Failed(error) => {
+                    error!("Onchain receive failed: {error}");
+                    if let Err(e) =
+                        storage.mark_onchain_receive_as_failed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark onchain receive as failed: {e}");
+                    }
+
+                    HarborCore::send_msg(
+                        &mut sender,
+                        Some(msg_id),
+                        CoreUIMsg::ReceiveFailed(error),
+                    )
+                    .await;
+
+                    break;
+                }
+                DepositStateV2::WaitingForConfirmation {
+                    btc_deposited,
+                    btc_out_point,
+                } => {
+                    info!(
+                        "Onchain receive waiting for confirmation: {btc_deposited} from {btc_out_point:?}"
+                    );
+
+                    let recv = storage
+                        .get_onchain_receive(operation_id.fmt_full().to_string())
+                        .ok()
+                        .flatten();
+
+                    // only update txid and send notification, if txid isn't already set
+                    // we don't want to do this multiple times
+                    if recv.is_none_or(|r| r.txid().is_none()) {
+                        let txid = btc_out_point.txid;
+                        let fee_sats = 0; // fees for receives may exist one day
+                        if let Err(e) = storage.set_onchain_receive_txid(
+                            operation_id.fmt_full().to_string(),
+                            txid,
+                            btc_deposited.to_sat(),
+                            fee_sats,
+                        ) {
+                            error!("Could not mark onchain payment txid: {e}");
+                        }
+
+                        let received = Amount::from_sats(btc_deposited.to_sat());
+                        HarborCore::send_msg(
+                            &mut sender,
+                            Some(msg_id),
+                            CoreUIMsg::DepositSeen {
+                                txid,
+                                amount: received,
+                            },
+                        )
+                        .await;
+
+                        // the federation can't claim a deposit below its minimum,
+                        // so it would sit here forever without telling the user why
+                        let msg = match minimum {
+                            Some(minimum) if received < minimum => {
+                                warn!("Deposit of {received} is below the minimum of {minimum}");
+                                CoreUIMsg::DepositBelowMinimum { received, minimum }
+                            }
+                            _ => CoreUIMsg::ReceiveSuccess(ReceiveSuccessMsg::Onchain { txid }),
+                        };
+                        HarborCore::send_msg(&mut sender, Some(msg_id), msg).await;
+                    }
+
+                    if !update_history(storage.clone(), msg_id, &mut sender).await {
+                        debug!("UI is gone, no longer following the onchain receive");
+                        break;
+                    }
+                }
+                DepositStateV2::Confirmed {
+                    btc_deposited,
+                    btc_out_point,
+                } => {
+                    info!("Onchain receive confirmed: {btc_deposited} from {btc_out_point:?}");
+                }
+                DepositStateV2::Claimed {
+                    btc_deposited,
+                    btc_out_point,
+                } => {
+                    info!("Onchain receive claimed: {btc_deposited} from {btc_out_point:?}");
+                    if let Err(e) = storage
+                        .mark_onchain_receive_as_confirmed(operation_id.fmt_full().to_string())
+                    {
+                        error!("Could not mark onchain payment txid: {e}");
+                    }
+
+                    update_history(storage.clone(), msg_id, &mut sender).await;
+
+                    client
+                        .backup_to_federation(Metadata::empty())
+                        .await
+                        .expect("Could not backup");
+
+                    break;
+                }
+            }
+        }
+    };
+    spawn_subscription(&subscriptions, task.instrument(span));
+}
+
+/// Serializes a federation's key/value pairs for saving. Saved data records the version of
+/// the codec that wrote it, so it can still be read after the default codec changes.
+pub(crate) trait BlobCodec: Send + Sync {
+    /// Format version stored in the header of data this codec wrote
+    fn version(&self) -> u8;
+
+    fn encode(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8>;
+
+    fn decode(&self, bytes: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// The pairs serialized with bincode, also how data saved before the header existed is stored
+pub(crate) struct BincodeCodec;
+
+impl BlobCodec for BincodeCodec {
+    fn version(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        bincode::serialize(pairs).expect("byte pairs always serialize")
+    }
+
+    fn decode(&self, bytes: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Codecs saved federation data can be read with, new data is written with the first
+const BLOB_CODECS: &[&dyn BlobCodec] = &[&BincodeCodec];
+
+fn blob_codec(version: u8) -> Option<&'static dyn BlobCodec> {
+    BLOB_CODECS.iter().copied().find(|c| c.version() == version)
+}
+
+/// Marks federation data saved with a header, older data is plain bincode
+const FEDERATION_DATA_MAGIC: &[u8; 4] = b"HRBR";
+
+const FEDERATION_DATA_HEADER_LEN: usize = FEDERATION_DATA_MAGIC.len() + 1 + 32;
+
+/// Encodes federation data with the default codec, see [`encode_federation_data_with`]
+pub(crate) fn encode_federation_data(pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    encode_federation_data_with(BLOB_CODECS[0], pairs)
+}
+
+/// Encodes the pairs with the codec, prefixed with a header holding the codec's version and
+/// a sha256 checksum, so corruption can be told apart from a format change when loading.
+pub(crate) fn encode_federation_data_with(
+    codec: &dyn BlobCodec,
+    pairs: &[(Vec<u8>, Vec<u8>)],
+) -> Vec<u8> {
+    let data = codec.encode(pairs);
+    let mut blob = Vec::with_capacity(FEDERATION_DATA_HEADER_LEN + data.len());
+    blob.extend_from_slice(FEDERATION_DATA_MAGIC);
+    blob.push(codec.version());
+    blob.extend_from_slice(sha256::Hash::hash(&data).as_byte_array());
+    blob.extend_from_slice(&data);
+    blob
+}
+
+/// Reads the header written by [`encode_federation_data_with`], returning the codec for the
+/// data, its checksum and the encoded data. Data saved before the header existed has no
+/// checksum.
+fn read_federation_header(
+    blob: &[u8],
+) -> Result<(&'static dyn BlobCodec, Option<&[u8]>, &[u8]), FedimintError> {
+    if !blob.starts_with(FEDERATION_DATA_MAGIC) {
+        return Ok((&BincodeCodec, None, blob));
+    }
+    if blob.len() < FEDERATION_DATA_HEADER_LEN {
+        return Err(FedimintError::CorruptFederationData);
+    }
+
+    let version = blob[FEDERATION_DATA_MAGIC.len()];
+    let codec = blob_codec(version).ok_or(FedimintError::UnsupportedFederationData(version))?;
+    let checksum = &blob[FEDERATION_DATA_MAGIC.len() + 1..FEDERATION_DATA_HEADER_LEN];
+    Ok((codec, Some(checksum), &blob[FEDERATION_DATA_HEADER_LEN..]))
+}
+
+/// Checks the header written by [`encode_federation_data_with`] and decodes the pairs
+/// with the codec it names
+pub(crate) fn decode_federation_data(
+    blob: &[u8],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, FedimintError> {
+    let (codec, checksum, data) = read_federation_header(blob)?;
+    if checksum.is_some_and(|c| sha256::Hash::hash(data).as_byte_array() != c) {
+        return Err(FedimintError::CorruptFederationData);
+    }
+    codec
+        .decode(data)
+        .map_err(|_| FedimintError::CorruptFederationData)
+}
+
+/// Seals federation data before it is saved and opens it again when loading. Implement this
+/// to use another key, or to leave the data readable in tests.
+pub trait DataEncryptor: Send + Sync {
+    fn encrypt(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+    fn decrypt(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Leaves the data as it is
+pub struct NoEncryption;
+
+impl DataEncryptor for NoEncryption {
+    fn encrypt(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(data)
+    }
+
+    fn decrypt(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        Ok(data)
+    }
+}
+
+/// Child of the root secret the federation data key is derived from, fedimint doesn't use it
+const DATA_ENCRYPTION_CHILD_ID: ChildId = ChildId(0x6861_7262);
+
+/// The default encryptor, seals data with ChaCha20-Poly1305 under a key derived from the
+/// wallet's root secret, so a backup restored with the same seed can still be read
+pub struct SeedEncryptor {
+    key: LessSafeKey,
+}
+
+impl SeedEncryptor {
+    pub fn new(secret_provider: &dyn RootSecretProvider) -> Self {
+        // wiped once the key is set up, ring keeps its own copy
+        let key_bytes = Zeroizing::new(
+            secret_provider
+                .root_secret()
+                .child_key(DATA_ENCRYPTION_CHILD_ID)
+                .to_random_bytes::<32>(),
+        );
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key_bytes.as_ref()).expect("key is 32 bytes");
+        Self {
+            key: LessSafeKey::new(key),
+        }
+    }
+}
+
+impl DataEncryptor for SeedEncryptor {
+    /// The random nonce is prepended to the sealed data
+    fn encrypt(&self, mut data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| anyhow!("Could not encrypt federation data"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + data.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&data);
+        Ok(sealed)
+    }
+
+    fn decrypt(&self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            return Err(anyhow!("Encrypted federation data is truncated"));
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| anyhow!("Invalid nonce for federation data"))?;
+        let mut sealed = sealed.to_vec();
+        let opened = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| anyhow!("Could not decrypt federation data"))?;
+        Ok(opened.to_vec())
+    }
+}
+
+/// Marks federation data sealed by a [`DataEncryptor`], older data was saved in plaintext
+const SEALED_FEDERATION_DATA_MAGIC: &[u8; 4] = b"HRBE";
+
+/// Encodes the pairs with the default codec and seals them with the encryptor
+pub(crate) fn seal_federation_data(
+    encryptor: &dyn DataEncryptor,
+    pairs: &[(Vec<u8>, Vec<u8>)],
+) -> anyhow::Result<Vec<u8>> {
+    let sealed = encryptor.encrypt(encode_federation_data(pairs))?;
+    let mut blob = Vec::with_capacity(SEALED_FEDERATION_DATA_MAGIC.len() + sealed.len());
+    blob.extend_from_slice(SEALED_FEDERATION_DATA_MAGIC);
+    blob.extend_from_slice(&sealed);
+    Ok(blob)
+}
+
+/// Opens data written by [`seal_federation_data`], returning it ready for
+/// [`decode_federation_data`] and whether it was sealed. Plaintext data is returned as is.
+pub(crate) fn open_federation_data(
+    encryptor: &dyn DataEncryptor,
+    blob: &[u8],
+) -> Result<(Vec<u8>, bool), FedimintError> {
+    match blob.strip_prefix(SEALED_FEDERATION_DATA_MAGIC) {
+        Some(sealed) => {
+            let data = encryptor
+                .decrypt(sealed.to_vec())
+                .map_err(|_| FedimintError::UndecryptableFederationData)?;
+            Ok((data, true))
+        }
+        None => Ok((blob.to_vec(), false)),
+    }
+}
+
+/// Row a key is saved under, its hash so keys aren't readable at rest either
+fn federation_kv_row_key(key: &[u8]) -> Vec<u8> {
+    sha256::Hash::hash(key).to_byte_array().to_vec()
+}
+
+/// Seals a key/value pair into the row it is saved as. The pair itself is encoded like
+/// the rest of the federation data, see [`encode_federation_data`], and kept in the sealed
+/// value so it can be loaded back.
+fn seal_federation_kv(
+    encryptor: &dyn DataEncryptor,
+    key: &[u8],
+    value: &[u8],
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let pair = encode_federation_data(&[(key.to_vec(), value.to_vec())]);
+    Ok((federation_kv_row_key(key), encryptor.encrypt(pair)?))
+}
+
+/// Loads the key/value pairs a federation client saved one by one
+pub(crate) fn load_federation_kv(
+    storage: &(dyn DBConnection + Send + Sync),
+    encryptor: &dyn DataEncryptor,
+    federation_id: FederationId,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, FedimintError> {
+    let rows = storage
+        .get_fedimint_kv(federation_id.to_string())?
+        .into_iter()
+        .map(|(_, sealed)| {
+            let pair = encryptor
+                .decrypt(sealed)
+                .map_err(|_| FedimintError::UndecryptableFederationData)?;
+            decode_federation_data(&pair)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows.concat())
+}
+
+/// Replaces the pairs a federation client saved with the given ones
+fn replace_federation_kv(
+    storage: &(dyn DBConnection + Send + Sync),
+    encryptor: &dyn DataEncryptor,
+    federation_id: FederationId,
+    pairs: &[(Vec<u8>, Vec<u8>)],
+) -> anyhow::Result<()> {
+    let id = federation_id.to_string();
+    let old_keys = storage
+        .get_fedimint_kv(id.clone())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let rows = pairs
+        .iter()
+        .map(|(key, value)| seal_federation_kv(encryptor, key, value))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    storage.write_fedimint_kv(id, old_keys, rows)
+}
+
+/// Decodes a federation's saved data into hex encoded key/value pairs for inspecting by hand.
+/// Data that fails its checksum is still decoded as far as possible.
+#[cfg(feature = "debug-dump")]
+pub(crate) fn federation_kv_hex(blob: &[u8]) -> Result<Vec<(String, String)>, FedimintError> {
+    let (codec, checksum, data) = read_federation_header(blob)?;
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+    if checksum.is_some_and(|c| sha256::Hash::hash(data).as_byte_array() != c) {
+        warn!("Federation data failed its checksum, dumping it anyway");
+    }
+
+    let pairs = codec
+        .decode(data)
+        .map_err(|_| FedimintError::CorruptFederationData)?;
+    Ok(pairs
+        .into_iter()
+        .map(|(key, value)| (hex::encode(key), hex::encode(value)))
+        .collect())
+}
+
+#[derive(Clone)]
+pub struct FedimintStorage {
+    storage: Arc<dyn DBConnection + Send + Sync>,
+    encryptor: Arc<dyn DataEncryptor>,
+    fedimint_memory: Arc<MemDatabase>,
+    /// Held while a transaction is committed, so changes are saved in the order they were made
+    commit_lock: Arc<Mutex<()>>,
+    federation_id: FederationId,
+    /// Size of the keys and values loaded into memory when opened
+    loaded_bytes: usize,
+}
+
+impl FedimintStorage {
+    pub async fn new(
+        storage: Arc<dyn DBConnection + Send + Sync>,
+        encryptor: Arc<dyn DataEncryptor>,
+        federation_id: FederationId,
+        invite_code: Option<InviteCode>,
+        network: Network,
+    ) -> Result<Self, FedimintError> {
+        let fedimint_memory = MemDatabase::new();
+
+        // get the fedimint data or create a new fedimint entry if it doesn't exist
+        let fedimint_data: Vec<(Vec<u8>, Vec<u8>)> = match storage
+            .get_federation_value(federation_id.to_string())?
+        {
+            // nothing has been saved for the federation yet
+            Some(v) if v.is_empty() => {
+                storage.set_federation_active(federation_id)?;
+                load_federation_kv(storage.as_ref(), encryptor.as_ref(), federation_id)
+                    .inspect_err(|e| {
+                        error!("Could not load federation {federation_id}: {e}");
+                    })?
+            }
+            // data saved as a single blob, from before pairs were saved one by one or
+            // restored from a backup, replaces the saved pairs
+            Some(v) => {
+                storage.set_federation_active(federation_id)?;
+                let (data, _) = open_federation_data(encryptor.as_ref(), &v).inspect_err(|e| {
+                    error!("Could not load federation {federation_id}: {e}");
+                })?;
+                let pairs = decode_federation_data(&data).inspect_err(|e| {
+                    error!("Could not load federation {federation_id}: {e}");
+                })?;
+
+                info!("Saving data for federation {federation_id} as separate pairs");
+                replace_federation_kv(storage.as_ref(), encryptor.as_ref(), federation_id, &pairs)?;
+                // cleared last, so an interrupted move is redone on the next load
+                storage.update_fedimint_data(federation_id.to_string(), vec![])?;
+                pairs
+            }
+            None => {
+                let invite_code = invite_code.ok_or(anyhow::anyhow!("invite_code missing"))?;
+                storage.insert_new_federation(NewFedimint {
+                    id: federation_id.to_string(),
+                    value: vec![],
+                    invite_code: invite_code.to_string(),
+                    network: Some(network.to_string()),
+                })?;
+                vec![]
+            }
+        };
+
+        let loaded_bytes = fedimint_data.iter().map(|(k, v)| k.len() + v.len()).sum();
+        trace!("Loading {loaded_bytes} bytes of federation data into memory");
+
+        // get the value and load it into fedimint memory
+        if !fedimint_data.is_empty() {
+            let mut mem_db_tx = fedimint_memory.begin_transaction().await;
+            for (key, value) in fedimint_data {
+                mem_db_tx.raw_insert_bytes(&key, &value).await?;
+            }
+            mem_db_tx.commit_tx().await?;
+        }
+
+        Ok(Self {
+            storage,
+            encryptor,
+            federation_id,
+            fedimint_memory: Arc::new(fedimint_memory),
+            commit_lock: Arc::new(Mutex::new(())),
+            loaded_bytes,
+        })
+    }
+
+    pub fn loaded_bytes(&self) -> usize {
+        self.loaded_bytes
+    }
+}
+
+impl fmt::Debug for FedimintStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FedimintDB").finish()
+    }
+}
+
+#[async_trait]
+impl IRawDatabase for FedimintStorage {
+    type Transaction<'a> = SQLPseudoTransaction<'a>;
+
+    async fn begin_transaction<'a>(&'a self) -> SQLPseudoTransaction {
+        SQLPseudoTransaction {
+            storage: self.storage.clone(),
+            encryptor: self.encryptor.clone(),
+            commit_lock: self.commit_lock.clone(),
+            federation_id: self.federation_id.to_string(),
+            mem: self.fedimint_memory.begin_transaction().await,
+            dirty: BTreeMap::new(),
+            savepoint: BTreeMap::new(),
+        }
+    }
+
+    fn checkpoint(&self, _backup_path: &Path) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SQLPseudoTransaction<'a> {
+    pub(crate) storage: Arc<dyn DBConnection + Send + Sync>,
+    encryptor: Arc<dyn DataEncryptor>,
+    commit_lock: Arc<Mutex<()>>,
+    federation_id: String,
+    mem: MemTransaction<'a>,
+    /// Keys changed in the transaction, with their new value or `None` if removed
+    dirty: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// The changed keys when the savepoint was set, restored when rolling back to it
+    savepoint: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Debug for SQLPseudoTransaction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SQLPseudoTransaction")
+            .field("federation_id", &self.federation_id)
+            .field("mem", &self.mem)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl IRawDatabaseTransaction for SQLPseudoTransaction<'_> {
+    async fn commit_tx(self) -> anyhow::Result<()> {
+        let commit_lock = self.commit_lock.clone();
+        let _guard = commit_lock.lock().await;
+        self.mem.commit_tx().await?;
+
+        // only the pairs the transaction changed are written
+        let mut upserts = vec![];
+        let mut deletes = vec![];
+        for (key, value) in self.dirty {
+            match value {
+                Some(value) => {
+                    upserts.push(seal_federation_kv(self.encryptor.as_ref(), &key, &value)?)
+                }
+                None => deletes.push(federation_kv_row_key(&key)),
+            }
+        }
+
+        if deletes.is_empty() && upserts.is_empty() {
+            return Ok(());
+        }
+        self.storage
+            .write_fedimint_kv(self.federation_id, deletes, upserts)
+    }
+}
+
+#[async_trait]
+impl IDatabaseTransactionOpsCore for SQLPseudoTransaction<'_> {
+    async fn raw_insert_bytes(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let previous = self.mem.raw_insert_bytes(key, value).await?;
+        self.dirty.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(previous)
+    }
+
+    async fn raw_get_bytes(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        self.mem.raw_get_bytes(key).await
+    }
+
+    async fn raw_remove_entry(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let previous = self.mem.raw_remove_entry(key).await?;
+        self.dirty.insert(key.to_vec(), None);
+        Ok(previous)
+    }
+
+    async fn raw_find_by_prefix(&mut self, key_prefix: &[u8]) -> anyhow::Result<PrefixStream<'_>> {
+        self.mem.raw_find_by_prefix(key_prefix).await
+    }
+
+    async fn raw_remove_by_prefix(&mut self, key_prefix: &[u8]) -> anyhow::Result<()> {
+        let keys = self
+            .mem
+            .raw_find_by_prefix(key_prefix)
+            .await?
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>()
+            .await;
+        self.mem.raw_remove_by_prefix(key_prefix).await?;
+        for key in keys {
+            self.dirty.insert(key, None);
+        }
+        Ok(())
+    }
+
+    async fn raw_find_by_prefix_sorted_descending(
+        &mut self,
+        key_prefix: &[u8],
+    ) -> anyhow::Result<PrefixStream<'_>> {
+        self.mem
+            .raw_find_by_prefix_sorted_descending(key_prefix)
+            .await
+    }
+
+    async fn raw_find_by_range(&mut self, range: Range<&[u8]>) -> anyhow::Result<PrefixStream<'_>> {
+        self.mem.raw_find_by_range(range).await
+    }
+}
+
+#[async_trait]
+impl IDatabaseTransactionOps for SQLPseudoTransaction<'_> {
+    async fn rollback_tx_to_savepoint(&mut self) -> anyhow::Result<()> {
+        self.mem.rollback_tx_to_savepoint().await?;
+        self.dirty = self.savepoint.clone();
+        Ok(())
+    }
+
+    async fn set_tx_savepoint(&mut self) -> anyhow::Result<()> {
+        self.mem.set_tx_savepoint().await?;
+        self.savepoint = self.dirty.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::setup_db;
+    use fedimint_core::config::{ClientModuleConfig, GlobalClientConfig};
+    use fedimint_core::core::ModuleKind;
+    use fedimint_core::encoding::DynRawFallback;
+    use fedimint_core::module::{CoreConsensusVersion, ModuleConsensusVersion};
+    use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+    use futures::channel::mpsc;
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    const INVITE_CODE: &str = "fed11qgqzc2nhwden5te0vejkg6tdd9h8gepwvejkg6tdd9h8garhduhx6at5d9h8jmn9wshxxmmd9uqqzgxg6s3evnr6m9zdxr6hxkdkukexpcs3mn7mj3g5pc5dfh63l4tj6g9zk4er";
+    const FEDERATION_ID: &str = "c8d423964c7ad944d30f57359b6e5b260e211dcfdb945140e28d4df51fd572d2";
+    const INVOICE: &str = "lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0";
+
+    /// Opens a fresh database, the directory holding it is removed when dropped
+    fn setup_test_storage() -> (TempDir, Arc<dyn DBConnection + Send + Sync>) {
+        let tmp_dir = TempDir::new("harbor").expect("Could not create temp dir");
+        let url = format!("sqlite://{}/harbor.sqlite", tmp_dir.path().display());
+        let storage: Arc<dyn DBConnection + Send + Sync> =
+            setup_db(&url, "password".to_string()).expect("Could not setup db");
+        (tmp_dir, storage)
+    }
+
+    /// A client context on regtest with a new seed, whose UI messages go nowhere
+    fn test_client_context(
+        storage: Arc<dyn DBConnection + Send + Sync>,
+        recoveries: Recoveries,
+    ) -> ClientContext {
+        let mnemonic = storage.generate_mnemonic(None).unwrap();
+        let (sender, _) = mpsc::channel(10);
+        ClientContext {
+            storage: storage.clone(),
+            secret_provider: Arc::new(mnemonic),
+            network: Network::Regtest,
+            reconnect_policy: ReconnectPolicy::default(),
+            stop: Arc::new(AtomicBool::new(false)),
+            backgrounded: Arc::new(AtomicBool::new(false)),
+            sender,
+            balance_updates: BalanceUpdates::new(storage),
+            subscription_limit: SubscriptionLimit::default(),
+            recoveries,
+        }
+    }
+
+    #[test]
+    fn test_route_hints_capacity() {
+        use fedimint_ln_common::route_hints::RouteHintHop;
+
+        let hop = |htlc_maximum_msat: Option<u64>| RouteHintHop {
+            src_node_id: PublicKey::from_str(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+            short_channel_id: 1,
+            base_msat: 0,
+            proportional_millionths: 0,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: None,
+            htlc_maximum_msat,
+        };
+
+        assert_eq!(route_hints_capacity(&[]), None);
+        assert_eq!(route_hints_capacity(&[RouteHint(vec![hop(None)])]), None);
+        assert_eq!(
+            route_hints_capacity(&[
+                RouteHint(vec![hop(Some(5_000)), hop(Some(2_000))]),
+                RouteHint(vec![hop(Some(3_000))]),
+            ]),
+            Some(Amount::from_msats(3_000))
+        );
+        assert_eq!(
+            route_hints_capacity(&[RouteHint(vec![hop(Some(1_000)), hop(None)])]),
+            Some(Amount::from_msats(1_000))
+        );
+        // one unlimited hint means the gateway doesn't advertise a limit
+        assert_eq!(
+            route_hints_capacity(&[
+                RouteHint(vec![hop(Some(3_000))]),
+                RouteHint(vec![hop(None)]),
+            ]),
+            None
+        );
+    }
+
+    fn config_with_modules(modules: &[(ModuleInstanceId, ModuleKind)]) -> ClientConfig {
+        ClientConfig {
+            global: GlobalClientConfig {
+                api_endpoints: BTreeMap::new(),
+                broadcast_public_keys: None,
+                consensus_version: CoreConsensusVersion::new(2, 0),
+                meta: BTreeMap::new(),
+            },
+            modules: modules
+                .iter()
+                .map(|(id, kind)| {
+                    let module = ClientModuleConfig {
+                        kind: kind.clone(),
+                        version: ModuleConsensusVersion::new(2, 0),
+                        config: DynRawFallback::Raw {
+                            module_instance_id: *id,
+                            raw: vec![],
+                        },
+                    };
+                    (*id, module)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_invite_code_from_config() {
+        use fedimint_core::config::PeerUrl;
+        use fedimint_core::util::SafeUrl;
+
+        let mut config = config_with_modules(&[]);
+        assert!(invite_code_from_config(&config, None).is_none());
+
+        let url = SafeUrl::parse("wss://guardian.example.com/").unwrap();
+        config.global.api_endpoints.insert(
+            PeerId::from(0),
+            PeerUrl {
+                url: url.clone(),
+                name: "guardian".to_string(),
+            },
+        );
+
+        let invite = invite_code_from_config(&config, None).unwrap();
+        assert_eq!(invite.federation_id(), config.calculate_federation_id());
+        assert_eq!(invite.url(), url);
+        assert_eq!(invite.api_secret(), None);
+
+        let invite = invite_code_from_config(&config, Some("secret".to_string())).unwrap();
+        assert_eq!(invite.api_secret(), Some("secret".to_string()));
+    }
+
+    #[test]
+    fn test_federation_invite_or_id_parse() {
+        let parsed = FederationInviteOrId::from_str(&format!(" {INVITE_CODE}\n")).unwrap();
+        assert!(matches!(parsed, FederationInviteOrId::Invite(_)));
+        assert_eq!(parsed.to_string(), INVITE_CODE);
+
+        let id = parsed.federation_id().to_string();
+        let parsed = FederationInviteOrId::from_str(&id).unwrap();
+        assert!(matches!(parsed, FederationInviteOrId::Id(_)));
+        assert_eq!(parsed.to_string(), id);
+
+        assert!(matches!(
+            FederationInviteOrId::from_str("not a federation"),
+            Err(ParseFederationInviteOrIdError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_watchdog_reports_stall() {
+        let (mut tx, mut rx) = mpsc::channel::<CoreUIMsgPacket>(10);
+        let operation_id = OperationId::new_random();
+        let mut updates = UpdateWatchdog::<u8>::with_outcome(
+            futures::stream::pending().boxed(),
+            Box::new(|| async { None::<u8> }.boxed()),
+            operation_id,
+            Uuid::nil(),
+            Duration::from_millis(10),
+        );
+
+        // nothing arrives and the log has no outcome, so it keeps waiting after reporting
+        let next = tokio::time::timeout(Duration::from_millis(100), updates.next(&mut tx)).await;
+        assert!(next.is_err());
+        let packet = rx.try_next().unwrap().unwrap();
+        assert!(matches!(
+            packet.msg,
+            CoreUIMsg::PaymentStalled { operation_id: id } if id == operation_id
+        ));
+
+        // the stall is only reported once
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_watchdog_uses_logged_outcome() {
+        let (mut tx, mut rx) = mpsc::channel::<CoreUIMsgPacket>(10);
+        let mut updates = UpdateWatchdog::<u8>::with_outcome(
+            futures::stream::pending().boxed(),
+            Box::new(|| async { Some(7u8) }.boxed()),
+            OperationId::new_random(),
+            Uuid::nil(),
+            Duration::from_millis(10),
+        );
+
+        assert_eq!(updates.next(&mut tx).await, Some(7));
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_download_with_retry() {
+        use std::sync::atomic::AtomicU32;
+
+        let timeout = Duration::from_millis(10);
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            jitter: 0.0,
+        };
+        let stop = AtomicBool::new(false);
+
+        // an attempt that never finishes is retried, then reported as a timeout
+        let attempts = AtomicU32::new(0);
+        let result = download_with_retry(
+            || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                futures::future::pending::<Result<(), DownloadError>>()
+            },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert!(matches!(result, Err(FedimintError::JoinTimeout(t)) if t == timeout));
+        assert_eq!(attempts.load(Ordering::Relaxed), JOIN_DOWNLOAD_RETRIES + 1);
+
+        // a slow guardian that answers in time on a retry is fine
+        let attempts = AtomicU32::new(0);
+        let result = download_with_retry(
+            || {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if attempt == 0 {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                    Ok(attempt)
+                }
+            },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert_eq!(result.unwrap(), 1);
+
+        // an invalid config isn't asked for again
+        let attempts = AtomicU32::new(0);
+        let result = download_with_retry(
+            || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(DownloadError::Invalid(anyhow!("invalid config"))) }
+            },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert!(matches!(result, Err(FedimintError::ConfigInvalid(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+        // guardians that can't be reached are asked again like slow ones
+        let attempts = AtomicU32::new(0);
+        let result = download_with_retry(
+            || {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if attempt < 2 {
+                        return Err(DownloadError::Network(anyhow!("connection refused")));
+                    }
+                    Ok(attempt)
+                }
+            },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let result = download_with_retry(
+            || async { Err::<(), _>(DownloadError::Network(anyhow!("connection refused"))) },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert!(matches!(result, Err(FedimintError::DownloadFailed(_))));
+
+        // nothing is retried once stopping
+        stop.store(true, Ordering::Relaxed);
+        let attempts = AtomicU32::new(0);
+        let result = download_with_retry(
+            || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                futures::future::pending::<Result<(), DownloadError>>()
+            },
+            timeout,
+            &policy,
+            &stop,
+        )
+        .await;
+        assert!(matches!(result, Err(FedimintError::JoinTimeout(_))));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_recovery_percent() {
+        let mut progress = BTreeMap::new();
+        assert_eq!(recovery_percent(&progress), 0);
+
+        progress.insert(0, (0, 0));
+        assert_eq!(recovery_percent(&progress), 0);
+
+        progress.insert(1, (50, 200));
+        assert_eq!(recovery_percent(&progress), 25);
+
+        progress.insert(0, (200, 200));
+        assert_eq!(recovery_percent(&progress), 62);
+
+        // a module reporting more than it has to do doesn't go over 100
+        progress.insert(1, (300, 200));
+        assert_eq!(recovery_percent(&progress), 100);
+    }
+
+    #[test]
+    fn test_reconnect_policy() {
+        let policy = ReconnectPolicy {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.5,
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+        assert_eq!(policy.backoff(7), Duration::from_secs(60));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(60));
+
+        for _ in 0..100 {
+            let delay = policy.delay(4);
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(12));
+        }
+
+        let no_jitter = ReconnectPolicy {
+            jitter: 0.0,
+            ..policy
+        };
+        assert_eq!(no_jitter.delay(3), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_federation_health() {
+        let status = |reachable: &[bool]| {
+            let guardians = reachable
+                .iter()
+                .enumerate()
+                .map(|(i, reachable)| GuardianStatus {
+                    peer_id: PeerId::from(i as u16),
+                    reachable: *reachable,
+                })
+                .collect();
+            FederationStatus::from_guardians(guardians).health
+        };
+
+        assert_eq!(status(&[true, true, true, true]), FederationHealth::Online);
+        // 4 guardians tolerate 1 being down, not 2
+        assert_eq!(
+            status(&[true, true, true, false]),
+            FederationHealth::Degraded
+        );
+        assert_eq!(
+            status(&[true, true, false, false]),
+            FederationHealth::Offline
+        );
+        // a single guardian tolerates none
+        assert_eq!(status(&[true]), FederationHealth::Online);
+        assert_eq!(status(&[false]), FederationHealth::Offline);
+        // 7 guardians tolerate 2
+        assert_eq!(
+            status(&[true, true, true, true, true, false, false]),
+            FederationHealth::Degraded
+        );
+        assert_eq!(status(&[]), FederationHealth::Offline);
+    }
+
+    #[test]
+    fn test_primary_module_instance() {
+        let config = config_with_modules(&[
+            (0, fedimint_ln_common::KIND),
+            (1, fedimint_wallet_client::KIND),
+            (3, fedimint_mint_client::KIND),
+        ]);
+        assert_eq!(primary_module_instance(&config).unwrap(), 3);
+
+        let config = config_with_modules(&[
+            (0, fedimint_ln_common::KIND),
+            (1, fedimint_wallet_client::KIND),
+        ]);
+        assert!(matches!(
+            primary_module_instance(&config),
+            Err(FedimintError::NoMintModule)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_report_unavailable_modules() {
+        let config = config_with_modules(&[
+            (0, fedimint_ln_common::KIND),
+            (1, fedimint_mint_client::KIND),
+            (2, fedimint_wallet_client::KIND),
+            (3, ModuleKind::from_static_str("meta")),
+        ]);
+        let (mut tx, mut rx) = mpsc::channel(10);
+        report_unavailable_modules(&mut tx, None, &config, "bad version".to_string()).await;
+        drop(tx);
+
+        // only modules we would have used are reported, never the mint
+        let mut kinds = vec![];
+        while let Some(packet) = rx.next().await {
+            match packet.msg {
+                CoreUIMsg::ModuleUnavailable { id, kind, reason } => {
+                    assert_eq!(id, config.calculate_federation_id());
+                    assert_eq!(reason, "bad version");
+                    kinds.push(kind);
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+        assert_eq!(
+            kinds,
+            vec![fedimint_ln_common::KIND, fedimint_wallet_client::KIND]
+        );
+    }
+
+    #[test]
+    fn test_cached_join_config() {
+        let (_tmp_dir, storage) = setup_test_storage();
+
+        let config = config_with_modules(&[(0, fedimint_mint_client::KIND)]);
+        let federation_id = config.calculate_federation_id();
+        assert!(cached_join_config(&storage, federation_id, Network::Signet).is_none());
+
+        save_join_config(&storage, federation_id, &config, Network::Signet);
+        let cached = cached_join_config(&storage, federation_id, Network::Signet).unwrap();
+        assert_eq!(cached.calculate_federation_id(), federation_id);
+
+        // a config saved on another network isn't reused
+        assert!(cached_join_config(&storage, federation_id, Network::Bitcoin).is_none());
+
+        // nor is one saved under the wrong federation
+        let other_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        save_join_config(&storage, other_id, &config, Network::Signet);
+        assert!(cached_join_config(&storage, other_id, Network::Signet).is_none());
+    }
+
+    #[test]
+    fn test_federation_data_checksum() {
+        let pairs = vec![(vec![1u8, 2], vec![3u8, 4])];
+        let blob = encode_federation_data(&pairs);
+        assert_eq!(decode_federation_data(&blob).unwrap(), pairs);
+
+        // data saved before the header existed still loads
+        let data = bincode::serialize(&pairs).unwrap();
+        assert_eq!(decode_federation_data(&data).unwrap(), pairs);
+
+        let mut corrupt = blob.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_federation_data(&corrupt),
+            Err(FedimintError::CorruptFederationData)
+        ));
+
+        let truncated = &blob[..FEDERATION_DATA_HEADER_LEN - 1];
+        assert!(matches!(
+            decode_federation_data(truncated),
+            Err(FedimintError::CorruptFederationData)
+        ));
+
+        let mut newer = blob.clone();
+        newer[FEDERATION_DATA_MAGIC.len()] = u8::MAX;
+        assert!(matches!(
+            decode_federation_data(&newer),
+            Err(FedimintError::UnsupportedFederationData(u8::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_federation_kv_row_checksum() {
+        let (_, row) = seal_federation_kv(&NoEncryption, &[1, 2], &[3, 4]).unwrap();
+        assert!(row.starts_with(FEDERATION_DATA_MAGIC));
+        assert_eq!(
+            decode_federation_data(&row).unwrap(),
+            vec![(vec![1u8, 2], vec![3u8, 4])]
+        );
+
+        let mut corrupt = row.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_federation_data(&corrupt),
+            Err(FedimintError::CorruptFederationData)
+        ));
+    }
+
+    #[test]
+    fn test_federation_data_codec_version() {
+        /// Stores the pairs reversed, to tell it apart from bincode
+        struct ReversedCodec;
+
+        impl BlobCodec for ReversedCodec {
+            fn version(&self) -> u8 {
+                2
+            }
+
+            fn encode(&self, pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+                let mut pairs = pairs.to_vec();
+                pairs.reverse();
+                BincodeCodec.encode(&pairs)
+            }
+
+            fn decode(&self, bytes: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+                let mut pairs = BincodeCodec.decode(bytes)?;
+                pairs.reverse();
+                Ok(pairs)
+            }
+        }
+
+        let pairs = vec![(vec![1u8], vec![2u8]), (vec![3u8], vec![4u8])];
+        let blob = encode_federation_data_with(&ReversedCodec, &pairs);
+        assert_eq!(blob[FEDERATION_DATA_MAGIC.len()], 2);
+        // the header names a codec this build doesn't have
+        assert!(matches!(
+            decode_federation_data(&blob),
+            Err(FedimintError::UnsupportedFederationData(2))
+        ));
+
+        // the default codec is picked from the header, not assumed
+        let blob = encode_federation_data(&pairs);
+        assert_eq!(blob[FEDERATION_DATA_MAGIC.len()], BincodeCodec.version());
+        assert_eq!(decode_federation_data(&blob).unwrap(), pairs);
+    }
+
+    #[cfg(feature = "debug-dump")]
+    #[test]
+    fn test_federation_kv_hex() {
+        let pairs = vec![(vec![0xabu8, 0x01], vec![0xffu8])];
+        let data = bincode::serialize(&pairs).unwrap();
+        let expected = vec![("ab01".to_string(), "ff".to_string())];
+
+        let blob = encode_federation_data(&pairs);
+        assert_eq!(federation_kv_hex(&blob).unwrap(), expected);
+        assert_eq!(federation_kv_hex(&data).unwrap(), expected);
+        assert!(federation_kv_hex(&[]).unwrap().is_empty());
+
+        // corrupt data is still dumped
+        let mut corrupt = blob.clone();
+        corrupt[FEDERATION_DATA_HEADER_LEN - 1] ^= 1;
+        assert_eq!(federation_kv_hex(&corrupt).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_lightning_progress() {
+        assert!(ln_pay_progress(&LnPayState::Created).is_some());
+        assert!(ln_pay_progress(&LnPayState::Funded { block_height: 1 }).is_some());
+        assert!(ln_pay_progress(&LnPayState::AwaitingChange).is_some());
+        // terminal states are reported as before, not as progress
+        assert!(ln_pay_progress(&LnPayState::Canceled).is_none());
+        assert!(
+            ln_pay_progress(&LnPayState::Success {
+                preimage: "00".to_string()
+            })
+            .is_none()
+        );
+
+        assert!(ln_receive_progress(&LnReceiveState::Funded).is_some());
+        assert!(ln_receive_progress(&LnReceiveState::AwaitingFunds).is_some());
+        assert!(ln_receive_progress(&LnReceiveState::Created).is_none());
+        assert!(ln_receive_progress(&LnReceiveState::Claimed).is_none());
+    }
+
+    #[test]
+    fn test_seal_federation_data() {
+        let pairs = vec![(vec![1u8, 2], vec![3u8, 4])];
+        let mnemonic = Mnemonic::generate_in(bip39::Language::English, 12).unwrap();
+        let encryptor = SeedEncryptor::new(&mnemonic);
+
+        let blob = seal_federation_data(&encryptor, &pairs).unwrap();
+        assert!(blob.starts_with(SEALED_FEDERATION_DATA_MAGIC));
+        let (data, sealed) = open_federation_data(&encryptor, &blob).unwrap();
+        assert!(sealed);
+        assert_eq!(decode_federation_data(&data).unwrap(), pairs);
+
+        // the same seed derives the same key
+        let (data, _) = open_federation_data(&SeedEncryptor::new(&mnemonic), &blob).unwrap();
+        assert_eq!(decode_federation_data(&data).unwrap(), pairs);
+
+        let other = Mnemonic::generate_in(bip39::Language::English, 12).unwrap();
+        assert!(matches!(
+            open_federation_data(&SeedEncryptor::new(&other), &blob),
+            Err(FedimintError::UndecryptableFederationData)
+        ));
+
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open_federation_data(&encryptor, &tampered),
+            Err(FedimintError::UndecryptableFederationData)
+        ));
+
+        // plaintext data is passed through to be decoded as before
+        let plain = encode_federation_data(&pairs);
+        let (data, sealed) = open_federation_data(&encryptor, &plain).unwrap();
+        assert!(!sealed);
+        assert_eq!(data, plain);
+
+        // the no-op encryptor still marks the data so it round trips
+        let blob = seal_federation_data(&NoEncryption, &pairs).unwrap();
+        let (data, _) = open_federation_data(&NoEncryption, &blob).unwrap();
+        assert_eq!(decode_federation_data(&data).unwrap(), pairs);
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_federation_data_is_encrypted() {
+        let (_tmp_dir, storage) = setup_test_storage();
+
+        let invite = InviteCode::from_str(INVITE_CODE).unwrap();
+        let federation_id = invite.federation_id();
+        let pairs = vec![(vec![1u8, 2], vec![3u8, 4, 5])];
+        let plain = encode_federation_data(&pairs);
+        storage
+            .insert_new_federation(NewFedimint {
+                id: federation_id.to_string(),
+                value: plain.clone(),
+                invite_code: invite.to_string(),
+                network: Some(Network::Signet.to_string()),
+            })
+            .unwrap();
+
+        let mnemonic = Mnemonic::generate_in(bip39::Language::English, 12).unwrap();
+        let encryptor: Arc<dyn DataEncryptor> = Arc::new(SeedEncryptor::new(&mnemonic));
+        let db = FedimintStorage::new(
+            storage.clone(),
+            encryptor.clone(),
+            federation_id,
+            None,
+            Network::Signet,
+        )
+        .await
+        .unwrap();
+        assert_eq!(db.loaded_bytes(), 5);
+
+        // loading moved the plaintext into sealed pairs
+        assert_eq!(
+            storage
+                .get_federation_value(federation_id.to_string())
+                .unwrap(),
+            Some(vec![])
+        );
+        let rows = storage.get_fedimint_kv(federation_id.to_string()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_ne!(rows[0].0, vec![1u8, 2]);
+        assert!(!rows[0].1.windows(3).any(|w| w == [3u8, 4, 5]));
+
+        // and it loads again from the sealed pairs
+        let db = FedimintStorage::new(storage, encryptor, federation_id, None, Network::Signet)
+            .await
+            .unwrap();
+        assert_eq!(db.loaded_bytes(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_commit_saves_changed_pairs() {
+        let (_tmp_dir, storage) = setup_test_storage();
+
+        let invite = InviteCode::from_str(INVITE_CODE).unwrap();
+        let federation_id = invite.federation_id();
+        let open = || {
+            FedimintStorage::new(
+                storage.clone(),
+                Arc::new(NoEncryption),
+                federation_id,
+                Some(invite.clone()),
+                Network::Signet,
+            )
+        };
+        let saved = || {
+            let mut pairs =
+                load_federation_kv(storage.as_ref(), &NoEncryption, federation_id).unwrap();
+            pairs.sort();
+            pairs
+        };
+
+        let db = open().await.unwrap();
+        let mut tx = db.begin_transaction().await;
+        tx.raw_insert_bytes(&[1, 1], &[1]).await.unwrap();
+        tx.raw_insert_bytes(&[1, 2], &[2]).await.unwrap();
+        tx.raw_insert_bytes(&[2, 1], &[3]).await.unwrap();
+        tx.commit_tx().await.unwrap();
+        assert_eq!(
+            saved(),
+            vec![
+                (vec![1, 1], vec![1]),
+                (vec![1, 2], vec![2]),
+                (vec![2, 1], vec![3])
+            ]
+        );
+
+        // changes rolled back to a savepoint aren't saved, those before it are
+        let mut tx = db.begin_transaction().await;
+        tx.raw_insert_bytes(&[1, 1], &[4]).await.unwrap();
+        tx.set_tx_savepoint().await.unwrap();
+        tx.raw_insert_bytes(&[3, 1], &[5]).await.unwrap();
+        tx.raw_remove_entry(&[1, 2]).await.unwrap();
+        tx.raw_remove_by_prefix(&[2]).await.unwrap();
+        tx.rollback_tx_to_savepoint().await.unwrap();
+        tx.commit_tx().await.unwrap();
+        assert_eq!(
+            saved(),
+            vec![
+                (vec![1, 1], vec![4]),
+                (vec![1, 2], vec![2]),
+                (vec![2, 1], vec![3])
+            ]
+        );
 
-                    break;
-                }
-            }
-        }
-    });
-}
+        let mut tx = db.begin_transaction().await;
+        tx.raw_remove_by_prefix(&[1]).await.unwrap();
+        tx.commit_tx().await.unwrap();
+        assert_eq!(saved(), vec![(vec![2, 1], vec![3])]);
 
-pub(crate) async fn spawn_onchain_receive_subscription(
-    mut sender: Sender<CoreUIMsgPacket>,
-    client: ClientHandleArc,
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    operation_id: OperationId,
-    msg_id: Uuid,
-    subscription: UpdateStreamOrOutcome<DepositStateV2>,
-) {
-    info!(
-        "Spawning onchain receive subscription for operation id: {}",
-        operation_id.fmt_full()
-    );
-    spawn(async move {
-        let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
-            match op_state {
-                DepositStateV2::WaitingForTransaction => {}
-                DepositStateV2::Failed(error) => {
-                    error!("Onchain receive failed: {error}");
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::ReceiveFailed(error),
-                    )
-                    .await;
+        // what was saved is what the next client loads
+        let db = open().await.unwrap();
+        assert_eq!(db.loaded_bytes(), 3);
+    }
 
-                    if let Err(e) =
-                        storage.mark_onchain_receive_as_failed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark onchain receive as failed: {e}");
-                    }
+    #[tokio::test]
+    async fn test_commit_writes_pairs_atomically() {
+        let (tmp_dir, storage) = setup_test_storage();
+        let path = tmp_dir.path().join("harbor.sqlite");
 
-                    break;
-                }
-                DepositStateV2::WaitingForConfirmation {
-                    btc_deposited,
-                    btc_out_point,
-                } => {
-                    info!(
-                        "Onchain receive waiting for confirmation: {btc_deposited} from {btc_out_point:?}"
-                    );
+        let invite = InviteCode::from_str(INVITE_CODE).unwrap();
+        let federation_id = invite.federation_id();
+        let saved = || {
+            let mut pairs =
+                load_federation_kv(storage.as_ref(), &NoEncryption, federation_id).unwrap();
+            pairs.sort();
+            pairs
+        };
 
-                    let recv = storage
-                        .get_onchain_receive(operation_id.fmt_full().to_string())
-                        .ok()
-                        .flatten();
+        let db = FedimintStorage::new(
+            storage.clone(),
+            Arc::new(NoEncryption),
+            federation_id,
+            Some(invite.clone()),
+            Network::Signet,
+        )
+        .await
+        .unwrap();
+        let mut tx = db.begin_transaction().await;
+        tx.raw_insert_bytes(&[1], &[1]).await.unwrap();
+        tx.raw_insert_bytes(&[2], &[2]).await.unwrap();
+        tx.commit_tx().await.unwrap();
 
-                    // only update txid and send notification, if txid isn't already set
-                    // we don't want to do this multiple times
-                    if recv.is_none_or(|r| r.txid().is_none()) {
-                        let txid = btc_out_point.txid;
-                        let params = ReceiveSuccessMsg::Onchain { txid };
-                        HarborCore::send_msg(
-                            &mut sender,
-                            Some(msg_id),
-                            CoreUIMsg::ReceiveSuccess(params),
-                        )
-                        .await;
+        // make saving pairs fail after the deletes went through
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "PRAGMA key = 'password';
+            CREATE TRIGGER fail_kv_insert BEFORE INSERT ON fedimint_kv
+            BEGIN SELECT RAISE(ABORT, 'insert failed'); END;",
+        )
+        .unwrap();
 
-                        let fee_sats = 0; // fees for receives may exist one day
-                        if let Err(e) = storage.set_onchain_receive_txid(
-                            operation_id.fmt_full().to_string(),
-                            txid,
-                            btc_deposited.to_sat(),
-                            fee_sats,
-                        ) {
-                            error!("Could not mark onchain payment txid: {e}");
-                        }
-                    }
+        let mut tx = db.begin_transaction().await;
+        tx.raw_remove_entry(&[1]).await.unwrap();
+        tx.raw_insert_bytes(&[3], &[3]).await.unwrap();
+        assert!(tx.commit_tx().await.is_err());
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
-                }
-                DepositStateV2::Confirmed {
-                    btc_deposited,
-                    btc_out_point,
-                } => {
-                    info!("Onchain receive confirmed: {btc_deposited} from {btc_out_point:?}");
-                }
-                DepositStateV2::Claimed {
-                    btc_deposited,
-                    btc_out_point,
-                } => {
-                    info!("Onchain receive claimed: {btc_deposited} from {btc_out_point:?}");
-                    let new_balance = client.get_balance().await;
-                    HarborCore::send_msg(
-                        &mut sender,
-                        Some(msg_id),
-                        CoreUIMsg::MintBalanceUpdated {
-                            id: MintIdentifier::Fedimint(client.federation_id()),
-                            balance: new_balance,
-                        },
-                    )
-                    .await;
+        // the delete was rolled back with the failed insert
+        assert_eq!(saved(), vec![(vec![1], vec![1]), (vec![2], vec![2])]);
+    }
 
-                    if let Err(e) = storage
-                        .mark_onchain_receive_as_confirmed(operation_id.fmt_full().to_string())
-                    {
-                        error!("Could not mark onchain payment txid: {e}");
-                    }
+    #[test]
+    fn test_reconcile_operation() {
+        let (_tmp_dir, storage) = setup_test_storage();
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+        let invoice = Bolt11Invoice::from_str(INVOICE).unwrap();
+        let receive = |outcome| LoggedOperation {
+            kind: OperationKind::LightningReceive,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::ZERO,
+            outcome,
+        };
 
-                    update_history(storage.clone(), msg_id, &mut sender).await;
+        // a receive stuck as pending that the log says was claimed
+        let stuck = OperationId::new_random();
+        storage
+            .create_ln_receive(
+                stuck.fmt_full().to_string(),
+                Some(federation_id),
+                None,
+                invoice.clone(),
+                Amount::from_sats(1_000),
+                Amount::ZERO,
+            )
+            .unwrap();
+        let repair = |operation_id, operation| {
+            reconcile_operation(
+                &storage,
+                federation_id,
+                operation_id,
+                1_700_000_000,
+                operation,
+            )
+            .unwrap()
+        };
+        assert_eq!(repair(stuck, receive(None)), None);
+        assert_eq!(
+            repair(stuck, receive(Some(LoggedOutcome::Received))),
+            Some(Repair::MarkedSuccess)
+        );
+        let record = storage
+            .get_lightning_receive(stuck.fmt_full().to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.status(), PaymentStatus::Success);
+        assert_eq!(repair(stuck, receive(Some(LoggedOutcome::Received))), None);
 
-                    client
-                        .backup_to_federation(Metadata::empty())
-                        .await
-                        .expect("Could not backup");
+        // a payment we think is pending but was refunded
+        let refunded = OperationId::new_random();
+        storage
+            .create_lightning_payment(
+                refunded.fmt_full().to_string(),
+                Some(federation_id),
+                None,
+                invoice,
+                Amount::from_sats(1_000),
+                Amount::from_sats(1),
+                OperationKind::LightningSend,
+            )
+            .unwrap();
+        let send = |outcome| LoggedOperation {
+            kind: OperationKind::LightningSend,
+            amount: Amount::from_sats(1_000),
+            fee: Amount::from_sats(1),
+            outcome: Some(outcome),
+        };
+        assert_eq!(
+            repair(refunded, send(LoggedOutcome::Refunded)),
+            Some(Repair::MarkedRefunded)
+        );
+        let status = |operation_id: OperationId| {
+            storage
+                .get_lightning_payment(operation_id.fmt_full().to_string())
+                .unwrap()
+                .unwrap()
+                .status()
+        };
+        assert_eq!(status(refunded), PaymentStatus::Refunded);
+        assert_eq!(repair(refunded, send(LoggedOutcome::Refunded)), None);
+        // the refund is final, it isn't turned back into a plain failure
+        assert_eq!(repair(refunded, send(LoggedOutcome::Failed)), None);
+        assert_eq!(status(refunded), PaymentStatus::Refunded);
 
-                    break;
-                }
-            }
-        }
-    });
-}
+        // a successful receive we never recorded is added once, a failed one isn't
+        let missing = OperationId::new_random();
+        assert_eq!(
+            repair(missing, receive(Some(LoggedOutcome::Received))),
+            Some(Repair::Backfilled)
+        );
+        assert_eq!(
+            repair(missing, receive(Some(LoggedOutcome::Received))),
+            None
+        );
+        assert_eq!(
+            repair(
+                OperationId::new_random(),
+                receive(Some(LoggedOutcome::Failed))
+            ),
+            None
+        );
+        // history only shows the successful receives and the refund
+        assert_eq!(storage.get_transaction_history().unwrap().len(), 3);
+    }
 
-#[derive(Clone)]
-pub struct FedimintStorage {
-    storage: Arc<dyn DBConnection + Send + Sync>,
-    fedimint_memory: Arc<MemDatabase>,
-    federation_id: FederationId,
-}
+    #[tokio::test]
+    async fn test_storage_written_while_ui_channel_full() {
+        let (_tmp_dir, storage) = setup_test_storage();
 
-impl FedimintStorage {
-    pub async fn new(
-        storage: Arc<dyn DBConnection + Send + Sync>,
-        federation_id: FederationId,
-        invite_code: Option<InviteCode>,
-    ) -> anyhow::Result<Self> {
-        let fedimint_memory = MemDatabase::new();
+        let operation_id = OperationId::new_random();
+        let invoice = Bolt11Invoice::from_str(INVOICE).unwrap();
+        storage
+            .create_ln_receive(
+                operation_id.fmt_full().to_string(),
+                None,
+                None,
+                invoice,
+                Amount::from_sats(1_000),
+                Amount::ZERO,
+            )
+            .unwrap();
 
-        // get the fedimint data or create a new fedimint entry if it doesn't exist
-        let fedimint_data: Vec<(Vec<u8>, Vec<u8>)> =
-            match storage.get_federation_value(federation_id.to_string())? {
-                Some(v) => {
-                    storage.set_federation_active(federation_id)?;
-                    bincode::deserialize(&v)?
-                }
-                None => {
-                    let invite_code = invite_code.ok_or(anyhow::anyhow!("invite_code missing"))?;
-                    storage.insert_new_federation(NewFedimint {
-                        id: federation_id.to_string(),
-                        value: vec![],
-                        invite_code: invite_code.to_string(),
-                    })?;
-                    vec![]
-                }
-            };
+        // fill the channel so the next send blocks until the UI reads
+        let (mut sender, mut receiver) = mpsc::channel::<CoreUIMsgPacket>(0);
+        sender
+            .try_send(CoreUIMsgPacket {
+                id: None,
+                msg: CoreUIMsg::Sending,
+            })
+            .unwrap();
 
-        // get the value and load it into fedimint memory
-        if !fedimint_data.is_empty() {
-            let mut mem_db_tx = fedimint_memory.begin_transaction().await;
-            for (key, value) in fedimint_data {
-                mem_db_tx.raw_insert_bytes(&key, &value).await?;
-            }
-            mem_db_tx.commit_tx().await?;
-        }
+        let task_storage = storage.clone();
+        let handle = spawn(async move {
+            fail_ln_receive(
+                &task_storage,
+                &mut sender,
+                operation_id,
+                Uuid::new_v4(),
+                "Invoice expired".to_string(),
+            )
+            .await;
+        });
 
-        Ok(Self {
-            storage,
-            federation_id,
-            fedimint_memory: Arc::new(fedimint_memory),
-        })
-    }
-}
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!handle.is_finished());
+        let receive = storage
+            .get_lightning_receive(operation_id.fmt_full().to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(receive.status(), PaymentStatus::Failed);
 
-impl fmt::Debug for FedimintStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("FedimintDB").finish()
+        // draining the channel lets the UI message through
+        receiver.next().await.unwrap();
+        let msg = receiver.next().await.unwrap();
+        assert!(matches!(msg.msg, CoreUIMsg::ReceiveFailed(_)));
+        handle.await.unwrap();
     }
-}
 
-#[async_trait]
-impl IRawDatabase for FedimintStorage {
-    type Transaction<'a> = SQLPseudoTransaction<'a>;
+    fn test_gateway(alias: &str, private: bool, base_msat: u32, ppm: u32) -> LightningGateway {
+        use fedimint_core::util::SafeUrl;
+        use fedimint_ln_common::lightning_invoice::RoutingFees;
 
-    async fn begin_transaction<'a>(&'a self) -> SQLPseudoTransaction {
-        SQLPseudoTransaction {
-            storage: self.storage.clone(),
-            federation_id: self.federation_id.to_string(),
-            mem: self.fedimint_memory.begin_transaction().await,
+        let key = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        LightningGateway {
+            federation_index: 0,
+            gateway_redeem_key: key,
+            node_pub_key: key,
+            lightning_alias: alias.to_string(),
+            api: SafeUrl::parse("https://gateway.example.com").unwrap(),
+            route_hints: vec![],
+            fees: RoutingFees {
+                base_msat,
+                proportional_millionths: ppm,
+            },
+            gateway_id: key,
+            supports_private_payments: private,
         }
     }
 
-    fn checkpoint(&self, _backup_path: &Path) -> anyhow::Result<()> {
-        Ok(())
+    #[test]
+    fn test_effective_fee() {
+        let amount = Amount::from_sats(100_000);
+        assert_eq!(
+            effective_fee(&test_gateway("free", false, 0, 0), amount),
+            Amount::ZERO
+        );
+        assert_eq!(
+            effective_fee(&test_gateway("base", false, 1_000, 0), amount),
+            Amount::from_msats(1_000)
+        );
+        // 0.3% of 100,000,000 msats, which the lightning module's rounding would overstate
+        assert_eq!(
+            effective_fee(&test_gateway("ppm", false, 1_000, 3_000), amount),
+            Amount::from_msats(301_000)
+        );
+        assert_eq!(
+            effective_fee(
+                &test_gateway("huge", false, u32::MAX, u32::MAX),
+                Amount::from_msats(u64::MAX)
+            ),
+            Amount::from_msats(u64::MAX)
+        );
     }
-}
 
-pub struct SQLPseudoTransaction<'a> {
-    pub(crate) storage: Arc<dyn DBConnection + Send + Sync>,
-    federation_id: String,
-    mem: MemTransaction<'a>,
-}
+    #[test]
+    fn test_choose_gateway() {
+        let amount = Amount::from_sats(10_000);
+        let alias = |g: Option<LightningGateway>| g.map(|g| g.lightning_alias);
 
-impl Debug for SQLPseudoTransaction<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("SQLPseudoTransaction")
-            .field("federation_id", &self.federation_id)
-            .field("mem", &self.mem)
-            .finish()
+        // a vetted gateway wins even without private payments
+        let candidates = vec![
+            (false, test_gateway("private", true, 1_000, 100)),
+            (true, test_gateway("vetted", false, 2_000, 100)),
+        ];
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &GatewayRanking::default()
+            )),
+            Some("vetted".to_string())
+        );
+        // unless private payments are required
+        assert_eq!(
+            alias(choose_gateway(
+                candidates,
+                amount,
+                true,
+                &GatewayRanking::default()
+            )),
+            Some("private".to_string())
+        );
+
+        // without any private gateway we fall back when allowed, and fail when required
+        let candidates = vec![(false, test_gateway("public", false, 1_000, 100))];
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &GatewayRanking::default()
+            )),
+            Some("public".to_string())
+        );
+        assert_eq!(
+            alias(choose_gateway(
+                candidates,
+                amount,
+                true,
+                &GatewayRanking::default()
+            )),
+            None
+        );
+        assert!(matches!(
+            no_gateway_error(true).downcast_ref::<SendError>(),
+            Some(SendError::NoPrivateGateway)
+        ));
     }
-}
 
-#[async_trait]
-impl IRawDatabaseTransaction for SQLPseudoTransaction<'_> {
-    async fn commit_tx(mut self) -> anyhow::Result<()> {
-        let key_value_pairs = self
-            .mem
-            .raw_find_by_prefix(&[])
-            .await?
-            .collect::<Vec<(Vec<u8>, Vec<u8>)>>()
-            .await;
-        self.mem.commit_tx().await?;
+    #[test]
+    fn test_choose_gateway_ranks_by_fee() {
+        let alias = |g: Option<LightningGateway>| g.map(|g| g.lightning_alias);
+        let candidates = vec![
+            (false, test_gateway("flat", false, 5_000, 0)),
+            (false, test_gateway("proportional", false, 0, 1_000)),
+            (false, test_gateway("private flat", true, 5_000, 0)),
+        ];
 
-        let serialized_data = bincode::serialize(&key_value_pairs).map_err(anyhow::Error::new)?;
+        // proportional fees are cheaper for small amounts and more expensive for large ones
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                Amount::from_sats(1_000),
+                false,
+                &GatewayRanking::default()
+            )),
+            Some("proportional".to_string())
+        );
+        // on a tie the gateway with private payments is preferred
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                Amount::from_sats(1_000_000),
+                false,
+                &GatewayRanking::default()
+            )),
+            Some("private flat".to_string())
+        );
 
-        self.storage
-            .update_fedimint_data(self.federation_id, serialized_data)
+        // the cheapest vetted gateway is used over cheaper unvetted ones
+        let candidates = vec![
+            (false, test_gateway("cheap", false, 0, 0)),
+            (true, test_gateway("vetted expensive", false, 10_000, 0)),
+            (true, test_gateway("vetted", false, 1_000, 0)),
+        ];
+        assert_eq!(
+            alias(choose_gateway(
+                candidates,
+                Amount::from_sats(1_000),
+                false,
+                &GatewayRanking::default()
+            )),
+            Some("vetted".to_string())
+        );
     }
-}
 
-#[async_trait]
-impl IDatabaseTransactionOpsCore for SQLPseudoTransaction<'_> {
-    async fn raw_insert_bytes(
-        &mut self,
-        key: &[u8],
-        value: &[u8],
-    ) -> anyhow::Result<Option<Vec<u8>>> {
-        self.mem.raw_insert_bytes(key, value).await
+    #[test]
+    fn test_choose_gateway_by_priority() {
+        let amount = Amount::from_sats(10_000);
+        let alias = |g: Option<LightningGateway>| g.map(|g| g.lightning_alias);
+        let with_key = |mut gateway: LightningGateway, key: &str| {
+            gateway.node_pub_key = PublicKey::from_str(key).unwrap();
+            gateway
+        };
+        let cheap = with_key(
+            test_gateway("cheap", false, 0, 0),
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        );
+        let fast = with_key(
+            test_gateway("fast", false, 5_000, 0),
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        );
+        let vetted = with_key(
+            test_gateway("vetted", false, 10_000, 0),
+            "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        );
+        let candidates = vec![
+            (false, cheap.clone()),
+            (false, fast.clone()),
+            (true, vetted),
+        ];
+
+        let stats = HashMap::from([
+            (
+                cheap.node_pub_key,
+                GatewayStats {
+                    successes: 1,
+                    failures: 1,
+                    average_latency: Some(Duration::from_secs(30)),
+                },
+            ),
+            (
+                fast.node_pub_key,
+                GatewayStats {
+                    successes: 4,
+                    failures: 0,
+                    average_latency: Some(Duration::from_secs(2)),
+                },
+            ),
+        ]);
+        let ranking = |priority| GatewayRanking {
+            priority,
+            stats: stats.clone(),
+            ..Default::default()
+        };
+
+        // the default keeps ranking vetted gateways first, then by fee
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &ranking(SelectionPriority::CheapestFee)
+            )),
+            Some("vetted".to_string())
+        );
+        // gateways we have measured come before the vetted one we haven't
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &ranking(SelectionPriority::LowestLatency)
+            )),
+            Some("fast".to_string())
+        );
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &ranking(SelectionPriority::MostReliable)
+            )),
+            Some("fast".to_string())
+        );
+        assert_eq!(
+            alias(choose_gateway(
+                candidates,
+                amount,
+                false,
+                &ranking(SelectionPriority::PreferVetted)
+            )),
+            Some("vetted".to_string())
+        );
     }
 
-    async fn raw_get_bytes(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
-        self.mem.raw_get_bytes(key).await
+    #[test]
+    fn test_fee_rate() {
+        let estimate = 10_000;
+        assert_eq!(FeeRate::Economy.sats_per_kvb(estimate), 10_000);
+        assert_eq!(FeeRate::Normal.sats_per_kvb(estimate), 15_000);
+        assert_eq!(FeeRate::Priority.sats_per_kvb(estimate), 20_000);
+        assert_eq!(FeeRate::SatsPerVByte(25).sats_per_kvb(estimate), 25_000);
+        // never below what the federation accepts
+        assert_eq!(FeeRate::SatsPerVByte(2).sats_per_kvb(estimate), 10_000);
+        // capped so a typo can't burn the balance in fees
+        assert_eq!(
+            FeeRate::SatsPerVByte(u64::MAX).sats_per_kvb(estimate),
+            MAX_FEE_RATE_SATS_PER_VBYTE * 1_000
+        );
     }
 
-    async fn raw_remove_entry(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
-        self.mem.raw_remove_entry(key).await
+    #[test]
+    fn test_choose_restricted_gateway() {
+        let amount = Amount::from_sats(10_000);
+        let alias = |g: Option<LightningGateway>| g.map(|g| g.lightning_alias);
+        let with_id = |mut gateway: LightningGateway, id: &str| {
+            gateway.gateway_id = PublicKey::from_str(id).unwrap();
+            gateway
+        };
+        let cheap = with_id(
+            test_gateway("cheap", false, 0, 100),
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        );
+        let vetted = with_id(
+            test_gateway("vetted", false, 10_000, 0),
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        );
+        let candidates = vec![(false, cheap.clone()), (true, vetted.clone())];
+        let ranking = |priority| GatewayRanking {
+            priority,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &ranking(SelectionPriority::VettedOnly)
+            )),
+            Some("vetted".to_string())
+        );
+        assert_eq!(
+            alias(choose_gateway(
+                vec![(false, cheap.clone())],
+                amount,
+                false,
+                &ranking(SelectionPriority::VettedOnly)
+            )),
+            None
+        );
+        // the manually chosen gateway wins even over a vetted one
+        assert_eq!(
+            alias(choose_gateway(
+                candidates.clone(),
+                amount,
+                false,
+                &ranking(SelectionPriority::Manual(cheap.gateway_id))
+            )),
+            Some("cheap".to_string())
+        );
+        // a manually chosen gateway that isn't available is never swapped for another
+        assert_eq!(
+            alias(choose_gateway(
+                vec![(true, vetted)],
+                amount,
+                false,
+                &ranking(SelectionPriority::Manual(cheap.gateway_id))
+            )),
+            None
+        );
     }
 
-    async fn raw_find_by_prefix(&mut self, key_prefix: &[u8]) -> anyhow::Result<PrefixStream<'_>> {
-        self.mem.raw_find_by_prefix(key_prefix).await
+    #[test]
+    fn test_choose_preferred_gateway() {
+        let amount = Amount::from_sats(10_000);
+        let alias = |g: Option<LightningGateway>| g.map(|g| g.lightning_alias);
+        let mut cheap = test_gateway("cheap", false, 0, 0);
+        cheap.gateway_id = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let mut used = test_gateway("used", false, 5_000, 0);
+        used.gateway_id = PublicKey::from_str(
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        )
+        .unwrap();
+        let candidates = vec![(true, cheap), (false, used.clone())];
+        let ranking = GatewayRanking {
+            preferred: Some(used.gateway_id),
+            ..Default::default()
+        };
+
+        // the gateway used last is kept even though a cheaper vetted one is available
+        assert_eq!(
+            alias(choose_gateway(candidates.clone(), amount, false, &ranking)),
+            Some("used".to_string())
+        );
+        // but not when it isn't allowed anymore
+        let ranking = GatewayRanking {
+            priority: SelectionPriority::VettedOnly,
+            ..ranking
+        };
+        assert_eq!(
+            alias(choose_gateway(candidates, amount, false, &ranking)),
+            Some("cheap".to_string())
+        );
     }
 
-    async fn raw_remove_by_prefix(&mut self, key_prefix: &[u8]) -> anyhow::Result<()> {
-        self.mem.raw_remove_by_prefix(key_prefix).await
+    #[tokio::test]
+    async fn test_join_by_id_needs_invite_code() {
+        let (_tmp_dir, storage) = setup_test_storage();
+        let context = test_client_context(storage, Recoveries::default());
+        let federation_id = FederationId::from_str(FEDERATION_ID).unwrap();
+
+        let result = FedimintClient::new(
+            &context,
+            FederationInviteOrId::Id(federation_id),
+            JoinConfig::Cached,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(FedimintError::NeedInviteCode { federation_id: id }) if id == federation_id
+        ));
     }
 
-    async fn raw_find_by_prefix_sorted_descending(
-        &mut self,
-        key_prefix: &[u8],
-    ) -> anyhow::Result<PrefixStream<'_>> {
-        self.mem
-            .raw_find_by_prefix_sorted_descending(key_prefix)
-            .await
+    #[tokio::test]
+    async fn test_recovering_federation_not_opened() {
+        let (_tmp_dir, storage) = setup_test_storage();
+        let federation_id = FederationId::dummy();
+
+        let recoveries = Recoveries::default();
+        let guard = recoveries.start(federation_id).unwrap();
+        assert!(recoveries.start(federation_id).is_none());
+
+        let context = test_client_context(storage, recoveries.clone());
+        let result = FedimintClient::new(
+            &context,
+            FederationInviteOrId::Id(federation_id),
+            JoinConfig::Cached,
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(FedimintError::Recovering(id)) if id == federation_id
+        ));
+
+        // done once the recovery lets go of it
+        drop(guard);
+        assert!(!recoveries.is_recovering(federation_id));
     }
 
-    async fn raw_find_by_range(&mut self, range: Range<&[u8]>) -> anyhow::Result<PrefixStream<'_>> {
-        self.mem.raw_find_by_range(range).await
+    #[tokio::test]
+    async fn test_subscriptions_aborted_on_drop() {
+        use futures::channel::oneshot;
+
+        let tasks = Arc::new(SubscriptionTasks::new(SubscriptionLimit::default()));
+
+        // the task holds the sender until it is aborted
+        let (tx, rx) = oneshot::channel::<()>();
+        spawn_subscription(&tasks, async move {
+            let _tx = tx;
+            std::future::pending::<()>().await;
+        });
+
+        drop(tasks);
+        assert!(rx.await.is_err());
     }
-}
 
-#[async_trait]
-impl IDatabaseTransactionOps for SQLPseudoTransaction<'_> {
-    async fn rollback_tx_to_savepoint(&mut self) -> anyhow::Result<()> {
-        self.mem.rollback_tx_to_savepoint().await
+    #[tokio::test]
+    async fn test_subscription_limit_applies_to_waiting_updates() {
+        let limit = SubscriptionLimit::new(1);
+        let held = limit.acquire().await;
+
+        // queued behind the held permit until the limit is raised
+        let waiting = spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire().await;
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        limit.set(2);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("waiting update should get a permit")
+            .unwrap();
+
+        // lowering it back leaves only the held permit in use
+        limit.set(1);
+        assert_eq!(limit.permits.available_permits(), 0);
+        drop(held);
+        assert_eq!(limit.permits.available_permits(), 1);
     }
 
-    async fn set_tx_savepoint(&mut self) -> anyhow::Result<()> {
-        self.mem.set_tx_savepoint().await
+    #[tokio::test]
+    async fn test_update_history_failure() {
+        let (tmp_dir, storage) = setup_test_storage();
+        let path = tmp_dir.path().join("harbor.sqlite");
+
+        let (mut sender, mut receiver) = mpsc::channel::<CoreUIMsgPacket>(10);
+        update_history(storage.clone(), Uuid::nil(), &mut sender).await;
+        let msg = receiver.next().await.unwrap();
+        assert!(matches!(
+            msg.msg,
+            CoreUIMsg::TransactionHistoryPage { offset: 0, .. }
+        ));
+
+        // break the history query so every read fails
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("PRAGMA key = 'password'; DROP TABLE imported_history;")
+            .unwrap();
+
+        update_history(storage, Uuid::nil(), &mut sender).await;
+        let msg = receiver.next().await.unwrap();
+        assert!(matches!(msg.msg, CoreUIMsg::HistoryRefreshFailed(_)));
+    }
+
+    #[tokio::test]
+    async fn test_receive_batch_counts_opening_claim() {
+        let (_tmp_dir, storage) = setup_test_storage();
+        storage
+            .set_receive_batch_window(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let invoice = Bolt11Invoice::from_str(INVOICE).unwrap();
+        let operations = [1_000, 2_000, 3_000].map(|sats| {
+            let operation_id = OperationId::new_random();
+            storage
+                .create_ln_receive(
+                    operation_id.fmt_full().to_string(),
+                    None,
+                    None,
+                    invoice.clone(),
+                    Amount::from_sats(sats),
+                    Amount::ZERO,
+                )
+                .unwrap();
+            operation_id
+        });
+
+        let (mut sender, mut receiver) = mpsc::channel::<CoreUIMsgPacket>(100);
+        let receive_batch = ReceiveBatch::default();
+        for operation_id in operations {
+            notify_receive_claimed(
+                &storage,
+                &mut sender,
+                &receive_batch,
+                operation_id,
+                Uuid::nil(),
+                ReceiveSuccessMsg::Lightning,
+            )
+            .await;
+        }
+
+        let summary = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let CoreUIMsg::ReceiveBatch { count, total } = receiver.next().await.unwrap().msg
+                {
+                    return (count, total);
+                }
+            }
+        })
+        .await
+        .expect("No batch summary");
+        assert_eq!(summary, (3, Amount::from_sats(6_000)));
+        assert!(!receive_batch.0.lock().await.open);
     }
 }
@@ -0,0 +1,172 @@
+use fedimint_core::Amount;
+
+const MSATS_PER_SAT: u64 = 1_000;
+const SATS_PER_BTC: u64 = 100_000_000;
+
+/// The unit an amount is shown in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmountUnit {
+    /// Whole sats, sub-sat msats are rounded down like everywhere else we show sats
+    #[default]
+    Sats,
+    /// Exact msats
+    Msats,
+    /// BTC with 8 decimals, rounded down to the sat
+    Btc,
+}
+
+/// How an amount is written out, so it can follow the user's locale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Separator between groups of thousands, `None` to not group digits
+    pub thousands_separator: Option<char>,
+    pub decimal_separator: char,
+    /// Whether to append the unit, like "sats" or "BTC"
+    pub show_unit: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            thousands_separator: Some(','),
+            decimal_separator: '.',
+            show_unit: true,
+        }
+    }
+}
+
+/// Formats an amount for display. All UIs should use this so the same amount is rounded
+/// the same way wherever it is shown.
+pub fn format_amount(amount: Amount, unit: AmountUnit, opts: &FormatOptions) -> String {
+    let (number, value) = match unit {
+        AmountUnit::Sats => {
+            let sats = amount.msats / MSATS_PER_SAT;
+            (group_thousands(sats, opts.thousands_separator), sats)
+        }
+        AmountUnit::Msats => (
+            group_thousands(amount.msats, opts.thousands_separator),
+            amount.msats,
+        ),
+        AmountUnit::Btc => {
+            let sats = amount.msats / MSATS_PER_SAT;
+            let whole = group_thousands(sats / SATS_PER_BTC, opts.thousands_separator);
+            let fraction = sats % SATS_PER_BTC;
+            (
+                format!("{whole}{}{fraction:08}", opts.decimal_separator),
+                sats,
+            )
+        }
+    };
+
+    if !opts.show_unit {
+        return number;
+    }
+
+    let unit = match (unit, value) {
+        (AmountUnit::Sats, 1) => "sat",
+        (AmountUnit::Sats, _) => "sats",
+        (AmountUnit::Msats, 1) => "msat",
+        (AmountUnit::Msats, _) => "msats",
+        (AmountUnit::Btc, _) => "BTC",
+    };
+    format!("{number} {unit}")
+}
+
+fn group_thousands(value: u64, separator: Option<char>) -> String {
+    let digits = value.to_string();
+    let Some(separator) = separator else {
+        return digits;
+    };
+
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_sats() {
+        let opts = FormatOptions::default();
+        assert_eq!(
+            format_amount(Amount::ZERO, AmountUnit::Sats, &opts),
+            "0 sats"
+        );
+        assert_eq!(
+            format_amount(Amount::from_sats(1), AmountUnit::Sats, &opts),
+            "1 sat"
+        );
+        assert_eq!(
+            format_amount(Amount::from_sats(1_234_567), AmountUnit::Sats, &opts),
+            "1,234,567 sats"
+        );
+
+        let opts = FormatOptions {
+            thousands_separator: Some('.'),
+            decimal_separator: ',',
+            show_unit: false,
+        };
+        assert_eq!(
+            format_amount(Amount::from_sats(1_234_567), AmountUnit::Sats, &opts),
+            "1.234.567"
+        );
+    }
+
+    #[test]
+    fn test_format_sub_sat_amounts() {
+        let opts = FormatOptions::default();
+        // msats that don't make a whole sat are rounded down, never up
+        let amount = Amount::from_msats(1_999);
+        assert_eq!(format_amount(amount, AmountUnit::Sats, &opts), "1 sat");
+        assert_eq!(
+            format_amount(amount, AmountUnit::Msats, &opts),
+            "1,999 msats"
+        );
+        assert_eq!(
+            format_amount(amount, AmountUnit::Btc, &opts),
+            "0.00000001 BTC"
+        );
+
+        let amount = Amount::from_msats(999);
+        assert_eq!(format_amount(amount, AmountUnit::Sats, &opts), "0 sats");
+        assert_eq!(format_amount(amount, AmountUnit::Msats, &opts), "999 msats");
+    }
+
+    #[test]
+    fn test_format_btc() {
+        let opts = FormatOptions::default();
+        assert_eq!(
+            format_amount(Amount::from_sats(123_456_789), AmountUnit::Btc, &opts),
+            "1.23456789 BTC"
+        );
+        assert_eq!(
+            format_amount(
+                Amount::from_sats(1_000 * SATS_PER_BTC),
+                AmountUnit::Btc,
+                &opts
+            ),
+            "1,000.00000000 BTC"
+        );
+
+        let opts = FormatOptions {
+            thousands_separator: None,
+            decimal_separator: ',',
+            show_unit: true,
+        };
+        assert_eq!(
+            format_amount(
+                Amount::from_sats(1_000 * SATS_PER_BTC),
+                AmountUnit::Btc,
+                &opts
+            ),
+            "1000,00000000 BTC"
+        );
+    }
+}
@@ -2,39 +2,69 @@ use crate::cashu_client::{
     TorMintConnector, spawn_lightning_payment_thread, spawn_lightning_receive_thread,
 };
 use crate::db::DBConnection;
-use crate::db_models::MintItem;
-use crate::db_models::transaction_item::TransactionItem;
+use crate::db_models::mint_metadata::MintMetadata;
+use crate::db_models::transaction_item::{PaymentRoute, TransactionItem};
+use crate::db_models::{
+    FederationInfo, JoinFailure, MintItem, ModuleInfo, NewFedimint, OperationKind, PaymentStatus,
+    PrunePolicy,
+};
 use crate::fedimint_client::{
-    FederationInviteOrId, FedimintClient, select_gateway, spawn_internal_payment_subscription,
-    spawn_invoice_payment_subscription, spawn_invoice_receive_subscription,
-    spawn_onchain_payment_subscription, spawn_onchain_receive_subscription,
+    ClientContext, FederationInviteOrId, FederationStatus, FedimintClient, FedimintError, FeeRate,
+    GatewayRanking, JoinResult, OpLogEntry, ReconcileSummary, Recoveries, SeedEncryptor,
+    SelectionPriority, SubscriptionLimit, download_config, download_with_retry,
+    drive_invoice_payment, drive_lnv2_payment, estimate_lightning_fee, gateway_can_route,
+    invite_code_from_config, load_federation_kv, operation_log_entries, reconcile_history,
+    seal_federation_data, select_gateway, select_gateway_within_fee, send_history_page,
+    spawn_ecash_reissue_subscription, spawn_ecash_spend_subscription,
+    spawn_internal_payment_subscription, spawn_invoice_payment_subscription,
+    spawn_invoice_receive_subscription, spawn_onchain_payment_subscription,
+    spawn_onchain_receive_subscription, subscription_span, update_history,
+};
+#[cfg(feature = "debug-dump")]
+use crate::fedimint_client::{federation_kv_hex, open_federation_data};
+use crate::metadata::{
+    CACHE, FederationData, FederationMeta, get_federation_metadata, try_get_federation_metadata,
 };
-use crate::metadata::{CACHE, FederationData, FederationMeta, get_federation_metadata};
+use crate::price::{FiatAmount, get_btc_price};
 use ::fedimint_client::ClientHandleArc;
 use anyhow::anyhow;
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
 use bip39::Mnemonic;
 use bitcoin::address::NetworkUnchecked;
+use bitcoin::hashes::{Hash, sha256};
 use bitcoin::{Address, Network, Txid};
 use cdk::cdk_database::WalletDatabase;
 use cdk::mint_url::MintUrl;
-use cdk::nuts::{CurrencyUnit, MintInfo};
+use cdk::nuts::{CurrencyUnit, MeltOptions, MintInfo};
 use cdk::wallet::WalletBuilder;
 use cdk_redb::WalletRedbDatabase;
-use fedimint_client::{spawn_lnv2_payment_subscription, spawn_lnv2_receive_subscription};
+use fedimint_api_client::api::IGlobalFederationApi;
+use fedimint_client::{
+    JoinConfig, ReceiveBatch, ReconnectPolicy, RootSecretProvider, init_all_federations,
+    spawn_lnv2_payment_subscription, spawn_lnv2_receive_subscription,
+};
 use fedimint_core::Amount;
 use fedimint_core::config::{ClientConfig, FederationId};
 use fedimint_core::core::{ModuleKind, OperationId};
 use fedimint_core::invite_code::InviteCode;
+use fedimint_core::module::ApiVersion;
+use fedimint_core::secp256k1::PublicKey;
 use fedimint_ln_client::{LightningClientModule, PayType};
+use fedimint_ln_common::LightningGateway;
 use fedimint_ln_common::config::FeeToAmount;
 use fedimint_ln_common::lightning_invoice::{Bolt11InvoiceDescription, Description};
-use fedimint_wallet_client::WalletClientModule;
+use fedimint_mint_client::{MintClientModule, OOBNotes};
+use fedimint_wallet_client::{PegOutFees, WalletClientModule};
 use futures::{SinkExt, channel::mpsc::Sender};
-use lightning_address::make_lnurl_request;
+use lightning_address::{
+    make_withdraw_request, request_lnurl_invoice, submit_withdraw_invoice, withdraw_amount,
+};
 use lnurl::lnurl::LnUrl;
 use log::{error, trace};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -42,8 +72,10 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 use tokio::spawn;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tracing::Instrument;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 /// The directory where all application data is stored
 /// Defaults to ~/.harbor as the root directory
@@ -69,15 +101,19 @@ pub mod cashu_client;
 pub mod db;
 pub mod db_models;
 pub mod fedimint_client;
+pub mod format;
 mod http;
 pub mod lightning_address;
 pub mod metadata;
+pub mod price;
 
 pub use bip39;
 pub use bitcoin;
 pub use cdk;
 pub use cdk_redb;
 pub use fedimint_core;
+pub use fedimint_derive_secret;
+pub use zeroize;
 
 pub use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
 
@@ -114,6 +150,10 @@ pub enum UICoreMsg {
     SendLightning {
         mint: MintIdentifier,
         invoice: Bolt11Invoice,
+        /// Required for invoices without an amount, otherwise must match the invoice
+        amount: Option<Amount>,
+        /// Overrides the profile's max send fee for this payment
+        fee_limit: Option<Amount>,
     },
     SendLnurlPay {
         mint: MintIdentifier,
@@ -124,14 +164,29 @@ pub enum UICoreMsg {
         mint: MintIdentifier,
         amount: Amount,
     },
+    /// Pulls as much as the LNURL-withdraw service allows into the mint
+    ReceiveLnurlWithdraw {
+        mint: MintIdentifier,
+        lnurl: LnUrl,
+    },
     SendOnChain {
         mint: MintIdentifier,
         address: Address<NetworkUnchecked>,
         amount_sats: Option<u64>,
+        fee_rate: FeeRate,
+    },
+    /// Previews the fee of an onchain send without starting it, `None` sends everything
+    EstimateWithdrawFee {
+        federation_id: FederationId,
+        address: Address<NetworkUnchecked>,
+        amount_sats: Option<u64>,
+        fee_rate: FeeRate,
     },
     ReceiveOnChain {
         mint: MintIdentifier,
     },
+    /// Claims encoded ecash notes into the federation they are from
+    ReceiveEcash(String),
     Transfer {
         to: MintIdentifier,
         from: MintIdentifier,
@@ -140,8 +195,12 @@ pub enum UICoreMsg {
     GetFederationInfo(InviteCode),
     GetCashuMintInfo(MintUrl),
     AddFederation(InviteCode),
+    /// Restores our ecash in the federation from the seed, when its data was lost
+    RecoverFederation(InviteCode),
     AddCashuMint(MintUrl),
     RemoveMint(MintIdentifier),
+    /// Leaves a federation for good, deleting its data. Refused while it holds funds.
+    LeaveFederation(FederationId),
     RejoinMint(MintIdentifier),
     FederationListNeedsUpdate,
     Unlock(String),
@@ -150,23 +209,301 @@ pub enum UICoreMsg {
         seed: Option<Mnemonic>,
     },
     GetSeedWords,
+    GetBalanceBreakdown(MintIdentifier),
+    GetSnapshot,
+    /// Balances of every federation and their total
+    GetAggregateBalance,
+    /// Value of every mint's balance together in the currency, like "USD"
+    GetTotalFiatValue(String),
+    EstimateLightningFee {
+        federation_id: FederationId,
+        amount: Amount,
+    },
+    RefreshBalance(FederationId),
+    SelfTest(FederationId),
+    PingFederation(FederationId),
+    ReconcileHistory(FederationId),
+    GetInviteCode(FederationId),
+    RefreshFederationMetadata(FederationId),
+    RefreshHistory,
+    /// Asks for the page of the transaction history starting at the offset
+    GetTransactionHistoryPage(usize),
     SetOnchainReceiveEnabled(bool),
     SetTorEnabled(bool),
+    /// Whether the app is in the background, background probes of the federations are
+    /// paused while it is
+    SetBackgrounded(bool),
     TestStatusUpdates,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SendSuccessMsg {
-    Lightning { preimage: [u8; 32] },
-    Onchain { txid: Txid },
+    Lightning {
+        /// `None` when the payment's module doesn't report it, like for LNv2 payments
+        preimage: Option<[u8; 32]>,
+    },
+    Onchain {
+        txid: Txid,
+    },
     Transfer,
+    /// Spent ecash notes were redeemed by the recipient
+    Ecash,
+}
+
+/// Why downloading a federation's config for joining it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddFederationFailure {
+    /// The guardians didn't answer in time, joining later may work
+    Timeout,
+    /// The guardians couldn't be reached, joining later may work
+    Unreachable,
+    /// The guardians answered but their config can't be used
+    ConfigInvalid,
+}
+
+impl AddFederationFailure {
+    /// The failure a join error stands for, `None` for errors that aren't about the config
+    pub fn from_error(e: &FedimintError) -> Option<Self> {
+        match e {
+            FedimintError::JoinTimeout(_) => Some(AddFederationFailure::Timeout),
+            FedimintError::DownloadFailed(_) => Some(AddFederationFailure::Unreachable),
+            FedimintError::ConfigInvalid(_) | FedimintError::NoMintModule => {
+                Some(AddFederationFailure::ConfigInvalid)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Why a payment did not succeed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    /// The payment was canceled
+    Canceled,
+    /// The payment failed with the given reason
+    Failed(String),
+    /// The payment stopped reporting updates before reaching a final state
+    Incomplete,
+    /// The payment failed and what it locked up is back in our balance
+    Refunded { amount: Amount },
+    /// The mint's balance can't cover the payment, so it was never started
+    InsufficientFunds { needed: Amount, available: Amount },
+    /// The federation has a spend password and hasn't been unlocked
+    Locked,
+    /// Every gateway charges more than the fee limit, so the payment was never started
+    FeeExceedsLimit {
+        best_available_fee: Amount,
+        limit: Amount,
+    },
+    /// Private payments are required and no gateway supports them
+    NoPrivateGateway,
+    /// No gateway could be selected even after refreshing the gateway cache
+    StaleGateways,
+    /// The gateway chosen with [`SelectionPriority::Manual`] can't route the payment
+    GatewayUnavailable(PublicKey),
+    /// We already paid this invoice, so it was not paid again
+    AlreadyPaid {
+        operation_id: String,
+        /// Unix timestamp of the earlier payment
+        paid_at: u64,
+    },
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Canceled => write!(f, "Canceled"),
+            SendError::Failed(reason) => write!(f, "{reason}"),
+            SendError::Incomplete => write!(f, "Payment did not complete"),
+            SendError::Refunded { amount } => write!(
+                f,
+                "Payment failed and {} sats were refunded",
+                amount.sats_round_down()
+            ),
+            SendError::InsufficientFunds { needed, available } => write!(
+                f,
+                "Insufficient balance: Cannot pay {} sats, current balance is only {} sats",
+                needed.sats_round_down(),
+                available.sats_round_down()
+            ),
+            SendError::Locked => write!(f, "Unlock the mint to send from it"),
+            SendError::FeeExceedsLimit {
+                best_available_fee,
+                limit,
+            } => write!(
+                f,
+                "Fee of {} sats is above your limit of {} sats",
+                best_available_fee.sats_round_down(),
+                limit.sats_round_down()
+            ),
+            SendError::NoPrivateGateway => {
+                write!(f, "No gateway that supports private payments is available")
+            }
+            SendError::StaleGateways => write!(
+                f,
+                "Could not reach any lightning gateway, the federation's gateway list may be out of date"
+            ),
+            SendError::GatewayUnavailable(id) => write!(
+                f,
+                "The chosen gateway {id} is not available, choose another or let one be picked"
+            ),
+            SendError::AlreadyPaid { paid_at, .. } => {
+                match chrono::DateTime::from_timestamp(*paid_at as i64, 0) {
+                    Some(time) => write!(
+                        f,
+                        "You already paid this invoice at {}",
+                        time.format("%Y-%m-%d %H:%M UTC")
+                    ),
+                    None => write!(f, "You already paid this invoice"),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// A mint's balance split into settled ecash and payments that are still in flight
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BalanceBreakdown {
+    /// Ecash we hold, this is what can be spent right now
+    pub ecash: Amount,
+    /// Receives we are waiting on
+    pub pending_incoming: Amount,
+    /// Sends that have not completed yet, including fees
+    pub pending_outgoing: Amount,
+}
+
+/// How a mint's balance is adjusted before it is shown, for a cleaner figure. The raw
+/// balance is sent alongside it as that is what can actually be spent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DisplayBalancePolicy {
+    /// Add receives we are still waiting on to the balance
+    pub include_pending: bool,
+    /// Ecash balances below this are shown as zero
+    pub dust_threshold: Amount,
+}
+
+impl Default for DisplayBalancePolicy {
+    /// Shows the ecash balance as is
+    fn default() -> Self {
+        Self {
+            include_pending: false,
+            dust_threshold: Amount::ZERO,
+        }
+    }
+}
+
+impl DisplayBalancePolicy {
+    /// The balance to show for the `ecash` we hold, with `pending_incoming` still on its way
+    pub fn apply(&self, ecash: Amount, pending_incoming: Amount) -> Amount {
+        let ecash = if ecash < self.dust_threshold {
+            Amount::ZERO
+        } else {
+            ecash
+        };
+        if self.include_pending {
+            ecash + pending_incoming
+        } else {
+            ecash
+        }
+    }
+}
+
+/// Current balances and recent history in one message, so a new subscriber starts from a
+/// consistent state instead of waiting for the next update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletSnapshot {
+    /// Balance of every loaded mint, federations still loading only have a cached balance
+    /// in the mint list
+    pub balances: HashMap<MintIdentifier, Amount>,
+    /// The balances to show, adjusted by the user's [`DisplayBalancePolicy`]
+    pub display_balances: HashMap<MintIdentifier, Amount>,
+    /// The most recent [`SNAPSHOT_HISTORY_LIMIT`] transactions, newest first
+    pub recent_history: Vec<TransactionItem>,
+}
+
+/// A federation's balance as part of an [`AggregateBalance`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FederationBalance {
+    pub balance: Amount,
+    /// The federation is offline or didn't load, so this may not be its current balance
+    pub stale: bool,
+}
+
+/// Balances of every joined federation and their total
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AggregateBalance {
+    pub federations: HashMap<FederationId, FederationBalance>,
+    /// Sum of every federation's balance, stale ones included
+    pub total: Amount,
+}
+
+impl AggregateBalance {
+    pub fn new(federations: HashMap<FederationId, FederationBalance>) -> Self {
+        let total = federations.values().map(|f| f.balance).sum();
+        Self { federations, total }
+    }
+}
+
+/// The outcome of one check run by [`HarborCore::self_test`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Diagnostics for a joined federation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub federation_id: FederationId,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed)
+    }
+}
+
+/// What a string of ecash notes is worth, checked without claiming it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EcashInfo {
+    pub federation_id: FederationId,
+    pub amount: Amount,
+    /// Whether the notes are signed by the federation. Notes that were already spent
+    /// still look valid, that is only known once they are claimed.
+    pub valid: bool,
+}
+
+/// Format version of federation backups, bumped whenever the bundle changes
+pub const FEDERATION_BACKUP_VERSION: u32 = 1;
+
+/// A federation's config, metadata and client state, for moving it to another device
+/// without downloading the config or recovering notes again.
+/// The client state is only usable with the same seed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FederationBackup {
+    version: u32,
+    network: String,
+    federation_id: FederationId,
+    invite_code: String,
+    /// The federation's `ClientConfig` as JSON
+    config: String,
+    metadata: Option<FederationMeta>,
+    value: Vec<u8>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ReceiveSuccessMsg {
     Lightning,
-    Onchain { txid: Txid },
+    Onchain {
+        txid: Txid,
+    },
     Transfer,
+    /// Ecash notes we were given were reissued into our balance
+    Ecash,
 }
 
 #[derive(Debug, Clone)]
@@ -178,20 +515,118 @@ pub struct CoreUIMsgPacket {
 #[derive(Debug, Clone)]
 pub enum CoreUIMsg {
     Sending,
+    /// A lightning payment moved to a state worth showing while it is in flight
+    SendProgress {
+        msg_id: Uuid,
+        state: String,
+    },
     SendSuccess(SendSuccessMsg),
     SendFailure(String),
+    /// The same payment was already started within [`PAYMENT_IDEMPOTENCY_WINDOW`], its
+    /// outcome is reported to the request that started it
+    PaymentAlreadyStarted {
+        operation_id: String,
+    },
     ReceiveGenerating,
+    /// A lightning receive moved to a state worth showing while it is being funded
+    ReceiveProgress {
+        msg_id: Uuid,
+        state: String,
+    },
     ReceiveInvoiceGenerated(Bolt11Invoice),
     ReceiveAddressGenerated(Address),
+    /// A joined federation could not be loaded, it is retried in the background
+    FederationUnavailable(FederationId),
+    /// A federation that was unavailable has been loaded
+    FederationRecovered(FederationId),
+    /// Whether a loaded federation can currently be reached, sent when this changes
+    FederationConnectivity {
+        id: FederationId,
+        online: bool,
+    },
+    /// Which of a federation's guardians answered the latest status probe, sent every
+    /// federation status interval while the app is in the foreground
+    FederationStatusUpdated(FederationId, FederationStatus),
+    /// An onchain deposit was seen by the federation but isn't confirmed yet
+    DepositSeen {
+        txid: Txid,
+        amount: Amount,
+    },
+    /// An onchain deposit is smaller than the federation's minimum, so it will never be credited
+    DepositBelowMinimum {
+        received: Amount,
+        minimum: Amount,
+    },
     ReceiveSuccess(ReceiveSuccessMsg),
+    /// A lightning payment failed and what it locked up is back in our balance
+    PaymentRefunded {
+        operation_id: OperationId,
+        amount: Amount,
+    },
+    /// Ecash we spent wasn't redeemed in time, so it was reclaimed into our balance
+    EcashReclaimed {
+        federation_id: FederationId,
+        amount: Amount,
+    },
+    /// Receives claimed while a receive batch window was open, summarized once it closes
+    ReceiveBatch {
+        count: usize,
+        total: Amount,
+    },
+    /// A receive was paid a different amount than was requested
+    ReceiveAmountMismatch {
+        requested: Amount,
+        received: Amount,
+    },
     ReceiveFailed(String),
     TransferFailure(String),
-    TransactionHistoryUpdated(Vec<TransactionItem>),
+    /// A page of the transaction history, newest first. The first page is sent again
+    /// whenever the history changes.
+    TransactionHistoryPage {
+        items: Vec<TransactionItem>,
+        /// How many transactions there are in total
+        total: usize,
+        /// Position of the first item in the whole history
+        offset: usize,
+    },
+    /// The transaction history couldn't be read, the UI still shows the previous history
+    HistoryRefreshFailed(String),
     MintBalanceUpdated {
         id: MintIdentifier,
+        /// What can be spent, sends should be checked against this
         balance: Amount,
+        /// The balance to show, adjusted by the user's [`DisplayBalancePolicy`]
+        display_balance: Amount,
+    },
+    BalanceBreakdownUpdated {
+        id: MintIdentifier,
+        breakdown: BalanceBreakdown,
+    },
+    Snapshot(WalletSnapshot),
+    AggregateBalanceUpdated(AggregateBalance),
+    /// Value of every mint's balance together, `None` with the reason if it couldn't be priced.
+    /// The reason is also given when some mints had to be left out of the value.
+    TotalFiatUpdated {
+        currency: String,
+        value: Option<FiatAmount>,
+        reason: Option<String>,
+    },
+    /// The fee a lightning payment of the amount would pay, `None` if no gateway can route it
+    LightningFeeEstimate {
+        amount: Amount,
+        fee: Option<Amount>,
+    },
+    /// What an onchain send would send and pay in fees, nothing has been sent yet
+    WithdrawFeeEstimate {
+        amount: Amount,
+        fee: Amount,
     },
     AddMintFailed(String),
+    /// Joining a federation failed, with whether it was worth trying again
+    AddFederationFailed {
+        reason: AddFederationFailure,
+        message: String,
+    },
     RemoveFederationFailed(String),
     MintInfo {
         id: MintIdentifier,
@@ -200,7 +635,24 @@ pub enum CoreUIMsg {
     },
     AddMintSuccess(MintIdentifier),
     RemoveFederationSuccess,
+    /// The federation was left and its data deleted, see [`HarborCore::leave_federation`]
+    FederationLeft(FederationId),
     FederationListNeedsUpdate,
+    /// Joining a federation needs its invite code, which we don't have.
+    /// The UI should ask the user to paste it.
+    NeedInviteCode {
+        id: FederationId,
+    },
+    /// A federation's metadata was fetched again, the mint list should be reloaded to show it
+    FederationMetadataUpdated {
+        id: FederationId,
+        metadata: FederationMeta,
+    },
+    /// A federation's metadata couldn't be fetched again, what we had is kept
+    FederationMetadataRefreshFailed {
+        id: FederationId,
+        reason: String,
+    },
     MintListUpdated(Vec<MintItem>),
     NeedsInit,
     Initing,
@@ -211,6 +663,8 @@ pub enum CoreUIMsg {
     UnlockSuccess,
     UnlockFailed(String),
     SeedWords(String),
+    /// The seed words could not be shown, e.g. because a federation is still locked
+    SeedWordsFailed(String),
     OnchainReceiveEnabled(bool),
     TorEnabled(bool),
     InitialProfile {
@@ -222,13 +676,459 @@ pub enum CoreUIMsg {
         message: String,
         operation_id: Option<Uuid>,
     },
+    SelfTestResult(SelfTestReport),
+    HistoryReconciled(ReconcileSummary),
+    FederationInviteCode {
+        id: FederationId,
+        invite_code: Option<InviteCode>,
+    },
+    /// An onchain withdrawal hasn't completed within [`WITHDRAW_STUCK_THRESHOLD`]
+    WithdrawStuck {
+        operation_id: OperationId,
+    },
+    /// How far restoring a federation's ecash from the seed has come
+    RecoveryProgress {
+        id: FederationId,
+        percent: u8,
+    },
+    /// A federation's saved data is larger than the memory cap, it should be consolidated
+    StorageWarning {
+        id: FederationId,
+        loaded_bytes: usize,
+        cap: usize,
+    },
+    /// A module of a federation could not be initialized, so what it does is unavailable.
+    /// The rest of the federation, at least its ecash, still works.
+    ModuleUnavailable {
+        id: FederationId,
+        kind: ModuleKind,
+        reason: String,
+    },
+    /// A payment's updates stopped coming and the operation log has no outcome for it yet
+    PaymentStalled {
+        operation_id: OperationId,
+    },
+    /// A federation's payments now go through a different gateway than before, so their
+    /// fees may change
+    GatewayChanged {
+        federation_id: FederationId,
+        previous: PublicKey,
+        gateway: PublicKey,
+    },
+    /// A payment was refused because every gateway charges more than the fee limit,
+    /// it can be sent again with a higher limit
+    FeeExceedsLimit {
+        best_available_fee: Amount,
+        limit: Amount,
+    },
+}
+
+/// How long a repeated payment request is treated as a duplicate of the first,
+/// this guards against double clicks creating two payments.
+pub const PAYMENT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Locks held while starting a payment, one per idempotency key, so duplicate requests
+/// can't race the idempotency check while unrelated payments go ahead
+#[derive(Clone, Default)]
+struct PaymentLocks(Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>);
+
+impl PaymentLocks {
+    async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.0.lock().await;
+            // forget the locks nobody holds or waits on
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(key.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Identifies a payment request by where it is paid from, where it goes and how much,
+/// `None` meaning the entire balance.
+fn payment_idempotency_key(
+    mint: &MintIdentifier,
+    destination: &str,
+    amount_msats: Option<u64>,
+) -> String {
+    let amount = amount_msats.map_or("max".to_string(), |a| a.to_string());
+    let preimage = format!("{mint:?}:{destination}:{amount}");
+    sha256::Hash::hash(preimage.as_bytes()).to_string()
+}
+
+/// Works out how much to pay for an invoice. Invoices without an amount need one given,
+/// for invoices with an amount the given one has to match.
+fn invoice_pay_amount(
+    invoice_amount: Option<Amount>,
+    amount: Option<Amount>,
+) -> anyhow::Result<Amount> {
+    match (invoice_amount, amount) {
+        (Some(invoice_amount), None) => Ok(invoice_amount),
+        (Some(invoice_amount), Some(amount)) if invoice_amount == amount => Ok(amount),
+        (Some(invoice_amount), Some(amount)) => Err(anyhow!(
+            "Amount {} sats does not match the invoice amount of {} sats",
+            amount.sats_round_down(),
+            invoice_amount.sats_round_down()
+        )),
+        (None, Some(amount)) if amount > Amount::ZERO => Ok(amount),
+        (None, _) => Err(anyhow!(
+            "An amount is required to pay an invoice without one"
+        )),
+    }
+}
+
+/// Works out how much a withdrawal sends and the fees it pays at the fee rate.
+/// With no amount the entire balance is sent, less the fees.
+async fn withdraw_amount(
+    client: &FedimintClient,
+    address: &Address,
+    sats: Option<u64>,
+    fee_rate: FeeRate,
+) -> anyhow::Result<(bitcoin::Amount, PegOutFees)> {
+    match sats {
+        Some(sats) => {
+            let amount = bitcoin::Amount::from_sat(sats);
+            let fees = client
+                .estimate_withdraw_fee(address, amount, fee_rate)
+                .await?;
+            Ok((amount, fees))
+        }
+        None => {
+            let balance = client.fedimint_client.get_balance().await;
+
+            if balance.sats_round_down() == 0 {
+                return Err(anyhow!("No funds in wallet"));
+            }
+
+            // get fees for the entire balance
+            let fees = client
+                .estimate_withdraw_fee(
+                    address,
+                    bitcoin::Amount::from_sat(balance.sats_round_down()),
+                    fee_rate,
+                )
+                .await?;
+
+            let fees_paid = Amount::from_sats(fees.amount().to_sat());
+            let amount = balance.saturating_sub(fees_paid);
+
+            if amount.sats_round_down() < 546 {
+                return Err(anyhow!("Not enough funds to send"));
+            }
+
+            Ok((bitcoin::Amount::from_sat(amount.sats_round_down()), fees))
+        }
+    }
+}
+
+/// How far a received amount can be off from the requested one before we warn about it,
+/// small differences come from rounding between sats and msats.
+pub const RECEIVE_AMOUNT_TOLERANCE: Amount = Amount { msats: 1_000 };
+
+/// Whether a receive was paid an amount different enough from the request to warn about
+pub(crate) fn receive_amount_mismatch(requested: Amount, received: Amount) -> bool {
+    let difference = if received > requested {
+        received - requested
+    } else {
+        requested - received
+    };
+    difference > RECEIVE_AMOUNT_TOLERANCE
+}
+
+/// Saves what a lightning receive was actually paid when it differs from the request, and
+/// warns the UI with [`CoreUIMsg::ReceiveAmountMismatch`] when it's more than rounding
+pub(crate) async fn check_received_amount(
+    storage: &Arc<dyn DBConnection + Send + Sync>,
+    sender: &mut Sender<CoreUIMsgPacket>,
+    msg_id: Uuid,
+    operation_id: String,
+    requested: Amount,
+    received: Amount,
+) {
+    if received != requested {
+        if let Err(e) = storage.set_ln_receive_amount(operation_id, received) {
+            error!("Could not update lightning receive amount: {e}");
+        }
+    }
+    if receive_amount_mismatch(requested, received) {
+        log::warn!("Requested {requested} but received {received}");
+        HarborCore::send_msg(
+            sender,
+            Some(msg_id),
+            CoreUIMsg::ReceiveAmountMismatch {
+                requested,
+                received,
+            },
+        )
+        .await;
+    }
+}
+
+/// How long an onchain withdrawal can go without completing before we tell the UI it's stuck.
+pub const WITHDRAW_STUCK_THRESHOLD: Duration = Duration::from_secs(60 * 60);
+
+/// How long a payment subscription waits for an update before checking the operation log
+/// itself, unless configured otherwise in the profile.
+pub const DEFAULT_UPDATE_STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long downloading a federation's config may take when joining, unless configured
+/// otherwise in the profile.
+pub const DEFAULT_JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many more times downloading a federation's config is tried after timing out
+pub const JOIN_DOWNLOAD_RETRIES: u32 = 3;
+
+/// A deposit address that hasn't received anything is handed out again for this long,
+/// instead of allocating a new one for every request.
+pub const ONCHAIN_ADDRESS_REUSE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Lists joined federations using only what is saved in storage, with their last saved
+/// balance. Used to show something on startup while the clients are still loading.
+pub fn cached_mint_items(storage: &dyn DBConnection) -> anyhow::Result<Vec<MintItem>> {
+    let mut items = vec![];
+    for id in storage.list_federations()? {
+        let id = FederationId::from_str(&id)?;
+        let metadata = storage.get_federation_metadata(id)?.unwrap_or_default();
+        let balance = storage
+            .get_federation_balance(id)?
+            .unwrap_or(Amount::ZERO)
+            .sats_round_down();
+        items.push(MintItem {
+            id: MintIdentifier::Fedimint(id),
+            name: metadata
+                .federation_name
+                .clone()
+                .unwrap_or("Unknown".to_string()),
+            balance,
+            display_balance: balance,
+            guardians: None,
+            modules: None,
+            metadata,
+            on_chain_supported: false,
+            active: true,
+            balance_cached: true,
+        });
+    }
+    Ok(items)
+}
+
+/// How many federation clients are built at the same time on startup.
+pub const FEDERATION_INIT_CONCURRENCY: usize = 4;
+
+/// How many payment subscriptions run at once unless set otherwise in the profile.
+/// Subscriptions mostly wait on the federation, the limit guards against bursts.
+pub const DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS: usize = 64;
+
+/// How much federation data can be loaded into memory before the user is warned to
+/// consolidate, unless set otherwise in the profile.
+pub const DEFAULT_FEDERATION_MEMORY_CAP: usize = 64 * 1024 * 1024;
+
+/// How long to wait between attempts to load federations that failed on startup.
+pub const FEDERATION_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often a federation's lightning gateways are refreshed while it can be reached.
+pub const GATEWAY_CACHE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long each self test check can take before it is counted as failed.
+pub const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a federation has to answer a ping before it is considered offline.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often each federation's guardians are probed for [`CoreUIMsg::FederationStatusUpdated`],
+/// unless set otherwise in the profile.
+pub const DEFAULT_FEDERATION_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Federations' guardians are never probed more often than this, whatever the profile says
+pub const MIN_FEDERATION_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The newest core API version Harbor has been tested against. Federations on a newer
+/// version still work, but are flagged so bug reports can be triaged.
+pub const TESTED_CORE_API_VERSION: ApiVersion = ApiVersion { major: 0, minor: 5 };
+
+/// How many times a payment's update stream is subscribed to again after ending early,
+/// before the payment is reported as stalled.
+pub const PAYMENT_RESUBSCRIBE_ATTEMPTS: usize = 3;
+
+/// How long to wait before subscribing to a payment's updates again.
+pub const PAYMENT_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(2);
+
+/// How many times the transaction history is read before the UI is told it couldn't be loaded.
+pub const HISTORY_REFRESH_ATTEMPTS: usize = 3;
+
+/// How long to wait between attempts to read the transaction history.
+pub const HISTORY_REFRESH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Minimum time between two balance updates for the same mint being sent to the UI.
+pub const BALANCE_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When a mint's balance was last sent to the UI and the newest balance waiting to be sent
+#[derive(Default)]
+struct BalanceUpdateSlot {
+    last_sent: Option<Instant>,
+    pending: Option<(Option<Uuid>, Amount)>,
+}
+
+/// Coalesces the balance updates sent to the UI, shared by the core and everything it
+/// starts that reports balances
+#[derive(Clone)]
+pub struct BalanceUpdates {
+    slots: Arc<Mutex<HashMap<MintIdentifier, BalanceUpdateSlot>>>,
+    /// Where the [`DisplayBalancePolicy`] and pending receives are read from
+    storage: Arc<dyn DBConnection + Send + Sync>,
+}
+
+impl BalanceUpdates {
+    pub fn new(storage: Arc<dyn DBConnection + Send + Sync>) -> Self {
+        Self {
+            slots: Arc::new(Mutex::new(HashMap::new())),
+            storage,
+        }
+    }
+
+    /// Applies the user's [`DisplayBalancePolicy`] to a mint's raw balance
+    pub fn display_balance(&self, mint: &MintIdentifier, balance: Amount) -> Amount {
+        let policy = match self.storage.get_profile() {
+            Ok(Some(profile)) => profile.display_balance_policy(),
+            _ => return balance,
+        };
+        if policy == DisplayBalancePolicy::default() {
+            return balance;
+        }
+
+        let pending = if policy.include_pending {
+            pending_incoming(self.storage.as_ref(), mint).unwrap_or_else(|e| {
+                log::error!("Could not read pending receives: {e}");
+                Amount::ZERO
+            })
+        } else {
+            Amount::ZERO
+        };
+        policy.apply(balance, pending)
+    }
+
+    /// The update for a mint's raw balance, along with the balance to show for it
+    fn msg(&self, mint: MintIdentifier, balance: Amount) -> CoreUIMsg {
+        let display_balance = self.display_balance(&mint, balance);
+        CoreUIMsg::MintBalanceUpdated {
+            id: mint,
+            balance,
+            display_balance,
+        }
+    }
+
+    /// Sends at most one balance update per mint every [`BALANCE_UPDATE_INTERVAL`].
+    /// Updates that come in sooner are held back and only the latest is sent once the
+    /// interval is over, so a burst of claims doesn't flood the UI.
+    /// Returns whether the UI is still listening, like [`HarborCore::send_msg`].
+    pub async fn send(
+        &self,
+        sender: &mut Sender<CoreUIMsgPacket>,
+        id: Option<Uuid>,
+        mint: MintIdentifier,
+        balance: Amount,
+    ) -> bool {
+        let mut updates = self.slots.lock().await;
+        let slot = updates.entry(mint.clone()).or_default();
+
+        // a send is already scheduled, it will pick up this balance
+        if slot.pending.is_some() {
+            slot.pending = Some((id, balance));
+            return !sender.is_closed();
+        }
+
+        if let Some(wait) = slot
+            .last_sent
+            .and_then(|last| BALANCE_UPDATE_INTERVAL.checked_sub(last.elapsed()))
+        {
+            slot.pending = Some((id, balance));
+            let this = self.clone();
+            let mut sender = sender.clone();
+            spawn(async move {
+                tokio::time::sleep(wait).await;
+                let pending = {
+                    let mut updates = this.slots.lock().await;
+                    let slot = updates.entry(mint.clone()).or_default();
+                    slot.last_sent = Some(Instant::now());
+                    slot.pending.take()
+                };
+                if let Some((id, balance)) = pending {
+                    HarborCore::send_msg(&mut sender, id, this.msg(mint, balance)).await;
+                }
+            });
+            return !sender.is_closed();
+        }
+
+        slot.last_sent = Some(Instant::now());
+        drop(updates);
+
+        HarborCore::send_msg(sender, id, self.msg(mint, balance)).await
+    }
+}
+
+/// Repeated balance refreshes for the same federation within this window are ignored.
+pub const BALANCE_REFRESH_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// How long after refreshing a federation's metadata it can be refreshed again.
+pub const METADATA_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many of the most recent transactions are included in a [`WalletSnapshot`].
+pub const SNAPSHOT_HISTORY_LIMIT: usize = 50;
+
+/// How long an [`AggregateBalance`] is reused before every client is asked again.
+pub const AGGREGATE_BALANCE_TTL: Duration = Duration::from_secs(2);
+
+/// How many transactions are sent to the UI in each [`CoreUIMsg::TransactionHistoryPage`].
+pub const HISTORY_PAGE_SIZE: usize = 50;
+
+/// A federation unlocked for sending locks again after going this long without a send.
+pub const SPEND_UNLOCK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Only the argon2 hash of a spend password is kept, to check it against on unlock
+fn hash_spend_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Could not hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+/// Receives for the mint that we are still waiting on
+fn pending_incoming(storage: &dyn DBConnection, mint: &MintIdentifier) -> anyhow::Result<Amount> {
+    let lightning_incoming: Amount = storage
+        .get_pending_lightning_receives()?
+        .into_iter()
+        .filter(|r| &r.mint_identifier() == mint)
+        .map(|r| r.amount())
+        .sum();
+    let onchain_incoming: Amount = storage
+        .get_pending_onchain_receives()?
+        .into_iter()
+        .filter(|r| &r.mint_identifier() == mint)
+        .map(|r| Amount::from_sats(r.amount_sats.unwrap_or_default() as u64))
+        .sum();
+    Ok(lightning_incoming + onchain_incoming)
+}
+
+fn verify_spend_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Whether a federation's core API version is one Harbor has been tested against
+fn is_tested_api_version(version: ApiVersion) -> bool {
+    version.major == TESTED_CORE_API_VERSION.major && version.minor <= TESTED_CORE_API_VERSION.minor
 }
 
 #[derive(Clone)]
 #[non_exhaustive]
 pub struct HarborCore {
     pub network: Network,
-    pub mnemonic: Mnemonic,
+    /// Where federation secrets are derived from, the seed words themselves stay in storage
+    pub secret_provider: Arc<dyn RootSecretProvider>,
     pub data_dir: PathBuf,
     pub tx: Sender<CoreUIMsgPacket>,
     pub clients: Arc<RwLock<HashMap<FederationId, FedimintClient>>>,
@@ -237,14 +1137,41 @@ pub struct HarborCore {
     pub cashu_storage: Arc<WalletRedbDatabase>,
     pub stop: Arc<AtomicBool>,
     pub tor_enabled: Arc<AtomicBool>,
+    /// Whether the app is in the background, shared with the clients, see
+    /// [`HarborCore::set_backgrounded`]
+    pub backgrounded: Arc<AtomicBool>,
     pub metadata_fetch_cancel: Arc<AtomicBool>,
+    /// Cancels price fetches, kept apart so removing a mint doesn't cancel them
+    pub price_fetch_cancel: Arc<AtomicBool>,
+    /// How federation clients back off while their federation can't be reached
+    pub reconnect_policy: ReconnectPolicy,
+    /// Held while starting a payment so duplicate requests can't race the idempotency check
+    payment_locks: PaymentLocks,
+    /// Held while generating a deposit address so simultaneous requests can reuse one address
+    address_lock: Arc<Mutex<()>>,
+    /// When each federation's balance was last refreshed on request
+    balance_refreshes: Arc<Mutex<HashMap<FederationId, Instant>>>,
+    /// The last [`AggregateBalance`] and when it was put together
+    aggregate_balance: Arc<Mutex<Option<(Instant, AggregateBalance)>>>,
+    /// When each federation's metadata was last refreshed on request
+    metadata_refreshes: Arc<Mutex<HashMap<FederationId, Instant>>>,
+    /// Federations unlocked for sending, with when they were last used
+    spend_unlocks: Arc<Mutex<HashMap<FederationId, Instant>>>,
+    /// Coalesces balance updates, shared with the clients so their updates are too
+    balance_updates: BalanceUpdates,
+    /// Lightning receives claimed during the receive batch window
+    receive_batch: ReceiveBatch,
+    /// Caps how many subscription updates are handled at once, shared with the clients
+    subscription_limit: SubscriptionLimit,
+    /// Federations being recovered, shared with the clients so they aren't opened meanwhile
+    recoveries: Recoveries,
 }
 
 impl HarborCore {
     #[allow(clippy::too_many_arguments)]
     pub async fn new(
         network: Network,
-        mnemonic: Mnemonic,
+        secret_provider: Arc<dyn RootSecretProvider>,
         data_dir: PathBuf,
         tx: Sender<CoreUIMsgPacket>,
         clients: Arc<RwLock<HashMap<FederationId, FedimintClient>>>,
@@ -253,7 +1180,17 @@ impl HarborCore {
         cashu_storage: Arc<WalletRedbDatabase>,
         stop: Arc<AtomicBool>,
         tor_enabled: Arc<AtomicBool>,
+        backgrounded: Arc<AtomicBool>,
+        reconnect_policy: ReconnectPolicy,
+        balance_updates: BalanceUpdates,
+        subscription_limit: SubscriptionLimit,
+        recoveries: Recoveries,
     ) -> anyhow::Result<Self> {
+        if let Some(profile) = storage.get_profile()? {
+            subscription_limit.set(profile.max_concurrent_subscriptions());
+        }
+        let receive_batch = ReceiveBatch::default();
+
         // start subscription to pending events
         let pending_onchain_recv = storage.get_pending_onchain_receives()?;
         let pending_onchain_payments = storage.get_pending_onchain_payments()?;
@@ -269,16 +1206,19 @@ impl HarborCore {
         for item in pending_onchain_recv {
             if let Some(federation_id) = item.fedimint_id() {
                 if let Some(client) = fed_clients.get(&federation_id) {
-                    let onchain = client
+                    let Ok(onchain) = client
                         .fedimint_client
                         .get_first_module::<WalletClientModule>()
-                        .expect("must have wallet module");
+                    else {
+                        continue;
+                    };
 
                     let op_id = item.operation_id();
                     if let Ok(sub) = onchain.subscribe_deposit(op_id).await {
                         spawn_onchain_receive_subscription(
                             tx.clone(),
                             client.fedimint_client.clone(),
+                            client.subscriptions.clone(),
                             storage.clone(),
                             op_id,
                             Uuid::nil(),
@@ -293,16 +1233,19 @@ impl HarborCore {
         for item in pending_onchain_payments {
             if let Some(federation_id) = item.fedimint_id() {
                 if let Some(client) = fed_clients.get(&federation_id) {
-                    let onchain = client
+                    let Ok(onchain) = client
                         .fedimint_client
                         .get_first_module::<WalletClientModule>()
-                        .expect("must have wallet module");
+                    else {
+                        continue;
+                    };
 
                     let op_id = item.operation_id();
                     if let Ok(sub) = onchain.subscribe_withdraw_updates(op_id).await {
                         spawn_onchain_payment_subscription(
                             tx.clone(),
                             client.fedimint_client.clone(),
+                            client.subscriptions.clone(),
                             storage.clone(),
                             op_id,
                             Uuid::nil(),
@@ -318,17 +1261,22 @@ impl HarborCore {
             match item.mint_identifier() {
                 MintIdentifier::Fedimint(federation_id) => {
                     if let Some(client) = fed_clients.get(&federation_id) {
-                        let lightning_module = client
+                        let Ok(lightning_module) = client
                             .fedimint_client
                             .get_first_module::<LightningClientModule>()
-                            .expect("must have ln module");
+                        else {
+                            storage.mark_ln_receive_as_failed(item.operation_id)?;
+                            continue;
+                        };
 
                         let op_id = item.operation_id();
 
                         if let Ok(sub) = lightning_module.subscribe_ln_receive(op_id).await {
                             spawn_invoice_receive_subscription(
                                 tx.clone(),
+                                receive_batch.clone(),
                                 client.fedimint_client.clone(),
+                                client.subscriptions.clone(),
                                 storage.clone(),
                                 op_id,
                                 Uuid::nil(),
@@ -350,6 +1298,7 @@ impl HarborCore {
                         {
                             spawn_lightning_receive_thread(
                                 tx.clone(),
+                                balance_updates.clone(),
                                 client.clone(),
                                 storage.clone(),
                                 quote,
@@ -370,10 +1319,13 @@ impl HarborCore {
             match item.mint_identifier() {
                 MintIdentifier::Fedimint(federation_id) => {
                     if let Some(client) = fed_clients.get(&federation_id) {
-                        let lightning_module = client
+                        let Ok(lightning_module) = client
                             .fedimint_client
                             .get_first_module::<LightningClientModule>()
-                            .expect("must have ln module");
+                        else {
+                            storage.mark_lightning_payment_as_failed(item.operation_id)?;
+                            continue;
+                        };
 
                         let op_id = item.operation_id();
 
@@ -382,6 +1334,7 @@ impl HarborCore {
                             spawn_invoice_payment_subscription(
                                 tx.clone(),
                                 client.fedimint_client.clone(),
+                                client.subscriptions.clone(),
                                 storage.clone(),
                                 op_id,
                                 Uuid::nil(),
@@ -394,6 +1347,7 @@ impl HarborCore {
                             spawn_internal_payment_subscription(
                                 tx.clone(),
                                 client.fedimint_client.clone(),
+                                client.subscriptions.clone(),
                                 storage.clone(),
                                 op_id,
                                 Uuid::nil(),
@@ -414,6 +1368,7 @@ impl HarborCore {
                         {
                             spawn_lightning_payment_thread(
                                 tx.clone(),
+                                balance_updates.clone(),
                                 client.clone(),
                                 storage.clone(),
                                 quote,
@@ -432,7 +1387,7 @@ impl HarborCore {
 
         Ok(Self {
             network,
-            mnemonic,
+            secret_provider,
             data_dir,
             tx,
             clients,
@@ -441,28 +1396,55 @@ impl HarborCore {
             cashu_storage,
             stop,
             tor_enabled,
+            backgrounded,
             metadata_fetch_cancel: Arc::new(AtomicBool::new(false)),
+            price_fetch_cancel: Arc::new(AtomicBool::new(false)),
+            reconnect_policy,
+            payment_locks: PaymentLocks::default(),
+            address_lock: Arc::new(Mutex::new(())),
+            balance_refreshes: Arc::new(Mutex::new(HashMap::new())),
+            aggregate_balance: Arc::new(Mutex::new(None)),
+            metadata_refreshes: Arc::new(Mutex::new(HashMap::new())),
+            spend_unlocks: Arc::new(Mutex::new(HashMap::new())),
+            balance_updates,
+            receive_batch,
+            subscription_limit,
+            recoveries,
         })
     }
 
     // Initial setup messages that don't have an id
-    // Panics if fails to send
     async fn send_system_msg(&self, msg: CoreUIMsg) {
-        Self::send_msg(&mut self.tx.clone(), None, msg).await
+        Self::send_msg(&mut self.tx.clone(), None, msg).await;
     }
 
     // Standard core->ui communication with an id
-    // Panics if fails to send
     pub async fn msg(&self, id: Uuid, msg: CoreUIMsg) {
-        Self::send_msg(&mut self.tx.clone(), Some(id), msg).await
+        Self::send_msg(&mut self.tx.clone(), Some(id), msg).await;
     }
 
-    pub async fn send_msg(sender: &mut Sender<CoreUIMsgPacket>, id: Option<Uuid>, msg: CoreUIMsg) {
-        let msg = CoreUIMsgPacket { id, msg };
-        sender
-            .send(msg)
-            .await
-            .expect("Could not communicate with the UI");
+    // Balance updates should go through `BalanceUpdates` so they are coalesced
+    // Returns whether the UI is still listening, tasks streaming updates should stop once
+    // it isn't, which happens when the app is closed
+    pub async fn send_msg(
+        sender: &mut Sender<CoreUIMsgPacket>,
+        id: Option<Uuid>,
+        msg: CoreUIMsg,
+    ) -> bool {
+        match sender.send(CoreUIMsgPacket { id, msg }).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::debug!("UI is no longer listening, dropping message: {e}");
+                false
+            }
+        }
+    }
+
+    // Balance updates for the UI, coalesced by `BalanceUpdates`
+    async fn send_balance_update(&self, id: Option<Uuid>, mint: MintIdentifier, balance: Amount) {
+        self.balance_updates
+            .send(&mut self.tx.clone(), id, mint, balance)
+            .await;
     }
 
     // Convenience method for sending status updates
@@ -477,35 +1459,196 @@ impl HarborCore {
         .await;
     }
 
-    // Sends updates to the UI to reflect the initial state
-    pub async fn init_ui_state(&self) -> anyhow::Result<()> {
-        let federation_items = self.get_mint_items().await?;
-        self.send_system_msg(CoreUIMsg::MintListUpdated(federation_items))
-            .await;
+    /// Splits the mint's balance into ecash and pending payments.
+    /// Pending amounts come from the operations in storage that haven't finished yet.
+    pub async fn balance_breakdown(
+        &self,
+        mint: &MintIdentifier,
+    ) -> anyhow::Result<BalanceBreakdown> {
+        let ecash = match mint {
+            MintIdentifier::Fedimint(id) => {
+                self.get_client(*id)
+                    .await?
+                    .fedimint_client
+                    .get_balance()
+                    .await
+            }
+            MintIdentifier::Cashu(url) => {
+                let balance: u64 = self
+                    .get_cashu_client(url)
+                    .await
+                    .total_balance()
+                    .await?
+                    .into();
+                Amount::from_sats(balance)
+            }
+        };
 
-        for client in self.clients.read().await.values() {
-            let fed_balance = client.fedimint_client.get_balance().await;
-            self.send_system_msg(CoreUIMsg::MintBalanceUpdated {
-                id: MintIdentifier::Fedimint(client.fedimint_client.federation_id()),
-                balance: fed_balance,
-            })
-            .await;
+        let lightning_outgoing: Amount = self
+            .storage
+            .get_pending_lightning_payments()?
+            .into_iter()
+            .filter(|p| &p.mint_identifier() == mint)
+            .map(|p| p.amount() + p.fee())
+            .sum();
+        let onchain_outgoing: Amount = self
+            .storage
+            .get_pending_onchain_payments()?
+            .into_iter()
+            .filter(|p| &p.mint_identifier() == mint)
+            .map(|p| Amount::from_sats((p.amount_sats + p.fee_sats) as u64))
+            .sum();
+
+        Ok(BalanceBreakdown {
+            ecash,
+            pending_incoming: pending_incoming(self.storage.as_ref(), mint)?,
+            pending_outgoing: lightning_outgoing + onchain_outgoing,
+        })
+    }
+
+    /// The handles the core shares with its federation clients
+    fn client_context(&self) -> ClientContext {
+        ClientContext {
+            storage: self.storage.clone(),
+            secret_provider: self.secret_provider.clone(),
+            network: self.network,
+            reconnect_policy: self.reconnect_policy,
+            stop: self.stop.clone(),
+            backgrounded: self.backgrounded.clone(),
+            sender: self.tx.clone(),
+            balance_updates: self.balance_updates.clone(),
+            subscription_limit: self.subscription_limit.clone(),
+            recoveries: self.recoveries.clone(),
         }
+    }
 
-        for client in self.cashu_clients.read().await.values() {
-            let bal: u64 = client.total_balance().await?.into();
-            self.send_system_msg(CoreUIMsg::MintBalanceUpdated {
-                id: MintIdentifier::Cashu(client.mint_url.clone()),
-                balance: Amount::from_sats(bal),
-            })
-            .await;
+    /// Keeps trying to load federations that failed on startup, so one flaky federation
+    /// doesn't lock the user out of the others. The UI is told about each federation
+    /// when it becomes unavailable and again once it has been loaded.
+    pub async fn retry_unavailable_federations(&self, federations: Vec<FederationId>) {
+        if federations.is_empty() {
+            return;
         }
 
-        let history = self.storage.get_transaction_history()?;
-        self.send_system_msg(CoreUIMsg::TransactionHistoryUpdated(history))
-            .await;
+        for id in federations.iter() {
+            self.send_system_msg(CoreUIMsg::FederationUnavailable(*id))
+                .await;
+        }
 
-        let profile = self.storage.get_profile()?;
+        let core = self.clone();
+        spawn(async move {
+            let mut remaining = federations;
+            while !remaining.is_empty() {
+                tokio::time::sleep(FEDERATION_RETRY_INTERVAL).await;
+                if core.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let results =
+                    init_all_federations(&core.client_context(), std::mem::take(&mut remaining))
+                        .await;
+
+                let mut recovered = vec![];
+                for (id, result) in results {
+                    match result {
+                        Ok(client) => {
+                            log::info!("Federation {id} is available again");
+                            core.clients.write().await.insert(id, client.clone());
+                            recovered.push(client);
+                        }
+                        Err(e) => {
+                            log::warn!("Federation {id} is still unavailable: {e}");
+                            remaining.push(id);
+                        }
+                    }
+                }
+
+                if recovered.is_empty() {
+                    continue;
+                }
+                core.clear_aggregate_balance().await;
+
+                match core.get_mint_items().await {
+                    Ok(items) => {
+                        core.send_system_msg(CoreUIMsg::MintListUpdated(items))
+                            .await
+                    }
+                    Err(e) => error!("Could not get mint items: {e}"),
+                }
+
+                for client in recovered {
+                    let id = client.federation_id();
+                    core.send_balance_update(
+                        None,
+                        MintIdentifier::Fedimint(id),
+                        client.fedimint_client.get_balance().await,
+                    )
+                    .await;
+                    core.send_system_msg(CoreUIMsg::FederationRecovered(id))
+                        .await;
+                }
+            }
+        });
+    }
+
+    /// Reads the transaction history again and sends it to the UI, used to retry after
+    /// [`CoreUIMsg::HistoryRefreshFailed`].
+    pub async fn refresh_history(&self, msg_id: Uuid) {
+        update_history(self.storage.clone(), msg_id, &mut self.tx.clone()).await;
+    }
+
+    /// Sends the page of the transaction history starting at `offset`, for showing more of
+    /// it than the first page sent on every update.
+    pub async fn get_transaction_history_page(&self, msg_id: Uuid, offset: usize) {
+        send_history_page(self.storage.clone(), msg_id, &mut self.tx.clone(), offset).await;
+    }
+
+    /// Attaches key/value data to an operation so it can be matched up with an external
+    /// system, like an order id. It is returned with the operation's history item, replacing
+    /// anything set before, and an empty map removes it.
+    pub async fn set_operation_metadata(
+        &self,
+        msg_id: Uuid,
+        operation_id: String,
+        metadata: BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting metadata for operation {operation_id}");
+        self.storage
+            .set_operation_metadata(operation_id, metadata)?;
+        self.refresh_history(msg_id).await;
+        Ok(())
+    }
+
+    // Sends updates to the UI to reflect the initial state
+    pub async fn init_ui_state(&self) -> anyhow::Result<()> {
+        let federation_items = self.get_mint_items().await?;
+        self.send_system_msg(CoreUIMsg::MintListUpdated(federation_items))
+            .await;
+
+        for client in self.clients.read().await.values() {
+            let fed_balance = client.fedimint_client.get_balance().await;
+            self.send_balance_update(
+                None,
+                MintIdentifier::Fedimint(client.fedimint_client.federation_id()),
+                fed_balance,
+            )
+            .await;
+        }
+
+        for client in self.cashu_clients.read().await.values() {
+            let bal: u64 = client.total_balance().await?.into();
+            self.send_balance_update(
+                None,
+                MintIdentifier::Cashu(client.mint_url.clone()),
+                Amount::from_sats(bal),
+            )
+            .await;
+        }
+
+        // a history read failure shouldn't keep the rest of the state from the UI
+        self.refresh_history(Uuid::nil()).await;
+
+        let profile = self.storage.get_profile()?;
         if let Some(profile) = profile {
             // Send all profile settings in one message
             self.send_system_msg(CoreUIMsg::InitialProfile {
@@ -519,12 +1662,691 @@ impl HarborCore {
         Ok(())
     }
 
-    async fn get_client(&self, federation_id: FederationId) -> FedimintClient {
+    /// Gets the balance of every loaded mint along with the most recent history
+    pub async fn snapshot(&self) -> anyhow::Result<WalletSnapshot> {
+        let mut balances = HashMap::new();
+
+        for client in self.clients.read().await.values() {
+            let balance = client.fedimint_client.get_balance().await;
+            balances.insert(
+                MintIdentifier::Fedimint(client.fedimint_client.federation_id()),
+                balance,
+            );
+        }
+
+        for client in self.cashu_clients.read().await.values() {
+            let balance: u64 = client.total_balance().await?.into();
+            balances.insert(
+                MintIdentifier::Cashu(client.mint_url.clone()),
+                Amount::from_sats(balance),
+            );
+        }
+
+        let display_balances = balances
+            .iter()
+            .map(|(mint, balance)| {
+                let display = self.balance_updates.display_balance(mint, *balance);
+                (mint.clone(), display)
+            })
+            .collect();
+
+        let (recent_history, _) = self
+            .storage
+            .get_transaction_history_page(0, SNAPSHOT_HISTORY_LIMIT)?;
+
+        Ok(WalletSnapshot {
+            balances,
+            display_balances,
+            recent_history,
+        })
+    }
+
+    /// Sums the balances of every joined federation and sends them to the UI. A federation
+    /// that is offline or failed to load is included with its last known balance, marked
+    /// as stale. The result is reused for [`AGGREGATE_BALANCE_TTL`].
+    pub async fn total_balance(&self, msg_id: Uuid) -> AggregateBalance {
+        let mut cache = self.aggregate_balance.lock().await;
+        let aggregate = match cache.as_ref() {
+            Some((at, aggregate)) if at.elapsed() < AGGREGATE_BALANCE_TTL => aggregate.clone(),
+            _ => {
+                let aggregate = self.aggregate_balance().await;
+                *cache = Some((Instant::now(), aggregate.clone()));
+                aggregate
+            }
+        };
+        drop(cache);
+
+        self.msg(
+            msg_id,
+            CoreUIMsg::AggregateBalanceUpdated(aggregate.clone()),
+        )
+        .await;
+        aggregate
+    }
+
+    /// Drops the cached [`AggregateBalance`] so the next one includes a federation that was
+    /// just joined, left or archived
+    async fn clear_aggregate_balance(&self) {
+        *self.aggregate_balance.lock().await = None;
+    }
+
+    async fn aggregate_balance(&self) -> AggregateBalance {
+        let mut federations = HashMap::new();
+        for client in self.clients.read().await.values() {
+            federations.insert(
+                client.federation_id(),
+                FederationBalance {
+                    balance: client.fedimint_client.get_balance().await,
+                    stale: !client.is_online(),
+                },
+            );
+        }
+
+        // federations that didn't load only have the balance saved in storage
+        let ids = self.storage.list_federations().unwrap_or_else(|e| {
+            error!("Could not list federations: {e}");
+            vec![]
+        });
+        for id in ids {
+            let Ok(id) = FederationId::from_str(&id) else {
+                continue;
+            };
+            if federations.contains_key(&id) {
+                continue;
+            }
+            let balance = self.storage.get_federation_balance(id).unwrap_or_else(|e| {
+                error!("Could not read the saved balance of {id}: {e}");
+                None
+            });
+            federations.insert(
+                id,
+                FederationBalance {
+                    balance: balance.unwrap_or(Amount::ZERO),
+                    stale: true,
+                },
+            );
+        }
+
+        AggregateBalance::new(federations)
+    }
+
+    /// Values the balances of every loaded mint together in the currency and sends it to
+    /// the UI. When the price source can't be reached the last cached price is used,
+    /// `None` is returned if there is none. Cashu mints that can't give their balance are
+    /// left out, and the reason sent says so.
+    pub async fn total_fiat_value(&self, msg_id: Uuid, currency: &str) -> Option<FiatAmount> {
+        let result = self.price_total(currency).await;
+        let (value, reason) = match result {
+            Ok((value, skipped)) if skipped.is_empty() => (Some(value), None),
+            Ok((value, skipped)) => {
+                let skipped = skipped
+                    .iter()
+                    .map(|url| url.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                (Some(value), Some(format!("Left out mints: {skipped}")))
+            }
+            Err(e) => {
+                log::warn!("Could not value balances in {currency}: {e}");
+                (None, Some(e.to_string()))
+            }
+        };
+
+        self.msg(
+            msg_id,
+            CoreUIMsg::TotalFiatUpdated {
+                currency: currency.to_uppercase(),
+                value: value.clone(),
+                reason,
+            },
+        )
+        .await;
+        value
+    }
+
+    /// The value of every mint's balance, with the cashu mints that were left out
+    async fn price_total(&self, currency: &str) -> anyhow::Result<(FiatAmount, Vec<MintUrl>)> {
+        let mut balances = vec![];
+        for client in self.clients.read().await.values() {
+            balances.push(client.fedimint_client.get_balance().await);
+        }
+        let mut skipped = vec![];
+        for (url, client) in self.cashu_clients.read().await.iter() {
+            match client.total_balance().await {
+                Ok(balance) => balances.push(Amount::from_sats(balance.into())),
+                Err(e) => {
+                    log::warn!("Could not get balance of cashu mint {url}, leaving it out: {e}");
+                    skipped.push(url.clone());
+                }
+            }
+        }
+        let total: Amount = balances.into_iter().sum();
+
+        let tor_enabled = self.tor_enabled.load(Ordering::Relaxed);
+        let (price, stale_reason) =
+            get_btc_price(currency, tor_enabled, self.price_fetch_cancel.clone()).await?;
+        let value = FiatAmount {
+            stale_reason,
+            ..FiatAmount::from_amount(total, currency, price)
+        };
+        Ok((value, skipped))
+    }
+
+    /// Re-fetches a federation's balance and sends it to the UI.
+    /// Calls within [`BALANCE_REFRESH_DEBOUNCE`] of the last refresh are skipped.
+    pub async fn refresh_balance(&self, federation_id: FederationId) -> anyhow::Result<()> {
+        {
+            let mut refreshes = self.balance_refreshes.lock().await;
+            if refreshes
+                .get(&federation_id)
+                .is_some_and(|last| last.elapsed() < BALANCE_REFRESH_DEBOUNCE)
+            {
+                trace!("Skipping balance refresh for {federation_id}, refreshed recently");
+                return Ok(());
+            }
+            refreshes.insert(federation_id, Instant::now());
+        }
+
+        let balance = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .get_balance()
+            .await;
+
+        self.send_balance_update(None, MintIdentifier::Fedimint(federation_id), balance)
+            .await;
+
+        Ok(())
+    }
+
+    /// Sets a password that has to be given to [`HarborCore::unlock`] before sending from the
+    /// federation, `None` removes it. Changing an existing password needs the federation unlocked.
+    ///
+    /// This gates the send calls, it doesn't encrypt anything. The federation client needs its
+    /// secret to show the balance and history while locked, and its notes can't be spent
+    /// without it, so there is no separate spend secret the password could seal.
+    pub async fn set_spend_password(
+        &self,
+        federation_id: FederationId,
+        password: Option<String>,
+    ) -> anyhow::Result<()> {
+        self.check_unlocked(federation_id).await?;
+
+        let hash = password.map(|p| hash_spend_password(&p)).transpose()?;
+        self.storage.set_spend_password_hash(federation_id, hash)?;
+        self.lock(federation_id).await;
+        Ok(())
+    }
+
+    /// Unlocks sending from a federation until it goes unused for [`SPEND_UNLOCK_TIMEOUT`]
+    pub async fn unlock(&self, federation_id: FederationId, password: &str) -> anyhow::Result<()> {
+        let Some(hash) = self.storage.get_spend_password_hash(federation_id)? else {
+            return Ok(());
+        };
+        if !verify_spend_password(password, &hash) {
+            return Err(anyhow!("Invalid password"));
+        }
+
+        log::info!("Unlocked sending from federation: {federation_id}");
+        self.spend_unlocks
+            .lock()
+            .await
+            .insert(federation_id, Instant::now());
+        Ok(())
+    }
+
+    /// Locks sending from a federation again before the timeout
+    pub async fn lock(&self, federation_id: FederationId) {
+        self.spend_unlocks.lock().await.remove(&federation_id);
+    }
+
+    /// Whether sending from the federation is allowed, federations without a spend password
+    /// always are.
+    pub async fn is_unlocked(&self, federation_id: FederationId) -> anyhow::Result<bool> {
+        if self
+            .storage
+            .get_spend_password_hash(federation_id)?
+            .is_none()
+        {
+            return Ok(true);
+        }
+
+        let mut unlocks = self.spend_unlocks.lock().await;
+        match unlocks.get(&federation_id) {
+            Some(last) if last.elapsed() < SPEND_UNLOCK_TIMEOUT => Ok(true),
+            Some(_) => {
+                log::info!("Locking federation {federation_id} after being idle");
+                unlocks.remove(&federation_id);
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Fails with [`SendError::Locked`] unless sending from the federation is unlocked,
+    /// otherwise it stays unlocked for another [`SPEND_UNLOCK_TIMEOUT`].
+    async fn check_unlocked(&self, federation_id: FederationId) -> anyhow::Result<()> {
+        if !self.is_unlocked(federation_id).await? {
+            return Err(SendError::Locked.into());
+        }
+
+        if let Some(last) = self.spend_unlocks.lock().await.get_mut(&federation_id) {
+            *last = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn get_client(&self, federation_id: FederationId) -> anyhow::Result<FedimintClient> {
         let clients = self.clients.read().await;
         clients
             .get(&federation_id)
-            .expect("No client found for federation")
-            .clone()
+            .cloned()
+            .ok_or(anyhow!("Federation {federation_id} is not loaded"))
+    }
+
+    /// Lists the modules the federation runs, ordered by instance id.
+    pub async fn federation_modules(
+        &self,
+        federation_id: FederationId,
+    ) -> anyhow::Result<Vec<ModuleInfo>> {
+        let clients = self.clients.read().await;
+        let client = clients
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?;
+        let config = client.fedimint_client.config().await;
+        Ok(ModuleInfo::from_config(&config))
+    }
+
+    /// Estimates the gateway fee for paying the amount over lightning from the federation,
+    /// `None` if it isn't loaded or has no gateway that can route the amount.
+    pub async fn estimate_lightning_fee(
+        &self,
+        federation_id: FederationId,
+        amount: Amount,
+    ) -> Option<Amount> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)?
+            .fedimint_client
+            .clone();
+        estimate_lightning_fee(
+            &client,
+            amount,
+            self.require_private_payments(),
+            &self.gateway_ranking(federation_id),
+        )
+        .await
+    }
+
+    /// Whether the profile only allows gateways that support private payments
+    fn require_private_payments(&self) -> bool {
+        self.storage
+            .get_profile()
+            .ok()
+            .flatten()
+            .is_some_and(|p| p.require_private_payments())
+    }
+
+    /// How gateways are ranked for the federation, from the profile's priority, our past
+    /// payments and the gateway it used last
+    fn gateway_ranking(&self, federation_id: FederationId) -> GatewayRanking {
+        let priority = self
+            .storage
+            .get_profile()
+            .ok()
+            .flatten()
+            .map(|p| p.gateway_selection_priority())
+            .unwrap_or_default();
+        let stats = if priority.needs_stats() {
+            self.storage.get_gateway_stats().unwrap_or_else(|e| {
+                log::warn!("Could not read gateway stats: {e}");
+                HashMap::new()
+            })
+        } else {
+            HashMap::new()
+        };
+        let preferred = self
+            .storage
+            .get_preferred_gateway(federation_id)
+            .unwrap_or_else(|e| {
+                log::warn!("Could not read preferred gateway: {e}");
+                None
+            });
+        GatewayRanking {
+            priority,
+            stats,
+            preferred,
+        }
+    }
+
+    /// Saves the gateway a payment is going through as the federation's preferred one.
+    /// The UI is told with [`CoreUIMsg::GatewayChanged`] when it replaces another gateway.
+    async fn remember_gateway(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+        previous: Option<PublicKey>,
+        gateway: &LightningGateway,
+    ) {
+        if previous == Some(gateway.gateway_id) {
+            return;
+        }
+        if let Err(e) = self
+            .storage
+            .set_preferred_gateway(federation_id, gateway.gateway_id)
+        {
+            log::error!("Could not save preferred gateway: {e}");
+        }
+        if let Some(previous) = previous {
+            log::info!(
+                "Gateway for {federation_id} changed from {previous} to {}",
+                gateway.gateway_id
+            );
+            self.msg(
+                msg_id,
+                CoreUIMsg::GatewayChanged {
+                    federation_id,
+                    previous,
+                    gateway: gateway.gateway_id,
+                },
+            )
+            .await;
+        }
+    }
+
+    /// Whether the federation runs a wallet module, so it can send and receive onchain.
+    /// False for federations that aren't loaded, as their modules aren't known.
+    pub async fn supports_onchain(&self, federation_id: FederationId) -> bool {
+        self.federation_modules(federation_id)
+            .await
+            .is_ok_and(|modules| {
+                modules
+                    .iter()
+                    .any(|m| m.kind == fedimint_wallet_client::KIND)
+            })
+    }
+
+    /// Re-derives an invite code for the federation from its config, using the loaded client
+    /// or the saved join config. The api secret, if any, is kept from the original invite.
+    pub async fn federation_invite_code(&self, federation_id: FederationId) -> Option<InviteCode> {
+        let config = match self.clients.read().await.get(&federation_id) {
+            Some(c) => Some(c.fedimint_client.config().await),
+            None => None,
+        };
+        let config = match config {
+            Some(config) => config,
+            None => match self.storage.get_federation_config(federation_id) {
+                Ok(cached) => cached?.config().ok()?,
+                Err(e) => {
+                    log::error!("Could not load saved config for {federation_id}: {e}");
+                    return None;
+                }
+            },
+        };
+
+        let api_secret = self
+            .storage
+            .get_federation_invite_code(federation_id)
+            .ok()
+            .flatten()
+            .and_then(|invite| invite.api_secret());
+
+        invite_code_from_config(&config, api_secret)
+    }
+
+    /// The core API version we agreed on with a federation's guardians. This is cached by
+    /// the client, so it is cheap to ask for.
+    pub async fn federation_api_version(
+        &self,
+        federation_id: FederationId,
+    ) -> anyhow::Result<ApiVersion> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .clone();
+
+        let versions = client.load_and_refresh_common_api_version().await?;
+        Ok(versions.core)
+    }
+
+    /// Raw entries of a federation's operation log, newest first. This is read-only and
+    /// separate from [`Self::get_transaction_history`], it shows what the fedimint client
+    /// itself knows so support can reconcile harbor's history against it.
+    pub async fn operation_log_entries(
+        &self,
+        federation_id: FederationId,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<OpLogEntry>> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .clone();
+
+        Ok(operation_log_entries(&client, limit, offset).await)
+    }
+
+    /// Repairs a federation's history from its operation log: records stuck as pending or
+    /// marked wrongly are corrected and successful operations we never recorded are added.
+    /// Progress is sent as status updates.
+    pub async fn reconcile_history(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+    ) -> anyhow::Result<ReconcileSummary> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .clone();
+
+        log::info!("Reconciling history of {federation_id}");
+        let mut sender = self.tx.clone();
+        let summary = reconcile_history(&client, &self.storage, &mut sender, msg_id).await;
+        if summary.repairs() > 0 {
+            update_history(self.storage.clone(), msg_id, &mut sender).await;
+        }
+
+        Ok(summary)
+    }
+
+    /// Prunes finished transactions older than the policy allows, keeping their totals.
+    /// Returns how many were pruned.
+    pub async fn prune_history(&self, msg_id: Uuid, policy: PrunePolicy) -> anyhow::Result<usize> {
+        log::info!("Pruning history with policy: {policy:?}");
+        let pruned = self.storage.prune_history(&policy)?;
+        log::info!("Pruned {} transactions from history", pruned.len());
+        if !pruned.is_empty() {
+            let mut sender = self.tx.clone();
+            update_history(self.storage.clone(), msg_id, &mut sender).await;
+        }
+
+        Ok(pruned.len())
+    }
+
+    /// When a federation was joined and when it was first and last used
+    pub fn federation_info(&self, federation_id: FederationId) -> anyhow::Result<FederationInfo> {
+        self.storage
+            .get_federation_info(federation_id)?
+            .ok_or(anyhow!("Federation {federation_id} is not known"))
+    }
+
+    /// Checks whether a federation can be reached right now and tells the UI with
+    /// [`CoreUIMsg::FederationConnectivity`]
+    pub async fn ping_federation(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+    ) -> anyhow::Result<bool> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .clone();
+
+        let online = client.ping(PING_TIMEOUT).await;
+        log::info!("Pinged federation {federation_id}, online: {online}");
+        self.msg(
+            msg_id,
+            CoreUIMsg::FederationConnectivity {
+                id: federation_id,
+                online,
+            },
+        )
+        .await;
+        Ok(online)
+    }
+
+    /// Runs a set of checks against a federation to help diagnose problems:
+    /// guardians reachable, API version tested, gateway available and a backup succeeds.
+    /// Each check that talks to the federation gives up after [`SELF_TEST_TIMEOUT`].
+    pub async fn self_test(&self, federation_id: FederationId) -> anyhow::Result<SelfTestReport> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .clone();
+
+        let mut checks = Vec::with_capacity(4);
+
+        let guardians = tokio::time::timeout(SELF_TEST_TIMEOUT, client.api().session_count()).await;
+        checks.push(match guardians {
+            Ok(Ok(count)) => SelfTestCheck {
+                name: "Guardians reachable".to_string(),
+                passed: true,
+                detail: format!("Federation is at session {count}"),
+            },
+            Ok(Err(e)) => SelfTestCheck {
+                name: "Guardians reachable".to_string(),
+                passed: false,
+                detail: e.to_string(),
+            },
+            Err(_) => SelfTestCheck {
+                name: "Guardians reachable".to_string(),
+                passed: false,
+                detail: "Timed out".to_string(),
+            },
+        });
+
+        let version = tokio::time::timeout(
+            SELF_TEST_TIMEOUT,
+            self.federation_api_version(federation_id),
+        )
+        .await;
+        checks.push(match version {
+            Ok(Ok(version)) => SelfTestCheck {
+                name: "API version".to_string(),
+                passed: is_tested_api_version(version),
+                detail: format!(
+                    "Federation runs {}.{}, tested up to {}.{}",
+                    version.major,
+                    version.minor,
+                    TESTED_CORE_API_VERSION.major,
+                    TESTED_CORE_API_VERSION.minor
+                ),
+            },
+            Ok(Err(e)) => SelfTestCheck {
+                name: "API version".to_string(),
+                passed: false,
+                detail: e.to_string(),
+            },
+            Err(_) => SelfTestCheck {
+                name: "API version".to_string(),
+                passed: false,
+                detail: "Timed out".to_string(),
+            },
+        });
+
+        let gateway = match client.get_first_module::<LightningClientModule>() {
+            Ok(ln) => {
+                let count = ln.list_gateways().await.len();
+                let require_private = self.require_private_payments();
+                match tokio::time::timeout(
+                    SELF_TEST_TIMEOUT,
+                    select_gateway(
+                        &client,
+                        Amount::ZERO,
+                        require_private,
+                        &self.gateway_ranking(client.federation_id()),
+                    ),
+                )
+                .await
+                {
+                    Ok(Ok(g)) => SelfTestCheck {
+                        name: "Gateway available".to_string(),
+                        passed: true,
+                        detail: format!("Selected {} of {count} gateways", g.gateway_id),
+                    },
+                    Ok(Err(e)) => SelfTestCheck {
+                        name: "Gateway available".to_string(),
+                        passed: false,
+                        detail: format!("Could not select any of {count} gateways: {e}"),
+                    },
+                    Err(_) => SelfTestCheck {
+                        name: "Gateway available".to_string(),
+                        passed: false,
+                        detail: "Timed out".to_string(),
+                    },
+                }
+            }
+            Err(_) => SelfTestCheck {
+                name: "Gateway available".to_string(),
+                passed: false,
+                detail: "Federation does not have a lightning module".to_string(),
+            },
+        };
+        checks.push(gateway);
+
+        // A backup is a harmless request that has to go through the guardians
+        let backup = tokio::time::timeout(
+            SELF_TEST_TIMEOUT,
+            client.backup_to_federation(::fedimint_client::backup::Metadata::empty()),
+        )
+        .await;
+        checks.push(match backup {
+            Ok(Ok(_)) => SelfTestCheck {
+                name: "Backup to federation".to_string(),
+                passed: true,
+                detail: "Backup succeeded".to_string(),
+            },
+            Ok(Err(e)) => SelfTestCheck {
+                name: "Backup to federation".to_string(),
+                passed: false,
+                detail: e.to_string(),
+            },
+            Err(_) => SelfTestCheck {
+                name: "Backup to federation".to_string(),
+                passed: false,
+                detail: "Timed out".to_string(),
+            },
+        });
+
+        Ok(SelfTestReport {
+            federation_id,
+            checks,
+        })
     }
 
     async fn get_cashu_client(&self, mint_url: &MintUrl) -> cdk::Wallet {
@@ -540,11 +2362,16 @@ impl HarborCore {
         client: &ClientHandleArc,
         msg_id: Uuid,
         invoice: Bolt11Invoice,
+        fee_limit: Option<Amount>,
     ) -> anyhow::Result<OperationId> {
         let enable_lnv2 = cfg!(feature = "lnv2");
         if !enable_lnv2 {
             return Err(anyhow::anyhow!("LNv2 is not enabled"));
         }
+        // LNv2 picks its gateway itself, so its fee can't be checked before paying
+        if fee_limit.is_some() {
+            return Err(anyhow::anyhow!("LNv2 can't pay with a fee limit"));
+        }
 
         log::info!("Trying to pay {invoice} with LNv2...");
         let lnv2_module =
@@ -560,25 +2387,128 @@ impl HarborCore {
         msg_id: Uuid,
         from: MintIdentifier,
         invoice: Bolt11Invoice,
+        amount: Option<Amount>,
+        fee_limit: Option<Amount>,
         is_transfer: bool,
-    ) -> anyhow::Result<()> {
-        if invoice.amount_milli_satoshis().is_none() {
-            return Err(anyhow!("Invoice must have an amount"));
+    ) -> anyhow::Result<String> {
+        let amount = invoice_pay_amount(
+            invoice.amount_milli_satoshis().map(Amount::from_msats),
+            amount,
+        )?;
+
+        let key = payment_idempotency_key(&from, &invoice.to_string(), Some(amount.msats));
+        let _guard = self.payment_locks.lock(&key).await;
+        if let Some(operation_id) = self.recent_payment(&key)? {
+            log::warn!("Ignoring duplicate payment of {invoice}, already started {operation_id}");
+            self.payment_already_started(msg_id, &operation_id).await;
+            return Ok(operation_id);
+        }
+
+        if let Some(payment) = self
+            .storage
+            .get_paid_lightning_payment(*invoice.payment_hash())?
+        {
+            log::warn!(
+                "Refusing to pay {invoice} again, already paid in {}",
+                payment.operation_id
+            );
+            return Err(SendError::AlreadyPaid {
+                operation_id: payment.operation_id,
+                paid_at: payment.created_at.and_utc().timestamp() as u64,
+            }
+            .into());
         }
 
         self.status_update(msg_id, "Preparing to send lightning payment")
             .await;
 
-        match from {
+        let operation_id = match from {
             MintIdentifier::Cashu(mint_url) => {
-                self.send_lightning_from_cashu(msg_id, mint_url, invoice, is_transfer)
-                    .await
+                self.send_lightning_from_cashu(msg_id, mint_url, invoice, amount, is_transfer)
+                    .await?
             }
             MintIdentifier::Fedimint(id) => {
-                self.send_lightning_from_fedimint(msg_id, id, invoice, is_transfer)
-                    .await
+                self.send_lightning_from_fedimint(
+                    msg_id,
+                    id,
+                    invoice,
+                    amount,
+                    fee_limit,
+                    is_transfer,
+                )
+                .await?
             }
-        }
+        };
+
+        self.storage
+            .set_idempotent_operation(key, operation_id.clone())?;
+
+        Ok(operation_id)
+    }
+
+    /// The operation that successfully paid the invoice with this payment hash, if we did.
+    /// Sends check this so an invoice pasted twice isn't paid twice.
+    pub fn was_invoice_paid(&self, payment_hash: sha256::Hash) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .storage
+            .get_paid_lightning_payment(payment_hash)?
+            .map(|payment| payment.operation_id))
+    }
+
+    /// Tells the UI a request was a duplicate, so it stops waiting on it
+    async fn payment_already_started(&self, msg_id: Uuid, operation_id: &str) {
+        self.msg(
+            msg_id,
+            CoreUIMsg::PaymentAlreadyStarted {
+                operation_id: operation_id.to_string(),
+            },
+        )
+        .await;
+    }
+
+    /// Returns the operation already started for an idempotency key within
+    /// [`PAYMENT_IDEMPOTENCY_WINDOW`], unless that payment has failed.
+    fn recent_payment(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(operation_id) = self
+            .storage
+            .get_idempotent_operation(key.to_string(), PAYMENT_IDEMPOTENCY_WINDOW)?
+        else {
+            return Ok(None);
+        };
+
+        let status = match self.storage.get_lightning_payment(operation_id.clone())? {
+            Some(payment) => Some(payment.status()),
+            None => self
+                .storage
+                .get_onchain_payment(operation_id.clone())?
+                .map(|p| p.status()),
+        };
+
+        Ok(status
+            .filter(|s| *s != PaymentStatus::Failed)
+            .map(|_| operation_id))
+    }
+
+    /// Whether one of a federation's gateways can route a payment of the given amount, as far
+    /// as the gateway advertises. Gateways that don't advertise limits are assumed to manage.
+    pub async fn gateway_can_route(
+        &self,
+        federation_id: FederationId,
+        gateway_id: PublicKey,
+        amount: Amount,
+    ) -> anyhow::Result<bool> {
+        let client = self.get_client(federation_id).await?.fedimint_client;
+        let ln = client
+            .get_first_module::<LightningClientModule>()
+            .map_err(|_| anyhow!("Federation does not have a lightning module"))?;
+        let gateway = ln
+            .list_gateways()
+            .await
+            .into_iter()
+            .find(|g| g.info.gateway_id == gateway_id)
+            .ok_or(anyhow!("Gateway not found"))?;
+
+        Ok(gateway_can_route(&gateway.info, amount))
     }
 
     pub async fn send_lightning_from_cashu(
@@ -586,33 +2516,40 @@ impl HarborCore {
         msg_id: Uuid,
         mint_url: MintUrl,
         invoice: Bolt11Invoice,
+        amount: Amount,
         is_transfer: bool,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<String> {
         log::info!("Paying lightning invoice: {invoice} from cashu mint: {mint_url}");
-        let amount = Amount::from_msats(invoice.amount_milli_satoshis().expect("must have amount"));
 
         let client = self.get_cashu_client(&mint_url).await;
 
         self.status_update(msg_id, "Getting quote").await;
 
-        let quote = client.melt_quote(invoice.to_string(), None).await?;
+        let options = invoice
+            .amount_milli_satoshis()
+            .is_none()
+            .then(|| MeltOptions::new_amountless(amount.msats));
+        let quote = client.melt_quote(invoice.to_string(), options).await?;
 
         log::info!("Sending lightning invoice: {invoice}");
 
         self.status_update(msg_id, "Creating payment transaction")
             .await;
 
+        let operation_id = quote.id.clone();
         self.storage.create_lightning_payment(
-            quote.id.clone(),
+            operation_id.clone(),
             None,
             Some(mint_url),
             invoice,
             amount,
             Amount::from_msats(quote.fee_reserve.into()),
+            OperationKind::LightningSend,
         )?;
 
         spawn_lightning_payment_thread(
             self.tx.clone(),
+            self.balance_updates.clone(),
             client,
             self.storage.clone(),
             quote,
@@ -625,7 +2562,7 @@ impl HarborCore {
 
         log::info!("Payment sent");
 
-        Ok(())
+        Ok(operation_id)
     }
 
     pub async fn send_lightning_from_fedimint(
@@ -633,15 +2570,44 @@ impl HarborCore {
         msg_id: Uuid,
         federation_id: FederationId,
         invoice: Bolt11Invoice,
+        amount: Amount,
+        fee_limit: Option<Amount>,
         is_transfer: bool,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<String> {
         log::info!("Paying lightning invoice: {invoice} from federation: {federation_id}");
-        let amount = Amount::from_msats(invoice.amount_milli_satoshis().expect("must have amount"));
+        self.check_unlocked(federation_id).await?;
+
+        // The federation lightning modules only pay the amount in the invoice
+        if invoice.amount_milli_satoshis().is_none() {
+            return Err(anyhow!(
+                "Federations can't pay invoices without an amount yet, try a cashu mint"
+            ));
+        }
 
-        let client = self.get_client(federation_id).await.fedimint_client;
+        let fedimint_client = self.get_client(federation_id).await?;
+        let client = fedimint_client.fedimint_client;
+
+        // Fail early if the balance can't even cover the invoice, fees are checked once known
+        let balance = client.get_balance().await;
+        if amount > balance {
+            return Err(SendError::InsufficientFunds {
+                needed: amount,
+                available: balance,
+            }
+            .into());
+        }
+
+        // A limit given for this payment wins over the profile's
+        let fee_limit = match fee_limit {
+            Some(limit) => Some(limit),
+            None => self.storage.get_profile()?.and_then(|p| p.max_send_fee()),
+        };
 
         // Try sending using LNv2 first, if that doesn't work fall back to using LNv1
-        match self.send_lnv2(&client, msg_id, invoice.clone()).await {
+        let operation_id = match self
+            .send_lnv2(&client, msg_id, invoice.clone(), fee_limit)
+            .await
+        {
             Ok(operation_id) => {
                 let lnv2_module = client
                     .get_first_module::<fedimint_lnv2_client::LightningClientModule>()
@@ -669,6 +2635,7 @@ impl HarborCore {
                     invoice,
                     amount,
                     fees,
+                    OperationKind::LightningSend,
                 )?;
 
                 let sub = lnv2_module
@@ -677,6 +2644,7 @@ impl HarborCore {
                 spawn_lnv2_payment_subscription(
                     self.tx.clone(),
                     client,
+                    fedimint_client.subscriptions.clone(),
                     self.storage.clone(),
                     operation_id,
                     msg_id,
@@ -684,29 +2652,39 @@ impl HarborCore {
                     sub,
                 )
                 .await;
+
+                operation_id
             }
             Err(err) => {
                 log::warn!("LNv2 payment failed, trying LNv1. {err}");
                 let lightning_module = client
                     .get_first_module::<LightningClientModule>()
-                    .expect("must have ln module");
+                    .map_err(|_| anyhow!("Federation does not have a lightning module"))?;
 
                 self.status_update(msg_id, "Selecting gateway and calculating fees")
                     .await;
 
-                let gateway = select_gateway(&client)
-                    .await
-                    .ok_or(anyhow!("Internal error: No gateway found for federation"))?;
+                let require_private = self.require_private_payments();
+                let ranking = self.gateway_ranking(client.federation_id());
+                let gateway = match fee_limit {
+                    Some(limit) => {
+                        select_gateway_within_fee(&client, amount, limit, require_private, &ranking)
+                            .await?
+                    }
+                    None => select_gateway(&client, amount, require_private, &ranking).await?,
+                };
+                self.remember_gateway(msg_id, client.federation_id(), ranking.preferred, &gateway)
+                    .await;
 
                 let fees = gateway.fees.to_amount(&amount);
                 let total = fees + amount;
                 let balance = client.get_balance().await;
                 if total > balance {
-                    return Err(anyhow!(
-                        "Insufficient balance: Cannot pay {} sats, current balance is only {} sats",
-                        total.sats_round_down(),
-                        balance.sats_round_down()
-                    ));
+                    return Err(SendError::InsufficientFunds {
+                        needed: total,
+                        available: balance,
+                    }
+                    .into());
                 }
 
                 log::info!("Sending lightning invoice: {invoice}, paying fees: {fees}");
@@ -715,6 +2693,10 @@ impl HarborCore {
                 self.status_update(msg_id, "Creating payment transaction")
                     .await;
 
+                let gateway_route = PaymentRoute::Gateway {
+                    alias: gateway.lightning_alias.clone(),
+                    node_pub_key: gateway.node_pub_key,
+                };
                 let outgoing = lightning_module
                     .pay_bolt11_invoice(Some(gateway), invoice.clone(), ())
                     .await?;
@@ -722,14 +2704,22 @@ impl HarborCore {
                 self.status_update(msg_id, "Waiting for payment confirmation")
                     .await;
 
+                let operation_id = outgoing.payment_type.operation_id();
+                let (kind, route) = match outgoing.payment_type {
+                    PayType::Internal(_) => (OperationKind::InternalSend, PaymentRoute::Internal),
+                    PayType::Lightning(_) => (OperationKind::LightningSend, gateway_route),
+                };
                 self.storage.create_lightning_payment(
-                    outgoing.payment_type.operation_id().fmt_full().to_string(),
+                    operation_id.fmt_full().to_string(),
                     Some(client.federation_id()),
                     None,
                     invoice,
                     amount,
                     fees,
+                    kind,
                 )?;
+                self.storage
+                    .set_lightning_payment_route(operation_id.fmt_full().to_string(), route)?;
 
                 match outgoing.payment_type {
                     PayType::Internal(op_id) => {
@@ -737,6 +2727,7 @@ impl HarborCore {
                         spawn_internal_payment_subscription(
                             self.tx.clone(),
                             client,
+                            fedimint_client.subscriptions.clone(),
                             self.storage.clone(),
                             op_id,
                             msg_id,
@@ -749,6 +2740,7 @@ impl HarborCore {
                         spawn_invoice_payment_subscription(
                             self.tx.clone(),
                             client,
+                            fedimint_client.subscriptions.clone(),
                             self.storage.clone(),
                             op_id,
                             msg_id,
@@ -758,12 +2750,106 @@ impl HarborCore {
                         .await;
                     }
                 }
+
+                operation_id
             }
-        }
+        };
 
         log::info!("Payment sent");
 
-        Ok(())
+        Ok(operation_id.fmt_full().to_string())
+    }
+
+    /// Gets the invoice and preimage for a completed lightning payment, which together
+    /// prove the payment was made.
+    pub fn proof_of_payment(
+        &self,
+        operation_id: OperationId,
+    ) -> anyhow::Result<Option<(Bolt11Invoice, [u8; 32])>> {
+        let payment = self
+            .storage
+            .get_lightning_payment(operation_id.fmt_full().to_string())?;
+        Ok(payment.and_then(|p| p.proof_of_payment()))
+    }
+
+    /// Waits for a lightning payment from a federation, LNv1 or LNv2, to reach a final state.
+    /// Storage and the UI are updated the same way as for payments started from the UI,
+    /// with the UI's messages sent for `msg_id`.
+    pub async fn await_payment(
+        &self,
+        msg_id: Uuid,
+        operation_id: OperationId,
+    ) -> Result<SendSuccessMsg, SendError> {
+        let payment = self
+            .storage
+            .get_lightning_payment(operation_id.fmt_full().to_string())
+            .map_err(|e| SendError::Failed(e.to_string()))?
+            .ok_or(SendError::Failed("Payment not found".to_string()))?;
+        let federation_id = payment.fedimint_id().ok_or(SendError::Failed(
+            "Payment is not from a federation".to_string(),
+        ))?;
+
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(SendError::Failed(
+                "No client found for federation".to_string(),
+            ))?
+            .fedimint_client
+            .clone();
+
+        let is_lnv2 = client
+            .operation_log()
+            .get_operation(operation_id)
+            .await
+            .is_some_and(|op| op.operation_module_kind() == fedimint_lnv2_common::KIND.as_str());
+        if is_lnv2 {
+            let lnv2_module = client
+                .get_first_module::<fedimint_lnv2_client::LightningClientModule>()
+                .map_err(|_| SendError::Failed("Federation does not have LNv2".into()))?;
+            let sub = lnv2_module
+                .subscribe_send_operation_state_updates(operation_id)
+                .await
+                .map_err(|e| SendError::Failed(e.to_string()))?;
+
+            let span = subscription_span("lnv2_pay", &client, operation_id, msg_id);
+            return drive_lnv2_payment(
+                self.tx.clone(),
+                client,
+                self.subscription_limit.clone(),
+                self.storage.clone(),
+                operation_id,
+                msg_id,
+                false,
+                sub,
+            )
+            .instrument(span)
+            .await;
+        }
+
+        let lightning_module = client
+            .get_first_module::<LightningClientModule>()
+            .map_err(|_| SendError::Failed("Federation does not have a lightning module".into()))?;
+        let sub = lightning_module
+            .subscribe_ln_pay(operation_id)
+            .await
+            .map_err(|e| SendError::Failed(e.to_string()))?;
+
+        let span = subscription_span("ln_pay", &client, operation_id, msg_id);
+        drive_invoice_payment(
+            self.tx.clone(),
+            client,
+            self.subscription_limit.clone(),
+            self.storage.clone(),
+            operation_id,
+            msg_id,
+            false,
+            sub,
+        )
+        .instrument(span)
+        .await
     }
 
     pub async fn send_lnurl_pay(
@@ -778,36 +2864,86 @@ impl HarborCore {
         log::info!("Sending lnurl pay: {lnurl} from mint: {mint_identifier:?}");
 
         let tor_enabled = self.tor_enabled.load(Ordering::Relaxed);
-        self.status_update(msg_id, "Fetching payment details from recipient")
-            .await;
-
-        let pay_response =
-            make_lnurl_request(&lnurl, tor_enabled, self.metadata_fetch_cancel.clone()).await?;
-        log::info!("Pay response: {pay_response:?}");
-
         self.status_update(msg_id, "Requesting invoice from recipient")
             .await;
 
-        let amount_msats = amount_sats * 1000;
-        let invoice_response = lightning_address::get_invoice(
-            &pay_response,
-            amount_msats,
+        let invoice = request_lnurl_invoice(
+            &lnurl,
+            Amount::from_sats(amount_sats),
             tor_enabled,
             self.metadata_fetch_cancel.clone(),
         )
         .await?;
-        log::info!("Invoice response: {invoice_response:?}");
-
-        let invoice =
-            fedimint_ln_common::lightning_invoice::Bolt11Invoice::from_str(&invoice_response.pr)?;
+        log::info!("Invoice from recipient: {invoice}");
 
         // Now we'll let send_lightning handle the rest of the status updates
-        self.send_lightning(msg_id, mint_identifier, invoice, false)
+        self.send_lightning(msg_id, mint_identifier, invoice, None, None, false)
             .await?;
 
         Ok(())
     }
 
+    /// Pulls funds from an LNURL-withdraw service into the mint. As much as the service
+    /// allows is withdrawn, and the invoice for it is tracked like any other receive.
+    /// The invoice is sent to the UI before the service is asked to pay it, if the service
+    /// refuses the receive is marked as failed.
+    pub async fn receive_lnurl_withdraw(
+        &self,
+        msg_id: Uuid,
+        mint_identifier: MintIdentifier,
+        lnurl: LnUrl,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        log::info!("Receiving lnurl withdraw: {lnurl} into mint: {mint_identifier:?}");
+
+        let tor_enabled = self.tor_enabled.load(Ordering::Relaxed);
+        self.status_update(msg_id, "Fetching withdraw details")
+            .await;
+
+        let withdraw =
+            make_withdraw_request(&lnurl, tor_enabled, self.metadata_fetch_cancel.clone()).await?;
+        let amount = withdraw_amount(withdraw.min_withdrawable, withdraw.max_withdrawable)?;
+
+        let invoice = self
+            .receive_lightning(msg_id, mint_identifier, amount, false)
+            .await?;
+        // the service may pay before it answers, so the invoice is shown first
+        self.msg(msg_id, CoreUIMsg::ReceiveInvoiceGenerated(invoice.clone()))
+            .await;
+
+        self.status_update(msg_id, "Asking the service to pay")
+            .await;
+        if let Err(e) = submit_withdraw_invoice(
+            &withdraw,
+            &invoice,
+            tor_enabled,
+            self.metadata_fetch_cancel.clone(),
+        )
+        .await
+        {
+            // the service won't pay it, so it shouldn't be left pending
+            self.fail_lightning_receive(&invoice);
+            return Err(e);
+        }
+
+        Ok(invoice)
+    }
+
+    /// Marks the pending receive for the invoice as failed
+    fn fail_lightning_receive(&self, invoice: &Bolt11Invoice) {
+        let pending = match self.storage.get_pending_lightning_receives() {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Could not get pending lightning receives: {e}");
+                return;
+            }
+        };
+        for receive in pending.into_iter().filter(|r| &r.bolt11() == invoice) {
+            if let Err(e) = self.storage.mark_ln_receive_as_failed(receive.operation_id) {
+                error!("Could not mark lightning receive as failed: {e}");
+            }
+        }
+    }
+
     async fn receive_lnv2(
         &self,
         client: &ClientHandleArc,
@@ -867,7 +3003,8 @@ impl HarborCore {
             "Creating lightning invoice, amount: {amount} for federation: {federation_id}. Tor enabled: {tor_enabled}"
         );
 
-        let client = self.get_client(federation_id).await.fedimint_client;
+        let fedimint_client = self.get_client(federation_id).await?;
+        let client = fedimint_client.fedimint_client;
         match self.receive_lnv2(&client, msg_id, amount).await {
             Ok((invoice, operation_id)) => {
                 let operation = client
@@ -904,7 +3041,9 @@ impl HarborCore {
                     .await?;
                 spawn_lnv2_receive_subscription(
                     self.tx.clone(),
+                    self.receive_batch.clone(),
                     client.clone(),
+                    fedimint_client.subscriptions.clone(),
                     self.storage.clone(),
                     operation_id,
                     msg_id,
@@ -920,14 +3059,16 @@ impl HarborCore {
 
                 let lightning_module = client
                     .get_first_module::<LightningClientModule>()
-                    .expect("must have ln module");
+                    .map_err(|_| anyhow!("Federation does not have a lightning module"))?;
                 log::info!("Lightning module: {:?}", lightning_module.id);
 
                 self.status_update(msg_id, "Selecting gateway").await;
 
-                let gateway = select_gateway(&client)
-                    .await
-                    .ok_or(anyhow!("Internal error: No gateway found for federation"))?;
+                let require_private = self.require_private_payments();
+                let ranking = self.gateway_ranking(client.federation_id());
+                let gateway = select_gateway(&client, amount, require_private, &ranking).await?;
+                self.remember_gateway(msg_id, client.federation_id(), ranking.preferred, &gateway)
+                    .await;
                 log::info!("Gateway: {gateway:?}");
 
                 self.status_update(msg_id, "Generating invoice").await;
@@ -959,7 +3100,9 @@ impl HarborCore {
                     Ok(subscription) => {
                         spawn_invoice_receive_subscription(
                             self.tx.clone(),
+                            self.receive_batch.clone(),
                             client.clone(),
+                            fedimint_client.subscriptions.clone(),
                             self.storage.clone(),
                             op_id,
                             msg_id,
@@ -1015,6 +3158,7 @@ impl HarborCore {
 
         spawn_lightning_receive_thread(
             self.tx.clone(),
+            self.balance_updates.clone(),
             client,
             self.storage.clone(),
             quote,
@@ -1041,7 +3185,8 @@ impl HarborCore {
         self.status_update(msg_id, "Paying invoice from source mint")
             .await;
 
-        self.send_lightning(msg_id, from, invoice, true).await?;
+        self.send_lightning(msg_id, from, invoice, None, None, true)
+            .await?;
         Ok(())
     }
 
@@ -1052,62 +3197,116 @@ impl HarborCore {
         federation_id: FederationId,
         address: Address<NetworkUnchecked>,
         sats: Option<u64>,
-    ) -> anyhow::Result<()> {
+        fee_rate: FeeRate,
+    ) -> anyhow::Result<String> {
+        self.withdraw_onchain(msg_id, federation_id, address, sats, fee_rate, None)
+            .await
+    }
+
+    /// The amount a withdrawal would send and the fee it would pay, without starting it.
+    /// With no amount everything is sent, less the fee. Sent as
+    /// [`CoreUIMsg::WithdrawFeeEstimate`] so the fee can be shown before the user confirms.
+    pub async fn estimate_withdraw_fee(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+        address: Address<NetworkUnchecked>,
+        sats: Option<u64>,
+        fee_rate: FeeRate,
+    ) -> anyhow::Result<(Amount, Amount)> {
         let address = address
             .require_network(self.network)
             .map_err(|_| anyhow!("Address is for wrong network"))?;
+        let client = self.get_client(federation_id).await?;
 
-        log::info!(
-            "Sending onchain payment to address: {address} from federation: {federation_id}",
-        );
-        let client = self.get_client(federation_id).await.fedimint_client;
-        let onchain = client
-            .get_first_module::<WalletClientModule>()
-            .expect("must have wallet module");
-
-        let (fees, amount) = match sats {
-            Some(sats) => {
-                let amount = bitcoin::Amount::from_sat(sats);
-                let fees = onchain.get_withdraw_fees(&address, amount).await?;
-                (fees, amount)
-            }
-            None => {
-                let balance = client.get_balance().await;
-
-                if balance.sats_round_down() == 0 {
-                    return Err(anyhow!("No funds in wallet"));
-                }
+        let (amount, fees) = withdraw_amount(&client, &address, sats, fee_rate).await?;
+        let amount = Amount::from_sats(amount.to_sat());
+        let fee = Amount::from_sats(fees.amount().to_sat());
+        log::info!("Withdrawing {amount} to {address} would pay {fee} in fees");
+        self.msg(msg_id, CoreUIMsg::WithdrawFeeEstimate { amount, fee })
+            .await;
+        Ok((amount, fee))
+    }
 
-                // get fees for the entire balance
-                let fees = onchain
-                    .get_withdraw_fees(
-                        &address,
-                        bitcoin::Amount::from_sat(balance.sats_round_down()),
-                    )
-                    .await?;
+    /// Starts a new withdrawal to the same address and for the same amount as a failed one.
+    /// The fee rate can be raised above the federation's estimate, the new withdrawal is
+    /// linked to the failed one in history.
+    pub async fn retry_withdrawal(
+        &self,
+        msg_id: Uuid,
+        operation_id: OperationId,
+        fee_rate_sats_per_vb: Option<u64>,
+    ) -> anyhow::Result<String> {
+        let payment = self
+            .storage
+            .get_onchain_payment(operation_id.fmt_full().to_string())?
+            .ok_or(anyhow!("Withdrawal not found"))?;
+        if payment.status() != PaymentStatus::Failed || payment.txid().is_some() {
+            return Err(anyhow!("Only failed withdrawals can be retried"));
+        }
+        if let Some(retry) = self
+            .storage
+            .get_onchain_payment_retry(operation_id.fmt_full().to_string())?
+        {
+            return Err(anyhow!(
+                "Withdrawal was already retried as {}",
+                retry.operation_id().fmt_full()
+            ));
+        }
+        let federation_id = payment
+            .fedimint_id()
+            .ok_or(anyhow!("Only federation withdrawals can be retried"))?;
 
-                let fees_paid = Amount::from_sats(fees.amount().to_sat());
-                let amount = balance.saturating_sub(fees_paid);
+        log::info!("Retrying failed withdrawal {}", operation_id.fmt_full());
+        self.withdraw_onchain(
+            msg_id,
+            federation_id,
+            payment.address(),
+            Some(payment.amount_sats as u64),
+            fee_rate_sats_per_vb.map_or(FeeRate::Economy, FeeRate::SatsPerVByte),
+            Some(operation_id),
+        )
+        .await
+    }
 
-                if amount.sats_round_down() < 546 {
-                    return Err(anyhow!("Not enough funds to send"));
-                }
+    async fn withdraw_onchain(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+        address: Address<NetworkUnchecked>,
+        sats: Option<u64>,
+        fee_rate: FeeRate,
+        retry_of: Option<OperationId>,
+    ) -> anyhow::Result<String> {
+        self.check_unlocked(federation_id).await?;
 
-                (fees, bitcoin::Amount::from_sat(amount.sats_round_down()))
-            }
-        };
+        let address = address
+            .require_network(self.network)
+            .map_err(|_| anyhow!("Address is for wrong network"))?;
 
-        let total = fees.amount() + amount;
-        let balance = client.get_balance().await;
-        if total > bitcoin::Amount::from_sat(balance.sats_round_down()) {
-            return Err(anyhow!(
-                "Insufficient balance: Cannot pay {} sats, current balance is only {} sats",
-                total.to_sat(),
-                balance.sats_round_down()
-            ));
+        let key = payment_idempotency_key(
+            &MintIdentifier::Fedimint(federation_id),
+            &address.to_string(),
+            sats.map(|s| s * 1_000),
+        );
+        let _guard = self.payment_locks.lock(&key).await;
+        if let Some(operation_id) = self.recent_payment(&key)? {
+            log::warn!("Ignoring duplicate payment to {address}, already started {operation_id}");
+            self.payment_already_started(msg_id, &operation_id).await;
+            return Ok(operation_id);
         }
 
-        let op_id = onchain.withdraw(&address, amount, fees, ()).await?;
+        log::info!(
+            "Sending onchain payment to address: {address} from federation: {federation_id}",
+        );
+        let fedimint_client = self.get_client(federation_id).await?;
+        let (amount, fees) = withdraw_amount(&fedimint_client, &address, sats, fee_rate).await?;
+        let op_id = fedimint_client
+            .withdraw(address.clone(), amount, fees)
+            .await?;
+
+        let client = fedimint_client.fedimint_client;
+        let onchain = client.get_first_module::<WalletClientModule>()?;
 
         self.storage.create_onchain_payment(
             op_id.fmt_full().to_string(),
@@ -1116,6 +3315,7 @@ impl HarborCore {
             address,
             amount.to_sat(),
             fees.amount().to_sat(),
+            retry_of,
         )?;
 
         let sub = onchain.subscribe_withdraw_updates(op_id).await?;
@@ -1123,6 +3323,7 @@ impl HarborCore {
         spawn_onchain_payment_subscription(
             self.tx.clone(),
             client.clone(),
+            fedimint_client.subscriptions.clone(),
             self.storage.clone(),
             op_id,
             msg_id,
@@ -1130,13 +3331,166 @@ impl HarborCore {
         )
         .await;
 
-        Ok(())
+        let operation_id = op_id.fmt_full().to_string();
+        self.storage
+            .set_idempotent_operation(key, operation_id.clone())?;
+
+        Ok(operation_id)
+    }
+
+    /// Spends ecash from the federation as notes that can be handed to someone else,
+    /// returned encoded. Once they are redeemed [`SendSuccessMsg::Ecash`] is sent, if they
+    /// aren't redeemed within `try_cancel_after` they are reclaimed and
+    /// [`CoreUIMsg::EcashReclaimed`] is sent.
+    pub async fn spend_ecash(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+        amount: Amount,
+        try_cancel_after: Duration,
+    ) -> anyhow::Result<String> {
+        log::info!("Spending {amount} of ecash from federation: {federation_id}");
+        self.check_unlocked(federation_id).await?;
+
+        let fedimint_client = self.get_client(federation_id).await?;
+        let (operation_id, notes) = fedimint_client
+            .spend_ecash(amount, try_cancel_after)
+            .await?;
+
+        let client = fedimint_client.fedimint_client;
+        let mint = client.get_first_module::<MintClientModule>()?;
+        let sub = mint.subscribe_spend_notes(operation_id).await?;
+        let spent = OOBNotes::from_str(&notes)?.total_amount();
+        spawn_ecash_spend_subscription(
+            self.tx.clone(),
+            client.clone(),
+            fedimint_client.subscriptions.clone(),
+            self.storage.clone(),
+            operation_id,
+            msg_id,
+            spent,
+            sub,
+        )
+        .await;
+
+        self.send_balance_update(
+            Some(msg_id),
+            MintIdentifier::Fedimint(federation_id),
+            client.get_balance().await,
+        )
+        .await;
+
+        Ok(notes)
+    }
+
+    /// Claims ecash notes into the federation they are from, which must be one we have
+    /// joined. [`ReceiveSuccessMsg::Ecash`] is sent once they are reissued, or
+    /// [`CoreUIMsg::ReceiveFailed`] if they were already spent.
+    pub async fn receive_ecash(&self, msg_id: Uuid, notes: String) -> anyhow::Result<Amount> {
+        let parsed =
+            OOBNotes::from_str(notes.trim()).map_err(|e| anyhow!("Invalid ecash notes: {e}"))?;
+        let prefix = parsed.federation_id_prefix();
+        let fedimint_client = self
+            .clients
+            .read()
+            .await
+            .values()
+            .find(|c| c.federation_id().to_prefix() == prefix)
+            .cloned()
+            .ok_or(anyhow!(
+                "Ecash is from federation {prefix}, which is not one of your mints"
+            ))?;
+        log::info!(
+            "Receiving ecash into federation: {}",
+            fedimint_client.federation_id()
+        );
+
+        let (operation_id, amount) = fedimint_client.reissue_ecash(notes).await?;
+
+        let client = fedimint_client.fedimint_client;
+        let mint = client.get_first_module::<MintClientModule>()?;
+        let sub = mint.subscribe_reissue_external_notes(operation_id).await?;
+        spawn_ecash_reissue_subscription(
+            self.tx.clone(),
+            client,
+            fedimint_client.subscriptions.clone(),
+            self.storage.clone(),
+            operation_id,
+            msg_id,
+            sub,
+        )
+        .await;
+
+        Ok(amount)
+    }
+
+    /// Checks ecash notes against the mint module of the federation they are from, without
+    /// claiming them or touching the balance. Fails for notes from a federation we haven't
+    /// joined, as there are no keys to check them against.
+    pub async fn inspect_ecash(&self, notes: String) -> anyhow::Result<EcashInfo> {
+        let notes =
+            OOBNotes::from_str(notes.trim()).map_err(|e| anyhow!("Invalid ecash notes: {e}"))?;
+        let prefix = notes.federation_id_prefix();
+
+        let client = self
+            .clients
+            .read()
+            .await
+            .values()
+            .find(|c| c.federation_id().to_prefix() == prefix)
+            .map(|c| c.fedimint_client.clone())
+            .ok_or(anyhow!(
+                "Ecash is from federation {prefix}, which is not one of your mints"
+            ))?;
+
+        let mint = client.get_first_module::<MintClientModule>()?;
+        let (amount, valid) = match mint.validate_notes(&notes) {
+            Ok(amount) => (amount, true),
+            Err(e) => {
+                log::warn!("Ecash notes are not valid: {e}");
+                (notes.total_amount(), false)
+            }
+        };
+
+        Ok(EcashInfo {
+            federation_id: client.federation_id(),
+            amount,
+            valid,
+        })
+    }
+
+    /// Bumps the fee of a pending onchain withdrawal.
+    /// Federations reject RBF withdrawals so this always fails, we surface that instead of
+    /// silently leaving the withdrawal as is.
+    pub async fn bump_withdraw_fee(
+        &self,
+        operation_id: OperationId,
+        fee_rate_sats_per_vb: u64,
+    ) -> anyhow::Result<()> {
+        let payment = self
+            .storage
+            .get_onchain_payment(operation_id.fmt_full().to_string())?
+            .ok_or(anyhow!("Withdrawal not found"))?;
+        if payment.status() != PaymentStatus::Pending {
+            return Err(anyhow!("Withdrawal is no longer pending"));
+        }
+
+        log::warn!(
+            "Cannot bump fee of withdrawal {} to {fee_rate_sats_per_vb} sats/vB",
+            operation_id.fmt_full()
+        );
+        Err(anyhow!(
+            "Federation does not support bumping the fee of a withdrawal"
+        ))
     }
 
+    /// Gets a deposit address for the federation. A recently generated address that hasn't
+    /// been used yet is returned again, unless `force_new` is set.
     pub async fn receive_onchain(
         &self,
         msg_id: Uuid,
         federation_id: FederationId,
+        force_new: bool,
     ) -> anyhow::Result<Address> {
         // check if on-chain receive is enabled
         let profile = self.storage.get_profile()?;
@@ -1144,14 +3498,30 @@ impl HarborCore {
             return Err(anyhow!("on-chain receive is not enabled"));
         }
 
+        let _guard = self.address_lock.lock().await;
+        if !force_new {
+            if let Some(unused) = self
+                .storage
+                .get_unused_onchain_receive(federation_id, ONCHAIN_ADDRESS_REUSE_WINDOW)?
+            {
+                log::info!("Reusing unused address for federation: {federation_id}");
+                // the subscription for it is already running
+                return unused
+                    .address()
+                    .require_network(self.network)
+                    .map_err(|_| anyhow!("Address is for wrong network"));
+            }
+        }
+
         log::info!("Generating address for federation: {federation_id}");
 
         self.status_update(msg_id, "Connecting to mint").await;
 
-        let client = self.get_client(federation_id).await.fedimint_client;
+        let fedimint_client = self.get_client(federation_id).await?;
+        let client = fedimint_client.fedimint_client;
         let onchain = client
             .get_first_module::<WalletClientModule>()
-            .expect("must have wallet module");
+            .map_err(|_| FedimintError::OnchainUnsupported(federation_id))?;
 
         self.status_update(msg_id, "Generating address").await;
 
@@ -1169,6 +3539,7 @@ impl HarborCore {
         spawn_onchain_receive_subscription(
             self.tx.clone(),
             client.clone(),
+            fedimint_client.subscriptions.clone(),
             self.storage.clone(),
             op_id,
             msg_id,
@@ -1188,7 +3559,7 @@ impl HarborCore {
 
         self.status_update(msg_id, "Connecting to mint").await;
 
-        let seed = self.mnemonic.to_seed_normalized("");
+        let seed = self.cashu_seed()?;
 
         let builder = WalletBuilder::new()
             .mint_url(mint_url.clone())
@@ -1249,13 +3620,17 @@ impl HarborCore {
             } else {
                 fedimint_api_client::api::net::Connector::Tcp
             };
-            connector
-                .download_from_invite_code(&invite_code)
-                .await
-                .map_err(|e| {
-                    error!("Could not download federation info: {e}");
-                    e
-                })?
+            let timeout = self
+                .storage
+                .get_profile()?
+                .map_or(DEFAULT_JOIN_TIMEOUT, |p| p.join_timeout());
+            download_with_retry(
+                || download_config(&connector, &invite_code),
+                timeout,
+                &self.reconnect_policy,
+                &self.stop,
+            )
+            .await?
         };
         trace!(
             "Downloaded federation info in: {}ms",
@@ -1282,46 +3657,114 @@ impl HarborCore {
         Ok((config, metadata))
     }
 
-    pub async fn add_federation(
+    /// Joins a federation again with the invite code it was first joined with.
+    /// Fails with [`FedimintError::NeedInviteCode`] when no invite code was saved for it.
+    pub async fn rejoin_federation(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+    ) -> Result<JoinResult, FedimintError> {
+        let Some(invite_code) = self.storage.get_federation_invite_code(federation_id)? else {
+            return Err(FedimintError::NeedInviteCode { federation_id });
+        };
+        self.add_federation(msg_id, invite_code, JoinConfig::Cached)
+            .await
+    }
+
+    /// Joins a federation, `join_config` says where its config comes from if it is new to us
+    pub async fn add_federation(
+        &self,
+        msg_id: Uuid,
+        invite_code: InviteCode,
+        join_config: JoinConfig,
+    ) -> Result<JoinResult, FedimintError> {
+        log::info!("Adding federation with invite code: {invite_code}");
+        let id = invite_code.federation_id();
+
+        self.status_update(msg_id, "Starting mint setup").await;
+
+        let mut clients = self.clients.write().await;
+        if clients.get(&id).is_some() {
+            return Err(FedimintError::AlreadyJoined(id));
+        }
+
+        self.status_update(msg_id, "Initializing mint connection")
+            .await;
+
+        let join = FedimintClient::join(
+            &self.client_context(),
+            FederationInviteOrId::Invite(invite_code.clone()),
+            join_config,
+            Some(msg_id),
+        )
+        .await?;
+
+        self.status_update(msg_id, "Registering with mint").await;
+
+        clients.insert(id, join.client.clone());
+        drop(clients);
+        self.clear_aggregate_balance().await;
+
+        let tx = self.tx.clone();
+        let tor_enabled = self.tor_enabled.load(Ordering::Relaxed);
+        let metadata_fetch_cancel = self.metadata_fetch_cancel.clone();
+        let storage = self.storage.clone();
+        let client = join.client.fedimint_client.clone();
+        tokio::task::spawn(async move {
+            Self::update_mint_metadata(
+                vec![client],
+                metadata_fetch_cancel,
+                tor_enabled,
+                storage,
+                tx,
+            )
+            .await;
+        });
+
+        self.status_update(msg_id, "Mint setup complete!").await;
+
+        Ok(join)
+    }
+
+    /// Restores our ecash in a federation from the seed, see [`FedimintClient::recover`].
+    pub async fn recover_federation(
         &self,
         msg_id: Uuid,
         invite_code: InviteCode,
-    ) -> anyhow::Result<()> {
-        log::info!("Adding federation with invite code: {invite_code}");
+    ) -> Result<JoinResult, FedimintError> {
+        log::info!("Recovering federation with invite code: {invite_code}");
         let id = invite_code.federation_id();
 
-        self.status_update(msg_id, "Starting mint setup").await;
-
-        let mut clients = self.clients.write().await;
-        if clients.get(&id).is_some() {
-            return Err(anyhow!("Federation already added"));
+        // a recovery can take hours, the other federations can't wait on the lock that long
+        if self.clients.read().await.contains_key(&id) {
+            return Err(FedimintError::AlreadyJoined(id));
         }
 
-        self.status_update(msg_id, "Initializing mint connection")
+        self.status_update(msg_id, "Recovering mint from seed")
             .await;
 
-        let client = FedimintClient::new(
-            self.storage.clone(),
-            FederationInviteOrId::Invite(invite_code.clone()),
-            &self.mnemonic,
-            self.network,
-            self.stop.clone(),
-            self.tx.clone(),
-            Some(msg_id),
-        )
-        .await?;
-
-        self.status_update(msg_id, "Registering with mint").await;
+        let join =
+            FedimintClient::recover(&self.client_context(), invite_code, Some(msg_id)).await?;
 
-        clients.insert(id, client.clone());
+        let mut clients = self.clients.write().await;
+        if clients.contains_key(&id) {
+            // joined while we were recovering, the client already in use is kept
+            drop(clients);
+            join.client.shut_down().await;
+            return Err(FedimintError::AlreadyJoined(id));
+        }
+        clients.insert(id, join.client.clone());
+        drop(clients);
+        self.clear_aggregate_balance().await;
 
         let tx = self.tx.clone();
         let tor_enabled = self.tor_enabled.load(Ordering::Relaxed);
         let metadata_fetch_cancel = self.metadata_fetch_cancel.clone();
         let storage = self.storage.clone();
+        let client = join.client.fedimint_client.clone();
         tokio::task::spawn(async move {
             Self::update_mint_metadata(
-                vec![client.fedimint_client],
+                vec![client],
                 metadata_fetch_cancel,
                 tor_enabled,
                 storage,
@@ -1330,9 +3773,153 @@ impl HarborCore {
             .await;
         });
 
-        self.status_update(msg_id, "Mint setup complete!").await;
+        self.status_update(msg_id, "Mint recovered").await;
 
-        Ok(())
+        Ok(join)
+    }
+
+    /// Failed attempts to join a federation, newest first, for diagnosing join problems
+    pub fn recent_join_failures(&self) -> anyhow::Result<Vec<JoinFailure>> {
+        self.storage.recent_join_failures()
+    }
+
+    /// Dumps a federation's saved key/value pairs as hex, for debugging storage issues
+    #[cfg(feature = "debug-dump")]
+    pub fn dump_federation_kv(
+        &self,
+        federation_id: FederationId,
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let blob = self
+            .storage
+            .get_federation_value(federation_id.to_string())?
+            .ok_or(anyhow!("Federation not found"))?;
+        let encryptor = SeedEncryptor::new(self.secret_provider.as_ref());
+
+        // the blob is only set until the client loads it into separate pairs
+        if !blob.is_empty() {
+            let (data, _) = open_federation_data(&encryptor, &blob)?;
+            return Ok(federation_kv_hex(&data)?);
+        }
+        Ok(
+            load_federation_kv(self.storage.as_ref(), &encryptor, federation_id)?
+                .into_iter()
+                .map(|(key, value)| (hex::encode(key), hex::encode(value)))
+                .collect(),
+        )
+    }
+
+    /// Bundles everything needed to restore a federation on another device with the same seed.
+    /// A federation with a spend password has to be unlocked first.
+    pub async fn export_federation_backup(
+        &self,
+        federation_id: FederationId,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.check_unlocked(federation_id).await?;
+
+        let client = self.get_client(federation_id).await?.fedimint_client;
+        let config = serde_json::to_string(&client.config().await)?;
+
+        let invite_code = self
+            .storage
+            .get_federation_invite_code(federation_id)?
+            .ok_or(anyhow!("Federation not found"))?;
+        let encryptor = SeedEncryptor::new(self.secret_provider.as_ref());
+        let pairs = load_federation_kv(self.storage.as_ref(), &encryptor, federation_id)?;
+        if pairs.is_empty() {
+            return Err(anyhow!("Federation has no saved state"));
+        }
+        let value = seal_federation_data(&encryptor, &pairs)?;
+
+        let backup = FederationBackup {
+            version: FEDERATION_BACKUP_VERSION,
+            network: self.network.to_string(),
+            federation_id,
+            invite_code: invite_code.to_string(),
+            config,
+            metadata: self.storage.get_federation_metadata(federation_id)?,
+            value,
+        };
+
+        Ok(bincode::serialize(&backup)?)
+    }
+
+    /// Restores a federation from [`HarborCore::export_federation_backup`] and opens its client.
+    /// A federation that's already saved is only replaced when `overwrite` is set.
+    pub async fn import_federation_backup(
+        &self,
+        msg_id: Uuid,
+        bytes: Vec<u8>,
+        overwrite: bool,
+    ) -> anyhow::Result<FederationId> {
+        let backup: FederationBackup =
+            bincode::deserialize(&bytes).map_err(|_| anyhow!("Not a valid federation backup"))?;
+
+        if backup.version != FEDERATION_BACKUP_VERSION {
+            return Err(anyhow!(
+                "Unsupported federation backup version {}",
+                backup.version
+            ));
+        }
+        if backup.network != self.network.to_string() {
+            return Err(anyhow!(
+                "Backup is for {}, expected: {}",
+                backup.network,
+                self.network
+            ));
+        }
+
+        let config: ClientConfig = serde_json::from_str(&backup.config)?;
+        let id = backup.federation_id;
+        if config.calculate_federation_id() != id {
+            return Err(anyhow!("Backup config does not match its federation"));
+        }
+
+        self.status_update(msg_id, "Restoring mint from backup")
+            .await;
+
+        let mut clients = self.clients.write().await;
+        if clients.contains_key(&id) {
+            return Err(anyhow!("Federation is in use, remove it before importing"));
+        }
+
+        if self.storage.get_federation_value(id.to_string())?.is_some() {
+            if !overwrite {
+                return Err(anyhow!("Federation already exists"));
+            }
+            self.storage
+                .update_fedimint_data(id.to_string(), backup.value)?;
+            self.storage.set_federation_active(id)?;
+        } else {
+            self.storage.insert_new_federation(NewFedimint {
+                id: id.to_string(),
+                invite_code: backup.invite_code,
+                value: backup.value,
+                network: Some(backup.network),
+            })?;
+        }
+
+        if let Some(metadata) = backup.metadata {
+            self.storage.upsert_federation_metadata(id, metadata)?;
+        }
+
+        let client = FedimintClient::new(
+            &self.client_context(),
+            FederationInviteOrId::Id(id),
+            JoinConfig::Cached,
+            Some(msg_id),
+        )
+        .await?;
+        clients.insert(id, client);
+        drop(clients);
+        self.clear_aggregate_balance().await;
+
+        let items = self.get_mint_items().await?;
+        self.send_system_msg(CoreUIMsg::MintListUpdated(items))
+            .await;
+
+        self.status_update(msg_id, "Mint restored").await;
+
+        Ok(id)
     }
 
     pub async fn add_cashu_mint(&self, msg_id: Uuid, mint_url: MintUrl) -> anyhow::Result<()> {
@@ -1349,7 +3936,7 @@ impl HarborCore {
         self.status_update(msg_id, "Initializing mint connection")
             .await;
 
-        let seed = self.mnemonic.to_seed_normalized("");
+        let seed = self.cashu_seed()?;
 
         let builder = WalletBuilder::new()
             .mint_url(mint_url.clone())
@@ -1381,8 +3968,17 @@ impl HarborCore {
         Ok(())
     }
 
-    pub async fn remove_federation(&self, _msg_id: Uuid, id: FederationId) -> anyhow::Result<()> {
-        log::info!("Removing federation with id: {id}");
+    /// Removes a federation from the mint list. Federations are never deleted, this archives
+    /// it like [`HarborCore::archive_federation`].
+    pub async fn remove_federation(&self, msg_id: Uuid, id: FederationId) -> anyhow::Result<()> {
+        self.archive_federation(msg_id, id).await
+    }
+
+    /// Stops a federation's client and archives it, taking it out of the mint list. Its
+    /// history, metadata and client data are kept for records, and it can be brought back
+    /// with [`HarborCore::unarchive_federation`].
+    pub async fn archive_federation(&self, _msg_id: Uuid, id: FederationId) -> anyhow::Result<()> {
+        log::info!("Archiving federation with id: {id}");
 
         // Cancel any ongoing metadata fetch
         self.metadata_fetch_cancel.store(true, Ordering::Relaxed);
@@ -1390,24 +3986,91 @@ impl HarborCore {
         // Small delay to allow any in-progress operations to complete
         tokio::time::sleep(Duration::from_millis(100)).await;
 
-        let mut clients = self.clients.write().await;
-
-        // Check if federation exists before attempting removal
-        if !clients.contains_key(&id) {
+        // Remove from clients first
+        let Some(client) = self.clients.write().await.remove(&id) else {
             return Err(anyhow!("Federation doesn't exist"));
-        }
+        };
+        self.spend_unlocks.lock().await.remove(&id);
 
-        // Remove from clients first
-        clients.remove(&id);
-        drop(clients);
+        // stopped before it is dropped, unarchiving opens a new client on the same data
+        client.shut_down().await;
 
         // Then remove from storage
         self.storage.remove_federation(id)?;
+        self.clear_aggregate_balance().await;
 
         // Reset cancellation flag
         self.metadata_fetch_cancel.store(false, Ordering::Relaxed);
 
-        log::info!("Successfully removed federation: {id}");
+        log::info!("Successfully archived federation: {id}");
+        Ok(())
+    }
+
+    /// Leaves a federation, deleting its client data, see [`FedimintClient::leave_federation`].
+    /// Refused while the federation still holds funds.
+    pub async fn leave_federation(&self, _msg_id: Uuid, id: FederationId) -> anyhow::Result<()> {
+        log::info!("Leaving federation with id: {id}");
+
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .clone();
+        client.leave_federation().await?;
+
+        self.clients.write().await.remove(&id);
+        self.spend_unlocks.lock().await.remove(&id);
+        self.clear_aggregate_balance().await;
+
+        log::info!("Successfully left federation: {id}");
+        Ok(())
+    }
+
+    /// The federations that were archived, with the metadata saved for them
+    pub fn list_archived_federations(&self) -> anyhow::Result<Vec<MintMetadata>> {
+        self.storage.get_archived_fedimints()
+    }
+
+    /// Opens an archived federation's client again from its saved data and puts it back in
+    /// the mint list. Unlike joining again this doesn't need its invite code.
+    pub async fn unarchive_federation(&self, msg_id: Uuid, id: FederationId) -> anyhow::Result<()> {
+        log::info!("Unarchiving federation with id: {id}");
+
+        let mut clients = self.clients.write().await;
+        if clients.contains_key(&id) {
+            return Err(anyhow!("Federation is not archived"));
+        }
+        if self.storage.get_federation_value(id.to_string())?.is_none() {
+            return Err(anyhow!("Federation not found"));
+        }
+
+        self.storage.set_federation_active(id)?;
+        let client = match FedimintClient::new(
+            &self.client_context(),
+            FederationInviteOrId::Id(id),
+            JoinConfig::Cached,
+            Some(msg_id),
+        )
+        .await
+        {
+            Ok(client) => client,
+            Err(e) => {
+                // keep it archived rather than listed without a client
+                self.storage.remove_federation(id)?;
+                return Err(e.into());
+            }
+        };
+        clients.insert(id, client);
+        drop(clients);
+        self.clear_aggregate_balance().await;
+
+        let items = self.get_mint_items().await?;
+        self.send_system_msg(CoreUIMsg::MintListUpdated(items))
+            .await;
+
+        log::info!("Successfully unarchived federation: {id}");
         Ok(())
     }
 
@@ -1462,11 +4125,7 @@ impl HarborCore {
                 .map(|url| url.name.clone())
                 .collect();
 
-            let module_kinds = config
-                .modules
-                .into_values()
-                .map(|module_config| module_config.kind().to_owned())
-                .collect::<Vec<ModuleKind>>();
+            let modules = ModuleInfo::from_config(&config);
 
             // get metadata from in memory cache
             let metadata = metadata_cache
@@ -1487,21 +4146,39 @@ impl HarborCore {
                     Err(_) => false,
                 };
 
+            let id = MintIdentifier::Fedimint(c.fedimint_client.federation_id());
+            let display_balance = self.balance_updates.display_balance(&id, balance);
             res.push(MintItem {
-                id: MintIdentifier::Fedimint(c.fedimint_client.federation_id()),
+                id,
                 name: c
                     .fedimint_client
                     .get_config_meta("federation_name")
                     .unwrap_or("Unknown".to_string()),
                 balance: balance.sats_round_down(),
+                display_balance: display_balance.sats_round_down(),
                 guardians: Some(guardians),
-                module_kinds: Some(module_kinds),
+                modules: Some(modules),
                 metadata: metadata.unwrap_or_default(),
                 on_chain_supported,
                 active: true,
+                balance_cached: false,
             });
         }
 
+        // Federations that couldn't be loaded still show with their last saved balance,
+        // inactive so they can't be picked to pay from until they are loaded
+        res.extend(
+            cached_mint_items(self.storage.as_ref())?
+                .into_iter()
+                .filter(|item| {
+                    !matches!(&item.id, MintIdentifier::Fedimint(id) if clients.contains_key(id))
+                })
+                .map(|item| MintItem {
+                    active: false,
+                    ..item
+                }),
+        );
+
         for c in cashu_clients.values() {
             let balance: u64 = c.total_balance().await?.into();
 
@@ -1522,18 +4199,24 @@ impl HarborCore {
                 popup_countdown_message: None,
             };
 
+            let id = MintIdentifier::Cashu(c.mint_url.clone());
+            let display_balance = self
+                .balance_updates
+                .display_balance(&id, Amount::from_sats(balance));
             res.push(MintItem {
-                id: MintIdentifier::Cashu(c.mint_url.clone()),
+                id,
                 name: metadata
                     .federation_name
                     .clone()
                     .unwrap_or("Unknown".to_string()),
                 balance,
+                display_balance: display_balance.sats_round_down(),
                 guardians: None,
-                module_kinds: None,
+                modules: None,
                 metadata,
                 on_chain_supported: false,
                 active: true,
+                balance_cached: false,
             });
         }
 
@@ -1564,11 +4247,13 @@ impl HarborCore {
                 id: MintIdentifier::Fedimint(FederationId::from_str(&m.id)?),
                 name: m.name.clone().unwrap_or("Unknown".to_string()),
                 balance: 0,
+                display_balance: 0,
                 guardians: None,
-                module_kinds: None,
+                modules: None,
                 metadata: m.into(),
                 on_chain_supported: false,
                 active: false,
+                balance_cached: false,
             };
             res.push(item);
         }
@@ -1584,11 +4269,13 @@ impl HarborCore {
                     .and_then(|i| i.name.clone())
                     .unwrap_or(mint_url.to_string()),
                 balance: 0,
+                display_balance: 0,
                 guardians: None,
-                module_kinds: None,
+                modules: None,
                 metadata: info.into(),
                 on_chain_supported: false,
                 active: false,
+                balance_cached: false,
             };
             res.push(item);
         }
@@ -1596,6 +4283,64 @@ impl HarborCore {
         Ok(res)
     }
 
+    /// Fetches a federation's metadata again, like its name and icon, and saves it.
+    /// Fails when called within [`METADATA_REFRESH_INTERVAL`] of the last successful
+    /// refresh. If the fetch fails the metadata we already have is kept.
+    pub async fn refresh_federation_metadata(
+        &self,
+        msg_id: Uuid,
+        federation_id: FederationId,
+    ) -> anyhow::Result<FederationMeta> {
+        let client = self
+            .clients
+            .read()
+            .await
+            .get(&federation_id)
+            .ok_or(anyhow!("No client found for federation"))?
+            .fedimint_client
+            .clone();
+
+        if let Some(wait) = self
+            .metadata_refreshes
+            .lock()
+            .await
+            .get(&federation_id)
+            .and_then(|last| METADATA_REFRESH_INTERVAL.checked_sub(last.elapsed()))
+        {
+            return Err(anyhow!(
+                "Metadata was refreshed recently, try again in {}s",
+                wait.as_secs() + 1
+            ));
+        }
+
+        log::info!("Refreshing federation metadata: {federation_id}");
+        let metadata = try_get_federation_metadata(
+            FederationData::Client(&client),
+            self.tor_enabled.load(Ordering::Relaxed),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .await?;
+        self.metadata_refreshes
+            .lock()
+            .await
+            .insert(federation_id, Instant::now());
+
+        CACHE.write().await.insert(federation_id, metadata.clone());
+        self.storage
+            .upsert_federation_metadata(federation_id, metadata.clone())?;
+
+        self.msg(
+            msg_id,
+            CoreUIMsg::FederationMetadataUpdated {
+                id: federation_id,
+                metadata: metadata.clone(),
+            },
+        )
+        .await;
+
+        Ok(metadata)
+    }
+
     async fn update_mint_metadata(
         needs_metadata: Vec<ClientHandleArc>,
         metadata_fetch_cancel: Arc<AtomicBool>,
@@ -1629,8 +4374,27 @@ impl HarborCore {
         }
     }
 
-    pub async fn get_seed_words(&self) -> String {
-        self.mnemonic.to_string()
+    /// The seed words can restore every federation, so any federation with a spend
+    /// password has to be unlocked first, see [`HarborCore::unlock`].
+    pub async fn get_seed_words(&self) -> anyhow::Result<String> {
+        for id in self.storage.list_federations()? {
+            self.check_unlocked(FederationId::from_str(&id)?).await?;
+        }
+
+        let profile = self
+            .storage
+            .get_profile()?
+            .ok_or(anyhow!("No profile found"))?;
+        Ok(profile.mnemonic().to_string())
+    }
+
+    /// The seed cashu wallets are built from, read when needed so it isn't kept in memory
+    fn cashu_seed(&self) -> anyhow::Result<Zeroizing<[u8; 64]>> {
+        let profile = self
+            .storage
+            .get_profile()?
+            .ok_or(anyhow!("No profile found"))?;
+        Ok(Zeroizing::new(profile.mnemonic().to_seed_normalized("")))
     }
 
     pub async fn set_onchain_receive_enabled(&self, enabled: bool) -> anyhow::Result<()> {
@@ -1654,6 +4418,112 @@ impl HarborCore {
         Ok(())
     }
 
+    /// Sets whether lightning payments may only use gateways that support private payments.
+    /// When required and none is available payments fail with [`SendError::NoPrivateGateway`].
+    pub async fn set_require_private_payments(&self, required: bool) -> anyhow::Result<()> {
+        log::info!("Setting require private payments to: {required}");
+        self.storage.set_require_private_payments(required)?;
+        Ok(())
+    }
+
+    /// Sets what lightning gateway selection optimizes for, or restricts it to vetted
+    /// gateways or a single one. Latency and reliability are measured from our own
+    /// payments through each gateway.
+    pub async fn set_gateway_selection_priority(
+        &self,
+        priority: SelectionPriority,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting gateway selection priority to: {priority:?}");
+        self.storage.set_gateway_selection_priority(priority)?;
+        // the gateways used so far were picked for the old priority
+        self.storage.clear_preferred_gateways()?;
+        Ok(())
+    }
+
+    /// Sets how balances are adjusted before they are sent to the UI, the raw balance is
+    /// still available from [`HarborCore::balance_breakdown`]
+    pub async fn set_display_balance_policy(
+        &self,
+        policy: DisplayBalancePolicy,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting display balance policy to: {policy:?}");
+        self.storage.set_display_balance_policy(policy)?;
+        Ok(())
+    }
+
+    /// Sets how many payment subscriptions may run at once, `None` restores
+    /// [`DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS`]. Updates over the limit wait for one being
+    /// handled to finish.
+    pub async fn set_max_concurrent_subscriptions(
+        &self,
+        limit: Option<usize>,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting max concurrent subscriptions to: {limit:?}");
+        self.storage.set_max_concurrent_subscriptions(limit)?;
+        self.subscription_limit
+            .set(limit.unwrap_or(DEFAULT_MAX_CONCURRENT_SUBSCRIPTIONS));
+        Ok(())
+    }
+
+    /// Sets how often federations' guardians are probed for their status, `None` restores
+    /// [`DEFAULT_FEDERATION_STATUS_INTERVAL`]. Applies from each federation's next probe, and
+    /// is never shorter than [`MIN_FEDERATION_STATUS_INTERVAL`].
+    pub async fn set_federation_status_interval(
+        &self,
+        interval: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting federation status interval to: {interval:?}");
+        self.storage.set_federation_status_interval(interval)?;
+        Ok(())
+    }
+
+    /// Tells core whether the app is in the background. Federation status probes are
+    /// skipped while it is, so no requests are made just to update a hidden UI.
+    pub fn set_backgrounded(&self, backgrounded: bool) {
+        log::debug!("App backgrounded: {backgrounded}");
+        self.backgrounded.store(backgrounded, Ordering::Relaxed);
+    }
+
+    /// Sets how much of a federation's data can be loaded into memory before warning,
+    /// `None` restores [`DEFAULT_FEDERATION_MEMORY_CAP`]. Checked when federations are loaded.
+    pub async fn set_federation_memory_cap(&self, cap: Option<usize>) -> anyhow::Result<()> {
+        log::info!("Setting federation memory cap to: {cap:?}");
+        self.storage.set_federation_memory_cap(cap)?;
+        Ok(())
+    }
+
+    /// Sets how long after a receive the history refresh for further receives is held back,
+    /// they are then summarized as one [`CoreUIMsg::ReceiveBatch`]. `None` refreshes the
+    /// history after each receive
+    pub async fn set_receive_batch_window(&self, window: Option<Duration>) -> anyhow::Result<()> {
+        log::info!("Setting receive batch window to: {window:?}");
+        self.storage.set_receive_batch_window(window)?;
+        Ok(())
+    }
+
+    /// Sets the most a lightning payment may pay in gateway fees, `None` removes the limit
+    pub async fn set_max_send_fee(&self, max_fee: Option<Amount>) -> anyhow::Result<()> {
+        log::info!("Setting max send fee to: {max_fee:?}");
+        self.storage.set_max_send_fee(max_fee)?;
+        Ok(())
+    }
+
+    /// Sets how long payment updates can stall before we poll for the outcome,
+    /// `None` restores [`DEFAULT_UPDATE_STALL_TIMEOUT`]. Applies to payments started afterwards.
+    pub async fn set_update_stall_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        log::info!("Setting update stall timeout to: {timeout:?}");
+        self.storage.set_update_stall_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Sets how long downloading a federation's config may take before joining fails with
+    /// [`FedimintError::JoinTimeout`], `None` restores [`DEFAULT_JOIN_TIMEOUT`].
+    pub async fn set_join_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        log::info!("Setting join timeout to: {timeout:?}");
+        self.storage.set_join_timeout(timeout)?;
+        Ok(())
+    }
+
     pub async fn test_status_updates(&self, msg_id: Uuid) {
         self.status_update(msg_id, "Starting test sequence").await;
         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
@@ -1672,3 +4542,380 @@ impl HarborCore {
         self.status_update(msg_id, "Test sequence complete!").await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use futures::channel::mpsc;
+
+    #[tokio::test]
+    async fn test_payment_locks() {
+        use futures::FutureExt;
+
+        let locks = PaymentLocks::default();
+        let guard = locks.lock("a").await;
+
+        // a duplicate waits on the first, other payments don't
+        assert!(locks.lock("a").now_or_never().is_none());
+        let other = locks.lock("b").now_or_never();
+        assert!(other.is_some());
+        drop(other);
+
+        drop(guard);
+        assert!(locks.lock("a").now_or_never().is_some());
+        // unused locks are forgotten
+        let _guard = locks.lock("c").await;
+        assert_eq!(locks.0.lock().await.len(), 1);
+    }
+
+    #[test]
+    fn test_spend_password_hash() {
+        let hash = hash_spend_password("hunter2").unwrap();
+
+        assert!(verify_spend_password("hunter2", &hash));
+        assert!(!verify_spend_password("hunter3", &hash));
+        assert!(!verify_spend_password("hunter2", "not a hash"));
+        // salted, so the same password hashes differently each time
+        assert_ne!(hash, hash_spend_password("hunter2").unwrap());
+    }
+
+    #[test]
+    fn test_display_balance_policy() {
+        let ecash = Amount::from_sats(50);
+        let pending = Amount::from_sats(1_000);
+
+        assert_eq!(DisplayBalancePolicy::default().apply(ecash, pending), ecash);
+
+        let policy = DisplayBalancePolicy {
+            include_pending: true,
+            dust_threshold: Amount::ZERO,
+        };
+        assert_eq!(policy.apply(ecash, pending), Amount::from_sats(1_050));
+
+        // dust is hidden, but pending receives are still added when asked for
+        let policy = DisplayBalancePolicy {
+            include_pending: false,
+            dust_threshold: Amount::from_sats(100),
+        };
+        assert_eq!(policy.apply(ecash, pending), Amount::ZERO);
+        assert_eq!(
+            policy.apply(Amount::from_sats(100), pending),
+            Amount::from_sats(100)
+        );
+        let policy = DisplayBalancePolicy {
+            include_pending: true,
+            ..policy
+        };
+        assert_eq!(policy.apply(ecash, pending), pending);
+    }
+
+    #[test]
+    fn test_aggregate_balance() {
+        assert_eq!(AggregateBalance::new(HashMap::new()).total, Amount::ZERO);
+
+        let online = FederationId::dummy();
+        let offline = FederationId::from_str(&"11".repeat(32)).unwrap();
+        let aggregate = AggregateBalance::new(HashMap::from([
+            (
+                online,
+                FederationBalance {
+                    balance: Amount::from_sats(1_000),
+                    stale: false,
+                },
+            ),
+            (
+                offline,
+                FederationBalance {
+                    balance: Amount::from_msats(500),
+                    stale: true,
+                },
+            ),
+        ]));
+        // a stale balance still counts towards the total
+        assert_eq!(aggregate.total, Amount::from_msats(1_000_500));
+        assert!(aggregate.federations[&offline].stale);
+    }
+
+    #[test]
+    fn test_is_tested_api_version() {
+        assert!(is_tested_api_version(TESTED_CORE_API_VERSION));
+        assert!(is_tested_api_version(ApiVersion { major: 0, minor: 0 }));
+        assert!(!is_tested_api_version(ApiVersion {
+            major: TESTED_CORE_API_VERSION.major,
+            minor: TESTED_CORE_API_VERSION.minor + 1,
+        }));
+        assert!(!is_tested_api_version(ApiVersion {
+            major: TESTED_CORE_API_VERSION.major + 1,
+            minor: 0,
+        }));
+    }
+
+    #[test]
+    fn test_invoice_pay_amount_with_invoice_amount() {
+        let invoice_amount = Some(Amount::from_sats(1_000));
+
+        assert_eq!(
+            invoice_pay_amount(invoice_amount, None).unwrap(),
+            Amount::from_sats(1_000)
+        );
+        assert_eq!(
+            invoice_pay_amount(invoice_amount, Some(Amount::from_sats(1_000))).unwrap(),
+            Amount::from_sats(1_000)
+        );
+        assert!(invoice_pay_amount(invoice_amount, Some(Amount::from_sats(999))).is_err());
+    }
+
+    #[test]
+    fn test_invoice_pay_amount_without_invoice_amount() {
+        assert_eq!(
+            invoice_pay_amount(None, Some(Amount::from_sats(21))).unwrap(),
+            Amount::from_sats(21)
+        );
+        assert!(invoice_pay_amount(None, None).is_err());
+        assert!(invoice_pay_amount(None, Some(Amount::ZERO)).is_err());
+    }
+
+    #[test]
+    fn test_receive_amount_mismatch() {
+        let requested = Amount::from_sats(1_000);
+
+        assert!(!receive_amount_mismatch(requested, requested));
+        assert!(!receive_amount_mismatch(
+            requested,
+            Amount::from_msats(999_500)
+        ));
+        assert!(receive_amount_mismatch(requested, Amount::from_sats(900)));
+        assert!(receive_amount_mismatch(requested, Amount::from_sats(1_100)));
+    }
+
+    /// A core with empty storage and no federations loaded
+    async fn test_core() -> (
+        HarborCore,
+        mpsc::Receiver<CoreUIMsgPacket>,
+        tempdir::TempDir,
+    ) {
+        let tmp_dir = tempdir::TempDir::new("harbor_core").unwrap();
+        let db_path = tmp_dir.path().join("harbor.sqlite");
+        let storage = db::setup_db(db_path.to_str().unwrap(), "password".to_string()).unwrap();
+        let mnemonic = storage.generate_mnemonic(None).unwrap();
+        let cashu_storage =
+            Arc::new(WalletRedbDatabase::new(&tmp_dir.path().join("cashu.redb")).unwrap());
+
+        let (tx, rx) = mpsc::channel(100);
+        let core = HarborCore::new(
+            Network::Regtest,
+            Arc::new(mnemonic),
+            tmp_dir.path().to_path_buf(),
+            tx,
+            Arc::new(RwLock::new(HashMap::new())),
+            Arc::new(RwLock::new(HashMap::new())),
+            storage.clone(),
+            cashu_storage,
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+            ReconnectPolicy::default(),
+            BalanceUpdates::new(storage),
+            SubscriptionLimit::default(),
+            Recoveries::default(),
+        )
+        .await
+        .unwrap();
+        (core, rx, tmp_dir)
+    }
+
+    #[tokio::test]
+    async fn test_seed_words_need_unlock() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        assert!(core.get_seed_words().await.is_ok());
+
+        let federation_id = FederationId::dummy();
+        core.storage
+            .insert_new_federation(NewFedimint {
+                id: federation_id.to_string(),
+                invite_code: "invite".to_string(),
+                value: vec![],
+                network: None,
+            })
+            .unwrap();
+        core.set_spend_password(federation_id, Some("hunter2".to_string()))
+            .await
+            .unwrap();
+
+        assert!(core.get_seed_words().await.is_err());
+        assert!(core.export_federation_backup(federation_id).await.is_err());
+
+        core.unlock(federation_id, "hunter2").await.unwrap();
+        assert!(core.get_seed_words().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_balance_of_unloaded_federation() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        assert_eq!(core.aggregate_balance().await, AggregateBalance::default());
+
+        // a joined federation whose client didn't load counts with its saved balance
+        let federation_id = FederationId::dummy();
+        core.storage
+            .insert_new_federation(NewFedimint {
+                id: federation_id.to_string(),
+                invite_code: "invite".to_string(),
+                value: vec![],
+                network: None,
+            })
+            .unwrap();
+        let aggregate = core.aggregate_balance().await;
+        assert_eq!(aggregate.total, Amount::ZERO);
+        assert!(aggregate.federations[&federation_id].stale);
+
+        core.storage
+            .set_federation_balance(federation_id, Amount::from_sats(1_000))
+            .unwrap();
+        let aggregate = core.total_balance(Uuid::new_v4()).await;
+        assert_eq!(aggregate.total, Amount::from_sats(1_000));
+        assert_eq!(
+            aggregate.federations[&federation_id],
+            FederationBalance {
+                balance: Amount::from_sats(1_000),
+                stale: true,
+            }
+        );
+
+        // the cached total is reused until the federations change
+        core.storage
+            .set_federation_balance(federation_id, Amount::from_sats(2_000))
+            .unwrap();
+        assert_eq!(
+            core.total_balance(Uuid::new_v4()).await.total,
+            Amount::from_sats(1_000)
+        );
+        core.clear_aggregate_balance().await;
+        assert_eq!(
+            core.total_balance(Uuid::new_v4()).await.total,
+            Amount::from_sats(2_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_await_payment_without_client() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        let operation_id = OperationId::new_random();
+
+        let result = core.await_payment(Uuid::new_v4(), operation_id).await;
+        assert_eq!(
+            result,
+            Err(SendError::Failed("Payment not found".to_string()))
+        );
+
+        // a payment from a federation that isn't loaded can't be followed
+        let invoice = Bolt11Invoice::from_str("lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0").unwrap();
+        core.storage
+            .create_lightning_payment(
+                operation_id.fmt_full().to_string(),
+                Some(FederationId::dummy()),
+                None,
+                invoice,
+                Amount::from_sats(1_000),
+                Amount::ZERO,
+                OperationKind::LightningSend,
+            )
+            .unwrap();
+        let result = core.await_payment(Uuid::new_v4(), operation_id).await;
+        assert_eq!(
+            result,
+            Err(SendError::Failed(
+                "No client found for federation".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_msg_after_ui_closed() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        let (mut tx, rx) = mpsc::channel::<CoreUIMsgPacket>(16);
+        assert!(HarborCore::send_msg(&mut tx, None, CoreUIMsg::Locked).await);
+
+        // the app closing drops the receiver, sending must not panic
+        drop(rx);
+        assert!(!HarborCore::send_msg(&mut tx, None, CoreUIMsg::Locked).await);
+        assert!(
+            !core
+                .balance_updates
+                .send(
+                    &mut tx,
+                    None,
+                    MintIdentifier::Fedimint(FederationId::dummy()),
+                    Amount::from_sats(1),
+                )
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_balance_updates_are_coalesced() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        let (mut tx, mut rx) = mpsc::channel::<CoreUIMsgPacket>(16);
+        let mint = MintIdentifier::Fedimint(FederationId::dummy());
+        let updates = BalanceUpdates::new(core.storage.clone());
+
+        for sats in 1..=5 {
+            updates
+                .send(&mut tx, None, mint.clone(), Amount::from_sats(sats))
+                .await;
+        }
+
+        // updates for another core's mints aren't held back by these
+        let other = BalanceUpdates::new(core.storage.clone());
+        other
+            .send(&mut tx, None, mint.clone(), Amount::from_sats(7))
+            .await;
+
+        // the first update goes out right away
+        let first = rx.next().await.unwrap();
+        assert!(matches!(
+            first.msg,
+            CoreUIMsg::MintBalanceUpdated { balance, .. } if balance == Amount::from_sats(1)
+        ));
+        let other_first = rx.next().await.unwrap();
+        assert!(matches!(
+            other_first.msg,
+            CoreUIMsg::MintBalanceUpdated { balance, .. } if balance == Amount::from_sats(7)
+        ));
+        assert!(rx.try_next().is_err());
+
+        // the rest are coalesced into the latest balance
+        tokio::time::sleep(BALANCE_UPDATE_INTERVAL * 2).await;
+        let latest = rx.next().await.unwrap();
+        assert!(matches!(
+            latest.msg,
+            CoreUIMsg::MintBalanceUpdated { balance, .. } if balance == Amount::from_sats(5)
+        ));
+        assert!(rx.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_balance_update_keeps_spendable_balance() {
+        let (core, _rx, _tmp_dir) = test_core().await;
+        core.set_display_balance_policy(DisplayBalancePolicy {
+            include_pending: false,
+            dust_threshold: Amount::from_sats(100),
+        })
+        .await
+        .unwrap();
+
+        let (mut tx, mut rx) = mpsc::channel::<CoreUIMsgPacket>(16);
+        let mint = MintIdentifier::Fedimint(FederationId::dummy());
+        core.balance_updates
+            .send(&mut tx, None, mint, Amount::from_sats(50))
+            .await;
+
+        // dust is hidden from the shown balance, but can still be spent
+        let update = rx.next().await.unwrap();
+        assert!(matches!(
+            update.msg,
+            CoreUIMsg::MintBalanceUpdated { balance, display_balance, .. }
+                if balance == Amount::from_sats(50) && display_balance == Amount::ZERO
+        ));
+    }
+}
@@ -1,9 +1,15 @@
 use crate::http::{make_get_request_direct, make_get_request_tor};
+use anyhow::anyhow;
+use fedimint_core::Amount;
+use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
 use lnurl::{
+    Tag,
     lightning_address::LightningAddress,
     lnurl::LnUrl,
     pay::{LnURLPayInvoice, PayResponse},
+    withdraw::WithdrawalResponse,
 };
+use serde::de::DeserializeOwned;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
@@ -16,6 +22,47 @@ pub fn parse_lnurl(address: &str) -> anyhow::Result<LnUrl> {
     }
 }
 
+/// Gets an invoice for the amount from a lightning address or bech32 LNURL, ready to be
+/// paid like any other invoice
+pub async fn resolve_lnurl_pay(
+    address: &str,
+    amount: Amount,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<Bolt11Invoice> {
+    let lnurl = parse_lnurl(address)?;
+    request_lnurl_invoice(&lnurl, amount, tor_enabled, cancel_handle).await
+}
+
+/// Gets an invoice for the amount from an LNURL-pay service. The amount has to be one the
+/// service accepts, and the invoice it returns has to be for exactly that amount.
+pub async fn request_lnurl_invoice(
+    lnurl: &LnUrl,
+    amount: Amount,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<Bolt11Invoice> {
+    let pay = make_lnurl_request(lnurl, tor_enabled, cancel_handle.clone()).await?;
+    let invoice = get_invoice(&pay, amount.msats, tor_enabled, cancel_handle).await?;
+    let invoice = Bolt11Invoice::from_str(&invoice.pr)
+        .map_err(|e| anyhow!("Recipient returned an invalid invoice: {e}"))?;
+    check_invoice_amount(&invoice, amount)?;
+    Ok(invoice)
+}
+
+/// Makes sure an invoice from an LNURL service is for the amount we asked for, so the
+/// service can't get us to pay something else
+pub fn check_invoice_amount(invoice: &Bolt11Invoice, amount: Amount) -> anyhow::Result<()> {
+    match invoice.amount_milli_satoshis() {
+        Some(msats) if msats == amount.msats => Ok(()),
+        Some(msats) => Err(anyhow!(
+            "Recipient returned an invoice for {} instead of {amount}",
+            Amount::from_msats(msats)
+        )),
+        None => Err(anyhow!("Recipient returned an invoice without an amount")),
+    }
+}
+
 pub async fn get_invoice(
     pay: &PayResponse,
     msats: u64,
@@ -23,17 +70,18 @@ pub async fn get_invoice(
     cancel_handle: Arc<AtomicBool>,
 ) -> anyhow::Result<LnURLPayInvoice> {
     if msats < pay.min_sendable || msats > pay.max_sendable {
-        return Err(anyhow::anyhow!("Invalid amount"));
+        return Err(anyhow!(
+            "Amount must be between {} and {}",
+            Amount::from_msats(pay.min_sendable),
+            Amount::from_msats(pay.max_sendable)
+        ));
     }
 
     let symbol = if pay.callback.contains('?') { "&" } else { "?" };
     let url = format!("{}{}amount={}", pay.callback, symbol, msats);
 
-    if tor_enabled {
-        make_get_request_tor(&url, cancel_handle).await
-    } else {
-        make_get_request_direct(&url).await
-    }
+    let response = lnurl_get(&url, tor_enabled, cancel_handle).await?;
+    parse_lnurl_response(response, "an invoice")
 }
 
 pub async fn make_lnurl_request(
@@ -44,11 +92,136 @@ pub async fn make_lnurl_request(
     let lnurlp = lnurl.url.clone();
     log::info!("Making lnurl request: {lnurlp}, tor_enabled: {tor_enabled}");
 
-    if tor_enabled {
-        make_get_request_tor(&lnurlp, cancel_handle).await
+    let response = lnurl_get(&lnurlp, tor_enabled, cancel_handle).await?;
+    let pay: PayResponse = parse_lnurl_response(response, "lightning payment details")?;
+    if pay.tag != Tag::PayRequest {
+        return Err(anyhow!(
+            "{} is not a lightning payment request",
+            host(&lnurlp)
+        ));
+    }
+    Ok(pay)
+}
+
+/// Fetches what an LNURL-withdraw service will let us withdraw
+pub async fn resolve_lnurl_withdraw(
+    lnurl: &str,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<WithdrawalResponse> {
+    let lnurl = LnUrl::from_str(lnurl).map_err(|_| anyhow!("Invalid lnurl"))?;
+    make_withdraw_request(&lnurl, tor_enabled, cancel_handle).await
+}
+
+pub async fn make_withdraw_request(
+    lnurl: &LnUrl,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<WithdrawalResponse> {
+    let url = lnurl.url.clone();
+    log::info!("Making lnurl withdraw request: {url}, tor_enabled: {tor_enabled}");
+
+    let response = lnurl_get(&url, tor_enabled, cancel_handle).await?;
+    let withdraw: WithdrawalResponse = parse_lnurl_response(response, "withdraw details")?;
+    if withdraw.tag != Tag::WithdrawRequest {
+        return Err(anyhow!("{} is not a withdraw request", host(&url)));
+    }
+    Ok(withdraw)
+}
+
+/// The most a withdraw service lets us withdraw, in whole sats to make an invoice for.
+/// Fails if that is less than the least it lets us withdraw.
+pub fn withdraw_amount(
+    min_withdrawable: Option<u64>,
+    max_withdrawable: u64,
+) -> anyhow::Result<Amount> {
+    let amount = Amount::from_sats(max_withdrawable / 1_000);
+    let min = Amount::from_msats(min_withdrawable.unwrap_or_default());
+    if amount == Amount::ZERO || amount < min {
+        return Err(anyhow!(
+            "Nothing can be withdrawn, the service allows between {min} and {}",
+            Amount::from_msats(max_withdrawable)
+        ));
+    }
+    Ok(amount)
+}
+
+/// Gives an LNURL-withdraw service the invoice to pay. If it won't pay it the error is the
+/// reason the service gave.
+pub async fn submit_withdraw_invoice(
+    withdraw: &WithdrawalResponse,
+    invoice: &Bolt11Invoice,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let symbol = if withdraw.callback.contains('?') {
+        "&"
+    } else {
+        "?"
+    };
+    let url = format!(
+        "{}{symbol}k1={}&pr={invoice}",
+        withdraw.callback, withdraw.k1
+    );
+
+    let response = lnurl_get(&url, tor_enabled, cancel_handle).await?;
+    match lnurl_error_reason(&response) {
+        Some(reason) => Err(anyhow!(reason)),
+        None => Ok(()),
+    }
+}
+
+/// Fetches a JSON response from an LNURL service
+async fn lnurl_get(
+    url: &str,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<serde_json::Value> {
+    let response = if tor_enabled {
+        make_get_request_tor(url, cancel_handle).await
     } else {
-        make_get_request_direct(&lnurlp).await
+        make_get_request_direct(url).await
+    };
+    response.map_err(|e| anyhow!("{} did not return an LNURL response: {e}", host(url)))
+}
+
+/// Reads a response from an LNURL service, which is either what was asked for or an error
+/// with the service's reason
+fn parse_lnurl_response<T: DeserializeOwned>(
+    response: serde_json::Value,
+    expected: &str,
+) -> anyhow::Result<T> {
+    if let Some(reason) = lnurl_error_reason(&response) {
+        return Err(anyhow!("The LNURL service returned an error: {reason}"));
+    }
+
+    serde_json::from_value(response)
+        .map_err(|_| anyhow!("The LNURL service did not return {expected}"))
+}
+
+/// The reason an LNURL service gave if its response is an error
+fn lnurl_error_reason(response: &serde_json::Value) -> Option<String> {
+    let is_error = response
+        .get("status")
+        .and_then(|s| s.as_str())
+        .is_some_and(|s| s.eq_ignore_ascii_case("ERROR"));
+    if !is_error {
+        return None;
     }
+
+    let reason = response
+        .get("reason")
+        .and_then(|r| r.as_str())
+        .unwrap_or("no reason given");
+    Some(reason.to_string())
+}
+
+/// The host of an LNURL service's url, to say who a failure came from
+fn host(url: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or(url.to_string())
 }
 
 #[cfg(test)]
@@ -59,6 +232,60 @@ mod tests {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    #[test]
+    fn test_check_invoice_amount() {
+        // 10u on signet, 1,000 sats
+        let invoice = Bolt11Invoice::from_str("lntbs10u1pny86cupp52lkv666juacc9evu0fpfmduac6l6qp0qypxr0yk9wfpze2u5sngshp57t8sp5tcchfv0y29yg46nqujktk2ufwcjcc7zvyd8rteadd7rjyscqzzsxqyz5vqsp5nnhtrhvyfh077g6rdfrs7ml9hqks4mj6f0e50nyeejc73ee7gl3q9qyyssq3urmp6hy3c95rtddevae0djrfn8au0rumgd05zvddzshg8krwupzc4htl38kqufp27el5ev5l8ea4736y3a3rpq5cewxwftsdk2v52cp9w25a0").unwrap();
+
+        assert!(check_invoice_amount(&invoice, Amount::from_sats(1_000)).is_ok());
+        assert!(check_invoice_amount(&invoice, Amount::from_sats(999)).is_err());
+        assert!(check_invoice_amount(&invoice, Amount::from_msats(1_000_001)).is_err());
+    }
+
+    #[test]
+    fn test_parse_lnurl_response() {
+        let error = serde_json::json!({"status": "ERROR", "reason": "Amount too high"});
+        let err = parse_lnurl_response::<LnURLPayInvoice>(error, "an invoice").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The LNURL service returned an error: Amount too high"
+        );
+
+        let other = serde_json::json!({"hello": "world"});
+        let err = parse_lnurl_response::<LnURLPayInvoice>(other, "an invoice").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The LNURL service did not return an invoice"
+        );
+
+        let invoice = serde_json::json!({"pr": "lnbc1"});
+        let invoice = parse_lnurl_response::<LnURLPayInvoice>(invoice, "an invoice").unwrap();
+        assert_eq!(invoice.pr, "lnbc1");
+
+        let ok = serde_json::json!({"status": "OK"});
+        assert_eq!(lnurl_error_reason(&ok), None);
+        let error = serde_json::json!({"status": "ERROR", "reason": "Link already used"});
+        assert_eq!(
+            lnurl_error_reason(&error),
+            Some("Link already used".to_string())
+        );
+    }
+
+    #[test]
+    fn test_withdraw_amount() {
+        assert_eq!(
+            withdraw_amount(Some(1_000), 21_000).unwrap(),
+            Amount::from_sats(21)
+        );
+        assert_eq!(
+            withdraw_amount(None, 21_999).unwrap(),
+            Amount::from_sats(21)
+        );
+        // rounding down to a whole sat can't go under the minimum
+        assert!(withdraw_amount(Some(21_500), 21_999).is_err());
+        assert!(withdraw_amount(None, 999).is_err());
+    }
+
     async fn try_with_tor_mode(lnurl: &LnUrl, tor_enabled: bool) -> anyhow::Result<()> {
         log::debug!(
             "Starting lightning address test with tor_enabled={}",
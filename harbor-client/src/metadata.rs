@@ -1,4 +1,5 @@
 use crate::http::{make_get_request_direct, make_get_request_tor};
+use anyhow::bail;
 use bitcoin::secp256k1::PublicKey;
 use cdk::nuts::MintInfo;
 use fedimint_client::ClientHandleArc;
@@ -17,6 +18,7 @@ use tokio::sync::RwLock;
 pub(crate) static CACHE: Lazy<RwLock<HashMap<FederationId, FederationMeta>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
+#[derive(Clone, Copy)]
 pub(crate) enum FederationData<'a> {
     Client(&'a ClientHandleArc),
     Config(&'a ClientConfig),
@@ -108,43 +110,58 @@ pub(crate) async fn get_federation_metadata(
     tor_enabled: bool,
     cancel_handle: Arc<AtomicBool>,
 ) -> FederationMeta {
+    match try_get_federation_metadata(data, tor_enabled, cancel_handle.clone()).await {
+        Ok(metadata) => metadata,
+        // Check if cancelled before constructing response
+        Err(_) if cancel_handle.load(Ordering::Relaxed) => FederationMeta::default(),
+        Err(e) => {
+            error!("Error fetching external metadata: {}", e);
+            merge_metadata(&data, data.get_meta("meta_external_url"), None)
+        }
+    }
+}
+
+/// Like [`get_federation_metadata`], but fails when the external metadata can't be fetched
+/// instead of leaving it out
+pub(crate) async fn try_get_federation_metadata(
+    data: FederationData<'_>,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<FederationMeta> {
     // Check if cancelled before starting
     if cancel_handle.load(Ordering::Relaxed) {
-        return FederationMeta::default();
+        bail!("Metadata fetch was cancelled");
     }
 
     let meta_external_url = data.get_meta("meta_external_url");
     let config: Option<FederationMeta> = match meta_external_url.as_ref() {
         None => None,
         Some(url) => {
-            // Check if cancelled before making request
-            if cancel_handle.load(Ordering::Relaxed) {
-                return FederationMeta::default();
-            }
-
             let result = if tor_enabled {
                 make_get_request_tor::<FederationMetaConfig>(url, cancel_handle.clone()).await
             } else {
                 make_get_request_direct::<FederationMetaConfig>(url).await
             };
-            match result {
-                Ok(m) => m
-                    .federations
-                    .get(&data.federation_id().to_string())
-                    .cloned(),
-                Err(e) => {
-                    error!("Error fetching external metadata: {}", e);
-                    None
-                }
-            }
+            result?
+                .federations
+                .get(&data.federation_id().to_string())
+                .cloned()
         }
     };
 
-    // Check if cancelled before constructing response
     if cancel_handle.load(Ordering::Relaxed) {
-        return FederationMeta::default();
+        bail!("Metadata fetch was cancelled");
     }
 
+    Ok(merge_metadata(&data, meta_external_url, config))
+}
+
+/// The federation's own metadata, with what it leaves out taken from its external `config`
+fn merge_metadata(
+    data: &FederationData<'_>,
+    meta_external_url: Option<String>,
+    config: Option<FederationMeta>,
+) -> FederationMeta {
     FederationMeta {
         meta_external_url, // Already set...
         federation_name: merge_values(
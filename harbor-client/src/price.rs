@@ -0,0 +1,107 @@
+use crate::http::{make_get_request_direct, make_get_request_tor};
+use fedimint_core::Amount;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
+use tokio::sync::RwLock;
+
+/// Where the BTC price is fetched from, it returns the price in every currency it supports
+const PRICE_URL: &str = "https://mempool.space/api/v1/prices";
+
+const MSATS_PER_BTC: f64 = 100_000_000_000.0;
+
+/// The last price fetched for each currency, used when the price source can't be reached
+static CACHE: Lazy<RwLock<HashMap<String, BtcPrice>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The price of one BTC in a fiat currency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BtcPrice {
+    pub price: f64,
+    pub fetched_at: SystemTime,
+}
+
+/// An amount of a fiat currency, and the BTC price it was converted at
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatAmount {
+    /// Upper case currency code, like "USD"
+    pub currency: String,
+    pub value: f64,
+    pub btc_price: BtcPrice,
+    /// Why the last cached price was used instead of a current one
+    pub stale_reason: Option<String>,
+}
+
+impl FiatAmount {
+    /// Converts the amount at the given price
+    pub fn from_amount(amount: Amount, currency: &str, btc_price: BtcPrice) -> Self {
+        Self {
+            currency: currency.to_uppercase(),
+            value: amount.msats as f64 / MSATS_PER_BTC * btc_price.price,
+            btc_price,
+            stale_reason: None,
+        }
+    }
+}
+
+/// Gets the current BTC price in the currency. If the price source can't be reached the
+/// last price fetched is returned along with why, it is an error only if there is none.
+pub async fn get_btc_price(
+    currency: &str,
+    tor_enabled: bool,
+    cancel_handle: Arc<AtomicBool>,
+) -> anyhow::Result<(BtcPrice, Option<String>)> {
+    let currency = currency.to_uppercase();
+    let result = if tor_enabled {
+        make_get_request_tor::<HashMap<String, f64>>(PRICE_URL, cancel_handle).await
+    } else {
+        make_get_request_direct::<HashMap<String, f64>>(PRICE_URL).await
+    };
+
+    let reason = match result {
+        Ok(prices) => match prices.get(&currency) {
+            Some(price) if *price > 0.0 => {
+                let price = BtcPrice {
+                    price: *price,
+                    fetched_at: SystemTime::now(),
+                };
+                CACHE.write().await.insert(currency, price);
+                return Ok((price, None));
+            }
+            _ => format!("No price for {currency} from the price source"),
+        },
+        Err(e) => format!("Could not reach the price source: {e}"),
+    };
+
+    match CACHE.read().await.get(&currency) {
+        Some(price) => {
+            log::warn!("{reason}, using the last cached price");
+            Ok((*price, Some(reason)))
+        }
+        None => Err(anyhow::anyhow!(reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fiat_amount_conversion() {
+        let price = BtcPrice {
+            price: 50_000.0,
+            fetched_at: SystemTime::now(),
+        };
+
+        let fiat = FiatAmount::from_amount(Amount::from_sats(100_000_000), "usd", price);
+        assert_eq!(fiat.currency, "USD");
+        assert_eq!(fiat.value, 50_000.0);
+
+        let fiat = FiatAmount::from_amount(Amount::from_sats(2_000), "eur", price);
+        assert!((fiat.value - 1.0).abs() < 1e-9);
+
+        let fiat = FiatAmount::from_amount(Amount::ZERO, "eur", price);
+        assert_eq!(fiat.value, 0.0);
+    }
+}
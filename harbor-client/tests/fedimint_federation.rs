@@ -0,0 +1,149 @@
+//! End-to-end test of joining and receiving from a real federation.
+//!
+//! This needs a running federation with a lightning gateway and a funded `fedimint-cli`
+//! client, which is what fedimint's `devimint` sets up. From a checkout of fedimint:
+//!
+//! ```sh
+//! just mprocs   # or: devimint dev-fed
+//! ```
+//!
+//! Then, from a shell inside the devimint environment (so `fedimint-cli` pays from the
+//! devimint client), run from this repo:
+//!
+//! ```sh
+//! HARBOR_TEST_INVITE_CODE=$(fedimint-cli invite-code 0 | jq -r .invite_code) just integration-test
+//! ```
+//!
+//! The invoice harbor creates is paid by the devimint client in the same federation,
+//! so the payment settles internally in the mint.
+#![cfg(feature = "integration-tests")]
+
+use bip39::Mnemonic;
+use bitcoin::Network;
+use fedimint_core::Amount;
+use fedimint_core::invite_code::InviteCode;
+use futures::StreamExt;
+use futures::channel::mpsc;
+use harbor_client::db::{DBConnection, setup_db};
+use harbor_client::db_models::PaymentStatus;
+use harbor_client::db_models::transaction_item::{TransactionDirection, TransactionItem};
+use harbor_client::fedimint_client::{JoinConfig, ReconnectPolicy, Recoveries, SubscriptionLimit};
+use harbor_client::{BalanceUpdates, CoreUIMsg, HarborCore, MintIdentifier, ReceiveSuccessMsg};
+use std::collections::HashMap;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tempdir::TempDir;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[tokio::test]
+#[ignore = "needs a local fedimintd stack, see the module docs"]
+async fn test_join_and_receive() {
+    let invite_code = std::env::var("HARBOR_TEST_INVITE_CODE")
+        .expect("HARBOR_TEST_INVITE_CODE must be set to a devimint invite code");
+    let invite_code = InviteCode::from_str(invite_code.trim()).expect("invalid invite code");
+    let federation_id = invite_code.federation_id();
+
+    let tmp_dir = TempDir::new("harbor_integration").unwrap();
+    let db_path = tmp_dir.path().join("harbor.sqlite");
+    let storage = setup_db(db_path.to_str().unwrap(), "password".to_string()).unwrap();
+    let mnemonic: Mnemonic = storage.generate_mnemonic(None).unwrap();
+
+    let cashu_db_path = tmp_dir.path().join("cashu.redb");
+    let cashu_storage =
+        Arc::new(harbor_client::cdk_redb::WalletRedbDatabase::new(&cashu_db_path).unwrap());
+
+    let (tx, mut rx) = mpsc::channel(100);
+    let core = HarborCore::new(
+        Network::Regtest,
+        Arc::new(mnemonic),
+        tmp_dir.path().to_path_buf(),
+        tx,
+        Arc::new(RwLock::new(HashMap::new())),
+        Arc::new(RwLock::new(HashMap::new())),
+        storage.clone(),
+        cashu_storage,
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicBool::new(false)),
+        Arc::new(AtomicBool::new(false)),
+        ReconnectPolicy::default(),
+        BalanceUpdates::new(storage.clone()),
+        SubscriptionLimit::default(),
+        Recoveries::default(),
+    )
+    .await
+    .unwrap();
+
+    // Joining exercises the config download, network check and primary module selection
+    core.add_federation(Uuid::new_v4(), invite_code, JoinConfig::Refresh)
+        .await
+        .expect("should join federation");
+    assert!(core.clients.read().await.contains_key(&federation_id));
+    assert!(
+        storage
+            .get_federation_config(federation_id)
+            .unwrap()
+            .is_some()
+    );
+
+    let amount = Amount::from_sats(1_000);
+    let invoice = core
+        .receive_lightning(
+            Uuid::new_v4(),
+            MintIdentifier::Fedimint(federation_id),
+            amount,
+            false,
+        )
+        .await
+        .expect("should create invoice");
+
+    let output = Command::new("fedimint-cli")
+        .args(["ln-pay", &invoice.to_string()])
+        .output()
+        .expect("fedimint-cli should be on the PATH");
+    assert!(
+        output.status.success(),
+        "fedimint-cli could not pay: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // The subscription handlers should report the receive, the new balance and the history
+    let settled = |items: &[TransactionItem]| {
+        items.iter().any(|item| {
+            item.mint_identifier == MintIdentifier::Fedimint(federation_id)
+                && item.direction == TransactionDirection::Incoming
+                && item.status == PaymentStatus::Success
+                && item.amount == amount.sats_round_down()
+        })
+    };
+    let mut received = false;
+    let mut balance = None;
+    let mut history_updated = false;
+    tokio::time::timeout(RECEIVE_TIMEOUT, async {
+        while let Some(packet) = rx.next().await {
+            match packet.msg {
+                CoreUIMsg::ReceiveSuccess(ReceiveSuccessMsg::Lightning) => received = true,
+                CoreUIMsg::MintBalanceUpdated { id, balance: b, .. }
+                    if id == MintIdentifier::Fedimint(federation_id) =>
+                {
+                    balance = Some(b)
+                }
+                CoreUIMsg::TransactionHistoryPage { items, .. } => {
+                    history_updated |= settled(&items);
+                }
+                CoreUIMsg::ReceiveFailed(e) => panic!("receive failed: {e}"),
+                _ => {}
+            }
+            if received && balance.is_some_and(|b| b >= amount) && history_updated {
+                break;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the receive");
+}
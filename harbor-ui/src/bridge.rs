@@ -8,11 +8,16 @@ use harbor_client::cdk::nuts::CurrencyUnit;
 use harbor_client::cdk::wallet::WalletBuilder;
 use harbor_client::cdk_redb::WalletRedbDatabase;
 use harbor_client::db::{DBConnection, check_password, setup_db};
-use harbor_client::fedimint_client::{FederationInviteOrId, FedimintClient};
+use harbor_client::fedimint_client::{
+    ClientContext, FedimintError, JoinConfig, JoinResult, ReconnectPolicy, Recoveries,
+    SubscriptionLimit, init_all_federations,
+};
 use harbor_client::fedimint_core::config::FederationId;
 use harbor_client::metadata::FederationMeta;
+use harbor_client::zeroize::Zeroizing;
 use harbor_client::{
-    CoreUIMsg, CoreUIMsgPacket, HarborCore, MintIdentifier, UICoreMsg, UICoreMsgPacket, data_dir,
+    AddFederationFailure, BalanceUpdates, CoreUIMsg, CoreUIMsgPacket, HarborCore, MintIdentifier,
+    SendError, UICoreMsg, UICoreMsgPacket, cached_mint_items, data_dir,
 };
 use iced::futures::channel::mpsc::Sender;
 use iced::futures::{SinkExt, Stream, StreamExt};
@@ -102,30 +107,60 @@ async fn setup_harbor_core(
         .expect("Could not get profile from db");
     let mnemonic = profile.mnemonic();
 
+    // Show the last saved balances while the clients load
+    match cached_mint_items(db.as_ref()) {
+        Ok(items) => tx
+            .send(Message::core_msg(None, CoreUIMsg::MintListUpdated(items)))
+            .await
+            .expect("should send"),
+        Err(e) => error!("Could not load saved mints: {e}"),
+    }
+
     // Create stop signal
     let stop = Arc::new(AtomicBool::new(false));
+    // Shared with the clients so they know when the app is in the background
+    let backgrounded = Arc::new(AtomicBool::new(false));
+
+    // Shared by the core and the clients so their balance updates are coalesced together
+    let balance_updates = BalanceUpdates::new(db.clone());
+    // Shared the same way so every federation's subscriptions count against one limit
+    let subscription_limit = SubscriptionLimit::new(profile.max_concurrent_subscriptions());
+    // Shared so a federation recovering in the background isn't opened again meanwhile
+    let recoveries = Recoveries::default();
 
     // Setup federation clients
     let federation_ids = db
         .list_federations()
-        .expect("should load initial fedimints");
-    let mut clients = HashMap::with_capacity(federation_ids.len());
-    for f in federation_ids {
-        let client = FedimintClient::new(
-            db.clone(),
-            FederationInviteOrId::Id(
-                FederationId::from_str(&f).expect("should parse federation id"),
-            ),
-            &mnemonic,
-            network,
-            stop.clone(),
-            core_tx.clone(),
-            None,
-        )
-        .await
-        .expect("Could not create fedimint client");
-
-        clients.insert(client.federation_id(), client);
+        .expect("should load initial fedimints")
+        .iter()
+        .map(|f| FederationId::from_str(f).expect("should parse federation id"))
+        .collect();
+    let context = ClientContext {
+        storage: db.clone(),
+        secret_provider: Arc::new(mnemonic.clone()),
+        network,
+        reconnect_policy: ReconnectPolicy::default(),
+        stop: stop.clone(),
+        backgrounded: backgrounded.clone(),
+        sender: core_tx.clone(),
+        balance_updates: balance_updates.clone(),
+        subscription_limit: subscription_limit.clone(),
+        recoveries: recoveries.clone(),
+    };
+    let results = init_all_federations(&context, federation_ids).await;
+    let mut clients = HashMap::with_capacity(results.len());
+    let mut unavailable = vec![];
+    for (federation_id, result) in results {
+        match result {
+            Ok(client) => {
+                info!("Loaded federation {federation_id}");
+                clients.insert(federation_id, client);
+            }
+            Err(e) => {
+                error!("Could not load federation {federation_id}: {e}");
+                unavailable.push(federation_id);
+            }
+        }
     }
 
     let cashu_db_path = data_dir.join("cashu.redb");
@@ -141,9 +176,8 @@ async fn setup_harbor_core(
         .list_cashu_mints()
         .expect("should load initial fedimints");
     let mut cashu_clients = HashMap::with_capacity(mint_urls.len());
+    let seed = Zeroizing::new(mnemonic.to_seed_normalized(""));
     for url in mint_urls {
-        let seed = mnemonic.to_seed_normalized("");
-
         let mint_url = MintUrl::from_str(&url).expect("Could not create MintUrl");
 
         let builder = WalletBuilder::new()
@@ -181,22 +215,29 @@ async fn setup_harbor_core(
     });
 
     // Create and return HarborCore
-    Some(
-        HarborCore::new(
-            network,
-            mnemonic,
-            data_dir,
-            core_tx,
-            Arc::new(RwLock::new(clients)),
-            Arc::new(RwLock::new(cashu_clients)),
-            db,
-            cashu_db,
-            stop.clone(),
-            Arc::new(AtomicBool::new(profile.tor_enabled())),
-        )
-        .await
-        .expect("Failed to build harbor core"),
+    let core = HarborCore::new(
+        network,
+        Arc::new(mnemonic),
+        data_dir,
+        core_tx,
+        Arc::new(RwLock::new(clients)),
+        Arc::new(RwLock::new(cashu_clients)),
+        db,
+        cashu_db,
+        stop.clone(),
+        Arc::new(AtomicBool::new(profile.tor_enabled())),
+        backgrounded,
+        ReconnectPolicy::default(),
+        balance_updates,
+        subscription_limit,
+        recoveries,
     )
+    .await
+    .expect("Failed to build harbor core");
+
+    core.retry_unavailable_federations(unavailable).await;
+
+    Some(core)
 }
 
 /// Attempts to auto-unlock the wallet using a password from the environment or keyring.
@@ -470,7 +511,7 @@ pub fn run_core() -> impl Stream<Item = Message> {
 
                     let core = HarborCore::new(
                         network,
-                        db.generate_mnemonic(seed).expect("should generate words"),
+                        Arc::new(db.generate_mnemonic(seed).expect("should generate words")),
                         path.to_path_buf(),
                         core_tx,
                         Arc::new(RwLock::new(HashMap::new())),
@@ -479,6 +520,11 @@ pub fn run_core() -> impl Stream<Item = Message> {
                         cashu_db,
                         Arc::new(AtomicBool::new(false)), // stop
                         Arc::new(AtomicBool::new(true)),  // tor enabled
+                        Arc::new(AtomicBool::new(false)), // backgrounded
+                        ReconnectPolicy::default(),
+                        BalanceUpdates::new(db.clone()),
+                        SubscriptionLimit::default(),
+                        Recoveries::default(),
                     )
                     .await
                     .expect("Failed to build harbor core");
@@ -498,6 +544,44 @@ pub fn run_core() -> impl Stream<Item = Message> {
     })
 }
 
+/// Tells the UI whether joining or recovering a federation worked
+async fn report_federation_join(
+    core: &HarborCore,
+    msg_id: Uuid,
+    result: Result<JoinResult, FedimintError>,
+) {
+    match result {
+        Err(e) => {
+            error!("Error adding federation: {e}");
+            let msg_out = match AddFederationFailure::from_error(&e) {
+                Some(reason) => CoreUIMsg::AddFederationFailed {
+                    reason,
+                    message: e.to_string(),
+                },
+                None => CoreUIMsg::AddMintFailed(e.to_string()),
+            };
+            core.msg(msg_id, msg_out).await;
+        }
+        Ok(join) => {
+            info!(
+                "Joined federation {} ({}), new join: {}",
+                join.name.as_deref().unwrap_or("unnamed"),
+                join.federation_id,
+                join.was_new_join
+            );
+            if let Ok(new_federation_list) = core.get_mint_items().await {
+                core.msg(msg_id, CoreUIMsg::MintListUpdated(new_federation_list))
+                    .await;
+            }
+            core.msg(
+                msg_id,
+                CoreUIMsg::AddMintSuccess(MintIdentifier::Fedimint(join.federation_id)),
+            )
+            .await;
+        }
+    }
+}
+
 async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
     // Initialize the ui's state
     core.init_ui_state().await.expect("Could not init ui state");
@@ -509,13 +593,30 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
         tokio::spawn(async move {
             if let Some(msg) = msg {
                 match msg.msg {
-                    UICoreMsg::SendLightning { mint, invoice } => {
+                    UICoreMsg::SendLightning {
+                        mint,
+                        invoice,
+                        amount,
+                        fee_limit,
+                    } => {
                         log::info!("Got UICoreMsg::Send");
                         core.msg(msg.id, CoreUIMsg::Sending).await;
-                        if let Err(e) = core.send_lightning(msg.id, mint, invoice, false).await {
+                        if let Err(e) = core
+                            .send_lightning(msg.id, mint, invoice, amount, fee_limit, false)
+                            .await
+                        {
                             error!("Error sending: {e}");
-                            core.msg(msg.id, CoreUIMsg::SendFailure(e.to_string()))
-                                .await;
+                            let msg_to_ui = match e.downcast_ref::<SendError>() {
+                                Some(SendError::FeeExceedsLimit {
+                                    best_available_fee,
+                                    limit,
+                                }) => CoreUIMsg::FeeExceedsLimit {
+                                    best_available_fee: *best_available_fee,
+                                    limit: *limit,
+                                },
+                                _ => CoreUIMsg::SendFailure(e.to_string()),
+                            };
+                            core.msg(msg.id, msg_to_ui).await;
                         }
                     }
                     UICoreMsg::ReceiveLightning { mint, amount } => {
@@ -531,6 +632,15 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             }
                         }
                     }
+                    UICoreMsg::ReceiveLnurlWithdraw { mint, lnurl } => {
+                        core.msg(msg.id, CoreUIMsg::ReceiveGenerating).await;
+                        // the invoice is sent to the UI by the core before the service is asked
+                        if let Err(e) = core.receive_lnurl_withdraw(msg.id, mint, lnurl).await {
+                            error!("Error withdrawing from lnurl: {e}");
+                            core.msg(msg.id, CoreUIMsg::ReceiveFailed(e.to_string()))
+                                .await;
+                        }
+                    }
                     UICoreMsg::SendLnurlPay {
                         mint,
                         lnurl,
@@ -549,6 +659,7 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                         mint,
                         address,
                         amount_sats,
+                        fee_rate,
                     } => {
                         log::info!("Got UICoreMsg::SendOnChain");
                         core.msg(msg.id, CoreUIMsg::Sending).await;
@@ -557,7 +668,7 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             MintIdentifier::Fedimint(mint) => mint,
                         };
                         if let Err(e) = core
-                            .send_onchain(msg.id, federation_id, address, amount_sats)
+                            .send_onchain(msg.id, federation_id, address, amount_sats, fee_rate)
                             .await
                         {
                             error!("Error sending: {e}");
@@ -572,7 +683,7 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             MintIdentifier::Fedimint(mint) => mint,
                         };
 
-                        match core.receive_onchain(msg.id, federation_id).await {
+                        match core.receive_onchain(msg.id, federation_id, false).await {
                             Err(e) => {
                                 core.msg(msg.id, CoreUIMsg::ReceiveFailed(e.to_string()))
                                     .await;
@@ -583,6 +694,13 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             }
                         }
                     }
+                    UICoreMsg::ReceiveEcash(notes) => {
+                        if let Err(e) = core.receive_ecash(msg.id, notes).await {
+                            error!("Error receiving ecash: {e}");
+                            core.msg(msg.id, CoreUIMsg::ReceiveFailed(e.to_string()))
+                                .await;
+                        }
+                    }
                     UICoreMsg::Transfer { to, from, amount } => {
                         if let Err(e) = core.transfer(msg.id, to, from, amount).await {
                             error!("Error transferring: {e}");
@@ -594,8 +712,17 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                         match core.get_federation_info(msg.id, invite_code).await {
                             Err(e) => {
                                 error!("Error getting federation info: {e}");
-                                core.msg(msg.id, CoreUIMsg::AddMintFailed(e.to_string()))
-                                    .await;
+                                let msg_out = match e
+                                    .downcast_ref::<FedimintError>()
+                                    .and_then(AddFederationFailure::from_error)
+                                {
+                                    Some(reason) => CoreUIMsg::AddFederationFailed {
+                                        reason,
+                                        message: e.to_string(),
+                                    },
+                                    None => CoreUIMsg::AddMintFailed(e.to_string()),
+                                };
+                                core.msg(msg.id, msg_out).await;
                             }
                             Ok((config, metadata)) => {
                                 core.msg(
@@ -649,28 +776,14 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                         }
                     }
                     UICoreMsg::AddFederation(invite_code) => {
-                        let id = invite_code.federation_id();
-                        match core.add_federation(msg.id, invite_code).await {
-                            Err(e) => {
-                                error!("Error adding federation: {e}");
-                                core.msg(msg.id, CoreUIMsg::AddMintFailed(e.to_string()))
-                                    .await;
-                            }
-                            Ok(_) => {
-                                if let Ok(new_federation_list) = core.get_mint_items().await {
-                                    core.msg(
-                                        msg.id,
-                                        CoreUIMsg::MintListUpdated(new_federation_list),
-                                    )
-                                    .await;
-                                }
-                                core.msg(
-                                    msg.id,
-                                    CoreUIMsg::AddMintSuccess(MintIdentifier::Fedimint(id)),
-                                )
-                                .await;
-                            }
-                        }
+                        let result = core
+                            .add_federation(msg.id, invite_code, JoinConfig::Cached)
+                            .await;
+                        report_federation_join(&core, msg.id, result).await;
+                    }
+                    UICoreMsg::RecoverFederation(invite_code) => {
+                        let result = core.recover_federation(msg.id, invite_code).await;
+                        report_federation_join(&core, msg.id, result).await;
                     }
                     UICoreMsg::AddCashuMint(url) => match core
                         .add_cashu_mint(msg.id, url.clone())
@@ -755,29 +868,36 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             }
                         }
                     }
+                    UICoreMsg::LeaveFederation(id) => {
+                        if let Err(e) = core.leave_federation(msg.id, id).await {
+                            error!("Error leaving federation: {e}");
+                            core.msg(msg.id, CoreUIMsg::RemoveFederationFailed(e.to_string()))
+                                .await;
+                        }
+                    }
                     UICoreMsg::RejoinMint(mint) => match mint {
                         MintIdentifier::Fedimint(id) => {
-                            if let Ok(Some(invite_code)) =
-                                core.storage.get_federation_invite_code(id)
-                            {
-                                match core.add_federation(msg.id, invite_code).await {
-                                    Err(e) => {
-                                        error!("Error adding federation: {e}");
-                                        core.msg(msg.id, CoreUIMsg::AddMintFailed(e.to_string()))
-                                            .await;
-                                    }
-                                    Ok(_) => {
-                                        if let Ok(new_federation_list) = core.get_mint_items().await
-                                        {
-                                            core.msg(
-                                                msg.id,
-                                                CoreUIMsg::MintListUpdated(new_federation_list),
-                                            )
-                                            .await;
+                            match core.rejoin_federation(msg.id, id).await {
+                                Err(e) => {
+                                    error!("Error adding federation: {e}");
+                                    let msg_out = match e {
+                                        FedimintError::NeedInviteCode { federation_id } => {
+                                            CoreUIMsg::NeedInviteCode { id: federation_id }
                                         }
-                                        core.msg(msg.id, CoreUIMsg::AddMintSuccess(mint)).await;
-                                        info!("Rejoined federation: {id}");
+                                        _ => CoreUIMsg::AddMintFailed(e.to_string()),
+                                    };
+                                    core.msg(msg.id, msg_out).await;
+                                }
+                                Ok(_) => {
+                                    if let Ok(new_federation_list) = core.get_mint_items().await {
+                                        core.msg(
+                                            msg.id,
+                                            CoreUIMsg::MintListUpdated(new_federation_list),
+                                        )
+                                        .await;
                                     }
+                                    core.msg(msg.id, CoreUIMsg::AddMintSuccess(mint)).await;
+                                    info!("Rejoined federation: {id}");
                                 }
                             }
                         }
@@ -805,9 +925,129 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                                 .await;
                         }
                     }
-                    UICoreMsg::GetSeedWords => {
-                        let seed_words = core.get_seed_words().await;
-                        core.msg(msg.id, CoreUIMsg::SeedWords(seed_words)).await;
+                    UICoreMsg::GetSeedWords => match core.get_seed_words().await {
+                        Ok(seed_words) => {
+                            core.msg(msg.id, CoreUIMsg::SeedWords(seed_words)).await;
+                        }
+                        Err(e) => {
+                            error!("Could not read seed words: {e}");
+                            core.msg(msg.id, CoreUIMsg::SeedWordsFailed(e.to_string()))
+                                .await;
+                        }
+                    },
+                    UICoreMsg::GetBalanceBreakdown(mint) => {
+                        match core.balance_breakdown(&mint).await {
+                            Ok(breakdown) => {
+                                core.msg(
+                                    msg.id,
+                                    CoreUIMsg::BalanceBreakdownUpdated {
+                                        id: mint,
+                                        breakdown,
+                                    },
+                                )
+                                .await;
+                            }
+                            Err(e) => error!("Could not get balance breakdown: {e}"),
+                        }
+                    }
+                    UICoreMsg::GetSnapshot => match core.snapshot().await {
+                        Ok(snapshot) => {
+                            core.msg(msg.id, CoreUIMsg::Snapshot(snapshot)).await;
+                        }
+                        Err(e) => error!("Could not get snapshot: {e}"),
+                    },
+                    UICoreMsg::GetAggregateBalance => {
+                        core.total_balance(msg.id).await;
+                    }
+                    UICoreMsg::GetTotalFiatValue(currency) => {
+                        core.total_fiat_value(msg.id, &currency).await;
+                    }
+                    UICoreMsg::EstimateLightningFee {
+                        federation_id,
+                        amount,
+                    } => {
+                        let fee = core.estimate_lightning_fee(federation_id, amount).await;
+                        core.msg(msg.id, CoreUIMsg::LightningFeeEstimate { amount, fee })
+                            .await;
+                    }
+                    UICoreMsg::EstimateWithdrawFee {
+                        federation_id,
+                        address,
+                        amount_sats,
+                        fee_rate,
+                    } => {
+                        if let Err(e) = core
+                            .estimate_withdraw_fee(
+                                msg.id,
+                                federation_id,
+                                address,
+                                amount_sats,
+                                fee_rate,
+                            )
+                            .await
+                        {
+                            error!("Could not estimate withdraw fee: {e}");
+                        }
+                    }
+                    UICoreMsg::RefreshBalance(federation_id) => {
+                        if let Err(e) = core.refresh_balance(federation_id).await {
+                            error!("Could not refresh balance: {e}");
+                        }
+                    }
+                    UICoreMsg::SelfTest(federation_id) => {
+                        match core.self_test(federation_id).await {
+                            Ok(report) => {
+                                core.msg(msg.id, CoreUIMsg::SelfTestResult(report)).await;
+                            }
+                            Err(e) => error!("Could not run self test: {e}"),
+                        }
+                    }
+                    UICoreMsg::PingFederation(federation_id) => {
+                        if let Err(e) = core.ping_federation(msg.id, federation_id).await {
+                            error!("Could not ping federation: {e}");
+                        }
+                    }
+                    UICoreMsg::ReconcileHistory(federation_id) => {
+                        match core.reconcile_history(msg.id, federation_id).await {
+                            Ok(summary) => {
+                                core.msg(msg.id, CoreUIMsg::HistoryReconciled(summary))
+                                    .await;
+                            }
+                            Err(e) => error!("Could not reconcile history: {e}"),
+                        }
+                    }
+                    UICoreMsg::RefreshFederationMetadata(federation_id) => {
+                        if let Err(e) = core
+                            .refresh_federation_metadata(msg.id, federation_id)
+                            .await
+                        {
+                            error!("Could not refresh federation metadata: {e}");
+                            core.msg(
+                                msg.id,
+                                CoreUIMsg::FederationMetadataRefreshFailed {
+                                    id: federation_id,
+                                    reason: e.to_string(),
+                                },
+                            )
+                            .await;
+                        }
+                    }
+                    UICoreMsg::RefreshHistory => {
+                        core.refresh_history(msg.id).await;
+                    }
+                    UICoreMsg::GetTransactionHistoryPage(offset) => {
+                        core.get_transaction_history_page(msg.id, offset).await;
+                    }
+                    UICoreMsg::GetInviteCode(federation_id) => {
+                        let invite_code = core.federation_invite_code(federation_id).await;
+                        core.msg(
+                            msg.id,
+                            CoreUIMsg::FederationInviteCode {
+                                id: federation_id,
+                                invite_code,
+                            },
+                        )
+                        .await;
                     }
                     UICoreMsg::SetOnchainReceiveEnabled(enabled) => {
                         match core.set_onchain_receive_enabled(enabled).await {
@@ -830,6 +1070,9 @@ async fn process_core(core_handle: &mut CoreHandle, core: &HarborCore) {
                             }
                         }
                     }
+                    UICoreMsg::SetBackgrounded(backgrounded) => {
+                        core.set_backgrounded(backgrounded);
+                    }
                     UICoreMsg::TestStatusUpdates => {
                         core.test_status_updates(msg.id).await;
                     }
@@ -67,7 +67,7 @@ pub fn h_federation_item_preview(item: &MintItem) -> Element<Message> {
 pub fn h_federation_item(item: &MintItem) -> Element<Message> {
     let mut column = mint_info(&item.name, &item.guardians, &item.metadata, &item.id);
 
-    column = column.push(h_balance_display(item.balance));
+    column = column.push(h_balance_display(item.display_balance));
 
     let remove_button = h_small_button("", SvgIcon::Trash, false).on_press(
         Message::SetConfirmModal(Some(ConfirmModalState {
@@ -79,10 +79,35 @@ pub fn h_federation_item(item: &MintItem) -> Element<Message> {
         })),
     );
 
-    column = column.push(row![
-        horizontal_space().width(Length::Fill),
-        remove_button.width(48)
-    ]);
+    // Only federations can be diagnosed
+    let self_test_button = item.id.federation_id().map(|id| {
+        h_small_button("", SvgIcon::Shield, false)
+            .on_press(Message::SelfTest(id))
+            .width(48)
+    });
+
+    // Lets the user share the federation again, even without the original invite
+    let invite_button = item.id.federation_id().map(|id| {
+        h_small_button("", SvgIcon::Copy, false)
+            .on_press(Message::CopyInviteCode(id))
+            .width(48)
+    });
+
+    // Picks up a new name or icon the federation has set since we joined
+    let refresh_button = item.id.federation_id().map(|id| {
+        h_small_button("", SvgIcon::Restart, false)
+            .on_press(Message::RefreshMetadata(id))
+            .width(48)
+    });
+
+    column = column.push(
+        row![horizontal_space().width(Length::Fill)]
+            .push_maybe(refresh_button)
+            .push_maybe(invite_button)
+            .push_maybe(self_test_button)
+            .push(remove_button.width(48))
+            .spacing(8),
+    );
 
     container(column)
         .padding(16)
@@ -60,7 +60,7 @@ pub fn h_screen_header(
             .spacing(16)
             .width(Length::Shrink)
             .padding(Padding::new(0.).left(16));
-        let formatted_balance = format_amount(item.balance);
+        let formatted_balance = format_amount(item.display_balance);
 
         let balance = row![text(formatted_balance).size(24)]
             .align_y(Alignment::Center)
@@ -6,7 +6,7 @@ use harbor_client::bitcoin::Network;
 use harbor_client::bitcoin::hex::DisplayHex;
 use harbor_client::db_models::MintItem;
 use harbor_client::db_models::transaction_item::{
-    TransactionDirection, TransactionItem, TransactionItemKind,
+    PaymentRoute, TransactionDirection, TransactionItem, TransactionItemKind,
 };
 use harbor_client::fedimint_core::config::FederationId;
 use iced::widget::{column, container, rich_text, row, span, text, vertical_space};
@@ -19,6 +19,7 @@ pub fn h_transaction_details<'a>(
 ) -> Element<'a, Message> {
     let TransactionItem {
         kind,
+        operation_kind: _,
         amount,
         direction,
         mint_identifier,
@@ -26,6 +27,9 @@ pub fn h_transaction_details<'a>(
         status: _,
         txid,
         preimage,
+        imported,
+        route,
+        metadata: _,
     } = item;
 
     // Create title based on type and direction
@@ -34,6 +38,8 @@ pub fn h_transaction_details<'a>(
         (TransactionItemKind::Lightning, TransactionDirection::Outgoing) => "Lightning Send",
         (TransactionItemKind::Onchain, TransactionDirection::Incoming) => "On-chain Receive",
         (TransactionItemKind::Onchain, TransactionDirection::Outgoing) => "On-chain Send",
+        (TransactionItemKind::Ecash, TransactionDirection::Incoming) => "Ecash Receive",
+        (TransactionItemKind::Ecash, TransactionDirection::Outgoing) => "Ecash Send",
     };
 
     let formatted_amount = format_amount(*amount);
@@ -85,6 +91,36 @@ pub fn h_transaction_details<'a>(
 
     let mut details = column![mint_section, amount_section, time_section].spacing(16);
 
+    if *imported {
+        details = details.push(
+            column![
+                text("Source").size(16).style(subtitle),
+                text("Imported from another wallet").size(16)
+            ]
+            .spacing(8),
+        );
+    }
+
+    // Add the gateway the payment went through, shortening the node's pubkey like the preimage
+    if let Some(route) = route {
+        let via = match route {
+            PaymentRoute::Internal => "Internal, no gateway".to_string(),
+            PaymentRoute::Gateway {
+                alias,
+                node_pub_key,
+            } => {
+                let pubkey = node_pub_key.to_string();
+                format!(
+                    "{alias} ({}...{})",
+                    &pubkey[..5],
+                    &pubkey[pubkey.len() - 5..]
+                )
+            }
+        };
+        details = details
+            .push(column![text("Gateway").size(16).style(subtitle), text(via).size(16)].spacing(8));
+    }
+
     // Add TXID if it exists
     if let Some(txid) = txid {
         let base_url = match network {
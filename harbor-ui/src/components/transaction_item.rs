@@ -11,9 +11,14 @@ use iced::{
     widget::{Button, button, column, row, svg, text},
 };
 
-pub fn h_transaction_item(item: &TransactionItem, is_selected: bool) -> Element<Message> {
+pub fn h_transaction_item(
+    item: &TransactionItem,
+    is_selected: bool,
+    show_unconfirmed_deposits: bool,
+) -> Element<Message> {
     let TransactionItem {
         kind,
+        operation_kind: _,
         amount,
         direction,
         timestamp,
@@ -21,10 +26,14 @@ pub fn h_transaction_item(item: &TransactionItem, is_selected: bool) -> Element<
         status,
         txid: _,
         preimage: _,
+        imported: _,
+        route: _,
+        metadata: _,
     } = item;
     let kind_icon = match kind {
         TransactionItemKind::Lightning => map_icon(super::SvgIcon::Bolt, 24., 24.),
         TransactionItemKind::Onchain => map_icon(super::SvgIcon::Chain, 24., 24.),
+        TransactionItemKind::Ecash => map_icon(super::SvgIcon::LeftRight, 24., 24.),
     };
 
     let direction_icon = match direction {
@@ -44,7 +53,13 @@ pub fn h_transaction_item(item: &TransactionItem, is_selected: bool) -> Element<
         && matches!(direction, TransactionDirection::Incoming)
         && matches!(status, PaymentStatus::WaitingConfirmation)
     {
-        format!("{} (Pending)", format_amount(*amount))
+        if show_unconfirmed_deposits {
+            format!("{} (Available, unconfirmed)", format_amount(*amount))
+        } else {
+            format!("{} (Pending)", format_amount(*amount))
+        }
+    } else if matches!(status, PaymentStatus::Refunded) {
+        format!("{} (Refunded)", format_amount(*amount))
     } else {
         format_amount(*amount)
     };
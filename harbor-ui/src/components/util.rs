@@ -1,4 +1,6 @@
 use chrono::{DateTime, Local};
+use harbor_client::fedimint_core::Amount;
+use harbor_client::format::{self, AmountUnit, FormatOptions};
 use iced::Color;
 use palette::{FromColor, Hsl, rgb::Rgb};
 
@@ -42,22 +44,11 @@ pub fn format_timestamp(timestamp: &u64) -> String {
 }
 
 pub fn format_amount(amount: u64) -> String {
-    if amount == 1 {
-        return "1 sat".to_string();
-    }
-    // https://stackoverflow.com/questions/26998485/is-it-possible-to-print-a-number-formatted-with-thousand-separator-in-rust
-    // Rust is a real baby about doing useful things
-    let num = amount
-        .to_string()
-        .as_bytes()
-        .rchunks(3)
-        .rev()
-        .map(std::str::from_utf8)
-        .collect::<Result<Vec<&str>, _>>()
-        .unwrap()
-        .join(",");
-
-    format!("{num} sats")
+    format::format_amount(
+        Amount::from_sats(amount),
+        AmountUnit::Sats,
+        &FormatOptions::default(),
+    )
 }
 
 pub fn truncate_text(input: &str, max_len: usize, center: bool) -> String {
@@ -40,12 +40,18 @@ impl From<serde_json::Error> for ConfigError {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Config {
     pub network: Network,
+    /// Show deposits as available as soon as they are seen, before the federation claims
+    /// them. This only changes what is displayed, the federation still uses its own
+    /// threshold.
+    #[serde(default)]
+    pub show_unconfirmed_deposits: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             network: Network::Signet, // todo change to mainnet when launching
+            show_unconfirmed_deposits: false,
         }
     }
 }
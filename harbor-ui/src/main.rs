@@ -8,15 +8,16 @@ use harbor_client::Bolt11Invoice;
 use harbor_client::bip39::Mnemonic;
 use harbor_client::bitcoin::{Address, Network};
 use harbor_client::cdk::mint_url::MintUrl;
-use harbor_client::db_models::MintItem;
 use harbor_client::db_models::transaction_item::TransactionItem;
+use harbor_client::db_models::{MintItem, ModuleInfo};
+use harbor_client::fedimint_client::{FederationInviteOrId, FederationStatus, FeeRate};
 use harbor_client::fedimint_core::Amount;
-use harbor_client::fedimint_core::core::ModuleKind;
-use harbor_client::fedimint_core::invite_code::InviteCode;
+use harbor_client::fedimint_core::config::FederationId;
 use harbor_client::lightning_address::parse_lnurl;
+use harbor_client::price::FiatAmount;
 use harbor_client::{
-    CoreUIMsg, CoreUIMsgPacket, MintIdentifier, ReceiveSuccessMsg, SendSuccessMsg, UICoreMsg,
-    data_dir,
+    AddFederationFailure, AggregateBalance, BalanceBreakdown, CoreUIMsg, CoreUIMsgPacket,
+    MintIdentifier, ReceiveSuccessMsg, SendSuccessMsg, UICoreMsg, data_dir,
 };
 use iced::Font;
 use iced::Subscription;
@@ -25,9 +26,9 @@ use iced::widget::qr_code::Data;
 use iced::widget::row;
 use iced::{Color, clipboard};
 use iced::{Element, window};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use routes::Route;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -40,6 +41,9 @@ pub mod keyring;
 pub mod lock;
 pub mod routes;
 
+/// The currency balances are valued in on the home screen
+const FIAT_CURRENCY: &str = "USD";
+
 // This starts the program. Importantly, it registers the update and view methods, along with a subscription.
 // We can also run logic during load if we need to.
 pub fn main() -> iced::Result {
@@ -78,6 +82,19 @@ pub fn main() -> iced::Result {
         .run()
 }
 
+/// Treats the window losing focus as the app going to the background
+fn window_focus(
+    event: iced::Event,
+    _status: iced::event::Status,
+    _id: window::Id,
+) -> Option<Message> {
+    match event {
+        iced::Event::Window(window::Event::Focused) => Some(Message::SetBackgrounded(false)),
+        iced::Event::Window(window::Event::Unfocused) => Some(Message::SetBackgrounded(true)),
+        _ => None,
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq)]
 enum SendStatus {
     #[default]
@@ -171,6 +188,8 @@ pub enum Message {
     // Config commands
     ChangeNetwork(Network),
     SetTorEnabled(bool),
+    /// The window lost or gained focus
+    SetBackgrounded(bool),
     // Async commands we fire from the UI to core
     Noop,
     Send(String),
@@ -186,6 +205,11 @@ pub enum Message {
     RejoinMint(MintIdentifier),
     PeekMint(String),
     RemoveMint(MintIdentifier),
+    SelfTest(FederationId),
+    CopyInviteCode(FederationId),
+    RefreshMetadata(FederationId),
+    RetryHistory,
+    LoadMoreHistory,
     ChangeMint(MintIdentifier),
     Donate,
     SetOnchainReceiveEnabled(bool),
@@ -215,9 +239,19 @@ pub struct HarborWallet {
     toasts: Vec<Toast>,
     // Globals
     transaction_history: Vec<TransactionItem>,
+    /// How many transactions there are, more than shown until every page is loaded
+    transaction_history_total: usize,
+    /// Set when the latest history refresh failed, the shown history may be stale
+    history_refresh_failed: bool,
     selected_transaction: Option<TransactionItem>,
     mint_list: Vec<MintItem>,
     active_mint: Option<MintIdentifier>,
+    balance_breakdowns: HashMap<MintIdentifier, BalanceBreakdown>,
+    aggregate_balance: Option<AggregateBalance>,
+    /// Loaded mints that can't currently be reached
+    offline_mints: HashSet<MintIdentifier>,
+    /// Which guardians of each federation answered the latest status probe
+    federation_statuses: HashMap<FederationId, FederationStatus>,
     // Modal
     confirm_modal: Option<ConfirmModalState>,
     basic_modal: Option<BasicModalState>,
@@ -238,6 +272,14 @@ pub struct HarborWallet {
     is_max: bool,
     input_has_amount: bool,
     current_send_id: Option<Uuid>,
+    /// Fee limit to use for the next send, set when a send was refused for its fee
+    send_fee_limit: Option<Amount>,
+    /// Estimated fee for the amount being entered
+    send_fee_estimate: Option<Amount>,
+    /// The fee rate onchain sends are made at
+    send_fee_rate: FeeRate,
+    /// Value of all balances in fiat, as last reported by the core
+    total_fiat_value: Option<FiatAmount>,
     current_receive_id: Option<Uuid>,
     current_transfer_id: Option<Uuid>,
     // Receive
@@ -283,6 +325,14 @@ impl HarborWallet {
             .and_then(|id| self.mint_list.iter().find(|f| &f.id == id))
     }
 
+    fn mint_name(&self, id: &MintIdentifier) -> String {
+        self.mint_list
+            .iter()
+            .find(|f| &f.id == id)
+            .map(|f| f.name.clone())
+            .unwrap_or("Mint".to_string())
+    }
+
     fn next_federation(&self, name: &str) -> MintItem {
         let fed = self
             .mint_list
@@ -297,7 +347,10 @@ impl HarborWallet {
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        Subscription::run(run_core)
+        Subscription::batch([
+            Subscription::run(run_core),
+            iced::event::listen_with(window_focus),
+        ])
     }
 
     // Helper function to handle common UI handle pattern
@@ -347,6 +400,8 @@ impl HarborWallet {
         self.input_has_amount = false;
         self.confirm_modal = None;
         self.current_send_id = None;
+        self.send_fee_limit = None;
+        self.send_fee_estimate = None;
         // We dont' clear the success msg so the history screen can show the most recent
         // transaction
     }
@@ -371,6 +426,39 @@ impl HarborWallet {
         (id, task)
     }
 
+    /// Asks for the fee of a payment or withdrawal of the amount being entered, the estimate
+    /// is cleared until it arrives
+    fn request_fee_estimate(&mut self) -> Task<Message> {
+        self.send_fee_estimate = None;
+        let federation_id = self.active_mint.as_ref().and_then(|m| m.federation_id());
+        let sats = self.send_amount_input_str.parse::<u64>().ok();
+        // onchain sends pay the federation's fee instead of a gateway's
+        if let Ok(address) = Address::from_str(&self.send_dest_input_str) {
+            return match (federation_id, sats) {
+                (Some(federation_id), Some(sats)) if sats > 0 => {
+                    let (_, task) = self.send_from_ui(UICoreMsg::EstimateWithdrawFee {
+                        federation_id,
+                        address,
+                        amount_sats: Some(sats),
+                        fee_rate: self.send_fee_rate,
+                    });
+                    task
+                }
+                _ => Task::none(),
+            };
+        }
+        match (federation_id, sats) {
+            (Some(federation_id), Some(sats)) if sats > 0 => {
+                let (_, task) = self.send_from_ui(UICoreMsg::EstimateLightningFee {
+                    federation_id,
+                    amount: Amount::from_sats(sats),
+                });
+                task
+            }
+            _ => Task::none(),
+        }
+    }
+
     // Helper function to safely remove a toast by index
     fn remove_toast(&mut self, index: usize) {
         if index < self.toasts.len() {
@@ -485,11 +573,11 @@ impl HarborWallet {
                     self.send_amount_input_str = String::from("");
                 }
                 self.send_dest_input_str = input;
-                Task::none()
+                self.request_fee_estimate()
             }
             Message::SendAmountInputChanged(input) => {
                 self.send_amount_input_str = input;
-                Task::none()
+                self.request_fee_estimate()
             }
             Message::SetIsMax(is_max) => {
                 self.is_max = is_max;
@@ -603,14 +691,52 @@ impl HarborWallet {
                         }
                     };
 
+                    let lightning_supported = self
+                        .active_federation()
+                        .is_some_and(|f| f.supports_lightning());
+                    let lightning_unsupported_toast = || {
+                        Task::perform(async {}, |_| {
+                            Message::AddToast(Toast {
+                                title: "Cannot send over lightning".to_string(),
+                                body: Some(
+                                    "This mint does not support lightning payments".to_string(),
+                                ),
+                                status: ToastStatus::Bad,
+                            })
+                        })
+                    };
+
                     if let Ok(invoice) = Bolt11Invoice::from_str(&invoice_str) {
-                        let (id, task) =
-                            self.send_from_ui(UICoreMsg::SendLightning { mint, invoice });
+                        if !lightning_supported {
+                            return lightning_unsupported_toast();
+                        }
+                        // invoices without an amount pay what was entered
+                        let amount = if invoice.amount_milli_satoshis().is_some() {
+                            None
+                        } else {
+                            match self.send_amount_input_str.parse::<u64>() {
+                                Ok(amount) => Some(Amount::from_sats(amount)),
+                                Err(e) => {
+                                    error!("Error parsing amount: {e}");
+                                    self.send_failure_reason = Some(e.to_string());
+                                    return Task::none();
+                                }
+                            }
+                        };
+                        let (id, task) = self.send_from_ui(UICoreMsg::SendLightning {
+                            mint,
+                            invoice,
+                            amount,
+                            fee_limit: self.send_fee_limit.take(),
+                        });
                         self.current_send_id = Some(id);
                         task
                     } else {
                         match parse_lnurl(&invoice_str) {
                             Ok(lnurl) => {
+                                if !lightning_supported {
+                                    return lightning_unsupported_toast();
+                                }
                                 // TODO: can we handle is_max somehow?
                                 let amount = if self.is_max {
                                     return Task::perform(async {}, |_| {
@@ -643,6 +769,22 @@ impl HarborWallet {
                             }
                             _ => {
                                 if let Ok(address) = Address::from_str(&invoice_str) {
+                                    if !self
+                                        .active_federation()
+                                        .is_some_and(|f| f.has_wallet_module())
+                                    {
+                                        self.current_send_id = None;
+                                        return Task::perform(async {}, |_| {
+                                            Message::AddToast(Toast {
+                                                title: "Cannot send on-chain".to_string(),
+                                                body: Some(
+                                                    "This mint does not support on-chain payments"
+                                                        .to_string(),
+                                                ),
+                                                status: ToastStatus::Bad,
+                                            })
+                                        });
+                                    }
                                     let amount = if self.is_max {
                                         None
                                     } else {
@@ -659,6 +801,7 @@ impl HarborWallet {
                                         mint,
                                         address,
                                         amount_sats: amount,
+                                        fee_rate: self.send_fee_rate,
                                     });
                                     self.current_send_id = Some(id);
                                     task
@@ -746,6 +889,28 @@ impl HarborWallet {
                             panic!("No active federation, but we're trying to generate an invoice");
                         }
                     };
+                    if !self
+                        .active_federation()
+                        .is_some_and(|f| f.supports_lightning())
+                    {
+                        return Task::perform(async {}, |_| {
+                            Message::AddToast(Toast {
+                                title: "Failed to generate invoice".to_string(),
+                                body: Some(
+                                    "This mint does not support lightning payments".to_string(),
+                                ),
+                                status: ToastStatus::Bad,
+                            })
+                        });
+                    }
+                    // a pasted LNURL-withdraw is pulled from instead of asking for an amount
+                    if let Ok(lnurl) = parse_lnurl(self.receive_amount_str.trim()) {
+                        let (id, task) =
+                            self.send_from_ui(UICoreMsg::ReceiveLnurlWithdraw { mint, lnurl });
+                        self.current_receive_id = Some(id);
+                        self.receive_failure_reason = None;
+                        return task;
+                    }
                     match self.receive_amount_str.parse::<u64>() {
                         Ok(amount) => {
                             let (id, task) = self.send_from_ui(UICoreMsg::ReceiveLightning {
@@ -855,13 +1020,17 @@ impl HarborWallet {
                     }
                 }
             },
-            Message::AddMint(string) => match InviteCode::from_str(&string) {
-                Ok(invite) => {
+            Message::AddMint(string) => match FederationInviteOrId::from_str(&string) {
+                Ok(FederationInviteOrId::Invite(invite)) => {
                     self.add_federation_status = AddFederationStatus::Adding;
                     let (id, task) = self.send_from_ui(UICoreMsg::AddFederation(invite));
                     self.current_add_id = Some(id);
                     task
                 }
+                // only a mint we joined before can be added by id, with its saved invite code
+                Ok(FederationInviteOrId::Id(id)) => {
+                    self.update(Message::RejoinMint(MintIdentifier::Fedimint(id)))
+                }
                 Err(_) => match MintUrl::from_str(&string) {
                     Ok(mint_url) => {
                         self.add_federation_status = AddFederationStatus::Adding;
@@ -878,8 +1047,8 @@ impl HarborWallet {
                     }),
                 },
             },
-            Message::PeekMint(string) => match InviteCode::from_str(&string) {
-                Ok(invite) => {
+            Message::PeekMint(string) => match FederationInviteOrId::from_str(&string) {
+                Ok(FederationInviteOrId::Invite(invite)) => {
                     if self.mint_list.iter().any(|m| {
                         m.id.federation_id()
                             .is_some_and(|f| f == invite.federation_id())
@@ -898,6 +1067,21 @@ impl HarborWallet {
                     self.current_peek_id = Some(id);
                     task
                 }
+                // there's nothing to preview without an invite code, so go straight to joining
+                // it again, which asks for the invite code if it wasn't saved
+                Ok(FederationInviteOrId::Id(id)) => {
+                    let mint = MintIdentifier::Fedimint(id);
+                    if self.mint_list.iter().any(|m| m.active && m.id == mint) {
+                        return Task::perform(async {}, |_| {
+                            Message::AddToast(Toast {
+                                title: "Mint already added".to_string(),
+                                body: None,
+                                status: ToastStatus::Bad,
+                            })
+                        });
+                    }
+                    self.update(Message::RejoinMint(mint))
+                }
                 Err(_) => match MintUrl::from_str(&string) {
                     Ok(mint) => {
                         if self
@@ -927,6 +1111,29 @@ impl HarborWallet {
                     }),
                 },
             },
+            Message::SelfTest(federation_id) => {
+                let (_, task) = self.send_from_ui(UICoreMsg::SelfTest(federation_id));
+                task
+            }
+            Message::CopyInviteCode(federation_id) => {
+                let (_, task) = self.send_from_ui(UICoreMsg::GetInviteCode(federation_id));
+                task
+            }
+            Message::RefreshMetadata(federation_id) => {
+                let (_, task) =
+                    self.send_from_ui(UICoreMsg::RefreshFederationMetadata(federation_id));
+                task
+            }
+            Message::RetryHistory => {
+                let (_, task) = self.send_from_ui(UICoreMsg::RefreshHistory);
+                task
+            }
+            Message::LoadMoreHistory => {
+                let (_, task) = self.send_from_ui(UICoreMsg::GetTransactionHistoryPage(
+                    self.transaction_history.len(),
+                ));
+                task
+            }
             Message::RemoveMint(mint) => {
                 // Check if the federation still exists before trying to remove it
                 if !self.mint_list.iter().any(|f| f.id == mint) {
@@ -995,6 +1202,14 @@ impl HarborWallet {
                 let (_, task) = self.send_from_ui(UICoreMsg::SetTorEnabled(enabled));
                 task
             }
+            Message::SetBackgrounded(backgrounded) => {
+                // the window can change focus before core is running
+                if self.ui_handle.is_none() {
+                    return Task::none();
+                }
+                let (_, task) = self.send_from_ui(UICoreMsg::SetBackgrounded(backgrounded));
+                task
+            }
             Message::SelectTransaction(transaction) => {
                 self.selected_transaction = transaction;
                 Task::none()
@@ -1045,6 +1260,20 @@ impl HarborWallet {
                         Task::none()
                     }
                 }
+                CoreUIMsg::PaymentAlreadyStarted { operation_id } => {
+                    info!("Payment already started as {operation_id}");
+                    if self.current_send_id == msg.id {
+                        self.send_status = SendStatus::Idle;
+                        self.current_send_id = None;
+                    }
+                    Task::perform(async {}, |_| {
+                        Message::AddToast(Toast {
+                            title: "Payment already started".to_string(),
+                            body: Some("This payment is already being sent".to_string()),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
                 CoreUIMsg::SendFailure(reason) => {
                     if self.current_send_id == msg.id {
                         self.send_status = SendStatus::Idle;
@@ -1059,6 +1288,37 @@ impl HarborWallet {
                         })
                     })
                 }
+                CoreUIMsg::GatewayChanged { gateway, .. } => Task::perform(async {}, move |_| {
+                    Message::AddToast(Toast {
+                        title: "Lightning gateway changed".to_string(),
+                        body: Some(format!(
+                            "Payments now go through gateway {gateway}, fees may differ from before"
+                        )),
+                        status: ToastStatus::Neutral,
+                    })
+                }),
+                CoreUIMsg::FeeExceedsLimit {
+                    best_available_fee,
+                    limit,
+                } => {
+                    if self.current_send_id == msg.id {
+                        self.send_status = SendStatus::Idle;
+                        self.current_send_id = None;
+                        // sending again pays the fee anyway
+                        self.send_fee_limit = Some(best_available_fee);
+                    }
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Fee is above your limit".to_string(),
+                            body: Some(format!(
+                                "The cheapest fee is {} sats, your limit is {} sats. Send again to pay it anyway.",
+                                best_available_fee.sats_round_down(),
+                                limit.sats_round_down()
+                            )),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
                 CoreUIMsg::ReceiveSuccess(params) => {
                     info!("Receive success: {params:?}");
                     if self.current_receive_id == msg.id {
@@ -1122,11 +1382,32 @@ impl HarborWallet {
                         })
                     })
                 }
-                CoreUIMsg::TransactionHistoryUpdated(history) => {
-                    self.transaction_history = history;
+                CoreUIMsg::TransactionHistoryPage {
+                    items,
+                    total,
+                    offset,
+                } => {
+                    // the first page is sent on every change and replaces what's shown,
+                    // later pages are only added if they follow on from it
+                    if offset == 0 {
+                        self.transaction_history = items;
+                    } else if offset == self.transaction_history.len() {
+                        self.transaction_history.extend(items);
+                    }
+                    self.transaction_history_total = total;
+                    self.history_refresh_failed = false;
+                    Task::none()
+                }
+                CoreUIMsg::HistoryRefreshFailed(reason) => {
+                    error!("Could not refresh history: {reason}");
+                    self.history_refresh_failed = true;
                     Task::none()
                 }
-                CoreUIMsg::MintBalanceUpdated { id, balance } => {
+                CoreUIMsg::MintBalanceUpdated {
+                    id,
+                    balance,
+                    display_balance,
+                } => {
                     debug!(
                         "Balance update received - ID: {:?}, Balance: {:?}",
                         id, balance
@@ -1135,8 +1416,69 @@ impl HarborWallet {
                     // Update the balance in the federation list
                     if let Some(federation) = self.mint_list.iter_mut().find(|f| f.id == id) {
                         federation.balance = balance.sats_round_down();
+                        federation.display_balance = display_balance.sats_round_down();
+                        federation.balance_cached = false;
                     }
 
+                    // Pending payments, the total and what everything is worth likely changed too
+                    let (_id, breakdown) = self.send_from_ui(UICoreMsg::GetBalanceBreakdown(id));
+                    let (_id, aggregate) = self.send_from_ui(UICoreMsg::GetAggregateBalance);
+                    let (_id, fiat) =
+                        self.send_from_ui(UICoreMsg::GetTotalFiatValue(FIAT_CURRENCY.to_string()));
+                    Task::batch([breakdown, aggregate, fiat])
+                }
+                CoreUIMsg::LightningFeeEstimate { amount, fee } => {
+                    // ignore estimates for an amount that has since been changed
+                    if self.send_amount_input_str.parse::<u64>().ok()
+                        == Some(amount.sats_round_down())
+                    {
+                        self.send_fee_estimate = fee;
+                    }
+                    Task::none()
+                }
+                CoreUIMsg::WithdrawFeeEstimate { amount, fee } => {
+                    if self.send_amount_input_str.parse::<u64>().ok()
+                        == Some(amount.sats_round_down())
+                    {
+                        self.send_fee_estimate = Some(fee);
+                    }
+                    Task::none()
+                }
+                CoreUIMsg::TotalFiatUpdated {
+                    currency,
+                    value,
+                    reason,
+                } => {
+                    if let Some(reason) = reason {
+                        warn!("Could not value balances in {currency}: {reason}");
+                    }
+                    self.total_fiat_value = value;
+                    Task::none()
+                }
+                CoreUIMsg::Snapshot(snapshot) => {
+                    for (id, balance) in snapshot.balances {
+                        if let Some(mint) = self.mint_list.iter_mut().find(|m| m.id == id) {
+                            mint.balance = balance.sats_round_down();
+                            mint.balance_cached = false;
+                        }
+                    }
+                    for (id, balance) in snapshot.display_balances {
+                        if let Some(mint) = self.mint_list.iter_mut().find(|m| m.id == id) {
+                            mint.display_balance = balance.sats_round_down();
+                        }
+                    }
+                    // the snapshot only has recent history, the first page of it comes with updates
+                    if self.transaction_history.is_empty() {
+                        self.transaction_history = snapshot.recent_history;
+                    }
+                    Task::none()
+                }
+                CoreUIMsg::BalanceBreakdownUpdated { id, breakdown } => {
+                    self.balance_breakdowns.insert(id, breakdown);
+                    Task::none()
+                }
+                CoreUIMsg::AggregateBalanceUpdated(aggregate) => {
+                    self.aggregate_balance = Some(aggregate);
                     Task::none()
                 }
                 CoreUIMsg::ReceiveGenerating => {
@@ -1144,6 +1486,10 @@ impl HarborWallet {
                     Task::none()
                 }
                 CoreUIMsg::ReceiveInvoiceGenerated(invoice) => {
+                    // ignore invoices for a receive that has since been canceled or replaced
+                    if self.current_receive_id != msg.id {
+                        return Task::none();
+                    }
                     self.receive_status = ReceiveStatus::WaitingToReceive;
                     debug!("Received invoice: {invoice}");
                     self.receive_qr_data = Some(
@@ -1167,6 +1513,36 @@ impl HarborWallet {
                         })
                     })
                 }
+                CoreUIMsg::AddFederationFailed { reason, message } => {
+                    let message = message.clone();
+                    self.clear_add_federation_state();
+                    let title = match reason {
+                        AddFederationFailure::Timeout => "Mint did not respond, try again later",
+                        AddFederationFailure::Unreachable => {
+                            "Could not reach the mint, try again later"
+                        }
+                        AddFederationFailure::ConfigInvalid => "Mint sent an invalid config",
+                    };
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: title.to_string(),
+                            body: Some(message.clone()),
+                            status: ToastStatus::Bad,
+                        })
+                    })
+                }
+                CoreUIMsg::NeedInviteCode { id } => {
+                    warn!("Need an invite code to join {id}");
+                    self.clear_add_federation_state();
+                    self.active_route = Route::Mints(routes::MintSubroute::Add);
+                    Task::perform(async {}, |_| {
+                        Message::AddToast(Toast {
+                            title: "Invite code needed".to_string(),
+                            body: Some("Paste the invite code to join this mint again".to_string()),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
                 CoreUIMsg::RemoveFederationFailed(reason) => {
                     let reason = reason.clone();
                     self.clear_add_federation_state();
@@ -1178,6 +1554,21 @@ impl HarborWallet {
                         })
                     })
                 }
+                CoreUIMsg::FederationMetadataUpdated { id, .. } => {
+                    info!("Federation metadata refreshed: {id}");
+                    let (_, task) = self.send_from_ui(UICoreMsg::FederationListNeedsUpdate);
+                    task
+                }
+                CoreUIMsg::FederationMetadataRefreshFailed { id, reason } => {
+                    error!("Could not refresh metadata for {id}: {reason}");
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Could not refresh federation info".to_string(),
+                            body: Some(reason.clone()),
+                            status: ToastStatus::Bad,
+                        })
+                    })
+                }
                 CoreUIMsg::FederationListNeedsUpdate => {
                     let (_, task) = self.send_from_ui(UICoreMsg::FederationListNeedsUpdate);
                     task
@@ -1194,7 +1585,7 @@ impl HarborWallet {
                         }
                     });
 
-                    let (guardians, module_kinds) = match config {
+                    let (guardians, modules) = match config {
                         None => (vec![], vec![]),
                         Some(config) => {
                             let guardians: Vec<String> = config
@@ -1204,13 +1595,9 @@ impl HarborWallet {
                                 .map(|url| url.name.clone())
                                 .collect();
 
-                            let module_kinds = config
-                                .modules
-                                .into_values()
-                                .map(|module_config| module_config.kind().to_owned())
-                                .collect::<Vec<ModuleKind>>();
+                            let modules = ModuleInfo::from_config(&config);
 
-                            (guardians, module_kinds)
+                            (guardians, modules)
                         }
                     };
 
@@ -1218,11 +1605,13 @@ impl HarborWallet {
                         id,
                         name,
                         balance: 0,
+                        display_balance: 0,
                         guardians: Some(guardians),
-                        module_kinds: Some(module_kinds),
+                        modules: Some(modules),
                         metadata,
                         on_chain_supported: false,
                         active: true,
+                        balance_cached: false,
                     };
 
                     self.peek_federation_item = Some(item);
@@ -1256,6 +1645,20 @@ impl HarborWallet {
                         })
                     })
                 }
+                CoreUIMsg::FederationLeft(id) => {
+                    self.mint_list
+                        .retain(|m| m.id != MintIdentifier::Fedimint(id));
+                    self.federation_statuses.remove(&id);
+                    self.active_route = Route::Mints(routes::MintSubroute::List);
+                    self.confirm_modal = None;
+                    Task::perform(async {}, |_| {
+                        Message::AddToast(Toast {
+                            title: "Left federation".to_string(),
+                            body: None,
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
                 CoreUIMsg::MintListUpdated(mut list) => {
                     list.sort();
                     trace!("Updated federation list: {:#?}", list);
@@ -1269,7 +1672,10 @@ impl HarborWallet {
                     self.show_add_a_mint_cta = list.is_empty() && !self.has_navigated_to_mints;
 
                     self.mint_list = list;
-                    Task::none()
+
+                    // a federation was joined or removed, so the total changed
+                    let (_id, task) = self.send_from_ui(UICoreMsg::GetAggregateBalance);
+                    task
                 }
                 CoreUIMsg::ReceiveAddressGenerated(address) => {
                     self.receive_status = ReceiveStatus::WaitingToReceive;
@@ -1284,6 +1690,29 @@ impl HarborWallet {
                     self.receive_address = Some(address);
                     Task::none()
                 }
+                CoreUIMsg::DepositSeen { txid, amount } => {
+                    info!("Deposit seen: {txid}, amount: {amount}");
+                    Task::none()
+                }
+                CoreUIMsg::DepositBelowMinimum { received, minimum } => {
+                    if self.current_receive_id == msg.id {
+                        self.receive_status = ReceiveStatus::Idle;
+                        self.current_receive_id = None;
+                        self.clear_receive_state();
+                    }
+                    let body = format!(
+                        "Received {} sats, but this mint only credits deposits of at least {} sats",
+                        received.sats_round_down(),
+                        minimum.sats_round_down()
+                    );
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Deposit too small".to_string(),
+                            body: Some(body.clone()),
+                            status: ToastStatus::Bad,
+                        })
+                    })
+                }
                 CoreUIMsg::NeedsInit => {
                     info!("Got init message");
                     self.init_status = WelcomeStatus::NeedsInit;
@@ -1339,6 +1768,13 @@ impl HarborWallet {
 
                     Task::none()
                 }
+                CoreUIMsg::SeedWordsFailed(reason) => Task::perform(async {}, move |_| {
+                    Message::AddToast(Toast {
+                        title: "Could not show seed words".to_string(),
+                        body: Some(reason.clone()),
+                        status: ToastStatus::Bad,
+                    })
+                }),
                 CoreUIMsg::OnchainReceiveEnabled(enabled) => {
                     self.onchain_receive_enabled = enabled;
                     Task::perform(async {}, |_| Message::Noop)
@@ -1362,6 +1798,251 @@ impl HarborWallet {
                     self.tor_enabled = tor_enabled;
                     Task::none()
                 }
+                CoreUIMsg::SelfTestResult(report) => {
+                    info!("Self test result: {report:?}");
+                    let title = if report.passed() {
+                        "Mint looks healthy"
+                    } else {
+                        "Mint has problems"
+                    };
+                    let body = report
+                        .checks
+                        .iter()
+                        .map(|c| {
+                            let mark = if c.passed { "OK" } else { "FAILED" };
+                            format!("{mark} {}: {}", c.name, c.detail)
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    let status = if report.passed() {
+                        ToastStatus::Good
+                    } else {
+                        ToastStatus::Bad
+                    };
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: title.to_string(),
+                            body: Some(body.clone()),
+                            status,
+                        })
+                    })
+                }
+                CoreUIMsg::HistoryReconciled(summary) => {
+                    info!("History reconciled: {summary:?}");
+                    let body = if summary.repairs() == 0 {
+                        format!(
+                            "Checked {} transactions, nothing to repair",
+                            summary.checked
+                        )
+                    } else {
+                        format!(
+                            "{} marked successful, {} marked failed, {} marked refunded, {} added",
+                            summary.marked_success,
+                            summary.marked_failed,
+                            summary.marked_refunded,
+                            summary.backfilled
+                        )
+                    };
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "History reconciled".to_string(),
+                            body: Some(body.clone()),
+                            status: ToastStatus::Good,
+                        })
+                    })
+                }
+                CoreUIMsg::FederationInviteCode { id, invite_code } => match invite_code {
+                    Some(invite_code) => {
+                        let invite_code = invite_code.to_string();
+                        Task::perform(async {}, move |_| {
+                            Message::CopyToClipboard(invite_code.clone())
+                        })
+                    }
+                    None => {
+                        error!("Could not build an invite code for {id}");
+                        Task::perform(async {}, |_| {
+                            Message::AddToast(Toast {
+                                title: "Could not get invite code".to_string(),
+                                body: Some(
+                                    "No guardian addresses are known for this mint".to_string(),
+                                ),
+                                status: ToastStatus::Bad,
+                            })
+                        })
+                    }
+                },
+                CoreUIMsg::RecoveryProgress { id, percent } => {
+                    debug!("Recovery of {id} is {percent}% done");
+                    if let Some(msg_id) = msg.id {
+                        self.operation_status.insert(
+                            msg_id,
+                            OperationStatus {
+                                message: format!("Recovering notes: {percent}%"),
+                            },
+                        );
+                    }
+                    Task::none()
+                }
+                CoreUIMsg::StorageWarning {
+                    id,
+                    loaded_bytes,
+                    cap,
+                } => {
+                    warn!("Federation {id} loaded {loaded_bytes} bytes, over the cap of {cap}");
+                    let name = self.mint_name(&MintIdentifier::Fedimint(id));
+                    let megabytes = loaded_bytes / (1024 * 1024);
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: format!("{name} is using a lot of memory"),
+                            body: Some(format!(
+                                "Its data takes up {megabytes} MB, consider consolidating it"
+                            )),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::ModuleUnavailable { id, kind, reason } => {
+                    warn!("Federation {id} module {kind} unavailable: {reason}");
+                    let name = self.mint_name(&MintIdentifier::Fedimint(id));
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: format!("Some features of {name} are unavailable"),
+                            body: Some(format!("The {kind} module could not be loaded")),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::FederationUnavailable(id) => {
+                    warn!("Federation unavailable: {id}");
+                    let name = self.mint_name(&MintIdentifier::Fedimint(id));
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: format!("{name} is unavailable"),
+                            body: Some("Could not connect, retrying in the background".to_string()),
+                            status: ToastStatus::Bad,
+                        })
+                    })
+                }
+                CoreUIMsg::FederationRecovered(id) => {
+                    info!("Federation recovered: {id}");
+                    let name = self.mint_name(&MintIdentifier::Fedimint(id));
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: format!("{name} is available again"),
+                            body: None,
+                            status: ToastStatus::Good,
+                        })
+                    })
+                }
+                CoreUIMsg::FederationConnectivity { id, online } => {
+                    let mint = MintIdentifier::Fedimint(id);
+                    if online {
+                        info!("Federation online: {id}");
+                        self.offline_mints.remove(&mint);
+                    } else {
+                        warn!("Federation offline: {id}");
+                        self.offline_mints.insert(mint);
+                    }
+                    Task::none()
+                }
+                CoreUIMsg::FederationStatusUpdated(id, status) => {
+                    trace!("Federation {id} status: {:?}", status.health);
+                    self.federation_statuses.insert(id, status);
+                    Task::none()
+                }
+                CoreUIMsg::PaymentRefunded {
+                    operation_id,
+                    amount,
+                } => {
+                    info!("Payment {} was refunded {amount}", operation_id.fmt_short());
+                    // the refund ends the payment, so it no longer counts as sending
+                    if self.current_send_id == msg.id {
+                        self.send_status = SendStatus::Idle;
+                        self.current_send_id = None;
+                    }
+                    if self.current_transfer_id == msg.id {
+                        self.transfer_status = SendStatus::Idle;
+                    }
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Payment refunded".to_string(),
+                            body: Some(format!(
+                                "The payment failed and {} sats are back in your balance",
+                                amount.sats_round_down()
+                            )),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::EcashReclaimed {
+                    federation_id,
+                    amount,
+                } => {
+                    info!("Reclaimed {amount} of unredeemed ecash from {federation_id}");
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Ecash reclaimed".to_string(),
+                            body: Some(format!(
+                                "{} sats weren't redeemed in time and are back in your balance",
+                                amount.sats_round_down()
+                            )),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::ReceiveBatch { count, total } => {
+                    info!("Received {count} payments totalling {total}");
+                    Task::none()
+                }
+                CoreUIMsg::ReceiveAmountMismatch {
+                    requested,
+                    received,
+                } => {
+                    warn!("Received {received} but requested {requested}");
+                    Task::perform(async {}, move |_| {
+                        Message::AddToast(Toast {
+                            title: "Received a different amount".to_string(),
+                            body: Some(format!(
+                                "Requested {} sats but received {} sats",
+                                requested.sats_round_down(),
+                                received.sats_round_down()
+                            )),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::WithdrawStuck { operation_id } => {
+                    warn!("Withdrawal is stuck: {}", operation_id.fmt_full());
+                    Task::perform(async {}, |_| {
+                        Message::AddToast(Toast {
+                            title: "Withdrawal is taking a while".to_string(),
+                            body: Some(
+                                "It has not completed yet, fees may be too low right now"
+                                    .to_string(),
+                            ),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::PaymentStalled { operation_id } => {
+                    warn!("Payment has stalled: {}", operation_id.fmt_full());
+                    Task::perform(async {}, |_| {
+                        Message::AddToast(Toast {
+                            title: "Payment is taking a while".to_string(),
+                            body: Some(
+                                "No updates have arrived, it will finish once the mint responds"
+                                    .to_string(),
+                            ),
+                            status: ToastStatus::Neutral,
+                        })
+                    })
+                }
+                CoreUIMsg::SendProgress { msg_id, state }
+                | CoreUIMsg::ReceiveProgress { msg_id, state } => {
+                    self.operation_status
+                        .insert(msg_id, OperationStatus { message: state });
+                    Task::none()
+                }
                 CoreUIMsg::StatusUpdate {
                     message,
                     operation_id,
@@ -1,6 +1,8 @@
 use iced::{Element, Length};
 
-use crate::components::{basic_layout, h_header, h_transaction_details, h_transaction_item, hr};
+use crate::components::{
+    SvgIcon, basic_layout, h_button, h_header, h_transaction_details, h_transaction_item, hr,
+};
 use crate::{HarborWallet, Message};
 use iced::widget::{column, horizontal_space, row, stack, text};
 
@@ -20,12 +22,32 @@ pub fn history(harbor: &HarborWallet) -> Element<Message> {
                     .map(|selected| selected == item)
                     .unwrap_or(false);
                 column
-                    .push(h_transaction_item(item, is_selected))
+                    .push(h_transaction_item(
+                        item,
+                        is_selected,
+                        harbor.config.show_unconfirmed_deposits,
+                    ))
                     .push(hr())
             })
             .spacing(16)
     };
-    let left_column = column![header, transactions].spacing(48);
+    let load_more =
+        (harbor.transaction_history.len() < harbor.transaction_history_total).then(|| {
+            h_button("Load more", SvgIcon::ChevronDown, false).on_press(Message::LoadMoreHistory)
+        });
+    let refresh_failed = harbor.history_refresh_failed.then(|| {
+        column![
+            text("Couldn't load the latest history.").size(18),
+            h_button("Retry", SvgIcon::Restart, false).on_press(Message::RetryHistory),
+        ]
+        .spacing(16)
+    });
+
+    let left_column = column![header]
+        .push_maybe(refresh_failed)
+        .push(transactions)
+        .push_maybe(load_more)
+        .spacing(48);
 
     let content = basic_layout(left_column);
     let mut layers = stack![content];
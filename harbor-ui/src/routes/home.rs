@@ -9,9 +9,68 @@ use super::Route;
 pub fn home(harbor: &HarborWallet) -> Element<Message> {
     let formatted_balance = harbor
         .active_federation()
-        .map_or_else(|| format_amount(0), |f| format_amount(f.balance));
+        .map_or_else(|| format_amount(0), |f| format_amount(f.display_balance));
 
     let balance = text(formatted_balance).size(64);
+
+    // Every federation's balance together, only worth showing with more than one
+    let aggregate = harbor
+        .aggregate_balance
+        .as_ref()
+        .filter(|a| a.federations.len() > 1)
+        .map(|a| {
+            let stale = if a.federations.values().any(|f| f.stale) {
+                " (some balances may be out of date)"
+            } else {
+                ""
+            };
+            text(format!(
+                "{} across all mints{stale}",
+                format_amount(a.total.sats_round_down())
+            ))
+            .size(16)
+        });
+
+    // What every mint's balance together is worth
+    let fiat_value = harbor.total_fiat_value.as_ref().map(|fiat| {
+        let stale = if fiat.stale_reason.is_some() {
+            " (last known price)"
+        } else {
+            ""
+        };
+        text(format!(
+            "≈ {:.2} {} in total{stale}",
+            fiat.value, fiat.currency
+        ))
+        .size(16)
+    });
+
+    // The client hasn't loaded yet so this is the balance from last time
+    let cached = harbor
+        .active_federation()
+        .filter(|f| f.balance_cached)
+        .map(|_| text("Last known balance, updating...").size(16));
+
+    let offline = harbor
+        .active_mint
+        .as_ref()
+        .filter(|id| harbor.offline_mints.contains(id))
+        .map(|_| text("Offline, reconnecting...").size(16));
+
+    // Show what is still in flight so it's clear why the balance may not add up
+    let pending = harbor
+        .active_mint
+        .as_ref()
+        .and_then(|id| harbor.balance_breakdowns.get(id))
+        .filter(|b| b.pending_incoming.msats > 0 || b.pending_outgoing.msats > 0)
+        .map(|b| {
+            text(format!(
+                "{} incoming, {} outgoing pending",
+                format_amount(b.pending_incoming.sats_round_down()),
+                format_amount(b.pending_outgoing.sats_round_down())
+            ))
+            .size(16)
+        });
     let send_disabled = harbor.active_federation().is_none_or(|f| f.balance == 0);
     let receive_disabled = harbor.active_federation().is_none();
     let send_button = h_button("Send", SvgIcon::UpRight, false);
@@ -34,7 +93,13 @@ pub fn home(harbor: &HarborWallet) -> Element<Message> {
     column![
         h_screen_header(harbor, false, false),
         container(center(
-            column![balance, buttons]
+            column![balance]
+                .push_maybe(aggregate)
+                .push_maybe(fiat_value)
+                .push_maybe(cached)
+                .push_maybe(offline)
+                .push_maybe(pending)
+                .push(buttons)
                 .spacing(32)
                 .align_x(Alignment::Center)
                 .max_width(512)
@@ -14,10 +14,11 @@ use super::{MintSubroute, Route};
 fn mints_list(harbor: &HarborWallet) -> Element<Message> {
     let header = h_header("Mints", "Manage your mints here.");
 
+    // federations that haven't loaded yet are inactive but haven't been left
     let active = harbor
         .mint_list
         .iter()
-        .filter(|a| a.active)
+        .filter(|a| a.active || a.balance_cached)
         .fold(column![], |column, item| {
             column.push(h_federation_item(item))
         })
@@ -26,7 +27,7 @@ fn mints_list(harbor: &HarborWallet) -> Element<Message> {
     let inactive = harbor
         .mint_list
         .iter()
-        .filter(|a| !a.active)
+        .filter(|a| !a.active && !a.balance_cached)
         .fold(column![], |column, item| {
             column.push(h_federation_archived(item, harbor))
         })
@@ -36,7 +37,11 @@ fn mints_list(harbor: &HarborWallet) -> Element<Message> {
         .on_press(Message::Navigate(Route::Mints(MintSubroute::Add)));
 
     // if we have inactive mints, display them
-    let column = if harbor.mint_list.iter().filter(|a| !a.active).count() > 0 {
+    let column = if harbor
+        .mint_list
+        .iter()
+        .any(|a| !a.active && !a.balance_cached)
+    {
         let archived_header = h_header("Archived Mints", "Mints you've joined and left.");
         column![
             header,
@@ -114,7 +119,11 @@ fn mints_add(harbor: &HarborWallet) -> Element<Message> {
 }
 
 pub fn mints(harbor: &HarborWallet) -> Element<Message> {
-    if harbor.mint_list.iter().filter(|f| f.active).count() == 0 {
+    if !harbor
+        .mint_list
+        .iter()
+        .any(|f| f.active || f.balance_cached)
+    {
         mints_add(harbor)
     } else {
         match harbor.active_route {
@@ -24,12 +24,15 @@ pub fn receive(harbor: &HarborWallet) -> Element<Message> {
 
 /// Renders the view before an invoice/address is generated.
 fn render_receive_form(harbor: &HarborWallet) -> Element<Message> {
-    // for on-chain to be shown, it needs to be a federation and either
-    // the user turned on on-chain receive or the federation supports it
+    // for on-chain to be shown, it needs to be a federation with a wallet module and
+    // either the user turned on on-chain receive or the federation supports it
     let on_chain_enabled = harbor
         .active_mint
         .as_ref()
         .is_some_and(|a| a.federation_id().is_some())
+        && harbor
+            .active_federation()
+            .is_some_and(|x| x.has_wallet_module())
         && (harbor.onchain_receive_enabled
             || harbor
                 .active_federation()
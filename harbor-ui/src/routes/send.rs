@@ -1,9 +1,9 @@
 use iced::Element;
-use iced::widget::{column, row};
+use iced::widget::{column, row, text};
 
 use crate::components::{
-    ConfirmModalState, InputArgs, SvgIcon, basic_layout, h_button, h_checkbox, h_header, h_input,
-    h_screen_header, operation_status_for_id,
+    ConfirmModalState, InputArgs, SvgIcon, basic_layout, format_amount, h_button, h_checkbox,
+    h_header, h_input, h_screen_header, operation_status_for_id, subtitle,
 };
 use crate::{HarborWallet, Message, SendStatus};
 
@@ -29,6 +29,18 @@ pub fn send(harbor: &HarborWallet) -> Element<Message> {
         ..InputArgs::default()
     });
 
+    let fee_estimate = harbor
+        .send_fee_estimate
+        .filter(|_| !harbor.is_max)
+        .map(|fee| {
+            text(format!(
+                "Estimated fee: {}",
+                format_amount(fee.sats_round_down())
+            ))
+            .size(16)
+            .style(subtitle)
+        });
+
     let send_button = h_button(
         "Send",
         SvgIcon::UpRight,
@@ -67,14 +79,11 @@ pub fn send(harbor: &HarborWallet) -> Element<Message> {
         button_and_status = button_and_status.push(status).spacing(16);
     }
 
-    let content = column![
-        header,
-        dest_input,
-        amount_input,
-        checkbox,
-        button_and_status
-    ]
-    .spacing(48);
+    let content = column![header, dest_input, amount_input]
+        .push_maybe(fee_estimate)
+        .push(checkbox)
+        .push(button_and_status)
+        .spacing(48);
 
     column![h_screen_header(harbor, true, false), basic_layout(content)].into()
 }